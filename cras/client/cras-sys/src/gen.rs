@@ -333,6 +333,7 @@ pub const ionode_attr_IONODE_ATTR_PLUGGED: ionode_attr = 0;
 pub const ionode_attr_IONODE_ATTR_VOLUME: ionode_attr = 1;
 pub const ionode_attr_IONODE_ATTR_CAPTURE_GAIN: ionode_attr = 2;
 pub const ionode_attr_IONODE_ATTR_SWAP_LEFT_RIGHT: ionode_attr = 3;
+pub const ionode_attr_IONODE_ATTR_SPEAKER_PROTECTED: ionode_attr = 4;
 pub type ionode_attr = u32;
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
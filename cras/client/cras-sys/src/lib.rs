@@ -38,6 +38,7 @@ unsafe impl data_model::DataInit for gen::cras_dump_audio_thread {}
 unsafe impl data_model::DataInit for gen::cras_iodev_info {}
 unsafe impl data_model::DataInit for gen::cras_ionode_info {}
 unsafe impl data_model::DataInit for gen::cras_server_state {}
+unsafe impl data_model::DataInit for gen::cras_set_node_attr {}
 unsafe impl data_model::DataInit for gen::cras_set_system_mute {}
 unsafe impl data_model::DataInit for gen::cras_set_system_volume {}
 
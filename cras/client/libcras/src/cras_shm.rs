@@ -579,7 +579,7 @@ impl<'a> CrasServerState<'a> {
         let state_version = unsafe { vref_from_addr!(addr, state_version) };
         if state_version.load() != CRAS_SERVER_STATE_VERSION {
             return Err(io::Error::new(
-                io::ErrorKind::Other,
+                io::ErrorKind::InvalidData,
                 format!(
                     "CrasServerState version {} does not match expected version {}",
                     state_version.load(),
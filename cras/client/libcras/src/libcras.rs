@@ -309,6 +309,36 @@ impl<'a> CrasClient<'a> {
         Ok(())
     }
 
+    /// Marks the node `ionode_index` of the device `iodev_index` as protected
+    /// by the calibrated speaker protection or not.
+    ///
+    /// Send a message to the server to request setting the speaker protected
+    /// attribute of the node. No response is returned from the server.
+    ///
+    /// # Errors
+    ///
+    /// If writing the message to the server socket failed.
+    pub fn set_node_speaker_protected(
+        &mut self,
+        iodev_index: u32,
+        ionode_index: u32,
+        protected: bool,
+    ) -> Result<()> {
+        let header = cras_server_message {
+            length: mem::size_of::<cras_set_node_attr>() as u32,
+            id: CRAS_SERVER_MESSAGE_ID::CRAS_SERVER_SET_NODE_ATTR,
+        };
+        let msg = cras_set_node_attr {
+            header,
+            node_id: (u64::from(iodev_index) << 32) | u64::from(ionode_index),
+            attr: ionode_attr_IONODE_ATTR_SPEAKER_PROTECTED,
+            value: protected as i32,
+        };
+
+        self.server_socket.send_server_message_with_fds(&msg, &[])?;
+        Ok(())
+    }
+
     /// Gets the system volume.
     ///
     /// Read the current value for system volume from the server shared memory.
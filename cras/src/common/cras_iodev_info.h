@@ -68,12 +68,15 @@ struct __attribute__((__packed__)) cras_ionode_info {
  *    IONODE_ATTR_VOLUME - set the node's output volume.
  *    IONODE_ATTR_CAPTURE_GAIN - set the node's capture gain.
  *    IONODE_ATTR_SWAP_LEFT_RIGHT - Swap the node's left and right channel.
+ *    IONODE_ATTR_SPEAKER_PROTECTED - Mark the node as protected by the
+ *        calibrated speaker protection, set by sound_card_init.
  */
 enum ionode_attr {
 	IONODE_ATTR_PLUGGED,
 	IONODE_ATTR_VOLUME,
 	IONODE_ATTR_CAPTURE_GAIN,
-	IONODE_ATTR_SWAP_LEFT_RIGHT
+	IONODE_ATTR_SWAP_LEFT_RIGHT,
+	IONODE_ATTR_SPEAKER_PROTECTED
 };
 
 #endif /* CRAS_IODEV_INFO_H_ */
@@ -102,6 +102,9 @@ enum CRAS_IODEV_STATE {
  *        client.
  *    ui_gain_scaler - The adjustable gain scaler set by client.
  *    left_right_swapped - If left and right output channels are swapped.
+ *    speaker_protected - If the speaker is protected by the calibrated speaker
+ *        protection, which tells the uncalibrated devices apart for the volume
+ *        policy.
  *    type - Type displayed to the user.
  *    position - Specify where on the system this node locates.
  *    name - Name displayed to the user.
@@ -125,6 +128,7 @@ struct cras_ionode {
 	long capture_gain;
 	float ui_gain_scaler;
 	int left_right_swapped;
+	int speaker_protected;
 	enum CRAS_NODE_TYPE type;
 	enum CRAS_NODE_POSITION position;
 	char name[CRAS_NODE_NAME_BUFFER_SIZE];
@@ -1681,6 +1681,21 @@ static int set_node_left_right_swapped(struct cras_iodev *iodev,
 	return 0;
 }
 
+static int set_node_speaker_protected(struct cras_iodev *iodev,
+				      unsigned int node_idx, int protected)
+{
+	struct cras_ionode *node;
+
+	node = find_node(iodev, node_idx);
+	if (!node)
+		return -EINVAL;
+
+	syslog(LOG_INFO, "Speaker protection of node %s: %d", node->name,
+	       !!protected);
+	node->speaker_protected = !!protected;
+	return 0;
+}
+
 int cras_iodev_list_set_node_attr(cras_node_id_t node_id, enum ionode_attr attr,
 				  int value)
 {
@@ -1706,6 +1721,10 @@ int cras_iodev_list_set_node_attr(cras_node_id_t node_id, enum ionode_attr attr,
 		rc = set_node_left_right_swapped(iodev, node_index_of(node_id),
 						 value);
 		break;
+	case IONODE_ATTR_SPEAKER_PROTECTED:
+		rc = set_node_speaker_protected(iodev, node_index_of(node_id),
+						value);
+		break;
 	default:
 		return -EINVAL;
 	}
@@ -1418,6 +1418,32 @@ TEST_F(IoDevTestSuite, SetNodeSwapLeftRight) {
   cras_iodev_list_deinit();
 }
 
+TEST_F(IoDevTestSuite, SetNodeSpeakerProtected) {
+  int rc;
+
+  cras_iodev_list_init();
+
+  rc = cras_iodev_list_add_output(&d1_);
+  ASSERT_EQ(0, rc);
+  node1.idx = 1;
+  node1.dev = &d1_;
+
+  rc = cras_iodev_list_set_node_attr(cras_make_node_id(d1_.info.idx, 1),
+                                     IONODE_ATTR_SPEAKER_PROTECTED, 1);
+  EXPECT_EQ(0, rc);
+  EXPECT_EQ(1, node1.speaker_protected);
+
+  rc = cras_iodev_list_set_node_attr(cras_make_node_id(d1_.info.idx, 1),
+                                     IONODE_ATTR_SPEAKER_PROTECTED, 0);
+  EXPECT_EQ(0, rc);
+  EXPECT_EQ(0, node1.speaker_protected);
+
+  rc = cras_iodev_list_set_node_attr(cras_make_node_id(d1_.info.idx, 2),
+                                     IONODE_ATTR_SPEAKER_PROTECTED, 1);
+  EXPECT_EQ(-EINVAL, rc);
+  cras_iodev_list_deinit();
+}
+
 TEST_F(IoDevTestSuite, AddActiveNode) {
   int rc;
   struct cras_rstream rstream;
@@ -26,14 +26,30 @@ pub fn impl_control_ops(ast: &syn::DeriveInput) -> TokenStream {
             .parse()
             .expect("failed to create a default path for derive macro: ControlOps"),
     };
+    // The lifetime of `Ctl` is the generic parameter of `Control` and `ControlOps`. The other
+    // generic parameters, ex: the number of value entries, only belong to the control structure.
+    let trait_generics = match ast.generics.lifetimes().next() {
+        Some(lifetime) => {
+            let lifetime = &lifetime.lifetime;
+            quote! { <#lifetime> }
+        }
+        None => quote! {},
+    };
     let gen = quote! {
-        impl #impl_generics #path::ControlOps #impl_generics for #name #ty_generics #where_clause {
-            fn load(&mut self) -> ::std::result::Result<<Self as #path::Control #impl_generics>::Item, #path::ControlError> {
+        impl #impl_generics #path::ControlOps #trait_generics for #name #ty_generics #where_clause {
+            fn load(&mut self) -> ::std::result::Result<<Self as #path::Control #trait_generics>::Item, #path::ControlError> {
                 Ok(<Self as #path::Control>::Item::load(self.handle, &self.id)?)
             }
-            fn save(&mut self, val: <Self as #path::Control #impl_generics>::Item) -> ::std::result::Result<bool, #path::ControlError> {
+            fn save(&mut self, val: <Self as #path::Control #trait_generics>::Item) -> ::std::result::Result<bool, #path::ControlError> {
                 Ok(<Self as #path::Control>::Item::save(self.handle, &self.id, val)?)
             }
+            fn wait_for(&mut self, expected: <Self as #path::Control #trait_generics>::Item, timeout: ::std::time::Duration) -> ::std::result::Result<(), #path::ControlError>
+            where
+                <Self as #path::Control #trait_generics>::Item: ::std::cmp::PartialEq,
+            {
+                #path::elem::wait_for::<<Self as #path::Control>::Item, _>(self.handle, &self.id, |val| *val == expected, timeout)?;
+                Ok(())
+            }
         }
     };
     gen.into()
@@ -0,0 +1,386 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It provides `AsyncCard`, which waits for the control values from async code.
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc::{pollfd, POLLIN};
+
+use crate::card::{Card, Error, Result};
+use crate::elem::{Elem, ElemWait, Subscription};
+
+/// `AsyncCard` wraps a `Card` to wait for the control values from async code.
+///
+/// `wait_for()` is driven by the poll descriptor of the ctl handle. The descriptors of all the
+/// pending waits are polled by a single reactor thread, which wakes the task once events arrive
+/// or the timeout elapses. Therefore, it does not depend on a specific async runtime.
+///
+/// The control reads and writes are ioctls which do not wait for the hardware, so they are
+/// accessed synchronously through `card()`.
+pub struct AsyncCard {
+    card: Card,
+}
+
+impl AsyncCard {
+    /// Creates an `AsyncCard`.
+    pub fn new(card: Card) -> Self {
+        AsyncCard { card }
+    }
+
+    /// Borrows the inner `Card`.
+    pub fn card(&mut self) -> &mut Card {
+        &mut self.card
+    }
+
+    /// Consumes the `AsyncCard` and returns the inner `Card`.
+    pub fn into_inner(self) -> Card {
+        self.card
+    }
+
+    /// The async version of `Card::wait_for()`. If the future is dropped before it completes,
+    /// the ctl handle is unsubscribed from the events.
+    ///
+    /// # Examples
+    ///
+    /// ``` no_run
+    /// # use std::time::Duration;
+    /// # use cros_alsa::{AsyncCard, CardError};
+    /// # async fn wait(card: &mut AsyncCard) -> Result<(), CardError> {
+    /// card.wait_for::<[bool; 1], _>("Calibration Done", |v| v[0], Duration::from_secs(1))
+    ///     .await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// * The errors of `Card::wait_for()`.
+    /// * If it fails to poll the ctl handle.
+    pub async fn wait_for<E, F>(
+        &mut self,
+        control_name: &str,
+        predicate: F,
+        timeout: Duration,
+    ) -> Result<E::T>
+    where
+        E: Elem,
+        F: Fn(&E::T) -> bool,
+    {
+        let id = self.card.elem_id::<E>(control_name)?;
+        let (handle, mut on_event) = self.card.event_handle();
+        let fd = handle.poll_descriptor()?;
+        let mut subscription = Subscription::new(handle)?;
+        let mut wait = ElemWait::<E, F>::new(&id, predicate, timeout);
+        let res = loop {
+            match wait.poll(&mut subscription, &mut on_event) {
+                Ok(Some(val)) => break Ok(val),
+                Ok(None) => {}
+                Err(e) => break Err(e.into()),
+            }
+            if let Err(e) = Readable::new(fd, wait.deadline()).await {
+                break Err(e);
+            }
+        };
+        subscription.unsubscribe()?;
+        res
+    }
+}
+
+// A wait registered to the reactor.
+struct Waiter {
+    fd: RawFd,
+    deadline: Instant,
+    waker: Waker,
+    // The outcome of the wait, which is set by the reactor. It keeps the raw os error because
+    // io::Error is not Clone.
+    result: Option<std::result::Result<(), i32>>,
+}
+
+#[derive(Default)]
+struct Waiters {
+    next_key: u64,
+    waiters: BTreeMap<u64, Waiter>,
+}
+
+// The reactor thread polls the descriptors of the registered waits, and the eventfd which the
+// tasks signal when they change the registrations.
+struct Reactor {
+    waiters: Mutex<Waiters>,
+    event: File,
+}
+
+impl Reactor {
+    // Returns the process-wide reactor, which starts the reactor thread on the first use.
+    fn get() -> io::Result<&'static Reactor> {
+        static REACTOR: OnceLock<Reactor> = OnceLock::new();
+        if let Some(reactor) = REACTOR.get() {
+            return Ok(reactor);
+        }
+        // Safe because eventfd() does not access any memory.
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because fd is a new file descriptor exclusively owned by the `File`.
+        let event = unsafe { File::from_raw_fd(fd) };
+        let mut started = false;
+        let reactor = REACTOR.get_or_init(|| {
+            started = true;
+            Reactor {
+                waiters: Mutex::new(Waiters::default()),
+                event,
+            }
+        });
+        if started {
+            thread::Builder::new()
+                .name("cros_alsa_reactor".to_owned())
+                .spawn(move || reactor.run())?;
+        }
+        Ok(reactor)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Waiters> {
+        self.waiters.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Registers a wait and returns its key.
+    fn register(&self, fd: RawFd, deadline: Instant, waker: Waker) -> u64 {
+        let mut waiters = self.lock();
+        let key = waiters.next_key;
+        waiters.next_key += 1;
+        waiters.waiters.insert(
+            key,
+            Waiter {
+                fd,
+                deadline,
+                waker,
+                result: None,
+            },
+        );
+        drop(waiters);
+        self.notify();
+        key
+    }
+
+    fn deregister(&self, key: u64) {
+        // The reactor ignores the outcome of a stale poll of the removed wait, so it needs no
+        // notification.
+        self.lock().waiters.remove(&key);
+    }
+
+    // Interrupts the poll of the reactor thread so that it polls the new registrations.
+    fn notify(&self) {
+        // The write only fails if the counter is about to overflow, which still interrupts the
+        // poll.
+        let _ = (&self.event).write(&1u64.to_ne_bytes());
+    }
+
+    fn run(&self) {
+        loop {
+            let mut keys = Vec::new();
+            let mut pfds = vec![pollfd {
+                fd: self.event.as_raw_fd(),
+                events: POLLIN,
+                revents: 0,
+            }];
+            let mut deadline = None;
+            for (key, waiter) in self.lock().waiters.iter() {
+                if waiter.result.is_some() {
+                    continue;
+                }
+                keys.push(*key);
+                pfds.push(pollfd {
+                    fd: waiter.fd,
+                    events: POLLIN,
+                    revents: 0,
+                });
+                deadline =
+                    Some(deadline.map_or(waiter.deadline, |d: Instant| d.min(waiter.deadline)));
+            }
+
+            let timeout_ms = match deadline {
+                // Rounds up the timeout so that the reactor does not wake up before the deadline.
+                Some(deadline) => deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_micros()
+                    .div_ceil(1000)
+                    .min(i32::MAX as u128) as i32,
+                None => -1,
+            };
+            // Safe because pfds is a valid array of pollfd and nfds is its length.
+            let rc =
+                unsafe { libc::poll(pfds.as_mut_ptr(), pfds.len() as libc::nfds_t, timeout_ms) };
+            let err = if rc < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                err.raw_os_error()
+            } else {
+                None
+            };
+
+            if pfds[0].revents != 0 {
+                let mut buf = [0u8; 8];
+                let _ = (&self.event).read(&mut buf);
+            }
+            let now = Instant::now();
+            let mut waiters = self.lock();
+            for (key, pfd) in keys.iter().zip(&pfds[1..]) {
+                let waiter = match waiters.waiters.get_mut(key) {
+                    Some(waiter) => waiter,
+                    None => continue,
+                };
+                waiter.result = match err {
+                    Some(err) => Some(Err(err)),
+                    None if pfd.revents != 0 || now >= waiter.deadline => Some(Ok(())),
+                    None => continue,
+                };
+                waiter.waker.wake_by_ref();
+            }
+        }
+    }
+}
+
+// A future which is resolved when the poll descriptor becomes readable or the deadline passes.
+struct Readable {
+    fd: RawFd,
+    deadline: Instant,
+    key: Option<(&'static Reactor, u64)>,
+}
+
+impl Readable {
+    fn new(fd: RawFd, deadline: Instant) -> Self {
+        Readable {
+            fd,
+            deadline,
+            key: None,
+        }
+    }
+}
+
+impl Future for Readable {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let (reactor, key) = match self.key {
+            Some(key) => key,
+            None => {
+                let reactor = Reactor::get().map_err(Error::PollFailed)?;
+                let key = reactor.register(self.fd, self.deadline, cx.waker().clone());
+                self.key = Some((reactor, key));
+                return Poll::Pending;
+            }
+        };
+        let mut waiters = reactor.lock();
+        let waiter = match waiters.waiters.get_mut(&key) {
+            Some(waiter) => waiter,
+            None => return Poll::Ready(Ok(())),
+        };
+        match waiter.result {
+            Some(res) => {
+                Poll::Ready(res.map_err(|e| Error::PollFailed(io::Error::from_raw_os_error(e))))
+            }
+            None => {
+                if !waiter.waker.will_wake(cx.waker()) {
+                    waiter.waker = cx.waker().clone();
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Readable {
+    fn drop(&mut self) {
+        if let Some((reactor, key)) = self.key {
+            reactor.deregister(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    struct CountWaker(AtomicUsize);
+
+    impl Wake for CountWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // Polls the future until it is ready.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let count = Arc::new(CountWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(count.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            if let Poll::Ready(res) = future.as_mut().poll(&mut cx) {
+                return res;
+            }
+            // Waits for the reactor to wake the task up.
+            while count.0.swap(0, Ordering::SeqCst) == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    fn pipe() -> (File, File) {
+        let mut fds = [0; 2];
+        // Safe because fds has the space of two file descriptors.
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        // Safe because the file descriptors are exclusively owned by the `File`.
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+    }
+
+    #[test]
+    fn readable_times_out() {
+        let (reader, _writer) = pipe();
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(20);
+        block_on(Readable::new(reader.as_raw_fd(), deadline)).unwrap();
+        assert!(Instant::now() >= deadline);
+    }
+
+    #[test]
+    fn readable_wakes_on_input() {
+        let (reader, mut writer) = pipe();
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            writer.write_all(b"x").unwrap();
+        });
+        block_on(Readable::new(reader.as_raw_fd(), deadline)).unwrap();
+        assert!(Instant::now() < deadline);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn dropped_readable_deregisters() {
+        let (reader, _writer) = pipe();
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let mut readable = Box::pin(Readable::new(reader.as_raw_fd(), deadline));
+        let waker = Waker::from(Arc::new(CountWaker(AtomicUsize::new(0))));
+        let mut cx = Context::from_waker(&waker);
+        assert!(readable.as_mut().poll(&mut cx).is_pending());
+        let (reactor, key) = readable.key.unwrap();
+        drop(readable);
+        assert!(!reactor.lock().waiters.contains_key(&key));
+    }
+}
@@ -2,35 +2,96 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::collections::{HashMap, HashSet};
 use std::error;
 use std::fmt;
+use std::io;
+use std::thread;
+use std::time::Duration;
 
 use remain::sorted;
 
 use crate::control::{self, Control};
 use crate::control_primitive;
-use crate::control_primitive::{Ctl, ElemId, ElemIface};
+use crate::control_primitive::{
+    card_next, Ctl, CtlCardInfo, ElemId, ElemIface, ElemInfo, Event, PcmInfo, PcmStream,
+};
+use crate::control_set::{Access, ControlHandle};
+use crate::elem::{self, Elem};
+use crate::topology::Topology;
+use crate::trace::Trace;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+// The number of times to reopen the ctl handle when the sound card is gone temporarily, ex: a
+// codec reset or a controller runtime suspend glitch.
+const RECONNECT_RETRIES: usize = 3;
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(100);
+
 #[sorted]
 #[derive(Debug)]
 /// Possible errors that can occur in cros-alsa::card.
 pub enum Error {
     /// Failed to call AlsaControlAPI.
     AlsaControlAPI(control_primitive::Error),
+    /// No sound card matches the pattern.
+    CardNotFound(String),
+    /// The read back chunk does not match the written one.
+    ChunkVerifyFailed(String, usize),
     /// Error occurs in Control.
     Control(control::Error),
+    /// Failed to read or write the control.
+    ControlAccessFailed(String, elem::Error),
+    /// The control is not in the allow-list of `Card`.
+    ControlNotAllowed(String),
+    /// Error occurs in Elem.
+    Elem(elem::Error),
+    /// The controls declared by `ControlSetBuilder` mismatch the sound card.
+    InvalidControlSet(Vec<String>),
+    /// Failed to poll the ctl handle.
+    PollFailed(io::Error),
+    /// The control can't be written.
+    ReadOnlyControl(String),
+    /// Failed to restore the controls after a failed `apply_controls()`.
+    RollbackFailed(Box<Error>, Box<Error>),
+    /// The topology mismatches the sound card, ex: the card index or the controls changed.
+    StaleTopology(String),
+    /// The control does not meet the condition before the timeout.
+    WaitForTimeout(String, Duration),
+    /// The read back value of the control mismatches the written one.
+    WriteVerifyFailed(String),
 }
 
 impl error::Error for Error {}
 
+impl Error {
+    /// Returns true if the error is caused by the removal of the sound card.
+    pub fn is_enodev(&self) -> bool {
+        use Error::*;
+        match self {
+            AlsaControlAPI(e) => e.is_enodev(),
+            Control(e) => e.is_enodev(),
+            ControlAccessFailed(_, e) | Elem(e) => e.is_enodev(),
+            _ => false,
+        }
+    }
+}
+
 impl From<control::Error> for Error {
     fn from(err: control::Error) -> Error {
         Error::Control(err)
     }
 }
 
+impl From<elem::Error> for Error {
+    fn from(err: elem::Error) -> Error {
+        match err {
+            elem::Error::WaitForTimeout(name, timeout) => Error::WaitForTimeout(name, timeout),
+            err => Error::Elem(err),
+        }
+    }
+}
+
 impl From<control_primitive::Error> for Error {
     fn from(err: control_primitive::Error) -> Error {
         Error::AlsaControlAPI(err)
@@ -42,15 +103,148 @@ impl fmt::Display for Error {
         use Error::*;
         match self {
             AlsaControlAPI(e) => write!(f, "{}", e),
+            CardNotFound(pattern) => write!(f, "no sound card matches: {}", pattern),
+            ChunkVerifyFailed(name, offset) => {
+                write!(
+                    f,
+                    "{} failed to verify the chunk at offset: {}",
+                    name, offset
+                )
+            }
             Control(e) => write!(f, "{}", e),
+            ControlAccessFailed(name, e) => write!(f, "{}: {}", name, e),
+            ControlNotAllowed(name) => write!(f, "{} is not in the allow-list", name),
+            Elem(e) => write!(f, "{}", e),
+            InvalidControlSet(errors) => write!(f, "invalid controls: {}", errors.join("; ")),
+            PollFailed(e) => write!(f, "failed to poll the ctl handle: {}", e),
+            ReadOnlyControl(name) => write!(f, "{} is read-only", name),
+            RollbackFailed(e, rollback) => {
+                write!(f, "failed to roll back after {}: {}", e, rollback)
+            }
+            StaleTopology(card_id) => write!(f, "the topology of {} is stale", card_id),
+            WaitForTimeout(name, timeout) => {
+                write!(f, "{} does not meet the condition in {:?}", name, timeout)
+            }
+            WriteVerifyFailed(name) => {
+                write!(f, "{} read back value mismatches the written one", name)
+            }
         }
     }
 }
 
+/// `CardInfo` describes an available sound card.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CardInfo {
+    /// The sound card index, ex: 0 for hw:0.
+    pub index: i32,
+    /// The sound card id, ex: sofcmlmax98390d.
+    pub id: String,
+    /// The sound card name.
+    pub name: String,
+    /// The sound card long name.
+    pub longname: String,
+    /// The driver name of the sound card.
+    pub driver: String,
+    /// The components of the sound card, ex: the codec names.
+    pub components: String,
+}
+
+impl CardInfo {
+    fn new(index: i32) -> Result<Self> {
+        CardInfo::from_ctl(&mut Ctl::new(&format!("hw:{}", index))?)
+    }
+
+    fn from_ctl(handle: &mut Ctl) -> Result<Self> {
+        let info = CtlCardInfo::new(handle)?;
+        Ok(CardInfo {
+            index: info.card(),
+            id: info.id()?.to_owned(),
+            name: info.name()?.to_owned(),
+            longname: info.longname()?.to_owned(),
+            driver: info.driver()?.to_owned(),
+            components: info.components()?.to_owned(),
+        })
+    }
+}
+
+/// `PcmDevice` describes a PCM device of a sound card.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PcmDevice {
+    /// The PCM device number.
+    pub device: u32,
+    /// The PCM device id.
+    pub id: String,
+    /// The PCM device name, ex: the name of the DAI link.
+    pub name: String,
+    /// The number of subdevices.
+    pub subdevices: u32,
+    /// The alsa PCM name to open the device, ex: hw:sofcmlmax98390d,0.
+    pub pcm: String,
+}
+
+/// An iterator over the available sound cards. It's created by `cards()`.
+pub struct Cards {
+    // The index of the last visited sound card, or -1 before the first one.
+    index: i32,
+    done: bool,
+}
+
+impl Iterator for Cards {
+    type Item = Result<CardInfo>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match card_next(self.index) {
+            Ok(Some(index)) => {
+                self.index = index;
+                Some(CardInfo::new(index))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e.into()))
+            }
+        }
+    }
+}
+
+/// Iterates over the available sound cards.
+///
+/// # Examples
+///
+/// ``` no_run
+/// for card in cros_alsa::cards() {
+///     match card {
+///         Ok(info) => println!("{}: {} ({})", info.index, info.id, info.longname),
+///         Err(e) => eprintln!("{}", e),
+///     }
+/// }
+/// ```
+pub fn cards() -> Cards {
+    Cards {
+        index: -1,
+        done: false,
+    }
+}
+
 /// `Card` represents a sound card.
 pub struct Card {
     handle: Ctl,
     name: String,
+    info: CardInfo,
+    // The controls which can be written. All controls can be written if it's None.
+    allow_list: Option<HashSet<String>>,
+    // The numids of the mixer controls keyed by name and index. It saves the kernel from
+    // scanning all control elements to look up a control by name.
+    // It's rebuilt on the next lookup if it's None.
+    numids: Option<HashMap<(String, u32), u32>>,
+    // Reads back the controls after writing them if it's true.
+    verify_writes: bool,
 }
 
 impl Card {
@@ -64,23 +258,246 @@ impl Card {
     ///
     /// * If card_name is an invalid CString.
     /// * If snd_ctl_open() fails.
+    /// * If it fails to read the sound card info.
+    /// * If it fails to list the control elements.
     pub fn new(card_name: &str) -> Result<Self> {
-        let handle = Ctl::new(&format!("hw:{}", card_name))?;
-        Ok(Card {
+        let mut handle = Ctl::new(&format!("hw:{}", card_name))?;
+        let info = CardInfo::from_ctl(&mut handle)?;
+        let mut card = Card {
             name: card_name.to_owned(),
             handle,
+            info,
+            allow_list: None,
+            numids: None,
+            verify_writes: false,
+        };
+        card.refresh_controls()?;
+        Ok(card)
+    }
+
+    /// Creates a `Card` by the sound card index, ex: 0 for hw:0.
+    ///
+    /// # Errors
+    ///
+    /// * If snd_ctl_open() fails.
+    /// * If it fails to read the sound card info.
+    /// * If it fails to list the control elements.
+    pub fn from_index(index: u32) -> Result<Self> {
+        let mut handle = Ctl::new(&format!("hw:{}", index))?;
+        let info = CardInfo::from_ctl(&mut handle)?;
+        let mut card = Card {
+            name: info.id.clone(),
+            handle,
+            info,
+            allow_list: None,
+            numids: None,
+            verify_writes: false,
+        };
+        card.refresh_controls()?;
+        Ok(card)
+    }
+
+    /// Creates a `Card` by the sound card id, or by a substring of its longname or components
+    /// if no sound card has the id. It's used when the id of a sound card may change with the
+    /// kernel version.
+    ///
+    /// # Examples
+    ///
+    /// ``` no_run
+    /// # use cros_alsa::{Card, CardError};
+    /// # fn main() -> Result<(), CardError> {
+    /// let card = Card::find("max98390")?;
+    /// println!("{}", card.name());
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to enumerate the sound cards.
+    /// * If no sound card matches `pattern`.
+    /// * If it fails to open the matched sound card.
+    pub fn find(pattern: &str) -> Result<Self> {
+        let mut matched = None;
+        for info in cards() {
+            let info = info?;
+            if info.id == pattern {
+                return Card::from_index(info.index as u32);
+            }
+            if matched.is_none()
+                && (info.longname.contains(pattern) || info.components.contains(pattern))
+            {
+                matched = Some(info.index);
+            }
+        }
+        match matched {
+            Some(index) => Card::from_index(index as u32),
+            None => Err(Error::CardNotFound(pattern.to_owned())),
+        }
+    }
+
+    /// Reopens the sound card of the topology saved by `topology()`, and reuses its control
+    /// numids instead of listing the control elements. The topology is only checked against
+    /// the card id and the number of the control elements, and the controls of stale numids
+    /// are looked up again on access.
+    ///
+    /// # Errors
+    ///
+    /// * If snd_ctl_open() fails.
+    /// * If it fails to read the sound card info or to count the control elements.
+    /// * If the sound card mismatches the topology.
+    pub fn from_topology(topology: &Topology) -> Result<Self> {
+        let mut handle = Ctl::new(&format!("hw:{}", topology.index()))?;
+        let info = CardInfo::from_ctl(&mut handle)?;
+        if info.id != topology.card_id() || handle.elem_count()? != topology.elem_count() {
+            return Err(Error::StaleTopology(topology.card_id().to_owned()));
+        }
+        Ok(Card {
+            name: info.id.clone(),
+            handle,
+            info,
+            allow_list: None,
+            numids: Some(topology.numids().clone()),
+            verify_writes: false,
         })
     }
 
+    /// Returns the topology of the sound card, which can be saved to reopen the sound card
+    /// by `from_topology()` on the next boot.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to list or count the control elements.
+    pub fn topology(&mut self) -> Result<Topology> {
+        if self.numids.is_none() {
+            self.refresh_controls()?;
+        }
+        Ok(Topology::new(
+            &self.info.id,
+            self.info.index,
+            self.handle.elem_count()?,
+            self.numids.clone().unwrap_or_default(),
+        ))
+    }
+
     /// Gets sound card name.
     pub fn name(&self) -> &str {
         &self.name
     }
 
+    /// Gets the sound card info, ex: the driver and components, which tell the codecs of the
+    /// sound card.
+    ///
+    /// # Examples
+    ///
+    /// ``` no_run
+    /// # use cros_alsa::{Card, CardError};
+    /// # fn main() -> Result<(), CardError> {
+    /// let card = Card::new("sofcmlmax98390d")?;
+    /// let info = card.info();
+    /// println!("{} {} {}", info.driver, info.longname, info.components);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn info(&self) -> &CardInfo {
+        &self.info
+    }
+
+    /// Lists the playback PCM devices of the sound card.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to enumerate the PCM devices or to read their info.
+    pub fn playback_devices(&mut self) -> Result<Vec<PcmDevice>> {
+        self.pcm_devices(PcmStream::Playback)
+    }
+
+    /// Lists the capture PCM devices of the sound card.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to enumerate the PCM devices or to read their info.
+    pub fn capture_devices(&mut self) -> Result<Vec<PcmDevice>> {
+        self.pcm_devices(PcmStream::Capture)
+    }
+
+    fn pcm_devices(&mut self, stream: PcmStream) -> Result<Vec<PcmDevice>> {
+        self.with_reconnect(|card| {
+            let mut devices = Vec::new();
+            let mut device = -1;
+            while let Some(next) = card.handle.pcm_next_device(device)? {
+                device = next as i32;
+                // Skips the devices which do not support the stream direction.
+                if let Some(info) = PcmInfo::new(&mut card.handle, next, stream)? {
+                    devices.push(PcmDevice {
+                        device: info.device(),
+                        id: info.id()?.to_owned(),
+                        name: info.name()?.to_owned(),
+                        subdevices: info.subdevices_count(),
+                        pcm: format!("hw:{},{}", card.name, info.device()),
+                    });
+                }
+            }
+            Ok(devices)
+        })
+    }
+
+    /// Finds the first playback PCM device whose id or name contains `pattern`, ex: Speaker.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to enumerate the PCM devices or to read their info.
+    pub fn find_playback_device(&mut self, pattern: &str) -> Result<Option<PcmDevice>> {
+        Ok(self
+            .playback_devices()?
+            .into_iter()
+            .find(|dev| dev.id.contains(pattern) || dev.name.contains(pattern)))
+    }
+
+    /// Enables the allow-list mode. Only the given controls can be written afterwards, so a bug
+    /// in the caller can't accidentally change the unrelated mixer controls.
+    /// `control_by_*()` fail for the controls outside the list since a `Control` can write the
+    /// underlying mixer control, while `load_controls()` and `wait_for()` can still read them.
+    ///
+    /// # Examples
+    ///
+    /// ``` no_run
+    /// # use cros_alsa::{Card, CardError, IntControl};
+    /// # fn main() -> Result<(), CardError> {
+    /// let mut card = Card::new("sofcmlmax98390d")?;
+    /// card.set_allow_list(&["Left Rdc", "Left Ambient Temperature"]);
+    /// // Fails with `ControlNotAllowed`.
+    /// assert!(card.control_by_name::<IntControl>("Master Playback Volume").is_err());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn set_allow_list(&mut self, control_names: &[&str]) {
+        self.allow_list = Some(control_names.iter().map(|name| name.to_string()).collect());
+    }
+
+    /// Enables or disables the write-and-verify mode. In the mode, `save_controls()` and
+    /// `set()` read back the controls after writing them and fail on mismatch, which catches
+    /// the codecs silently rejecting out-of-range values.
+    pub fn set_verify_writes(&mut self, verify: bool) {
+        self.verify_writes = verify;
+    }
+
+    /// Starts recording the successful control accesses of the sound card, including the ones
+    /// of the `Control`s it creates. It drops the accesses recorded before.
+    pub fn start_recording(&mut self) {
+        self.handle.set_recording(Some(Vec::new()));
+    }
+
+    /// Stops recording and returns the recorded control accesses, see the `trace` module.
+    pub fn stop_recording(&mut self) -> Trace {
+        Trace::from(self.handle.take_recording().unwrap_or_default())
+    }
+
     /// Creates a `Control` from control name.
     ///
     /// # Errors
     ///
+    /// * If the allow-list mode is enabled and the control is not in the list.
     /// * If control name is an invalid CString.
     /// * If control dose not exist.
     /// * If `Control` elem_type() mismatches the type of underlying mixer control.
@@ -89,7 +506,547 @@ impl Card {
     where
         T: Control<'a>,
     {
-        let id = ElemId::new(ElemIface::Mixer, control_name)?;
+        self.check_allowed(control_name)?;
+        let id = self.probe_id(control_name, 0)?;
+        Ok(T::from(&mut self.handle, id)?)
+    }
+
+    /// Creates a `Control` from control name and index. It's used when the sound card exposes
+    /// multiple control elements with the same name.
+    ///
+    /// # Errors
+    ///
+    /// * If the allow-list mode is enabled and the control is not in the list.
+    /// * If control name is an invalid CString.
+    /// * If control dose not exist.
+    /// * If `Control` elem_type() mismatches the type of underlying mixer control.
+    /// * If `Control` size() mismatches the number of value entries of underlying mixer control.
+    pub fn control_by_name_and_index<'a, T>(
+        &'a mut self,
+        control_name: &str,
+        index: u32,
+    ) -> Result<T>
+    where
+        T: Control<'a>,
+    {
+        self.check_allowed(control_name)?;
+        let id = self.probe_id(control_name, index)?;
+        Ok(T::from(&mut self.handle, id)?)
+    }
+
+    /// Creates a `Control` from control numid.
+    ///
+    /// # Errors
+    ///
+    /// * If control dose not exist.
+    /// * If the allow-list mode is enabled and the control is not in the list.
+    /// * If `Control` elem_type() mismatches the type of underlying mixer control.
+    /// * If `Control` size() mismatches the number of value entries of underlying mixer control.
+    pub fn control_by_numid<'a, T>(&'a mut self, numid: u32) -> Result<T>
+    where
+        T: Control<'a>,
+    {
+        // Looks up the complete id so that the control name is available to its users.
+        let id = self.with_reconnect(|card| {
+            Ok(ElemInfo::new(&mut card.handle, &ElemId::from_numid(numid)?)?.id()?)
+        })?;
+        self.check_allowed(id.name()?)?;
         Ok(T::from(&mut self.handle, id)?)
     }
+
+    /// Returns true if the sound card has the control.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to list the control elements.
+    pub fn has_control(&mut self, control_name: &str) -> Result<bool> {
+        self.with_reconnect(|card| {
+            let key = (control_name.to_owned(), 0);
+            if !card
+                .numids
+                .as_ref()
+                .is_some_and(|numids| numids.contains_key(&key))
+            {
+                card.refresh_controls()?;
+            }
+            Ok(card
+                .numids
+                .as_ref()
+                .is_some_and(|numids| numids.contains_key(&key)))
+        })
+    }
+
+    /// Locks the control so that other mixer clients can't change its value. The lock is
+    /// released by `unlock_control()` or when the `Card` is dropped.
+    ///
+    /// # Errors
+    ///
+    /// * If control name is an invalid CString.
+    /// * If the control is locked by another mixer client.
+    pub fn lock_control(&mut self, control_name: &str) -> Result<()> {
+        self.with_reconnect(|card| {
+            let id = card.lookup_id(control_name, 0)?;
+            Ok(card.handle.elem_lock(&id)?)
+        })
+    }
+
+    /// Unlocks the control locked by `lock_control()`.
+    ///
+    /// # Errors
+    ///
+    /// * If control name is an invalid CString.
+    /// * If the control is not locked by this `Card`.
+    pub fn unlock_control(&mut self, control_name: &str) -> Result<()> {
+        self.with_reconnect(|card| {
+            let id = card.lookup_id(control_name, 0)?;
+            Ok(card.handle.elem_unlock(&id)?)
+        })
+    }
+
+    /// Waits until the value of the control meets `predicate` or `timeout` elapses.
+    /// It subscribes to the control element notifications and reads the value when it is
+    /// changed, instead of polling the value.
+    ///
+    /// # Arguments
+    ///
+    /// * `control_name` - The control name.
+    /// * `predicate` - The condition of the control value.
+    /// * `timeout` - The maximum duration to wait.
+    ///
+    /// # Results
+    ///
+    /// * The control value which meets `predicate`.
+    ///
+    /// # Examples
+    ///
+    /// ``` no_run
+    /// # use std::time::Duration;
+    /// # use cros_alsa::{Card, CardError};
+    /// # fn main() -> Result<(), CardError> {
+    /// let mut card = Card::new("sofcmlmax98390d")?;
+    /// // Waits until the single boolean value of "Calibration Done" becomes true.
+    /// card.wait_for::<[bool; 1], _>("Calibration Done", |v| v[0], Duration::from_secs(1))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// * If control name is an invalid CString.
+    /// * If control dose not exist.
+    /// * If `E::elem_type()` mismatches the type of underlying mixer control.
+    /// * If `E::size()` mismatches the number of value entries of underlying mixer control.
+    /// * If it fails to subscribe to or read the control events.
+    /// * If the control value does not meet `predicate` before `timeout`.
+    pub fn wait_for<E, F>(
+        &mut self,
+        control_name: &str,
+        predicate: F,
+        timeout: Duration,
+    ) -> Result<E::T>
+    where
+        E: Elem,
+        F: Fn(&E::T) -> bool,
+    {
+        self.with_reconnect(|card| {
+            let id = card.elem_id::<E>(control_name)?;
+            let (handle, on_event) = card.event_handle();
+            Ok(elem::wait_for_with::<E, &F>(
+                handle, &id, &predicate, timeout, on_event,
+            )?)
+        })
+    }
+
+    /// Reads the control of the `ControlHandle`.
+    ///
+    /// # Errors
+    ///
+    /// * If the control does not exist.
+    /// * If it fails to read from the control.
+    pub fn get<E: Elem>(&mut self, control: &ControlHandle<E>) -> Result<E::T> {
+        self.with_reconnect(|card| {
+            let id = card.elem_id::<E>(control.name())?;
+            E::load(&mut card.handle, &id)
+                .map_err(|e| Error::ControlAccessFailed(control.name().to_owned(), e))
+        })
+    }
+
+    /// Writes the control of the `ControlHandle`.
+    ///
+    /// # Errors
+    ///
+    /// * If the control is declared as `Access::ReadOnly`.
+    /// * If the allow-list mode is enabled and the control is not in the list.
+    /// * If the control does not exist.
+    /// * If it fails to write to the control.
+    pub fn set<E: Elem>(&mut self, control: &ControlHandle<E>, val: E::T) -> Result<()>
+    where
+        E::T: Clone + PartialEq,
+    {
+        if control.access() != Access::ReadWrite {
+            return Err(Error::ReadOnlyControl(control.name().to_owned()));
+        }
+        self.check_allowed(control.name())?;
+        self.with_reconnect(|card| card.save_elem::<E>(control.name(), val.clone()))
+    }
+
+    /// Reads the values of multiple controls of the same `Elem` type in one call.
+    ///
+    /// # Examples
+    ///
+    /// ``` no_run
+    /// # use cros_alsa::{Card, CardError};
+    /// # fn main() -> Result<(), CardError> {
+    /// let mut card = Card::new("sofcmlmax98390d")?;
+    /// let values = card.load_controls::<[i32; 1]>(&["Left Rdc", "Right Rdc"])?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// * If any control name is an invalid CString.
+    /// * If any control dose not exist.
+    /// * If `E::elem_type()` mismatches the type of any underlying mixer control.
+    /// * If `E::size()` mismatches the number of value entries of any underlying mixer control.
+    /// * If it fails to read from any control.
+    pub fn load_controls<E: Elem>(&mut self, control_names: &[&str]) -> Result<Vec<E::T>> {
+        self.with_reconnect(|card| {
+            control_names
+                .iter()
+                .map(|name| {
+                    let id = card.elem_id::<E>(name)?;
+                    E::load(&mut card.handle, &id)
+                        .map_err(|e| Error::ControlAccessFailed(name.to_string(), e))
+                })
+                .collect()
+        })
+    }
+
+    /// Writes the values to multiple controls of the same `Elem` type in one call.
+    /// The controls are written in order, and it stops at the first failure.
+    ///
+    /// # Examples
+    ///
+    /// ``` no_run
+    /// # use cros_alsa::{Card, CardError};
+    /// # fn main() -> Result<(), CardError> {
+    /// let mut card = Card::new("sofcmlmax98390d")?;
+    /// card.save_controls::<[i32; 1]>(vec![("Left Rdc", [13000]), ("Right Rdc", [13000])])?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// * If the allow-list mode is enabled and any control is not in the list.
+    /// * If any control name is an invalid CString.
+    /// * If any control dose not exist.
+    /// * If `E::elem_type()` mismatches the type of any underlying mixer control.
+    /// * If `E::size()` mismatches the number of value entries of any underlying mixer control.
+    /// * If it fails to write to any control.
+    /// * If the write-and-verify mode is enabled and any read back value mismatches.
+    pub fn save_controls<E: Elem>(&mut self, controls: Vec<(&str, E::T)>) -> Result<()>
+    where
+        E::T: Clone + PartialEq,
+    {
+        for (name, _) in &controls {
+            self.check_allowed(name)?;
+        }
+        self.with_reconnect(|card| {
+            for (name, val) in &controls {
+                card.save_elem::<E>(name, val.clone())?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Writes the values to multiple controls of the same `Elem` type all or nothing. The
+    /// current values of the controls are read first, and they are restored if any write
+    /// fails partway. The controls which already have the values are not written, so that
+    /// reapplying the same values does not reprogram the codec.
+    ///
+    /// # Examples
+    ///
+    /// ``` no_run
+    /// # use cros_alsa::{Card, CardError};
+    /// # fn main() -> Result<(), CardError> {
+    /// let mut card = Card::new("sofcmlmax98390d")?;
+    /// card.apply_controls::<[i32; 1]>(vec![
+    ///     ("Left Rdc", [13000]),
+    ///     ("Left Ambient Temperature", [1700]),
+    /// ])?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// * The errors of `save_controls()`. The controls are restored in this case.
+    /// * If it fails to read the current values of the controls.
+    /// * If it fails to restore the controls.
+    pub fn apply_controls<E: Elem>(&mut self, controls: Vec<(&str, E::T)>) -> Result<()>
+    where
+        E::T: Clone + PartialEq,
+    {
+        for (name, _) in &controls {
+            self.check_allowed(name)?;
+        }
+        let names: Vec<&str> = controls.iter().map(|(name, _)| *name).collect();
+        let snapshot = self.load_controls::<E>(&names)?;
+        for (i, (name, val)) in controls.into_iter().enumerate() {
+            if snapshot[i] == val {
+                continue;
+            }
+            if let Err(e) = self.save_controls::<E>(vec![(name, val)]) {
+                // The failed control is restored as well because it may be partially written.
+                let restore = names[..=i].iter().copied().zip(snapshot).collect();
+                return match self.save_controls::<E>(restore) {
+                    Ok(()) => Err(e),
+                    Err(rollback) => Err(Error::RollbackFailed(Box::new(e), Box::new(rollback))),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a byte array larger than the single-transfer limit of a byte control in
+    /// offset-based chunks. The driver exposes an integer control to select the byte offset
+    /// and a byte control of `N` bytes as the data window at that offset. Each chunk is read
+    /// back and verified before the next one is written. The last chunk is padded with zeros.
+    ///
+    /// # Examples
+    ///
+    /// ``` no_run
+    /// # use cros_alsa::{Card, CardError};
+    /// # fn main() -> Result<(), CardError> {
+    /// let mut card = Card::new("sofcmlmax98390d")?;
+    /// let blob = vec![0u8; 4096];
+    /// card.save_bytes_chunked::<512>("DSM Param Offset", "DSM Param Data", &blob)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// * If the allow-list mode is enabled and any control is not in the list.
+    /// * If any control name is an invalid CString.
+    /// * If any control dose not exist.
+    /// * If `offset_ctrl` is not a single integer control.
+    /// * If `data_ctrl` is not a byte control of `N` bytes.
+    /// * If it fails to write to or read from any control.
+    /// * If a chunk read back does not match the written one.
+    pub fn save_bytes_chunked<const N: usize>(
+        &mut self,
+        offset_ctrl: &str,
+        data_ctrl: &str,
+        data: &[u8],
+    ) -> Result<()> {
+        self.check_allowed(offset_ctrl)?;
+        self.check_allowed(data_ctrl)?;
+        // Restarts from the first chunk if the sound card is reset during the transfer.
+        self.with_reconnect(|card| card.save_bytes_chunked_once::<N>(offset_ctrl, data_ctrl, data))
+    }
+
+    fn save_bytes_chunked_once<const N: usize>(
+        &mut self,
+        offset_ctrl: &str,
+        data_ctrl: &str,
+        data: &[u8],
+    ) -> Result<()> {
+        let offset_id = self.elem_id::<[i32; 1]>(offset_ctrl)?;
+        let data_id = self.elem_id::<[u8; N]>(data_ctrl)?;
+        for (i, chunk) in data.chunks(N).enumerate() {
+            let offset = i * N;
+            <[i32; 1]>::save(&mut self.handle, &offset_id, [offset as i32])
+                .map_err(|e| Error::ControlAccessFailed(offset_ctrl.to_owned(), e))?;
+            let mut window = [0u8; N];
+            window[..chunk.len()].copy_from_slice(chunk);
+            <[u8; N]>::save(&mut self.handle, &data_id, window)
+                .map_err(|e| Error::ControlAccessFailed(data_ctrl.to_owned(), e))?;
+            let read_back = <[u8; N]>::load(&mut self.handle, &data_id)
+                .map_err(|e| Error::ControlAccessFailed(data_ctrl.to_owned(), e))?;
+            if read_back != window {
+                return Err(Error::ChunkVerifyFailed(data_ctrl.to_owned(), offset));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reopens the ctl handle of the sound card. It's called automatically when an operation
+    /// of `Card` fails because the sound card is gone temporarily, ex: a codec reset.
+    /// The control locks are released, and the `Control`s created before are invalid.
+    ///
+    /// # Errors
+    ///
+    /// * If snd_ctl_open() fails.
+    /// * If it fails to read the sound card info.
+    /// * If it fails to list the control elements.
+    pub fn reconnect(&mut self) -> Result<()> {
+        // The new handle is opened first, so the recording stays on the old handle if it fails.
+        let mut handle = Ctl::new(&format!("hw:{}", self.name))?;
+        // Keeps recording across the reconnection.
+        handle.set_recording(self.handle.take_recording());
+        self.handle = handle;
+        self.info = CardInfo::from_ctl(&mut self.handle)?;
+        self.refresh_controls()
+    }
+
+    // Runs `op` and retries it after reopening the ctl handle if it fails with ENODEV.
+    fn with_reconnect<T, F>(&mut self, mut op: F) -> Result<T>
+    where
+        F: FnMut(&mut Card) -> Result<T>,
+    {
+        let mut retries = 0;
+        loop {
+            match op(self) {
+                Err(e) if e.is_enodev() && retries < RECONNECT_RETRIES => {
+                    retries += 1;
+                    thread::sleep(RECONNECT_INTERVAL);
+                    match self.reconnect() {
+                        // The sound card may not be back yet, and `op` will fail again.
+                        Err(e) if e.is_enodev() => continue,
+                        res => res?,
+                    }
+                }
+                res => return res,
+            }
+        }
+    }
+
+    // Writes the control, and reads it back in the write-and-verify mode.
+    fn save_elem<E: Elem>(&mut self, control_name: &str, val: E::T) -> Result<()>
+    where
+        E::T: Clone + PartialEq,
+    {
+        let id = self.elem_id::<E>(control_name)?;
+        let access_failed = |e| Error::ControlAccessFailed(control_name.to_owned(), e);
+        if !self.verify_writes {
+            E::save(&mut self.handle, &id, val).map_err(access_failed)?;
+            return Ok(());
+        }
+        E::save(&mut self.handle, &id, val.clone()).map_err(access_failed)?;
+        if E::load(&mut self.handle, &id).map_err(access_failed)? != val {
+            return Err(Error::WriteVerifyFailed(control_name.to_owned()));
+        }
+        Ok(())
+    }
+
+    // Looks up the control and checks that it's accessible, reopening the ctl handle if needed.
+    fn probe_id(&mut self, control_name: &str, index: u32) -> Result<ElemId> {
+        self.with_reconnect(|card| Ok(card.lookup_info(control_name, index)?.id()?))
+    }
+
+    // Reads the info of the mixer control. The numid of a stale topology may address another
+    // control or no control, so the control is looked up again after refreshing the control
+    // element list if the info mismatches the name.
+    fn lookup_info(&mut self, control_name: &str, index: u32) -> Result<ElemInfo> {
+        let id = self.lookup_id(control_name, index)?;
+        match ElemInfo::new(&mut self.handle, &id) {
+            Ok(info) if info.id()?.name()? == control_name && info.id()?.index() == index => {
+                return Ok(info)
+            }
+            Err(e) if id.numid() == 0 => return Err(e.into()),
+            _ => (),
+        }
+        self.refresh_controls()?;
+        let id = self.lookup_id(control_name, index)?;
+        Ok(ElemInfo::new(&mut self.handle, &id)?)
+    }
+
+    /// Rebuilds the cached control element list. The cache is also refreshed when a control
+    /// is not found in it or when the control element list change events are received.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to list the control elements.
+    pub fn refresh_controls(&mut self) -> Result<()> {
+        let numids = self
+            .handle
+            .elem_list()?
+            .into_iter()
+            .filter(|entry| entry.iface == ElemIface::Mixer as u32)
+            .map(|entry| ((entry.name, entry.index), entry.numid))
+            .collect();
+        self.numids = Some(numids);
+        Ok(())
+    }
+
+    // Creates the `ElemId` of the mixer control with the cached numid, so that the kernel can
+    // look it up without comparing the names of all control elements.
+    fn lookup_id(&mut self, control_name: &str, index: u32) -> Result<ElemId> {
+        let mut id = ElemId::with_index(ElemIface::Mixer, control_name, index)?;
+        let key = (control_name.to_owned(), index);
+        let mut numid = self
+            .numids
+            .as_ref()
+            .and_then(|numids| numids.get(&key))
+            .copied();
+        if numid.is_none() {
+            // The control may be added after the cache is built.
+            self.refresh_controls()?;
+            numid = self
+                .numids
+                .as_ref()
+                .and_then(|numids| numids.get(&key))
+                .copied();
+        }
+        // Leaves the kernel to report the missing control if it's not in the list.
+        if let Some(numid) = numid {
+            id.set_numid(numid);
+        }
+        Ok(id)
+    }
+
+    // Invalidates the cached control element list if controls are added or removed.
+    fn handle_event(numids: &mut Option<HashMap<(String, u32), u32>>, event: &Event) {
+        if event.is_elem_list_changed() {
+            *numids = None;
+        }
+    }
+
+    // Splits the ctl handle from the handler of its events, so that a wait can hold the handle
+    // while it keeps the cached control element list up to date.
+    pub(crate) fn event_handle(&mut self) -> (&mut Ctl, impl FnMut(&Event) + '_) {
+        let numids = &mut self.numids;
+        (&mut self.handle, move |event: &Event| {
+            Card::handle_event(numids, event)
+        })
+    }
+
+    fn check_allowed(&self, control_name: &str) -> Result<()> {
+        match &self.allow_list {
+            Some(list) if !list.contains(control_name) => {
+                Err(Error::ControlNotAllowed(control_name.to_owned()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn handle(&mut self) -> &mut Ctl {
+        &mut self.handle
+    }
+
+    // Looks up the control and validates it against `E`. The returned `ElemId` addresses the
+    // control by numid, which saves the kernel from looking up the name again on each access.
+    pub(crate) fn elem_id<E: Elem>(&mut self, control_name: &str) -> Result<ElemId> {
+        let info = self.lookup_info(control_name, 0)?;
+        if info.elem_type()? != E::elem_type() {
+            return Err(control::Error::MismatchElemType(
+                control_name.to_owned(),
+                info.elem_type()?,
+                E::elem_type(),
+            )
+            .into());
+        }
+        if E::size() != 0 && info.count() != E::size() {
+            return Err(control::Error::MismatchElemCount(
+                control_name.to_owned(),
+                info.count(),
+                E::size(),
+            )
+            .into());
+        }
+        Ok(info.id()?)
+    }
 }
@@ -59,6 +59,7 @@
 
 use std::error;
 use std::fmt;
+use std::time::Duration;
 
 use cros_alsa_derive::ControlOps;
 use remain::sorted;
@@ -77,14 +78,29 @@ pub enum Error {
     AlsaControlAPI(control_primitive::Error),
     /// Error occurs in Elem.
     Elem(elem::Error),
+    /// The item does not exist in the enumerated mixer control.
+    EnumItemNotFound(String, String),
     /// Elem::size() does not match the element count of the mixer control.
     MismatchElemCount(String, usize, usize),
     /// Elem::elem_type() does not match the data type of the mixer control.
     MismatchElemType(String, ElemType, ElemType),
+    /// The mixer control does not support TLV read and write.
+    UnsupportedTlv(String),
 }
 
 impl error::Error for Error {}
 
+impl Error {
+    /// Returns true if the error is caused by the removal of the sound card.
+    pub fn is_enodev(&self) -> bool {
+        match self {
+            Error::AlsaControlAPI(e) => e.is_enodev(),
+            Error::Elem(e) => e.is_enodev(),
+            _ => false,
+        }
+    }
+}
+
 impl From<control_primitive::Error> for Error {
     fn from(err: control_primitive::Error) -> Error {
         Error::AlsaControlAPI(err)
@@ -103,6 +119,7 @@ impl fmt::Display for Error {
         match self {
             AlsaControlAPI(e) => write!(f, "{}", e),
             Elem(e) => write!(f, "{}", e),
+            EnumItemNotFound(name, item) => write!(f, "{} does not have item: {}", name, item),
             MismatchElemCount(name, count, elem_count) => write!(
                 f,
                 "invalid `Control::size()` of {}: expect: {}, get: {}",
@@ -113,6 +130,7 @@ impl fmt::Display for Error {
                 "invalid `Control::elem_type()` of {}: expect: {}, get: {}",
                 name, t, elem_type
             ),
+            UnsupportedTlv(name) => write!(f, "{} does not support TLV read and write", name),
         }
     }
 }
@@ -167,14 +185,38 @@ pub trait ControlOps<'a>: Control<'a> {
     fn load(&mut self) -> Result<<Self as Control<'a>>::Item>;
     /// Saves the values to the mixer control.
     fn save(&mut self, val: <Self as Control<'a>>::Item) -> Result<bool>;
+    /// Waits until the values of the mixer control equal `expected` or `timeout` elapses.
+    /// It's used to wait for the status controls, ex: calibration done or DSP ready.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to read from the control or its events.
+    /// * If the values do not equal `expected` before `timeout`.
+    fn wait_for(&mut self, expected: <Self as Control<'a>>::Item, timeout: Duration) -> Result<()>
+    where
+        <Self as Control<'a>>::Item: PartialEq;
 }
 
-/// `Control` that reads and writes a single integer value entry.
+/// `Control` that reads and writes `N` integer value entries. `N` defaults to 1, and
+/// `IntControl` reads and writes a single integer value entry.
 /// Since this crate is the `cros_alsa` crate, we replace the `cros_alsa`
 /// path to `crate` in derive macros by `cros_alsa` attribute.
+///
+/// # Examples
+///
+/// ``` no_run
+/// # use cros_alsa::{Card, CardError, IntControl};
+/// # fn main() -> Result<(), CardError> {
+/// let mut card = Card::new("sofcmlmax98390d")?;
+/// // Fails with `MismatchElemCount` if the control does not have exactly 4 value entries.
+/// let mut coef_ctrl: IntControl<4> = card.control_by_name("DSM Coefficients")?;
+/// coef_ctrl.set_values([0, 1, 2, 3])?;
+/// # Ok(())
+/// # }
+/// ```
 #[derive(ControlOps)]
 #[cros_alsa(path = "crate")]
-pub struct IntControl<'a> {
+pub struct IntControl<'a, const N: usize = 1> {
     handle: &'a mut Ctl,
     id: ElemId,
 }
@@ -201,8 +243,68 @@ impl<'a> IntControl<'a> {
     }
 }
 
-impl<'a> Control<'a> for IntControl<'a> {
-    type Item = [i32; 1];
+impl<'a, const N: usize> IntControl<'a, N> {
+    /// Gets all i32 values from the mixer control.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to read from the control.
+    pub fn values(&mut self) -> Result<[i32; N]> {
+        self.load()
+    }
+
+    /// Updates all i32 values to the mixer control.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to write to the control.
+    pub fn set_values(&mut self, val: [i32; N]) -> Result<()> {
+        self.save(val)?;
+        Ok(())
+    }
+}
+
+impl<'a, const N: usize> Control<'a> for IntControl<'a, N> {
+    type Item = [i32; N];
+    fn new(handle: &'a mut Ctl, id: ElemId) -> Self {
+        Self { handle, id }
+    }
+}
+
+/// `Control` that reads and writes a single 64-bit integer value entry.
+/// Since this crate is the `cros_alsa` crate, we replace the `cros_alsa`
+/// path to `crate` in derive macros by `cros_alsa` attribute.
+#[derive(ControlOps)]
+#[cros_alsa(path = "crate")]
+pub struct Int64Control<'a> {
+    handle: &'a mut Ctl,
+    id: ElemId,
+}
+
+impl<'a> Int64Control<'a> {
+    /// Gets an i64 value from the mixer control.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to read from the control.
+    pub fn get(&mut self) -> Result<i64> {
+        let val = self.load()?;
+        Ok(val[0])
+    }
+
+    /// Updates an i64 value to the mixer control.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to write to the control.
+    pub fn set(&mut self, val: i64) -> Result<()> {
+        self.save([val])?;
+        Ok(())
+    }
+}
+
+impl<'a> Control<'a> for Int64Control<'a> {
+    type Item = [i64; 1];
     fn new(handle: &'a mut Ctl, id: ElemId) -> Self {
         Self { handle, id }
     }
@@ -247,12 +349,13 @@ impl<'a> Control<'a> for StereoVolumeControl<'a> {
     }
 }
 
-/// `Control` that reads and writes a single boolean value entry.
+/// `Control` that reads and writes `N` boolean value entries. `N` defaults to 1, and
+/// `SwitchControl` reads and writes a single boolean value entry.
 /// Since this crate is the `cros_alsa` crate, we replace the `cros_alsa`
 /// path to `crate` in derive macros by `cros_alsa` attribute.
 #[derive(ControlOps)]
 #[cros_alsa(path = "crate")]
-pub struct SwitchControl<'a> {
+pub struct SwitchControl<'a, const N: usize = 1> {
     handle: &'a mut Ctl,
     id: ElemId,
 }
@@ -289,9 +392,172 @@ impl<'a> SwitchControl<'a> {
     }
 }
 
-impl<'a> Control<'a> for SwitchControl<'a> {
-    type Item = [bool; 1];
+impl<'a, const N: usize> SwitchControl<'a, N> {
+    /// Reads the states of all switches of the mixer control.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to read from the control.
+    pub fn states(&mut self) -> Result<[bool; N]> {
+        self.load()
+    }
+
+    /// Updates the states of all switches of the mixer control.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to write to the control.
+    pub fn set_states(&mut self, val: [bool; N]) -> Result<()> {
+        self.save(val)?;
+        Ok(())
+    }
+}
+
+impl<'a, const N: usize> Control<'a> for SwitchControl<'a, N> {
+    type Item = [bool; N];
+    fn new(handle: &'a mut Ctl, id: ElemId) -> Self {
+        Self { handle, id }
+    }
+}
+
+/// `Control` that reads and writes a single enumerated value entry.
+/// Since this crate is the `cros_alsa` crate, we replace the `cros_alsa`
+/// path to `crate` in derive macros by `cros_alsa` attribute.
+#[derive(ControlOps)]
+#[cros_alsa(path = "crate")]
+pub struct EnumControl<'a> {
+    handle: &'a mut Ctl,
+    id: ElemId,
+}
+
+impl<'a> EnumControl<'a> {
+    /// Gets the index of the selected item.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to read from the control.
+    pub fn get(&mut self) -> Result<u32> {
+        let val = self.load()?;
+        Ok(val[0])
+    }
+
+    /// Selects the item by index.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to write to the control.
+    pub fn set(&mut self, idx: u32) -> Result<()> {
+        self.save([idx])?;
+        Ok(())
+    }
+
+    /// Gets the names of all items.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to read the item names from the control.
+    pub fn item_names(&mut self) -> Result<Vec<String>> {
+        let info = ElemInfo::new(self.handle, &self.id)?;
+        Ok(info.item_names(self.handle)?)
+    }
+
+    /// Gets the name of the selected item.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to read from the control.
+    /// * If it fails to read the item names from the control.
+    pub fn item_name(&mut self) -> Result<String> {
+        let idx = self.get()?;
+        self.item_names()?
+            .into_iter()
+            .nth(idx as usize)
+            .ok_or_else(|| Error::EnumItemNotFound(self.name(), idx.to_string()))
+    }
+
+    /// Selects the item by name.
+    ///
+    /// # Errors
+    ///
+    /// * If the item does not exist.
+    /// * If it fails to write to the control.
+    pub fn set_by_name(&mut self, item: &str) -> Result<()> {
+        let idx = self
+            .item_names()?
+            .iter()
+            .position(|name| name == item)
+            .ok_or_else(|| Error::EnumItemNotFound(self.name(), item.to_owned()))?;
+        self.set(idx as u32)
+    }
+
+    fn name(&self) -> String {
+        self.id.name().unwrap_or_default().to_owned()
+    }
+}
+
+impl<'a> Control<'a> for EnumControl<'a> {
+    type Item = [u32; 1];
+    fn new(handle: &'a mut Ctl, id: ElemId) -> Self {
+        Self { handle, id }
+    }
+}
+
+/// `Control` that reads and writes a byte array through the TLV interface.
+/// It's used by the byte controls whose data exceeds the normal element value size, ex: DSP
+/// parameter blobs.
+/// Since this crate is the `cros_alsa` crate, we replace the `cros_alsa`
+/// path to `crate` in derive macros by `cros_alsa` attribute.
+#[derive(ControlOps)]
+#[cros_alsa(path = "crate")]
+pub struct TlvBytesControl<'a> {
+    handle: &'a mut Ctl,
+    id: ElemId,
+}
+
+impl<'a> TlvBytesControl<'a> {
+    /// Reads the byte array from the mixer control.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to read from the control.
+    pub fn get(&mut self) -> Result<Vec<u8>> {
+        self.load()
+    }
+
+    /// Writes the byte array to the mixer control.
+    ///
+    /// # Errors
+    ///
+    /// * If the byte array is larger than the capacity of the control.
+    /// * If it fails to write to the control.
+    pub fn set(&mut self, val: &[u8]) -> Result<()> {
+        self.save(val.to_vec())?;
+        Ok(())
+    }
+}
+
+impl<'a> Control<'a> for TlvBytesControl<'a> {
+    type Item = Vec<u8>;
     fn new(handle: &'a mut Ctl, id: ElemId) -> Self {
         Self { handle, id }
     }
+
+    /// The capacity of a TLV byte control varies with the driver, so it validates the TLV
+    /// access instead of the number of value entries.
+    fn from(handle: &'a mut Ctl, id: ElemId) -> Result<Self> {
+        let info = ElemInfo::new(handle, &id)?;
+        if info.elem_type()? != Self::elem_type() {
+            return Err(Error::MismatchElemType(
+                id.name()?.to_owned(),
+                info.elem_type()?,
+                Self::elem_type(),
+            ));
+        }
+
+        if !info.is_tlv_readable() || !info.is_tlv_writable() {
+            return Err(Error::UnsupportedTlv(id.name()?.to_owned()));
+        }
+
+        Ok(Self::new(handle, id))
+    }
 }
@@ -7,14 +7,18 @@ use std::error;
 use std::ffi::{CStr, CString, FromBytesWithNulError, NulError};
 use std::fmt;
 use std::marker::PhantomData;
+use std::os::unix::io::RawFd;
 use std::ptr;
 use std::slice;
 use std::str;
+use std::time::Duration;
 
 use alsa_sys::*;
-use libc::strlen;
+use libc::{c_char, c_int, c_uint, pollfd, strlen};
 use remain::sorted;
 
+use crate::trace::{TraceAccess, TraceOp};
+
 pub type Result<T> = std::result::Result<T, Error>;
 type BoxError = Box<dyn error::Error + Send + Sync>;
 
@@ -25,6 +29,14 @@ pub enum FFIError {
     NullPtr,
 }
 
+impl FFIError {
+    /// Returns true if the sound card is gone, ex: a codec reset or a controller runtime
+    /// suspend glitch. The `Ctl` needs to be reopened to access the sound card again.
+    pub fn is_enodev(&self) -> bool {
+        matches!(self, FFIError::Rc(rc) if *rc == -libc::ENODEV)
+    }
+}
+
 impl fmt::Display for FFIError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use FFIError::*;
@@ -39,22 +51,64 @@ impl fmt::Display for FFIError {
 #[derive(Debug)]
 /// Possible errors that can occur in cros-alsa::control_primitive.
 pub enum Error {
+    /// Failed to call snd_card_next().
+    CardNextFailed(FFIError),
     /// Control with the given name does not exist.
     ControlNotFound(String),
+    /// Failed to call snd_ctl_card_info().
+    CtlCardInfoFailed(FFIError),
+    /// snd_ctl_card_info_get_*() returns null.
+    CtlCardInfoGetFailed(&'static str),
+    /// Failed to call snd_ctl_card_info_malloc().
+    CtlCardInfoMallocFailed(FFIError),
     /// Failed to call snd_ctl_open().
     CtlOpenFailed(FFIError, String),
+    /// Failed to call snd_ctl_pcm_info().
+    CtlPcmInfoFailed(FFIError),
+    /// Failed to call snd_ctl_pcm_next_device().
+    CtlPcmNextDeviceFailed(FFIError),
+    /// Failed to call snd_ctl_poll_descriptors().
+    CtlPollDescriptorsFailed(FFIError),
+    /// Failed to call snd_ctl_read().
+    CtlReadFailed(FFIError),
+    /// Failed to call snd_ctl_subscribe_events().
+    CtlSubscribeEventsFailed(FFIError),
+    /// Failed to call snd_ctl_wait().
+    CtlWaitFailed(FFIError),
     /// snd_ctl_elem_id_get_name() returns null.
     ElemIdGetNameFailed,
     /// Failed to call snd_ctl_elem_id_malloc().
     ElemIdMallocFailed(FFIError),
+    /// Failed to call snd_ctl_elem_info().
+    ElemInfoFailed(FFIError),
+    /// snd_ctl_elem_info_get_item_name() returns null.
+    ElemInfoGetItemNameFailed,
     /// Failed to call snd_ctl_elem_info_malloc().
     ElemInfoMallocFailed(FFIError),
+    /// Failed to call snd_ctl_elem_list_alloc_space().
+    ElemListAllocSpaceFailed(FFIError),
+    /// Failed to call snd_ctl_elem_list().
+    ElemListFailed(FFIError),
+    /// snd_ctl_elem_list_get_name() returns null.
+    ElemListGetNameFailed,
+    /// Failed to call snd_ctl_elem_list_malloc().
+    ElemListMallocFailed(FFIError),
+    /// Failed to call snd_ctl_elem_lock().
+    ElemLockFailed(FFIError, String),
+    /// Failed to call snd_ctl_elem_unlock().
+    ElemUnlockFailed(FFIError, String),
     /// Failed to call snd_ctl_elem_value_malloc().
     ElemValueMallocFailed(FFIError),
+    /// Failed to call snd_ctl_event_malloc().
+    EventMallocFailed(FFIError),
     /// Input is not a valid CString.
     InvalidCString(BoxError),
     /// Failed to convert to a valid ElemType.
     InvalidElemType(u32),
+    /// snd_pcm_info_get_* returns null.
+    PcmInfoGetFailed(&'static str),
+    /// Failed to call snd_pcm_info_malloc().
+    PcmInfoMallocFailed(FFIError),
     /// Failed to call snd_strerror().
     SndStrErrorFailed(i32),
     /// UTF-8 validation failed
@@ -67,20 +121,67 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use Error::*;
         match self {
+            CardNextFailed(e) => write!(f, "snd_card_next failed: {}", e),
             ControlNotFound(name) => write!(f, "control: {} does not exist", name),
+            CtlCardInfoFailed(e) => write!(f, "snd_ctl_card_info failed: {}", e),
+            CtlCardInfoGetFailed(field) => write!(f, "snd_ctl_card_info_get_{} failed", field),
+            CtlCardInfoMallocFailed(e) => write!(f, "snd_ctl_card_info_malloc failed: {}", e),
             CtlOpenFailed(e, name) => write!(f, "{} snd_ctl_open failed: {}", name, e,),
+            CtlPcmInfoFailed(e) => write!(f, "snd_ctl_pcm_info failed: {}", e),
+            CtlPcmNextDeviceFailed(e) => write!(f, "snd_ctl_pcm_next_device failed: {}", e),
+            CtlPollDescriptorsFailed(e) => write!(f, "snd_ctl_poll_descriptors failed: {}", e),
+            CtlReadFailed(e) => write!(f, "snd_ctl_read failed: {}", e),
+            CtlSubscribeEventsFailed(e) => write!(f, "snd_ctl_subscribe_events failed: {}", e),
+            CtlWaitFailed(e) => write!(f, "snd_ctl_wait failed: {}", e),
             ElemIdGetNameFailed => write!(f, "snd_ctl_elem_id_get_name failed"),
             ElemIdMallocFailed(e) => write!(f, "snd_ctl_elem_id_malloc failed: {}", e),
+            ElemInfoFailed(e) => write!(f, "snd_ctl_elem_info failed: {}", e),
+            ElemInfoGetItemNameFailed => write!(f, "snd_ctl_elem_info_get_item_name failed"),
             ElemInfoMallocFailed(e) => write!(f, "snd_ctl_elem_info_malloc failed: {}", e),
+            ElemListAllocSpaceFailed(e) => {
+                write!(f, "snd_ctl_elem_list_alloc_space failed: {}", e)
+            }
+            ElemListFailed(e) => write!(f, "snd_ctl_elem_list failed: {}", e),
+            ElemListGetNameFailed => write!(f, "snd_ctl_elem_list_get_name failed"),
+            ElemListMallocFailed(e) => write!(f, "snd_ctl_elem_list_malloc failed: {}", e),
+            ElemLockFailed(e, name) => write!(f, "{} snd_ctl_elem_lock failed: {}", name, e),
+            ElemUnlockFailed(e, name) => write!(f, "{} snd_ctl_elem_unlock failed: {}", name, e),
             ElemValueMallocFailed(e) => write!(f, "snd_ctl_elem_value_malloc failed: {}", e),
+            EventMallocFailed(e) => write!(f, "snd_ctl_event_malloc failed: {}", e),
             InvalidCString(e) => write!(f, "invalid CString: {}", e),
             InvalidElemType(v) => write!(f, "invalid ElemType: {}", v),
+            PcmInfoGetFailed(field) => write!(f, "snd_pcm_info_get_{} failed", field),
+            PcmInfoMallocFailed(e) => write!(f, "snd_pcm_info_malloc failed: {}", e),
             SndStrErrorFailed(e) => write!(f, "snd_strerror() failed: {}", e),
             Utf8Error(e) => write!(f, "{}", e),
         }
     }
 }
 
+impl Error {
+    /// Returns true if the error is caused by the removal of the sound card.
+    pub fn is_enodev(&self) -> bool {
+        use Error::*;
+        match self {
+            CardNextFailed(e)
+            | CtlCardInfoFailed(e)
+            | CtlOpenFailed(e, _)
+            | CtlPcmInfoFailed(e)
+            | CtlPcmNextDeviceFailed(e)
+            | CtlPollDescriptorsFailed(e)
+            | CtlReadFailed(e)
+            | CtlSubscribeEventsFailed(e)
+            | CtlWaitFailed(e)
+            | ElemInfoFailed(e)
+            | ElemListAllocSpaceFailed(e)
+            | ElemListFailed(e)
+            | ElemLockFailed(e, _)
+            | ElemUnlockFailed(e, _) => e.is_enodev(),
+            _ => false,
+        }
+    }
+}
+
 impl From<Error> for fmt::Error {
     fn from(_err: Error) -> fmt::Error {
         fmt::Error
@@ -120,12 +221,19 @@ pub enum ElemIface {
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 /// [snd_ctl_elem_type_t](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html#gac42e0ed6713b62711af5e80b4b3bcfec) wrapper.
 pub enum ElemType {
+    /// Invalid type.
     None = SND_CTL_ELEM_TYPE_NONE as isize,
+    /// Boolean values.
     Boolean = SND_CTL_ELEM_TYPE_BOOLEAN as isize,
+    /// Integer values.
     Integer = SND_CTL_ELEM_TYPE_INTEGER as isize,
+    /// The item indices of an enumerated control.
     Enumerated = SND_CTL_ELEM_TYPE_ENUMERATED as isize,
+    /// Byte values.
     Bytes = SND_CTL_ELEM_TYPE_BYTES as isize,
+    /// IEC958 (S/PDIF) settings.
     IEC958 = SND_CTL_ELEM_TYPE_IEC958 as isize,
+    /// 64-bit integer values.
     Integer64 = SND_CTL_ELEM_TYPE_INTEGER64 as isize,
 }
 
@@ -180,6 +288,50 @@ impl ElemId {
     /// * If memory allocation fails.
     /// * If ctl_name is not a valid CString.
     pub fn new(iface: ElemIface, ctl_name: &str) -> Result<ElemId> {
+        let id = ElemId::alloc()?;
+        // Safe because id.0.as_ptr() is a valid snd_ctl_elem_id_t*.
+        unsafe { snd_ctl_elem_id_set_interface(id.0.as_ptr(), iface as u32) };
+        let name = CString::new(ctl_name)?;
+        // Safe because id.0.as_ptr() is a valid snd_ctl_elem_id_t* and name is a safe CString.
+        unsafe { snd_ctl_elem_id_set_name(id.0.as_ptr(), name.as_ptr()) };
+        Ok(id)
+    }
+
+    /// Creates an `ElemId` object by `ElemIface`, name and index. The index distinguishes the
+    /// control elements which have the same name.
+    ///
+    /// # Errors
+    ///
+    /// * If memory allocation fails.
+    /// * If ctl_name is not a valid CString.
+    pub fn with_index(iface: ElemIface, ctl_name: &str, index: u32) -> Result<ElemId> {
+        let id = ElemId::new(iface, ctl_name)?;
+        // Safe because id.0.as_ptr() is a valid snd_ctl_elem_id_t*.
+        unsafe { snd_ctl_elem_id_set_index(id.0.as_ptr(), index as c_uint) };
+        Ok(id)
+    }
+
+    /// Creates an `ElemId` object by numid, which is the unique identifier of a control element
+    /// in a sound card.
+    ///
+    /// # Errors
+    ///
+    /// * If memory allocation fails.
+    pub fn from_numid(numid: u32) -> Result<ElemId> {
+        let id = ElemId::alloc()?;
+        // Safe because id.0.as_ptr() is a valid snd_ctl_elem_id_t*.
+        unsafe { snd_ctl_elem_id_set_numid(id.0.as_ptr(), numid as c_uint) };
+        Ok(id)
+    }
+
+    /// Safe [snd_ctl_elem_id_set_numid](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    /// The kernel looks up the control element by numid instead of by name if it is set.
+    pub fn set_numid(&mut self, numid: u32) {
+        // Safe because self.0.as_ptr() is a valid snd_ctl_elem_id_t*.
+        unsafe { snd_ctl_elem_id_set_numid(self.0.as_ptr(), numid as c_uint) };
+    }
+
+    fn alloc() -> Result<ElemId> {
         let mut id_ptr = ptr::null_mut();
         // Safe because we provide a valid id_ptr to be filled,
         // and we validate the return code before using id_ptr.
@@ -188,15 +340,33 @@ impl ElemId {
             return Err(Error::ElemIdMallocFailed(FFIError::Rc(rc)));
         }
         let id = ptr::NonNull::new(id_ptr).ok_or(Error::ElemIdMallocFailed(FFIError::NullPtr))?;
-
-        // Safe because id.as_ptr() is a valid snd_ctl_elem_id_t*.
-        unsafe { snd_ctl_elem_id_set_interface(id.as_ptr(), iface as u32) };
-        let name = CString::new(ctl_name)?;
-        // Safe because id.as_ptr() is a valid snd_ctl_elem_id_t* and name is a safe CString.
-        unsafe { snd_ctl_elem_id_set_name(id.as_ptr(), name.as_ptr()) };
         Ok(ElemId(id, PhantomData))
     }
 
+    /// Safe [snd_ctl_elem_id_get_numid](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    pub fn numid(&self) -> u32 {
+        // Safe because self.as_ptr() is a valid snd_ctl_elem_id_t*.
+        unsafe { snd_ctl_elem_id_get_numid(self.as_ptr()) as u32 }
+    }
+
+    /// Safe [snd_ctl_elem_id_get_index](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    pub fn index(&self) -> u32 {
+        // Safe because self.as_ptr() is a valid snd_ctl_elem_id_t*.
+        unsafe { snd_ctl_elem_id_get_index(self.as_ptr()) as u32 }
+    }
+
+    // Describes the control element by the fields used to look it up.
+    fn description(&self) -> Result<String> {
+        let name = self.name()?;
+        if name.is_empty() {
+            return Ok(format!("numid={}", self.numid()));
+        }
+        match self.index() {
+            0 => Ok(name.to_owned()),
+            index => Ok(format!("{},index={}", name, index)),
+        }
+    }
+
     /// Borrows the const inner pointer.
     pub fn as_ptr(&self) -> *const snd_ctl_elem_id_t {
         self.0.as_ptr()
@@ -289,6 +459,7 @@ impl ElemInfo {
     ///
     /// * If memory allocation fails.
     /// * If control does not exist.
+    /// * If snd_ctl_elem_info() fails.
     pub fn new(handle: &mut Ctl, id: &ElemId) -> Result<ElemInfo> {
         let mut info_ptr = ptr::null_mut();
 
@@ -307,12 +478,29 @@ impl ElemInfo {
         // Safe because handle.as_mut_ptr() is a valid snd_ctl_t* and info.as_ptr() is a valid
         // snd_ctl_elem_info_t*.
         let rc = unsafe { snd_ctl_elem_info(handle.as_mut_ptr(), info.as_ptr()) };
+        if rc == -libc::ENOENT {
+            return Err(Error::ControlNotFound(id.description()?));
+        }
         if rc < 0 {
-            return Err(Error::ControlNotFound(id.name()?.to_owned()));
+            return Err(Error::ElemInfoFailed(FFIError::Rc(rc)));
         }
         Ok(ElemInfo(info, PhantomData))
     }
 
+    /// Gets the complete `ElemId` of the control element, which includes both its numid and
+    /// name.
+    ///
+    /// # Errors
+    ///
+    /// * If memory allocation fails.
+    pub fn id(&self) -> Result<ElemId> {
+        let id = ElemId::alloc()?;
+        // Safe because self.0.as_ptr() is a valid snd_ctl_elem_info_t* and id.0.as_ptr() is a
+        // valid snd_ctl_elem_id_t*.
+        unsafe { snd_ctl_elem_info_get_id(self.0.as_ptr(), id.0.as_ptr()) };
+        Ok(id)
+    }
+
     /// Safe [snd_ctl_elem_info_get_type](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html#ga0fec5d22ee58d04f14b59f405adc595e) wrapper.
     pub fn elem_type(&self) -> Result<ElemType> {
         // Safe because self.0.as_ptr() is a valid snd_ctl_elem_info_t*.
@@ -324,10 +512,150 @@ impl ElemInfo {
         // Safe because self.0.as_ptr() is a valid snd_ctl_elem_info_t*.
         unsafe { snd_ctl_elem_info_get_count(self.0.as_ptr()) as usize }
     }
+
+    /// Safe [snd_ctl_elem_info_get_min](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    pub fn min(&self) -> i32 {
+        // Safe because self.0.as_ptr() is a valid snd_ctl_elem_info_t*.
+        unsafe { snd_ctl_elem_info_get_min(self.0.as_ptr()) as i32 }
+    }
+
+    /// Safe [snd_ctl_elem_info_get_max](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    pub fn max(&self) -> i32 {
+        // Safe because self.0.as_ptr() is a valid snd_ctl_elem_info_t*.
+        unsafe { snd_ctl_elem_info_get_max(self.0.as_ptr()) as i32 }
+    }
+
+    /// Safe [snd_ctl_elem_info_is_writable](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    pub fn is_writable(&self) -> bool {
+        // Safe because self.0.as_ptr() is a valid snd_ctl_elem_info_t*.
+        unsafe { snd_ctl_elem_info_is_writable(self.0.as_ptr()) != 0 }
+    }
+
+    /// Safe [snd_ctl_elem_info_is_tlv_readable](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    pub fn is_tlv_readable(&self) -> bool {
+        // Safe because self.0.as_ptr() is a valid snd_ctl_elem_info_t*.
+        unsafe { snd_ctl_elem_info_is_tlv_readable(self.0.as_ptr()) != 0 }
+    }
+
+    /// Safe [snd_ctl_elem_info_is_tlv_writable](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    pub fn is_tlv_writable(&self) -> bool {
+        // Safe because self.0.as_ptr() is a valid snd_ctl_elem_info_t*.
+        unsafe { snd_ctl_elem_info_is_tlv_writable(self.0.as_ptr()) != 0 }
+    }
+
+    /// Safe [snd_ctl_elem_info_get_items](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    pub fn items(&self) -> usize {
+        // Safe because self.0.as_ptr() is a valid snd_ctl_elem_info_t*.
+        unsafe { snd_ctl_elem_info_get_items(self.0.as_ptr()) as usize }
+    }
+
+    /// Gets the item names of an enumerated control element.
+    ///
+    /// # Errors
+    ///
+    /// * If snd_ctl_elem_info() fails.
+    /// * If snd_ctl_elem_info_get_item_name() fails.
+    /// * If an item name is not valid UTF-8 data.
+    pub fn item_names(&self, handle: &mut Ctl) -> Result<Vec<String>> {
+        (0..self.items())
+            .map(|i| {
+                // Safe because self.0.as_ptr() is a valid snd_ctl_elem_info_t*.
+                unsafe { snd_ctl_elem_info_set_item(self.0.as_ptr(), i as c_uint) };
+                // Safe because handle.as_mut_ptr() is a valid snd_ctl_t* and self.0.as_ptr() is a
+                // valid snd_ctl_elem_info_t*.
+                let rc = unsafe { snd_ctl_elem_info(handle.as_mut_ptr(), self.0.as_ptr()) };
+                if rc < 0 {
+                    return Err(Error::ElemInfoFailed(FFIError::Rc(rc)));
+                }
+                // Safe because self.0.as_ptr() is a valid snd_ctl_elem_info_t*.
+                let name = unsafe { snd_ctl_elem_info_get_item_name(self.0.as_ptr()) };
+                if name.is_null() {
+                    return Err(Error::ElemInfoGetItemNameFailed);
+                }
+                // Safe because name is a valid *const i8, and we copy it before the next
+                // snd_ctl_elem_info() call.
+                let s = CStr::from_bytes_with_nul(unsafe {
+                    slice::from_raw_parts(name as *const u8, strlen(name) + 1)
+                })?;
+                Ok(s.to_str()?.to_owned())
+            })
+            .collect()
+    }
+}
+
+/// An entry of the control element list of a sound card.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ElemListEntry {
+    /// The unique identifier of the control element.
+    pub numid: u32,
+    /// The interface of the control element.
+    pub iface: u32,
+    /// The name of the control element.
+    pub name: String,
+    /// The index of the control element among the elements with the same name.
+    pub index: u32,
+}
+
+// [snd_ctl_elem_list_t](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+struct ElemList(
+    ptr::NonNull<snd_ctl_elem_list_t>,
+    PhantomData<snd_ctl_elem_list_t>,
+);
+
+impl Drop for ElemList {
+    fn drop(&mut self) {
+        // Safe because self.0.as_ptr() is a valid snd_ctl_elem_list_t*. It's fine to free the
+        // space even if it has not been allocated.
+        unsafe {
+            snd_ctl_elem_list_free_space(self.0.as_ptr());
+            snd_ctl_elem_list_free(self.0.as_ptr());
+        }
+    }
+}
+
+impl ElemList {
+    fn new() -> Result<ElemList> {
+        let mut list_ptr = ptr::null_mut();
+        // Safe because we provide a valid list_ptr to be filled,
+        // and we validate the return code before using list_ptr.
+        let rc = unsafe { snd_ctl_elem_list_malloc(&mut list_ptr) };
+        if rc < 0 {
+            return Err(Error::ElemListMallocFailed(FFIError::Rc(rc)));
+        }
+        let list =
+            ptr::NonNull::new(list_ptr).ok_or(Error::ElemListMallocFailed(FFIError::NullPtr))?;
+        Ok(ElemList(list, PhantomData))
+    }
+
+    fn entry(&self, idx: u32) -> Result<ElemListEntry> {
+        // Safe because self.0.as_ptr() is a valid snd_ctl_elem_list_t* and idx is less than
+        // the number of used entries.
+        let name = unsafe { snd_ctl_elem_list_get_name(self.0.as_ptr(), idx as c_uint) };
+        if name.is_null() {
+            return Err(Error::ElemListGetNameFailed);
+        }
+        // Safe because name is a valid *const i8, which lives as long as self.
+        let name = unsafe { CStr::from_ptr(name) }.to_str()?.to_owned();
+        // Safe because self.0.as_ptr() is a valid snd_ctl_elem_list_t* and idx is less than
+        // the number of used entries.
+        unsafe {
+            Ok(ElemListEntry {
+                numid: snd_ctl_elem_list_get_numid(self.0.as_ptr(), idx as c_uint) as u32,
+                iface: snd_ctl_elem_list_get_interface(self.0.as_ptr(), idx as c_uint) as u32,
+                name,
+                index: snd_ctl_elem_list_get_index(self.0.as_ptr(), idx as c_uint) as u32,
+            })
+        }
+    }
 }
 
 /// [snd_ctl_t](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html#ga06628f38def84a0fe3da74041db9d51f) wrapper.
-pub struct Ctl(ptr::NonNull<snd_ctl_t>, PhantomData<snd_ctl_t>);
+/// It holds the recorded control accesses while the recording is on.
+pub struct Ctl(
+    ptr::NonNull<snd_ctl_t>,
+    PhantomData<snd_ctl_t>,
+    Option<Vec<TraceOp>>,
+);
 
 impl Drop for Ctl {
     fn drop(&mut self) {
@@ -361,13 +689,520 @@ impl Ctl {
             FFIError::NullPtr,
             name.to_str()?.to_owned(),
         ))?;
-        Ok(Ctl(ctl, PhantomData))
+        Ok(Ctl(ctl, PhantomData, None))
     }
 
     /// Borrows the mutable inner pointer
     pub fn as_mut_ptr(&mut self) -> *mut snd_ctl_t {
         self.0.as_ptr()
     }
+
+    // Takes the recorded accesses, which stops the recording, or None if it's off.
+    pub(crate) fn take_recording(&mut self) -> Option<Vec<TraceOp>> {
+        self.2.take()
+    }
+
+    // Records the following accesses after the recorded ones, or stops the recording if it's
+    // None.
+    pub(crate) fn set_recording(&mut self, recorded: Option<Vec<TraceOp>>) {
+        self.2 = recorded;
+    }
+
+    // Records a successful access of the control element if the recording is on. `values` is
+    // only called when it's on.
+    pub(crate) fn record<F>(
+        &mut self,
+        access: TraceAccess,
+        id: &ElemId,
+        elem_type: ElemType,
+        values: F,
+    ) where
+        F: FnOnce() -> Vec<i64>,
+    {
+        if let Some(recorded) = self.2.as_mut() {
+            recorded.push(TraceOp {
+                access,
+                control: id.name().unwrap_or_default().to_owned(),
+                elem_type,
+                values: values(),
+            });
+        }
+    }
+
+    /// Safe [snd_ctl_elem_list](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    /// Counts the control elements of the sound card without listing them.
+    ///
+    /// # Errors
+    ///
+    /// * If memory allocation fails.
+    /// * If `snd_ctl_elem_list()` fails.
+    pub fn elem_count(&mut self) -> Result<u32> {
+        let list = ElemList::new()?;
+        // Safe because self.as_mut_ptr() is a valid snd_ctl_t* and list.0.as_ptr() is a valid
+        // snd_ctl_elem_list_t*.
+        let rc = unsafe { snd_ctl_elem_list(self.as_mut_ptr(), list.0.as_ptr()) };
+        if rc < 0 {
+            return Err(Error::ElemListFailed(FFIError::Rc(rc)));
+        }
+        // Safe because list.0.as_ptr() is a valid snd_ctl_elem_list_t*.
+        Ok(unsafe { snd_ctl_elem_list_get_count(list.0.as_ptr()) })
+    }
+
+    /// Safe [snd_ctl_elem_list](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    /// Lists all control elements of the sound card.
+    ///
+    /// # Errors
+    ///
+    /// * If memory allocation fails.
+    /// * If `snd_ctl_elem_list()` fails.
+    /// * If any control element name is not valid UTF-8 data.
+    pub fn elem_list(&mut self) -> Result<Vec<ElemListEntry>> {
+        let list = ElemList::new()?;
+        // The first call gets the number of control elements, and the second call fills the
+        // allocated space.
+        // Safe because self.as_mut_ptr() is a valid snd_ctl_t* and list.0.as_ptr() is a valid
+        // snd_ctl_elem_list_t*.
+        let rc = unsafe { snd_ctl_elem_list(self.as_mut_ptr(), list.0.as_ptr()) };
+        if rc < 0 {
+            return Err(Error::ElemListFailed(FFIError::Rc(rc)));
+        }
+        // Safe because list.0.as_ptr() is a valid snd_ctl_elem_list_t*.
+        let rc = unsafe {
+            let count = snd_ctl_elem_list_get_count(list.0.as_ptr());
+            snd_ctl_elem_list_alloc_space(list.0.as_ptr(), count)
+        };
+        if rc < 0 {
+            return Err(Error::ElemListAllocSpaceFailed(FFIError::Rc(rc)));
+        }
+        // Safe because self.as_mut_ptr() is a valid snd_ctl_t* and list.0.as_ptr() is a valid
+        // snd_ctl_elem_list_t* with the allocated space.
+        let rc = unsafe { snd_ctl_elem_list(self.as_mut_ptr(), list.0.as_ptr()) };
+        if rc < 0 {
+            return Err(Error::ElemListFailed(FFIError::Rc(rc)));
+        }
+        // Safe because list.0.as_ptr() is a valid snd_ctl_elem_list_t*.
+        let used = unsafe { snd_ctl_elem_list_get_used(list.0.as_ptr()) };
+        (0..used).map(|idx| list.entry(idx)).collect()
+    }
+
+    /// Safe [snd_ctl_elem_lock](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    /// Other `Ctl`s can't write the locked control element until it is unlocked or the `Ctl`
+    /// is dropped.
+    ///
+    /// # Errors
+    ///
+    /// * If `snd_ctl_elem_lock()` fails, ex: the element is locked by another `Ctl`.
+    pub fn elem_lock(&mut self, id: &ElemId) -> Result<()> {
+        // Safe because self.as_mut_ptr() is a valid snd_ctl_t* and id.as_ptr() is a valid
+        // snd_ctl_elem_id_t*, which is not modified by snd_ctl_elem_lock().
+        let rc = unsafe { snd_ctl_elem_lock(self.as_mut_ptr(), id.as_ptr() as *mut _) };
+        if rc < 0 {
+            return Err(Error::ElemLockFailed(FFIError::Rc(rc), id.description()?));
+        }
+        Ok(())
+    }
+
+    /// Safe [snd_ctl_elem_unlock](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    ///
+    /// # Errors
+    ///
+    /// * If `snd_ctl_elem_unlock()` fails, ex: the element is not locked by this `Ctl`.
+    pub fn elem_unlock(&mut self, id: &ElemId) -> Result<()> {
+        // Safe because self.as_mut_ptr() is a valid snd_ctl_t* and id.as_ptr() is a valid
+        // snd_ctl_elem_id_t*, which is not modified by snd_ctl_elem_unlock().
+        let rc = unsafe { snd_ctl_elem_unlock(self.as_mut_ptr(), id.as_ptr() as *mut _) };
+        if rc < 0 {
+            return Err(Error::ElemUnlockFailed(FFIError::Rc(rc), id.description()?));
+        }
+        Ok(())
+    }
+
+    /// Safe [snd_ctl_subscribe_events](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    ///
+    /// # Errors
+    ///
+    /// * If `snd_ctl_subscribe_events()` fails.
+    pub fn subscribe_events(&mut self, subscribe: bool) -> Result<()> {
+        // Safe because self.as_mut_ptr() is a valid snd_ctl_t*.
+        let rc = unsafe { snd_ctl_subscribe_events(self.as_mut_ptr(), subscribe as c_int) };
+        if rc < 0 {
+            return Err(Error::CtlSubscribeEventsFailed(FFIError::Rc(rc)));
+        }
+        Ok(())
+    }
+
+    /// Safe [snd_ctl_wait](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    ///
+    /// # Results
+    ///
+    /// * `ready` - true if there are events to read, false if `timeout` elapses.
+    ///
+    /// # Errors
+    ///
+    /// * If `snd_ctl_wait()` fails.
+    pub fn wait(&mut self, timeout: Duration) -> Result<bool> {
+        let timeout_ms = timeout.as_millis().min(c_int::MAX as u128) as c_int;
+        // Safe because self.as_mut_ptr() is a valid snd_ctl_t*.
+        let rc = unsafe { snd_ctl_wait(self.as_mut_ptr(), timeout_ms) };
+        if rc < 0 {
+            return Err(Error::CtlWaitFailed(FFIError::Rc(rc)));
+        }
+        Ok(rc > 0)
+    }
+
+    /// Safe [snd_ctl_poll_descriptors](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    /// The hw control device has a single poll descriptor, which becomes readable when there
+    /// are events to read.
+    ///
+    /// # Errors
+    ///
+    /// * If `snd_ctl_poll_descriptors()` fails.
+    pub fn poll_descriptor(&mut self) -> Result<RawFd> {
+        let mut pfd = pollfd {
+            fd: -1,
+            events: 0,
+            revents: 0,
+        };
+        // Safe because self.as_mut_ptr() is a valid snd_ctl_t* and pfd has the space of one
+        // pollfd.
+        let rc = unsafe { snd_ctl_poll_descriptors(self.as_mut_ptr(), &mut pfd, 1) };
+        if rc < 0 {
+            return Err(Error::CtlPollDescriptorsFailed(FFIError::Rc(rc)));
+        }
+        Ok(pfd.fd)
+    }
+
+    /// Safe [snd_ctl_read](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    /// It blocks until an event is available.
+    ///
+    /// # Errors
+    ///
+    /// * If memory allocation fails.
+    /// * If `snd_ctl_read()` fails.
+    pub fn read_event(&mut self) -> Result<Event> {
+        let event = Event::new()?;
+        // Safe because self.as_mut_ptr() is a valid snd_ctl_t* and event.0.as_ptr() is a valid
+        // snd_ctl_event_t*.
+        let rc = unsafe { snd_ctl_read(self.as_mut_ptr(), event.0.as_ptr()) };
+        if rc < 0 {
+            return Err(Error::CtlReadFailed(FFIError::Rc(rc)));
+        }
+        Ok(event)
+    }
+
+    /// Safe [snd_ctl_pcm_next_device](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    ///
+    /// # Results
+    ///
+    /// * The next PCM device after `device`, or None if there are no more devices.
+    ///   Use -1 as `device` to get the first PCM device.
+    ///
+    /// # Errors
+    ///
+    /// * If `snd_ctl_pcm_next_device()` fails.
+    pub fn pcm_next_device(&mut self, device: i32) -> Result<Option<u32>> {
+        let mut device = device as c_int;
+        // Safe because self.as_mut_ptr() is a valid snd_ctl_t* and we provide a valid device
+        // to be filled.
+        let rc = unsafe { snd_ctl_pcm_next_device(self.as_mut_ptr(), &mut device) };
+        if rc < 0 {
+            return Err(Error::CtlPcmNextDeviceFailed(FFIError::Rc(rc)));
+        }
+        Ok(if device < 0 {
+            None
+        } else {
+            Some(device as u32)
+        })
+    }
+}
+
+/// [snd_ctl_card_info_t](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+pub struct CtlCardInfo(
+    ptr::NonNull<snd_ctl_card_info_t>,
+    PhantomData<snd_ctl_card_info_t>,
+);
+
+impl Drop for CtlCardInfo {
+    fn drop(&mut self) {
+        // Safe because self.0.as_ptr() is a valid snd_ctl_card_info_t*.
+        unsafe { snd_ctl_card_info_free(self.0.as_ptr()) };
+    }
+}
+
+impl CtlCardInfo {
+    /// Creates a `CtlCardInfo` of the sound card of the `Ctl`.
+    ///
+    /// # Errors
+    ///
+    /// * If memory allocation fails.
+    /// * If snd_ctl_card_info() fails.
+    pub fn new(handle: &mut Ctl) -> Result<CtlCardInfo> {
+        let mut info_ptr = ptr::null_mut();
+        // Safe because we provide a valid info_ptr to be filled,
+        // and we validate the return code before using info_ptr.
+        let rc = unsafe { snd_ctl_card_info_malloc(&mut info_ptr) };
+        if rc < 0 {
+            return Err(Error::CtlCardInfoMallocFailed(FFIError::Rc(rc)));
+        }
+        let info =
+            ptr::NonNull::new(info_ptr).ok_or(Error::CtlCardInfoMallocFailed(FFIError::NullPtr))?;
+        let info = CtlCardInfo(info, PhantomData);
+
+        // Safe because handle.as_mut_ptr() is a valid snd_ctl_t* and info.0.as_ptr() is a valid
+        // snd_ctl_card_info_t*.
+        let rc = unsafe { snd_ctl_card_info(handle.as_mut_ptr(), info.0.as_ptr()) };
+        if rc < 0 {
+            return Err(Error::CtlCardInfoFailed(FFIError::Rc(rc)));
+        }
+        Ok(info)
+    }
+
+    /// Safe [snd_ctl_card_info_get_card](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    pub fn card(&self) -> i32 {
+        // Safe because self.0.as_ptr() is a valid snd_ctl_card_info_t*.
+        unsafe { snd_ctl_card_info_get_card(self.0.as_ptr()) as i32 }
+    }
+
+    /// Safe [snd_ctl_card_info_get_id](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    pub fn id(&self) -> Result<&str> {
+        // Safe because self.0.as_ptr() is a valid snd_ctl_card_info_t*.
+        let s = unsafe { snd_ctl_card_info_get_id(self.0.as_ptr()) };
+        self.to_str(s, "id")
+    }
+
+    /// Safe [snd_ctl_card_info_get_driver](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    pub fn driver(&self) -> Result<&str> {
+        // Safe because self.0.as_ptr() is a valid snd_ctl_card_info_t*.
+        let s = unsafe { snd_ctl_card_info_get_driver(self.0.as_ptr()) };
+        self.to_str(s, "driver")
+    }
+
+    /// Safe [snd_ctl_card_info_get_name](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    pub fn name(&self) -> Result<&str> {
+        // Safe because self.0.as_ptr() is a valid snd_ctl_card_info_t*.
+        let s = unsafe { snd_ctl_card_info_get_name(self.0.as_ptr()) };
+        self.to_str(s, "name")
+    }
+
+    /// Safe [snd_ctl_card_info_get_longname](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    pub fn longname(&self) -> Result<&str> {
+        // Safe because self.0.as_ptr() is a valid snd_ctl_card_info_t*.
+        let s = unsafe { snd_ctl_card_info_get_longname(self.0.as_ptr()) };
+        self.to_str(s, "longname")
+    }
+
+    /// Safe [snd_ctl_card_info_get_components](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    pub fn components(&self) -> Result<&str> {
+        // Safe because self.0.as_ptr() is a valid snd_ctl_card_info_t*.
+        let s = unsafe { snd_ctl_card_info_get_components(self.0.as_ptr()) };
+        self.to_str(s, "components")
+    }
+
+    // Converts a string field of self to &str.
+    fn to_str(&self, s: *const c_char, field: &'static str) -> Result<&str> {
+        if s.is_null() {
+            return Err(Error::CtlCardInfoGetFailed(field));
+        }
+        // Safe because s is a valid *const i8, and its life time
+        // is the same as the passed reference of self.
+        let s = CStr::from_bytes_with_nul(unsafe {
+            slice::from_raw_parts(s as *const u8, strlen(s) + 1)
+        })?;
+        Ok(s.to_str()?)
+    }
+}
+
+/// The direction of a PCM stream.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PcmStream {
+    Playback = SND_PCM_STREAM_PLAYBACK as isize,
+    Capture = SND_PCM_STREAM_CAPTURE as isize,
+}
+
+/// [snd_pcm_info_t](https://www.alsa-project.org/alsa-doc/alsa-lib/group___p_c_m___info.html) wrapper.
+pub struct PcmInfo(ptr::NonNull<snd_pcm_info_t>, PhantomData<snd_pcm_info_t>);
+
+impl Drop for PcmInfo {
+    fn drop(&mut self) {
+        // Safe because self.0.as_ptr() is a valid snd_pcm_info_t*.
+        unsafe { snd_pcm_info_free(self.0.as_ptr()) };
+    }
+}
+
+impl PcmInfo {
+    /// Gets the `PcmInfo` of the stream of the PCM device.
+    ///
+    /// # Results
+    ///
+    /// * The `PcmInfo`, or None if the PCM device does not support the stream direction.
+    ///
+    /// # Errors
+    ///
+    /// * If memory allocation fails.
+    /// * If snd_ctl_pcm_info() fails.
+    pub fn new(handle: &mut Ctl, device: u32, stream: PcmStream) -> Result<Option<PcmInfo>> {
+        let mut info_ptr = ptr::null_mut();
+        // Safe because we provide a valid info_ptr to be filled,
+        // and we validate the return code before using info_ptr.
+        let rc = unsafe { snd_pcm_info_malloc(&mut info_ptr) };
+        if rc < 0 {
+            return Err(Error::PcmInfoMallocFailed(FFIError::Rc(rc)));
+        }
+        let info =
+            ptr::NonNull::new(info_ptr).ok_or(Error::PcmInfoMallocFailed(FFIError::NullPtr))?;
+        let info = PcmInfo(info, PhantomData);
+
+        // Safe because info.0.as_ptr() is a valid snd_pcm_info_t*.
+        unsafe {
+            snd_pcm_info_set_device(info.0.as_ptr(), device as c_uint);
+            snd_pcm_info_set_subdevice(info.0.as_ptr(), 0);
+            snd_pcm_info_set_stream(info.0.as_ptr(), stream as snd_pcm_stream_t);
+        }
+        // Safe because handle.as_mut_ptr() is a valid snd_ctl_t* and info.0.as_ptr() is a valid
+        // snd_pcm_info_t*.
+        let rc = unsafe { snd_ctl_pcm_info(handle.as_mut_ptr(), info.0.as_ptr()) };
+        if rc == -libc::ENOENT {
+            return Ok(None);
+        }
+        if rc < 0 {
+            return Err(Error::CtlPcmInfoFailed(FFIError::Rc(rc)));
+        }
+        Ok(Some(info))
+    }
+
+    /// Safe [snd_pcm_info_get_device](https://www.alsa-project.org/alsa-doc/alsa-lib/group___p_c_m___info.html) wrapper.
+    pub fn device(&self) -> u32 {
+        // Safe because self.0.as_ptr() is a valid snd_pcm_info_t*.
+        unsafe { snd_pcm_info_get_device(self.0.as_ptr()) as u32 }
+    }
+
+    /// Safe [snd_pcm_info_get_subdevices_count](https://www.alsa-project.org/alsa-doc/alsa-lib/group___p_c_m___info.html) wrapper.
+    pub fn subdevices_count(&self) -> u32 {
+        // Safe because self.0.as_ptr() is a valid snd_pcm_info_t*.
+        unsafe { snd_pcm_info_get_subdevices_count(self.0.as_ptr()) as u32 }
+    }
+
+    /// Safe [snd_pcm_info_get_id](https://www.alsa-project.org/alsa-doc/alsa-lib/group___p_c_m___info.html) wrapper.
+    pub fn id(&self) -> Result<&str> {
+        // Safe because self.0.as_ptr() is a valid snd_pcm_info_t*.
+        let s = unsafe { snd_pcm_info_get_id(self.0.as_ptr()) };
+        self.to_str(s, "id")
+    }
+
+    /// Safe [snd_pcm_info_get_name](https://www.alsa-project.org/alsa-doc/alsa-lib/group___p_c_m___info.html) wrapper.
+    pub fn name(&self) -> Result<&str> {
+        // Safe because self.0.as_ptr() is a valid snd_pcm_info_t*.
+        let s = unsafe { snd_pcm_info_get_name(self.0.as_ptr()) };
+        self.to_str(s, "name")
+    }
+
+    // Converts a string field of self to &str.
+    fn to_str(&self, s: *const c_char, field: &'static str) -> Result<&str> {
+        if s.is_null() {
+            return Err(Error::PcmInfoGetFailed(field));
+        }
+        // Safe because s is a valid *const i8, and its life time
+        // is the same as the passed reference of self.
+        let s = CStr::from_bytes_with_nul(unsafe {
+            slice::from_raw_parts(s as *const u8, strlen(s) + 1)
+        })?;
+        Ok(s.to_str()?)
+    }
+}
+
+/// Safe [snd_card_next](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+///
+/// # Results
+///
+/// * The index of the next sound card after `card`, or None if there are no more cards.
+///   Use -1 as `card` to get the first sound card.
+///
+/// # Errors
+///
+/// * If `snd_card_next()` fails.
+pub fn card_next(card: i32) -> Result<Option<i32>> {
+    let mut card = card as c_int;
+    // Safe because we provide a valid card to be filled.
+    let rc = unsafe { snd_card_next(&mut card) };
+    if rc < 0 {
+        return Err(Error::CardNextFailed(FFIError::Rc(rc)));
+    }
+    Ok(if card < 0 { None } else { Some(card as i32) })
+}
+
+// The event masks of control element changes, which are not exported by alsa-sys.
+const SND_CTL_EVENT_MASK_VALUE: c_uint = 1 << 0;
+const SND_CTL_EVENT_MASK_ADD: c_uint = 1 << 2;
+const SND_CTL_EVENT_MASK_REMOVE: c_uint = !0;
+
+/// [snd_ctl_event_t](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+pub struct Event(ptr::NonNull<snd_ctl_event_t>, PhantomData<snd_ctl_event_t>);
+
+impl Drop for Event {
+    fn drop(&mut self) {
+        // Safe because self.0.as_ptr() is a valid snd_ctl_event_t*.
+        unsafe { snd_ctl_event_free(self.0.as_ptr()) };
+    }
+}
+
+impl Event {
+    fn new() -> Result<Event> {
+        let mut event_ptr = ptr::null_mut();
+        // Safe because we provide a valid event_ptr to be filled,
+        // and we validate the return code before using event_ptr.
+        let rc = unsafe { snd_ctl_event_malloc(&mut event_ptr) };
+        if rc < 0 {
+            return Err(Error::EventMallocFailed(FFIError::Rc(rc)));
+        }
+        let event =
+            ptr::NonNull::new(event_ptr).ok_or(Error::EventMallocFailed(FFIError::NullPtr))?;
+        Ok(Event(event, PhantomData))
+    }
+
+    /// Returns true if it is a value change event of a control element.
+    pub fn is_elem_value_changed(&self) -> bool {
+        match self.elem_mask() {
+            Some(mask) => mask != SND_CTL_EVENT_MASK_REMOVE && mask & SND_CTL_EVENT_MASK_VALUE != 0,
+            None => false,
+        }
+    }
+
+    /// Returns true if a control element is added or removed.
+    pub fn is_elem_list_changed(&self) -> bool {
+        match self.elem_mask() {
+            Some(mask) => mask == SND_CTL_EVENT_MASK_REMOVE || mask & SND_CTL_EVENT_MASK_ADD != 0,
+            None => false,
+        }
+    }
+
+    fn elem_mask(&self) -> Option<c_uint> {
+        // Safe because self.0.as_ptr() is a valid snd_ctl_event_t*.
+        unsafe {
+            if snd_ctl_event_get_type(self.0.as_ptr()) != SND_CTL_EVENT_ELEM {
+                return None;
+            }
+            Some(snd_ctl_event_elem_get_mask(self.0.as_ptr()))
+        }
+    }
+
+    /// Safe [snd_ctl_event_elem_get_name](https://www.alsa-project.org/alsa-doc/alsa-lib/group___control.html) wrapper.
+    ///
+    /// # Errors
+    ///
+    /// * If the control element name is not valid UTF-8 data.
+    pub fn elem_name(&self) -> Result<&str> {
+        // Safe because self.0.as_ptr() is a valid snd_ctl_event_t*.
+        let name = unsafe { snd_ctl_event_elem_get_name(self.0.as_ptr()) };
+        if name.is_null() {
+            return Err(Error::ElemIdGetNameFailed);
+        }
+        // Safe because name is a valid *const i8, and its life time
+        // is the same as the passed reference of self.
+        let s = CStr::from_bytes_with_nul(unsafe {
+            slice::from_raw_parts(name as *const u8, strlen(name) + 1)
+        })?;
+        Ok(s.to_str()?)
+    }
 }
 
 /// Safe [snd_strerror](https://www.alsa-project.org/alsa-doc/alsa-lib/group___error.html#ga182bbadf2349e11602bc531e8cf22f7e) wrapper.
@@ -0,0 +1,123 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! `control_set` module provides a builder to define the controls used by a driver and validate
+//! them against the sound card once at startup.
+//!
+//! # Examples
+//!
+//! ``` no_run
+//! use cros_alsa::{Access, Card, CardError, ControlHandle, ControlSetBuilder};
+//!
+//! struct AmpControls {
+//!     rdc: ControlHandle<[i32; 1]>,
+//!     calib: ControlHandle<[bool; 1]>,
+//! }
+//!
+//! fn main() -> Result<(), CardError> {
+//!     let mut card = Card::new("sofcmlmax98390d")?;
+//!     let mut builder = ControlSetBuilder::new(&mut card);
+//!     let ctrls = AmpControls {
+//!         rdc: builder.control("Left Rdc", Access::ReadWrite),
+//!         calib: builder.control("Left DSM Calibration", Access::ReadWrite),
+//!     };
+//!     // Reports all the mismatched controls in a single error.
+//!     builder.build()?;
+//!
+//!     card.set(&ctrls.calib, [true])?;
+//!     let _rdc = card.get(&ctrls.rdc)?;
+//!     Ok(())
+//! }
+//! ```
+
+use std::marker::PhantomData;
+
+use crate::card::{Card, Error, Result};
+use crate::control_primitive::ElemInfo;
+use crate::elem::Elem;
+
+/// The access of a control required by the driver.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Access {
+    /// The driver only reads the control.
+    ReadOnly,
+    /// The driver reads and writes the control.
+    ReadWrite,
+}
+
+/// `ControlHandle` is a typed handle of a control validated by `ControlSetBuilder`.
+/// Use `Card::get()` and `Card::set()` to access the control.
+#[derive(Debug, Clone)]
+pub struct ControlHandle<E: Elem> {
+    name: String,
+    access: Access,
+    elem: PhantomData<E>,
+}
+
+impl<E: Elem> ControlHandle<E> {
+    pub(crate) fn new(control_name: &str, access: Access) -> Self {
+        ControlHandle {
+            name: control_name.to_owned(),
+            access,
+            elem: PhantomData,
+        }
+    }
+
+    /// Gets the control name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Gets the access of the control.
+    pub fn access(&self) -> Access {
+        self.access
+    }
+}
+
+/// `ControlSetBuilder` validates the controls of a driver against the sound card.
+pub struct ControlSetBuilder<'a> {
+    card: &'a mut Card,
+    errors: Vec<String>,
+}
+
+impl<'a> ControlSetBuilder<'a> {
+    /// Creates a `ControlSetBuilder` of the sound card.
+    pub fn new(card: &'a mut Card) -> Self {
+        ControlSetBuilder {
+            card,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Declares a control of the driver and validates its type, number of value entries and
+    /// access. The validation errors are reported by `build()`.
+    pub fn control<E: Elem>(&mut self, control_name: &str, access: Access) -> ControlHandle<E> {
+        if let Err(e) = self.validate::<E>(control_name, access) {
+            self.errors.push(e.to_string());
+        }
+        ControlHandle::new(control_name, access)
+    }
+
+    /// Finishes the validation.
+    ///
+    /// # Errors
+    ///
+    /// * If any declared control does not exist or mismatches the underlying mixer control.
+    pub fn build(self) -> Result<()> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidControlSet(self.errors))
+        }
+    }
+
+    fn validate<E: Elem>(&mut self, control_name: &str, access: Access) -> Result<()> {
+        let id = self.card.elem_id::<E>(control_name)?;
+        let info = ElemInfo::new(self.card.handle(), &id)?;
+        if access == Access::ReadWrite && !info.is_writable() {
+            return Err(Error::ReadOnlyControl(control_name.to_owned()));
+        }
+        Ok(())
+    }
+}
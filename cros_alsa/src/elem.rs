@@ -7,6 +7,7 @@
 //!
 //! The `Elem::type()` returns the type of value that a control element can interact with,
 //! and it is one of integer, integer64, boolean, enumerators, bytes or IEC958 structure.
+//! The enumerated values are represented by the u32 item indices.
 //! The `Elem::size()` returns the number of values it reads from or writes to the hardware
 //! at a time.
 //! The `Elem::load(..)` and `Elem::save(..)` are used by `ControlOps` trait to read and write
@@ -18,11 +19,18 @@
 use std::default::Default;
 use std::error;
 use std::fmt;
+use std::marker::PhantomData;
+use std::mem::{self, size_of};
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
 
-use libc::{c_long, c_uint};
+use libc::{c_long, c_longlong, c_uchar, c_uint};
 use remain::sorted;
 
-use crate::control_primitive::{self, snd_strerror, Ctl, ElemId, ElemType, ElemValue};
+use crate::control_primitive::{
+    self, snd_strerror, Ctl, ElemId, ElemInfo, ElemType, ElemValue, Event,
+};
+use crate::trace::TraceAccess;
 
 /// The Result type of cros-alsa::elem.
 pub type Result<T> = std::result::Result<T, Error>;
@@ -35,12 +43,37 @@ pub enum Error {
     AlsaControlAPI(control_primitive::Error),
     /// Failed to call `snd_ctl_elem_read()`.
     ElemReadFailed(i32),
+    /// Failed to call `snd_ctl_elem_tlv_read()`.
+    ElemTlvReadFailed(i32),
+    /// Failed to call `snd_ctl_elem_tlv_write()`.
+    ElemTlvWriteFailed(i32),
     /// Failed to call `snd_ctl_elem_write()`.
     ElemWriteFailed(i32),
+    /// The number of values mismatches the number of value entries of the control element.
+    MismatchValueCount(usize, usize),
+    /// The TLV data is larger than the control element capacity.
+    TlvDataTooLarge(usize, usize),
+    /// The control element does not meet the condition before the timeout.
+    WaitForTimeout(String, Duration),
 }
 
 impl error::Error for Error {}
 
+impl Error {
+    /// Returns true if the error is caused by the removal of the sound card.
+    pub fn is_enodev(&self) -> bool {
+        use Error::*;
+        match self {
+            AlsaControlAPI(e) => e.is_enodev(),
+            ElemReadFailed(rc)
+            | ElemTlvReadFailed(rc)
+            | ElemTlvWriteFailed(rc)
+            | ElemWriteFailed(rc) => *rc == -libc::ENODEV,
+            MismatchValueCount(..) | TlvDataTooLarge(..) | WaitForTimeout(..) => false,
+        }
+    }
+}
+
 impl From<control_primitive::Error> for Error {
     fn from(err: control_primitive::Error) -> Error {
         Error::AlsaControlAPI(err)
@@ -53,19 +86,40 @@ impl fmt::Display for Error {
         match self {
             AlsaControlAPI(e) => write!(f, "{}", e),
             ElemReadFailed(e) => write!(f, "snd_ctl_elem_read failed: {}", snd_strerror(*e)?),
+            ElemTlvReadFailed(e) => {
+                write!(f, "snd_ctl_elem_tlv_read failed: {}", snd_strerror(*e)?)
+            }
+            ElemTlvWriteFailed(e) => {
+                write!(f, "snd_ctl_elem_tlv_write failed: {}", snd_strerror(*e)?)
+            }
             ElemWriteFailed(e) => write!(f, "snd_ctl_elem_write failed: {}", snd_strerror(*e)?),
+            MismatchValueCount(count, expected) => write!(
+                f,
+                "got {} values, the control element has {} value entries",
+                count, expected
+            ),
+            TlvDataTooLarge(size, capacity) => write!(
+                f,
+                "TLV data size: {} exceeds the control capacity: {}",
+                size, capacity
+            ),
+            WaitForTimeout(name, timeout) => {
+                write!(f, "{} does not meet the condition in {:?}", name, timeout)
+            }
         }
     }
 }
 
-// Uses a recursive macro to generate implementation for [bool; n] and [i32; n], n = 1 to 128.
-// The `$t:ident $($ts:ident)*` part matches and removes one token at a time. It's used for
-// counting recursive steps.
+// Uses a macro to generate implementation for [bool; N], [i32; N], [i64; N], [u32; N] and
+// [u8; N].
+// The array length is a const generic parameter, so the number of value entries of a control
+// is checked by the type system, and `ValueCapacity` fails the build if it exceeds the
+// capacity of `snd_ctl_elem_value_t`.
 macro_rules! impl_for_array {
-    {$n:expr, $type:ty, $t:ident $($ts:ident)*} => {
-        impl Elem for [$type; $n] {
+    {$type:ty} => {
+        impl<const N: usize> Elem for [$type; N] {
             type T = Self;
-            /// Reads [$type; $n] data from the mixer control.
+            /// Reads [$type; N] data from the mixer control.
             ///
             /// # Errors
             ///
@@ -79,16 +133,20 @@ macro_rules! impl_for_array {
                 if rc < 0 {
                     return Err(Error::ElemReadFailed(rc));
                 }
-                let mut ret = [Default::default(); $n];
-                for i in 0..$n {
-                    // Safe because elem.as_ptr() is a valid snd_ctl_elem_value_t* and i is guaranteed to be
-                    // within a valid range.
+                let mut ret = [Default::default(); N];
+                let () = ValueCapacity::<$type, N>::CHECK;
+                for i in 0..N {
+                    // Safe because elem.as_ptr() is a valid snd_ctl_elem_value_t* and i is less
+                    // than N, which is within the capacity checked by `ValueCapacity`.
                     ret[i] = unsafe { <$type>::elem_value_get(&elem, i) };
                 }
+                handle.record(TraceAccess::Read, id, Self::elem_type(), || {
+                    ret.iter().map(|v| *v as i64).collect()
+                });
                 Ok(ret)
             }
 
-            /// Updates [$type; $n] data to the mixer control.
+            /// Updates [$type; N] data to the mixer control.
             ///
             /// # Results
             ///
@@ -100,9 +158,10 @@ macro_rules! impl_for_array {
             /// * If it fails to call `snd_ctl_elem_write()`.
             fn save(handle: &mut Ctl, id: &ElemId, val: Self::T) -> Result<bool> {
                 let mut elem = ElemValue::new(id)?;
-                for i in 0..$n {
-                    // Safe because elem.as_mut_ptr() is a valid snd_ctl_elem_value_t* and i is guaranteed to be
-                    // within a valid range.
+                let () = ValueCapacity::<$type, N>::CHECK;
+                for i in 0..N {
+                    // Safe because elem.as_mut_ptr() is a valid snd_ctl_elem_value_t* and i is
+                    // less than N, which is within the capacity checked by `ValueCapacity`.
                     unsafe { <$type>::elem_value_set(&mut elem, i, val[i]) };
                 }
                 // Safe because self.handle.as_mut_ptr() is a valid *mut snd_ctl_t and
@@ -111,6 +170,9 @@ macro_rules! impl_for_array {
                 if rc < 0 {
                     return Err(Error::ElemWriteFailed(rc));
                 }
+                handle.record(TraceAccess::Write, id, Self::elem_type(), || {
+                    val.iter().map(|v| *v as i64).collect()
+                });
                 Ok(rc > 0)
             }
 
@@ -121,32 +183,111 @@ macro_rules! impl_for_array {
 
             /// Gets the number of value entries itself can read and write.
             fn size() -> usize {
-                $n
+                N
             }
         }
-        impl_for_array!{($n - 1), $type, $($ts)*}
     };
-    {$n:expr, $type:ty,} => {};
 }
 
-// Implements `Elem` for [i32; n] where n = 1 to 128.
-impl_for_array! {128, i32,
-T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T
-T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T
-T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T
-T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T
-}
+// Implements `Elem` for [i32; N].
+impl_for_array! {i32}
+
+// Implements `Elem` for [i64; N].
+impl_for_array! {i64}
+
+// Implements `Elem` for [bool; N].
+impl_for_array! {bool}
+
+// Implements `Elem` for [u32; N].
+impl_for_array! {u32}
+
+// Implements `Elem` for [u8; N].
+impl_for_array! {u8}
+
+// Uses a macro to generate implementation for Vec<bool> and Vec<i32>.
+// The number of value entries is read from the control element at runtime, ex: the playback
+// switch and volume controls whose number of channels differs between sound cards.
+macro_rules! impl_for_vec {
+    {$type:ty} => {
+        impl Elem for Vec<$type> {
+            type T = Self;
+            /// Reads all the $type values of the mixer control.
+            ///
+            /// # Errors
+            ///
+            /// * If it fails to call `snd_ctl_elem_read()`.
+            fn load(handle: &mut Ctl, id: &ElemId) -> Result<Self::T> {
+                let count = ElemInfo::new(handle, id)?.count();
+                let mut elem = ElemValue::new(id)?;
+                // Safe because self.handle.as_mut_ptr() is a valid *mut snd_ctl_t and
+                // elem.as_mut_ptr() is also a valid *mut snd_ctl_elem_value_t.
+                let rc = unsafe { alsa_sys::snd_ctl_elem_read(handle.as_mut_ptr(), elem.as_mut_ptr()) };
+                if rc < 0 {
+                    return Err(Error::ElemReadFailed(rc));
+                }
+                // Safe because elem.as_ptr() is a valid snd_ctl_elem_value_t* and i is within
+                // the number of value entries of the control element.
+                let ret: Self::T = (0..count).map(|i| unsafe { <$type>::elem_value_get(&elem, i) }).collect();
+                handle.record(TraceAccess::Read, id, Self::elem_type(), || {
+                    ret.iter().map(|v| *v as i64).collect()
+                });
+                Ok(ret)
+            }
+
+            /// Updates all the $type values of the mixer control.
+            ///
+            /// # Results
+            ///
+            /// * `changed` - true on success when value was changed, false otherwise.
+            ///
+            /// # Errors
+            ///
+            /// * If the number of values mismatches the control element.
+            /// * If it fails to call `snd_ctl_elem_write()`.
+            fn save(handle: &mut Ctl, id: &ElemId, val: Self::T) -> Result<bool> {
+                let count = ElemInfo::new(handle, id)?.count();
+                if val.len() != count {
+                    return Err(Error::MismatchValueCount(val.len(), count));
+                }
+                let mut elem = ElemValue::new(id)?;
+                let values: Vec<i64> = val.iter().map(|v| *v as i64).collect();
+                for (i, v) in val.into_iter().enumerate() {
+                    // Safe because elem.as_mut_ptr() is a valid snd_ctl_elem_value_t* and i is
+                    // within the number of value entries of the control element.
+                    unsafe { <$type>::elem_value_set(&mut elem, i, v) };
+                }
+                // Safe because self.handle.as_mut_ptr() is a valid *mut snd_ctl_t and
+                // elem.as_mut_ptr() is also a valid *mut snd_ctl_elem_value_t.
+                let rc = unsafe { alsa_sys::snd_ctl_elem_write(handle.as_mut_ptr(), elem.as_mut_ptr()) };
+                if rc < 0 {
+                    return Err(Error::ElemWriteFailed(rc));
+                }
+                handle.record(TraceAccess::Write, id, Self::elem_type(), || values);
+                Ok(rc > 0)
+            }
 
-// Implements `Elem` for [bool; n] where n = 1 to 128.
-impl_for_array! {128, bool,
-T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T
-T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T
-T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T
-T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T T
+            /// Gets the data type itself can read and write.
+            fn elem_type() -> ElemType {
+                <$type>::elem_type()
+            }
+
+            /// The number of value entries is determined by the control element at runtime.
+            fn size() -> usize {
+                0
+            }
+        }
+    };
 }
 
+// Implements `Elem` for Vec<bool>.
+impl_for_vec! {bool}
+
+// Implements `Elem` for Vec<i32>.
+impl_for_vec! {i32}
+
 impl CtlElemValue for bool {
     type T = bool;
+    const CAPACITY: usize = 128;
     /// Gets a bool from the ElemValue.
     unsafe fn elem_value_get(elem: &ElemValue, idx: usize) -> bool {
         alsa_sys::snd_ctl_elem_value_get_boolean(elem.as_ptr(), idx as c_uint) != 0
@@ -163,6 +304,7 @@ impl CtlElemValue for bool {
 
 impl CtlElemValue for i32 {
     type T = i32;
+    const CAPACITY: usize = 128;
     /// Gets an i32 from the ElemValue.
     unsafe fn elem_value_get(elem: &ElemValue, idx: usize) -> i32 {
         alsa_sys::snd_ctl_elem_value_get_integer(elem.as_ptr(), idx as c_uint) as i32
@@ -177,10 +319,168 @@ impl CtlElemValue for i32 {
     }
 }
 
+impl CtlElemValue for i64 {
+    type T = i64;
+    const CAPACITY: usize = 64;
+    /// Gets an i64 from the ElemValue.
+    unsafe fn elem_value_get(elem: &ElemValue, idx: usize) -> i64 {
+        alsa_sys::snd_ctl_elem_value_get_integer64(elem.as_ptr(), idx as c_uint) as i64
+    }
+    /// Sets an i64 to the ElemValue.
+    unsafe fn elem_value_set(elem: &mut ElemValue, idx: usize, val: i64) {
+        alsa_sys::snd_ctl_elem_value_set_integer64(
+            elem.as_mut_ptr(),
+            idx as c_uint,
+            val as c_longlong,
+        );
+    }
+    /// Returns ElemType::Integer64.
+    fn elem_type() -> ElemType {
+        ElemType::Integer64
+    }
+}
+
+impl CtlElemValue for u32 {
+    type T = u32;
+    const CAPACITY: usize = 128;
+    /// Gets an enumerated item index from the ElemValue.
+    unsafe fn elem_value_get(elem: &ElemValue, idx: usize) -> u32 {
+        alsa_sys::snd_ctl_elem_value_get_enumerated(elem.as_ptr(), idx as c_uint) as u32
+    }
+    /// Sets an enumerated item index to the ElemValue.
+    unsafe fn elem_value_set(elem: &mut ElemValue, idx: usize, val: u32) {
+        alsa_sys::snd_ctl_elem_value_set_enumerated(
+            elem.as_mut_ptr(),
+            idx as c_uint,
+            val as c_uint,
+        );
+    }
+    /// Returns ElemType::Enumerated.
+    fn elem_type() -> ElemType {
+        ElemType::Enumerated
+    }
+}
+
+impl CtlElemValue for u8 {
+    type T = u8;
+    const CAPACITY: usize = 512;
+    /// Gets a byte from the ElemValue.
+    unsafe fn elem_value_get(elem: &ElemValue, idx: usize) -> u8 {
+        alsa_sys::snd_ctl_elem_value_get_byte(elem.as_ptr(), idx as c_uint)
+    }
+    /// Sets a byte to the ElemValue.
+    unsafe fn elem_value_set(elem: &mut ElemValue, idx: usize, val: u8) {
+        alsa_sys::snd_ctl_elem_value_set_byte(elem.as_mut_ptr(), idx as c_uint, val as c_uchar);
+    }
+    /// Returns ElemType::Bytes.
+    fn elem_type() -> ElemType {
+        ElemType::Bytes
+    }
+}
+
+// The TLV buffer consists of the TLV type, the data length in bytes and the data in u32 words.
+const TLV_TYPE_IDX: usize = 0;
+const TLV_LENGTH_IDX: usize = 1;
+const TLV_HEADER_WORDS: usize = 2;
+
+// Returns the number of u32 words needed to hold `bytes` bytes.
+fn tlv_words(bytes: usize) -> usize {
+    bytes.div_ceil(size_of::<u32>())
+}
+
+/// Implements `Elem` for `Vec<u8>`, which reads and writes a byte array through the TLV
+/// interface. It's used by the byte controls whose data exceeds the normal element value size,
+/// ex: DSP parameter blobs.
+impl Elem for Vec<u8> {
+    type T = Self;
+    /// Reads the TLV byte array from the mixer control.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to call `snd_ctl_elem_tlv_read()`.
+    fn load(handle: &mut Ctl, id: &ElemId) -> Result<Self::T> {
+        // The element count of a TLV byte control is its capacity in bytes.
+        let capacity = ElemInfo::new(handle, id)?.count();
+        let mut tlv = vec![0u32; TLV_HEADER_WORDS + tlv_words(capacity)];
+        // Safe because handle.as_mut_ptr() is a valid *mut snd_ctl_t, id.as_ptr() is a valid
+        // *const snd_ctl_elem_id_t and tlv is large enough to hold the given size in bytes.
+        let rc = unsafe {
+            alsa_sys::snd_ctl_elem_tlv_read(
+                handle.as_mut_ptr(),
+                id.as_ptr(),
+                tlv.as_mut_ptr(),
+                (tlv.len() * size_of::<u32>()) as c_uint,
+            )
+        };
+        if rc < 0 {
+            return Err(Error::ElemTlvReadFailed(rc));
+        }
+        let len = (tlv[TLV_LENGTH_IDX] as usize).min(capacity);
+        let ret: Vec<u8> = tlv[TLV_HEADER_WORDS..]
+            .iter()
+            .flat_map(|word| word.to_ne_bytes().to_vec())
+            .take(len)
+            .collect();
+        handle.record(TraceAccess::Read, id, Self::elem_type(), || {
+            ret.iter().map(|v| *v as i64).collect()
+        });
+        Ok(ret)
+    }
+
+    /// Writes the TLV byte array to the mixer control.
+    ///
+    /// # Results
+    ///
+    /// * `changed` - true on success when value was changed, false otherwise.
+    ///
+    /// # Errors
+    ///
+    /// * If the byte array is larger than the capacity of the mixer control.
+    /// * If it fails to call `snd_ctl_elem_tlv_write()`.
+    fn save(handle: &mut Ctl, id: &ElemId, val: Self::T) -> Result<bool> {
+        let capacity = ElemInfo::new(handle, id)?.count();
+        if val.len() > capacity {
+            return Err(Error::TlvDataTooLarge(val.len(), capacity));
+        }
+        let mut tlv = vec![0u32; TLV_HEADER_WORDS + tlv_words(val.len())];
+        tlv[TLV_TYPE_IDX] = 0;
+        tlv[TLV_LENGTH_IDX] = val.len() as u32;
+        for (i, chunk) in val.chunks(size_of::<u32>()).enumerate() {
+            let mut word = [0u8; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            tlv[TLV_HEADER_WORDS + i] = u32::from_ne_bytes(word);
+        }
+        // Safe because handle.as_mut_ptr() is a valid *mut snd_ctl_t, id.as_ptr() is a valid
+        // *const snd_ctl_elem_id_t and tlv holds the TLV header and data.
+        let rc = unsafe {
+            alsa_sys::snd_ctl_elem_tlv_write(handle.as_mut_ptr(), id.as_ptr(), tlv.as_ptr())
+        };
+        if rc < 0 {
+            return Err(Error::ElemTlvWriteFailed(rc));
+        }
+        handle.record(TraceAccess::Write, id, Self::elem_type(), || {
+            val.iter().map(|v| *v as i64).collect()
+        });
+        Ok(rc > 0)
+    }
+
+    /// Returns ElemType::Bytes.
+    fn elem_type() -> ElemType {
+        ElemType::Bytes
+    }
+
+    /// The size of TLV data is determined by the control element at runtime.
+    fn size() -> usize {
+        0
+    }
+}
+
 /// All primitive types of a control element should implement `CtlElemValue` trait.
 trait CtlElemValue {
     /// The primitive type of a control element.
     type T;
+    /// The number of values `snd_ctl_elem_value_t` holds, ex: `value.integer.value[128]`.
+    const CAPACITY: usize;
     /// Gets the value from the ElemValue.
     unsafe fn elem_value_get(value: &ElemValue, idx: usize) -> Self::T;
     /// Sets the value to the ElemValue.
@@ -189,6 +489,17 @@ trait CtlElemValue {
     fn elem_type() -> ElemType;
 }
 
+// Checks the length of the arrays which implement `Elem` against the capacity of
+// `snd_ctl_elem_value_t` when the implementation is used.
+struct ValueCapacity<T, const N: usize>(PhantomData<T>);
+
+impl<T: CtlElemValue, const N: usize> ValueCapacity<T, N> {
+    const CHECK: () = assert!(
+        N <= T::CAPACITY,
+        "the array exceeds the capacity of snd_ctl_elem_value_t"
+    );
+}
+
 /// Use `Elem` trait to access the underlying control element through the given `Ctl` and `ElemId`.
 pub trait Elem: Sized {
     /// The data type of a control element.
@@ -202,3 +513,176 @@ pub trait Elem: Sized {
     /// Gets the number of value entries itself can read and write.
     fn size() -> usize;
 }
+
+/// Waits until the value of the control element meets `predicate` or `timeout` elapses.
+/// It subscribes to the control element notifications and reads the value when it is changed,
+/// instead of polling the value.
+///
+/// # Errors
+///
+/// * If it fails to subscribe to or read the control events.
+/// * If it fails to read from the control element.
+/// * If the value does not meet `predicate` before `timeout`.
+pub fn wait_for<E, F>(
+    handle: &mut Ctl,
+    id: &ElemId,
+    predicate: F,
+    timeout: Duration,
+) -> Result<E::T>
+where
+    E: Elem,
+    F: Fn(&E::T) -> bool,
+{
+    wait_for_with::<E, F>(handle, id, predicate, timeout, |_| {})
+}
+
+// The `wait_for()` which passes the events it reads to `on_event`.
+pub(crate) fn wait_for_with<E, F>(
+    handle: &mut Ctl,
+    id: &ElemId,
+    predicate: F,
+    timeout: Duration,
+    mut on_event: impl FnMut(&Event),
+) -> Result<E::T>
+where
+    E: Elem,
+    F: Fn(&E::T) -> bool,
+{
+    let mut subscription = Subscription::new(handle)?;
+    let mut wait = ElemWait::<E, F>::new(id, predicate, timeout);
+    let res = loop {
+        match wait.poll(&mut subscription, &mut on_event) {
+            Ok(Some(val)) => break Ok(val),
+            Ok(None) => {}
+            Err(e) => break Err(e),
+        }
+        if let Err(e) = subscription.wait(wait.remaining()) {
+            break Err(e.into());
+        }
+    };
+    subscription.unsubscribe()?;
+    res
+}
+
+/// The state of a wait for the value of a control element. The ctl handle must be subscribed to
+/// the events during the wait. It does not block, so that the blocking waits and the async waits
+/// share it: they only differ in how they wait for the handle to become readable again.
+pub(crate) struct ElemWait<'a, E, F> {
+    id: &'a ElemId,
+    predicate: F,
+    timeout: Duration,
+    deadline: Instant,
+    // Whether the value may have changed since it was read.
+    changed: bool,
+    elem: PhantomData<E>,
+}
+
+impl<'a, E, F> ElemWait<'a, E, F>
+where
+    E: Elem,
+    F: Fn(&E::T) -> bool,
+{
+    pub(crate) fn new(id: &'a ElemId, predicate: F, timeout: Duration) -> Self {
+        ElemWait {
+            id,
+            predicate,
+            timeout,
+            deadline: Instant::now() + timeout,
+            changed: true,
+            elem: PhantomData,
+        }
+    }
+
+    /// Reads the value when it may have changed and consumes the pending events, which are
+    /// also passed to `on_event`.
+    ///
+    /// # Results
+    ///
+    /// * The value which meets `predicate`, or None if it has to wait for more events.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to read the control events or the control element.
+    /// * If the value does not meet `predicate` before the deadline.
+    pub(crate) fn poll(
+        &mut self,
+        handle: &mut Ctl,
+        on_event: &mut impl FnMut(&Event),
+    ) -> Result<Option<E::T>> {
+        loop {
+            if self.changed {
+                // Reads the value after subscribing to the events so that changes between the
+                // read and the wait are not missed.
+                let val = E::load(handle, self.id)?;
+                if (self.predicate)(&val) {
+                    return Ok(Some(val));
+                }
+                self.changed = false;
+            }
+            if !handle.wait(Duration::from_millis(0))? {
+                if Instant::now() >= self.deadline {
+                    let name = self.id.name().unwrap_or_default().to_owned();
+                    return Err(Error::WaitForTimeout(name, self.timeout));
+                }
+                return Ok(None);
+            }
+            let event = handle.read_event()?;
+            on_event(&event);
+            if event.is_elem_value_changed() && event.elem_name()? == self.id.name()? {
+                self.changed = true;
+            }
+        }
+    }
+
+    /// Returns the time when the wait times out.
+    pub(crate) fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Returns the remaining time before the deadline.
+    pub(crate) fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+}
+
+/// Subscribes the ctl handle to the control element events until it is dropped, so that a wait
+/// which fails or is cancelled does not leave the handle subscribed.
+pub(crate) struct Subscription<'a> {
+    handle: &'a mut Ctl,
+}
+
+impl<'a> Subscription<'a> {
+    /// Subscribes `handle` to the control element events.
+    pub(crate) fn new(handle: &'a mut Ctl) -> Result<Self> {
+        handle.subscribe_events(true)?;
+        Ok(Subscription { handle })
+    }
+
+    /// Unsubscribes from the events. Unlike dropping the `Subscription`, it returns the error.
+    pub(crate) fn unsubscribe(self) -> Result<()> {
+        let res = self.handle.subscribe_events(false);
+        mem::forget(self);
+        Ok(res?)
+    }
+}
+
+impl Deref for Subscription<'_> {
+    type Target = Ctl;
+
+    fn deref(&self) -> &Ctl {
+        self.handle
+    }
+}
+
+impl DerefMut for Subscription<'_> {
+    fn deref_mut(&mut self) -> &mut Ctl {
+        self.handle
+    }
+}
+
+impl Drop for Subscription<'_> {
+    fn drop(&mut self) {
+        // There is nothing to do with the error on the cancelled waits.
+        let _ = self.handle.subscribe_events(false);
+    }
+}
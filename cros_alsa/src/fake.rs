@@ -0,0 +1,625 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! `fake` module provides `FakeCard`, an in-memory sound card for testing the control sequences
+//! of the drivers without the hardware. It's enabled by the `fake` feature, and always built for
+//! the tests of the crate.
+//!
+//! A `FakeCard` declares its controls with `FakeControl`, and holds their values in memory. It
+//! has the same control access API as `Card` and returns the same errors, so the error handling
+//! of the drivers is exercised as well. The accesses are recorded in order, and the tests assert
+//! on them with `FakeCard::ops()`.
+//!
+//! A `FakeCard` may also replay a `Trace` recorded on a device by `FakeCard::from_trace()`, and
+//! `FakeCard::diverges_from()` tells where the accesses of the replay differ from the trace.
+//! `FakeCard::trace()` records the accesses of a `FakeCard` like `Card` does, so that a trace is
+//! also made from a scripted sequence.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use cros_alsa::{Access, CardError, FakeCard, FakeControl, FakeOp};
+//!
+//! fn main() -> Result<(), CardError> {
+//!     let mut card = FakeCard::new("sofcmlmax98390d")
+//!         .with_control("Left Rdc", FakeControl::new([12000i32]))
+//!         // The calibration is done after it's polled twice.
+//!         .with_control(
+//!             "Calibration Done",
+//!             FakeControl::new([false]).then([false]).then([true]),
+//!         );
+//!
+//!     let rdc = card.control::<[i32; 1]>("Left Rdc", Access::ReadWrite)?;
+//!     card.set(&rdc, [13000])?;
+//!     card.wait_for::<[bool; 1], _>("Calibration Done", |v| v[0], Duration::from_secs(1))?;
+//!
+//!     assert_eq!(card.ops()[0], FakeOp::Write("Left Rdc".to_owned(), vec![13000]));
+//!     assert_eq!(card.value("Left Rdc"), Some(&[13000][..]));
+//!     Ok(())
+//! }
+//! ```
+//!
+//! A recorded trace is replayed and checked like this:
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use cros_alsa::{Access, CardError, FakeCard, Trace};
+//!
+//! fn main() -> Result<(), CardError> {
+//!     // The trace of a device whose calibration is done at the second poll.
+//!     let trace: Trace = "read\tCalibration Done\tboolean\t0\n\
+//!                         read\tCalibration Done\tboolean\t1\n\
+//!                         write\tLeft Rdc\tinteger\t13000\n"
+//!         .parse()
+//!         .unwrap();
+//!     let mut card = FakeCard::from_trace("sofcmlmax98390d", &trace);
+//!
+//!     card.wait_for::<[bool; 1], _>("Calibration Done", |v| v[0], Duration::from_secs(1))?;
+//!     let rdc = card.control::<[i32; 1]>("Left Rdc", Access::ReadWrite)?;
+//!     card.set(&rdc, [13000])?;
+//!
+//!     assert_eq!(card.diverges_from(&trace), None);
+//!     Ok(())
+//! }
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::card::{Error, Result};
+use crate::control;
+use crate::control_primitive::{self, ElemType};
+use crate::control_set::{Access, ControlHandle};
+use crate::elem::{self, Elem};
+use crate::trace::{Trace, TraceAccess, TraceOp};
+
+/// `FakeElem` converts the values of an `Elem` from and to the values held by `FakeCard`. The
+/// booleans, integers and enumerated item indices are held as i64, and the bytes as one i64
+/// each.
+pub trait FakeElem: Elem<T = Self> + Clone + PartialEq {
+    /// Converts the values to the values held by `FakeCard`.
+    fn to_fake(&self) -> Vec<i64>;
+    /// Converts the values held by `FakeCard` to the values.
+    fn from_fake(values: &[i64]) -> Self;
+}
+
+// Uses a macro to generate implementation for [bool; N], [i32; N], [i64; N], [u32; N] and
+// [u8; N].
+macro_rules! impl_fake_for_array {
+    {$type:ty} => {
+        impl<const N: usize> FakeElem for [$type; N] {
+            fn to_fake(&self) -> Vec<i64> {
+                self.iter().map(|v| *v as i64).collect()
+            }
+
+            fn from_fake(values: &[i64]) -> Self {
+                let mut ret = [Default::default(); N];
+                for (v, fake) in ret.iter_mut().zip(values) {
+                    *v = <$type>::from_i64(*fake);
+                }
+                ret
+            }
+        }
+    };
+}
+
+impl_fake_for_array! {bool}
+impl_fake_for_array! {i32}
+impl_fake_for_array! {i64}
+impl_fake_for_array! {u32}
+impl_fake_for_array! {u8}
+
+// Uses a macro to generate implementation for Vec<bool>, Vec<i32> and Vec<u8>.
+macro_rules! impl_fake_for_vec {
+    {$type:ty} => {
+        impl FakeElem for Vec<$type> {
+            fn to_fake(&self) -> Vec<i64> {
+                self.iter().map(|v| *v as i64).collect()
+            }
+
+            fn from_fake(values: &[i64]) -> Self {
+                values.iter().map(|v| <$type>::from_i64(*v)).collect()
+            }
+        }
+    };
+}
+
+impl_fake_for_vec! {bool}
+impl_fake_for_vec! {i32}
+impl_fake_for_vec! {u8}
+
+// Converts a held value back to the primitive type. The values are only written through
+// `FakeElem::to_fake()`, so they are always in range.
+trait FromI64 {
+    fn from_i64(v: i64) -> Self;
+}
+
+impl FromI64 for bool {
+    fn from_i64(v: i64) -> Self {
+        v != 0
+    }
+}
+
+macro_rules! impl_from_i64 {
+    {$type:ty} => {
+        impl FromI64 for $type {
+            fn from_i64(v: i64) -> Self {
+                v as $type
+            }
+        }
+    };
+}
+
+impl_from_i64! {i32}
+impl_from_i64! {i64}
+impl_from_i64! {u32}
+impl_from_i64! {u8}
+
+/// `FakeControl` declares a control of `FakeCard`.
+#[derive(Debug, Clone)]
+pub struct FakeControl {
+    elem_type: ElemType,
+    values: Vec<i64>,
+    // The values the control takes after each read, which simulate the updates by the
+    // hardware, ex: a status control.
+    pending: VecDeque<Vec<i64>>,
+    // The recorded values of the reads of a replayed trace, which the reads return in order.
+    replayed: VecDeque<Vec<i64>>,
+    writable: bool,
+    latency: Duration,
+    // The negative error code returned by the accesses.
+    error: Option<i32>,
+}
+
+impl FakeControl {
+    /// Creates a writable control of the initial values. The type and the number of value
+    /// entries of the control are the ones of `E`.
+    pub fn new<E: FakeElem>(values: E) -> Self {
+        FakeControl {
+            elem_type: E::elem_type(),
+            values: values.to_fake(),
+            pending: VecDeque::new(),
+            replayed: VecDeque::new(),
+            writable: true,
+            latency: Duration::ZERO,
+            error: None,
+        }
+    }
+
+    /// Queues the values the control takes after the next read. The queued values are taken
+    /// in order, one per read.
+    pub fn then<E: FakeElem>(mut self, values: E) -> Self {
+        self.pending.push_back(values.to_fake());
+        self
+    }
+
+    /// Makes the control read-only.
+    pub fn read_only(mut self) -> Self {
+        self.writable = false;
+        self
+    }
+
+    /// Delays each access of the control by `latency`.
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Fails each access of the control with the errno, ex: libc::EIO. The errors are the ones
+    /// of the failed alsa-lib calls, so `Error::is_enodev()` works with libc::ENODEV.
+    pub fn with_error(mut self, errno: i32) -> Self {
+        self.error = Some(-errno);
+        self
+    }
+}
+
+/// An access of a `FakeCard` control.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FakeOp {
+    /// The control of the name is read.
+    Read(String),
+    /// The values are written to the control of the name.
+    Write(String, Vec<i64>),
+}
+
+/// `FakeCard` is an in-memory sound card of the declared controls.
+#[derive(Debug)]
+pub struct FakeCard {
+    name: String,
+    controls: HashMap<String, FakeControl>,
+    ops: Vec<FakeOp>,
+    // The accesses with their values, in the form `Card` records them.
+    recorded: Vec<TraceOp>,
+}
+
+impl FakeCard {
+    /// Creates a `FakeCard` without controls.
+    pub fn new(card_name: &str) -> Self {
+        FakeCard {
+            name: card_name.to_owned(),
+            controls: HashMap::new(),
+            ops: Vec::new(),
+            recorded: Vec::new(),
+        }
+    }
+
+    /// Creates a `FakeCard` which replays the trace. It has the controls accessed in the
+    /// trace, and the reads of each control return its recorded values in order. A control
+    /// keeps the last read or written values once its recorded reads run out.
+    pub fn from_trace(card_name: &str, trace: &Trace) -> Self {
+        let mut controls: HashMap<String, FakeControl> = HashMap::new();
+        for op in trace.ops() {
+            let control = controls
+                .entry(op.control.clone())
+                .or_insert_with(|| FakeControl {
+                    elem_type: op.elem_type,
+                    values: op.values.clone(),
+                    pending: VecDeque::new(),
+                    replayed: VecDeque::new(),
+                    writable: true,
+                    latency: Duration::ZERO,
+                    error: None,
+                });
+            if op.access == TraceAccess::Read {
+                control.replayed.push_back(op.values.clone());
+            }
+        }
+        FakeCard {
+            name: card_name.to_owned(),
+            controls,
+            ops: Vec::new(),
+            recorded: Vec::new(),
+        }
+    }
+
+    /// Declares a control of the sound card.
+    pub fn with_control(mut self, control_name: &str, control: FakeControl) -> Self {
+        self.controls.insert(control_name.to_owned(), control);
+        self
+    }
+
+    /// Gets sound card name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Fails each access of the control with the errno, or stops failing if it's None. It
+    /// injects the errors after the control is declared, ex: a codec reset in the middle of a
+    /// sequence.
+    pub fn set_error(&mut self, control_name: &str, errno: Option<i32>) {
+        if let Some(control) = self.controls.get_mut(control_name) {
+            control.error = errno.map(|errno| -errno);
+        }
+    }
+
+    /// Returns the accesses of the controls in order.
+    pub fn ops(&self) -> &[FakeOp] {
+        &self.ops
+    }
+
+    /// Returns the index of the first access of the trace which the accesses of the card differ
+    /// from, or None if the card made the same accesses. A read differs if it's of another
+    /// control, and a write if it's of another control or values. The accesses missing from
+    /// either side differ at the end of the shorter one.
+    pub fn diverges_from(&self, trace: &Trace) -> Option<usize> {
+        let same = |op: &FakeOp, i: usize| {
+            let expected = &trace.ops()[i];
+            match op {
+                FakeOp::Read(name) => {
+                    expected.access == TraceAccess::Read && *name == expected.control
+                }
+                FakeOp::Write(name, values) => {
+                    expected.access == TraceAccess::Write
+                        && *name == expected.control
+                        && *values == expected.values
+                }
+            }
+        };
+        let len = self.ops.len().min(trace.ops().len());
+        (0..len)
+            .find(|i| !same(&self.ops[*i], *i))
+            .or(if self.ops.len() == trace.ops().len() {
+                None
+            } else {
+                Some(len)
+            })
+    }
+
+    /// Returns the trace of the accesses in order, which `FakeCard::from_trace()` replays.
+    pub fn trace(&self) -> Trace {
+        Trace::from(self.recorded.clone())
+    }
+
+    /// Returns the written values of the controls in order.
+    pub fn writes(&self) -> Vec<(&str, &[i64])> {
+        self.ops
+            .iter()
+            .filter_map(|op| match op {
+                FakeOp::Write(name, values) => Some((name.as_str(), values.as_slice())),
+                FakeOp::Read(_) => None,
+            })
+            .collect()
+    }
+
+    /// Returns the current values of the control without recording a read.
+    pub fn value(&self, control_name: &str) -> Option<&[i64]> {
+        self.controls
+            .get(control_name)
+            .map(|control| control.values.as_slice())
+    }
+
+    /// Returns true if the sound card has the control.
+    pub fn has_control(&self, control_name: &str) -> bool {
+        self.controls.contains_key(control_name)
+    }
+
+    /// Creates the `ControlHandle` of a declared control. It validates the control like
+    /// `ControlSetBuilder`.
+    ///
+    /// # Errors
+    ///
+    /// * If the control does not exist.
+    /// * If `E::elem_type()` or `E::size()` mismatches the control.
+    /// * If the control is read-only and `access` is `Access::ReadWrite`.
+    pub fn control<E: FakeElem>(
+        &mut self,
+        control_name: &str,
+        access: Access,
+    ) -> Result<ControlHandle<E>> {
+        let control = self.lookup::<E>(control_name)?;
+        if access == Access::ReadWrite && !control.writable {
+            return Err(Error::ReadOnlyControl(control_name.to_owned()));
+        }
+        Ok(ControlHandle::new(control_name, access))
+    }
+
+    /// Reads the control of the `ControlHandle`.
+    ///
+    /// # Errors
+    ///
+    /// * The errors of `Card::get()`.
+    pub fn get<E: FakeElem>(&mut self, control: &ControlHandle<E>) -> Result<E> {
+        self.load::<E>(control.name())
+    }
+
+    /// Writes the control of the `ControlHandle`.
+    ///
+    /// # Errors
+    ///
+    /// * The errors of `Card::set()`.
+    pub fn set<E: FakeElem>(&mut self, control: &ControlHandle<E>, val: E) -> Result<()> {
+        if control.access() != Access::ReadWrite {
+            return Err(Error::ReadOnlyControl(control.name().to_owned()));
+        }
+        self.save(control.name(), val)
+    }
+
+    /// Reads the values of multiple controls of the same `Elem` type in one call.
+    ///
+    /// # Errors
+    ///
+    /// * The errors of `Card::load_controls()`.
+    pub fn load_controls<E: FakeElem>(&mut self, control_names: &[&str]) -> Result<Vec<E>> {
+        control_names
+            .iter()
+            .map(|name| self.load::<E>(name))
+            .collect()
+    }
+
+    /// Writes the values to multiple controls of the same `Elem` type in one call.
+    /// The controls are written in order, and it stops at the first failure.
+    ///
+    /// # Errors
+    ///
+    /// * The errors of `Card::save_controls()`.
+    pub fn save_controls<E: FakeElem>(&mut self, controls: Vec<(&str, E)>) -> Result<()> {
+        for (name, val) in controls {
+            self.save(name, val)?;
+        }
+        Ok(())
+    }
+
+    /// Waits until the value of the control meets `predicate` or `timeout` elapses. The
+    /// control takes its queued values one per read, so it times out immediately once they
+    /// run out instead of sleeping for `timeout`.
+    ///
+    /// # Errors
+    ///
+    /// * The errors of `Card::wait_for()`.
+    pub fn wait_for<E, F>(
+        &mut self,
+        control_name: &str,
+        predicate: F,
+        timeout: Duration,
+    ) -> Result<E>
+    where
+        E: FakeElem,
+        F: Fn(&E) -> bool,
+    {
+        let start_time = Instant::now();
+        loop {
+            // The value changes after the read only if there are queued values.
+            let changing = self
+                .controls
+                .get(control_name)
+                .is_some_and(|control| !control.pending.is_empty() || control.replayed.len() > 1);
+            let val = self.load::<E>(control_name)?;
+            if predicate(&val) {
+                return Ok(val);
+            }
+            if !changing || start_time.elapsed() >= timeout {
+                return Err(Error::WaitForTimeout(control_name.to_owned(), timeout));
+            }
+        }
+    }
+
+    // Looks up the control and validates it against `E` like `Card::elem_id()`.
+    fn lookup<E: FakeElem>(&mut self, control_name: &str) -> Result<&mut FakeControl> {
+        let control = self.controls.get_mut(control_name).ok_or_else(|| {
+            Error::AlsaControlAPI(control_primitive::Error::ControlNotFound(
+                control_name.to_owned(),
+            ))
+        })?;
+        if control.elem_type != E::elem_type() {
+            return Err(control::Error::MismatchElemType(
+                control_name.to_owned(),
+                control.elem_type,
+                E::elem_type(),
+            )
+            .into());
+        }
+        if E::size() != 0 && control.values.len() != E::size() {
+            return Err(control::Error::MismatchElemCount(
+                control_name.to_owned(),
+                control.values.len(),
+                E::size(),
+            )
+            .into());
+        }
+        Ok(control)
+    }
+
+    fn load<E: FakeElem>(&mut self, control_name: &str) -> Result<E> {
+        let control = self.lookup::<E>(control_name)?;
+        thread::sleep(control.latency);
+        if let Some(rc) = control.error {
+            let e = match control.elem_type {
+                ElemType::Bytes if E::size() == 0 => elem::Error::ElemTlvReadFailed(rc),
+                _ => elem::Error::ElemReadFailed(rc),
+            };
+            return Err(Error::ControlAccessFailed(control_name.to_owned(), e));
+        }
+        if let Some(values) = control.replayed.pop_front() {
+            control.values = values;
+        }
+        let val = E::from_fake(&control.values);
+        let recorded = TraceOp {
+            access: TraceAccess::Read,
+            control: control_name.to_owned(),
+            elem_type: control.elem_type,
+            values: control.values.clone(),
+        };
+        if let Some(values) = control.pending.pop_front() {
+            control.values = values;
+        }
+        self.ops.push(FakeOp::Read(control_name.to_owned()));
+        self.recorded.push(recorded);
+        Ok(val)
+    }
+
+    fn save<E: FakeElem>(&mut self, control_name: &str, val: E) -> Result<()> {
+        let control = self.lookup::<E>(control_name)?;
+        if !control.writable {
+            return Err(Error::ReadOnlyControl(control_name.to_owned()));
+        }
+        thread::sleep(control.latency);
+        if let Some(rc) = control.error {
+            let e = match control.elem_type {
+                ElemType::Bytes if E::size() == 0 => elem::Error::ElemTlvWriteFailed(rc),
+                _ => elem::Error::ElemWriteFailed(rc),
+            };
+            return Err(Error::ControlAccessFailed(control_name.to_owned(), e));
+        }
+        let values = val.to_fake();
+        control.values = values.clone();
+        let recorded = TraceOp {
+            access: TraceAccess::Write,
+            control: control_name.to_owned(),
+            elem_type: control.elem_type,
+            values: values.clone(),
+        };
+        self.ops
+            .push(FakeOp::Write(control_name.to_owned(), values));
+        self.recorded.push(recorded);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CARD: &str = "sofcmlmax98390d";
+
+    // A calibration sequence, which returns the values it reads.
+    fn calibrate(card: &mut FakeCard) -> Result<(bool, Vec<[i32; 1]>)> {
+        let done =
+            card.wait_for::<[bool; 1], _>("Calibration Done", |v| v[0], Duration::from_secs(1))?;
+        let rdcs = card.load_controls::<[i32; 1]>(&["Left Rdc", "Right Rdc"])?;
+        card.save_controls(vec![
+            ("Left Rdc", [rdcs[0][0] + 1000]),
+            ("Right Rdc", [rdcs[1][0] + 1000]),
+        ])?;
+        let mode = card.control::<[u32; 1]>("DSM Mode", Access::ReadWrite)?;
+        card.set(&mode, [2])?;
+        Ok((done[0], rdcs))
+    }
+
+    fn scripted_card() -> FakeCard {
+        FakeCard::new(CARD)
+            .with_control(
+                "Calibration Done",
+                FakeControl::new([false]).then([false]).then([true]),
+            )
+            .with_control("Left Rdc", FakeControl::new([12000i32]))
+            .with_control("Right Rdc", FakeControl::new([12500i32]))
+            .with_control("DSM Mode", FakeControl::new([0u32]))
+    }
+
+    #[test]
+    fn replays_recorded_trace() {
+        let mut card = scripted_card();
+        let recorded = calibrate(&mut card).unwrap();
+        // The trace goes through the file format like a capture of a device.
+        let trace: Trace = card.trace().to_string().parse().unwrap();
+        assert_eq!(trace.ops().len(), card.ops().len());
+
+        let mut replay = FakeCard::from_trace(CARD, &trace);
+        let replayed = calibrate(&mut replay).unwrap();
+        assert_eq!(replayed, recorded);
+        assert_eq!(replayed, (true, vec![[12000], [12500]]));
+        assert_eq!(replay.diverges_from(&trace), None);
+        for name in &["Left Rdc", "Right Rdc", "DSM Mode", "Calibration Done"] {
+            assert_eq!(replay.value(name), card.value(name), "{}", name);
+        }
+        assert_eq!(replay.value("Left Rdc"), Some(&[13000][..]));
+        assert_eq!(replay.value("DSM Mode"), Some(&[2][..]));
+        assert_eq!(replay.trace(), trace);
+    }
+
+    #[test]
+    fn replay_detects_divergence() {
+        let mut card = scripted_card();
+        calibrate(&mut card).unwrap();
+        let trace = card.trace();
+
+        let mut replay = FakeCard::from_trace(CARD, &trace);
+        replay
+            .wait_for::<[bool; 1], _>("Calibration Done", |v| v[0], Duration::from_secs(1))
+            .unwrap();
+        let rdc = replay
+            .control::<[i32; 1]>("Left Rdc", Access::ReadWrite)
+            .unwrap();
+        replay.set(&rdc, [14000]).unwrap();
+        // The replay writes Left Rdc instead of reading it after the wait.
+        assert_eq!(replay.diverges_from(&trace), Some(3));
+    }
+
+    #[test]
+    fn failed_accesses_are_not_recorded() {
+        let mut card = FakeCard::new(CARD).with_control(
+            "Left Rdc",
+            FakeControl::new([12000i32]).with_error(libc::EIO),
+        );
+        assert!(card.load_controls::<[i32; 1]>(&["Left Rdc"]).is_err());
+        card.set_error("Left Rdc", None);
+        card.load_controls::<[i32; 1]>(&["Left Rdc"]).unwrap();
+        assert_eq!(card.trace().ops().len(), 1);
+        assert_eq!(card.trace().ops()[0].values, vec![12000]);
+    }
+}
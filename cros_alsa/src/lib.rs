@@ -12,7 +12,7 @@
 //! use std::error::Error;
 //! use std::result::Result;
 //!
-//! use cros_alsa::{Card, SwitchControl, IntControl, StereoVolumeControl};
+//! use cros_alsa::{Card, EnumControl, SwitchControl, IntControl, StereoVolumeControl};
 //!
 //! fn main() -> Result<(), Box<dyn Error>> {
 //!
@@ -33,6 +33,10 @@
 //!   let mut volume_ctrl:StereoVolumeControl = card.control_by_name("Master Playback Volume")?;
 //!   volume_ctrl.set_volume(184, 184)?;
 //!
+//!   // Uses an EnumControl to select an item of an enumerated mixer control by name.
+//!   let mut mode_ctrl:EnumControl = card.control_by_name("DSM Mode")?;
+//!   mode_ctrl.set_by_name("Calibration")?;
+//!
 //!   Ok(())
 //! }
 //! ```
@@ -41,18 +45,40 @@
 #![recursion_limit = "256"]
 #![deny(missing_docs)]
 
+mod async_card;
 mod card;
 mod control;
 mod control_primitive;
+mod control_set;
 pub mod elem;
+#[cfg(any(test, feature = "fake"))]
+mod fake;
+mod mixer;
+mod topology;
+mod trace;
+pub mod ucm;
 
-pub use self::card::Card;
-pub use self::control::{Control, ControlOps, IntControl, StereoVolumeControl, SwitchControl};
-pub use self::control_primitive::{Ctl, ElemId};
+pub use self::async_card::AsyncCard;
+pub use self::card::{cards, Card, CardInfo, Cards, PcmDevice};
+pub use self::control::{
+    Control, ControlOps, EnumControl, Int64Control, IntControl, StereoVolumeControl, SwitchControl,
+    TlvBytesControl,
+};
+pub use self::control_primitive::{Ctl, ElemId, ElemType};
+pub use self::control_set::{Access, ControlHandle, ControlSetBuilder};
+#[cfg(any(test, feature = "fake"))]
+pub use self::fake::{FakeCard, FakeControl, FakeElem, FakeOp};
+pub use self::mixer::SimpleMixer;
+pub use self::topology::Topology;
+pub use self::trace::{Trace, TraceAccess, TraceOp};
+pub use self::ucm::Ucm;
 
 pub use self::card::Error as CardError;
 pub use self::control::Error as ControlError;
 pub use self::elem::Error as ElemError;
+pub use self::topology::Error as TopologyError;
+pub use self::trace::Error as TraceError;
+pub use self::ucm::Error as UcmError;
 
 #[allow(unused_imports)]
 pub use cros_alsa_derive::*;
@@ -0,0 +1,149 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! `mixer` module provides a thin simple mixer layer over the `Card` controls.
+//!
+//! A simple mixer element, ex: `Speaker`, groups the controls following the alsa naming
+//! convention: `Speaker Playback Switch` and `Speaker Playback Volume`. The controls of a
+//! simple mixer element may have one value entry per channel, and `SimpleMixer` applies the
+//! same state or volume to all the channels.
+//!
+//! # Examples
+//!
+//! ``` no_run
+//! use cros_alsa::{Card, CardError, SimpleMixer};
+//!
+//! fn main() -> Result<(), CardError> {
+//!     let mut card = Card::new("sofcmlmax98390d")?;
+//!     let mut mixer = SimpleMixer::new(&mut card);
+//!     // Ensures the speaker path is unmuted.
+//!     mixer.enable_playback("Speaker")?;
+//!     let (_min, max) = mixer.playback_volume_range("Speaker")?;
+//!     mixer.set_playback_volume("Speaker", max)?;
+//!     Ok(())
+//! }
+//! ```
+
+use crate::card::{Card, Result};
+use crate::control_primitive::ElemInfo;
+use crate::elem::Elem;
+
+/// `SimpleMixer` reads and writes the playback switch and volume of simple mixer elements.
+pub struct SimpleMixer<'a> {
+    card: &'a mut Card,
+}
+
+impl<'a> SimpleMixer<'a> {
+    /// Creates a `SimpleMixer` of the sound card.
+    pub fn new(card: &'a mut Card) -> Self {
+        SimpleMixer { card }
+    }
+
+    /// Returns true if the simple mixer element has a playback switch.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to list the control elements.
+    pub fn has_playback_switch(&mut self, elem: &str) -> Result<bool> {
+        self.card.has_control(&playback_switch(elem))
+    }
+
+    /// Returns true if the simple mixer element has a playback volume.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to list the control elements.
+    pub fn has_playback_volume(&mut self, elem: &str) -> Result<bool> {
+        self.card.has_control(&playback_volume(elem))
+    }
+
+    /// Returns true if all the channels of the playback switch are on.
+    ///
+    /// # Errors
+    ///
+    /// * If the playback switch does not exist.
+    /// * If it fails to read from the playback switch.
+    pub fn playback_switch(&mut self, elem: &str) -> Result<bool> {
+        let name = playback_switch(elem);
+        let states = self.card.load_controls::<Vec<bool>>(&[&name])?;
+        Ok(states.iter().flatten().all(|&on| on))
+    }
+
+    /// Turns on or off all the channels of the playback switch.
+    ///
+    /// # Errors
+    ///
+    /// * If the playback switch does not exist.
+    /// * If it fails to write to the playback switch.
+    pub fn set_playback_switch(&mut self, elem: &str, on: bool) -> Result<()> {
+        let name = playback_switch(elem);
+        let channels = self.channels::<Vec<bool>>(&name)?;
+        self.card
+            .save_controls::<Vec<bool>>(vec![(&name, vec![on; channels])])
+    }
+
+    /// Gets the playback volume of each channel.
+    ///
+    /// # Errors
+    ///
+    /// * If the playback volume does not exist.
+    /// * If it fails to read from the playback volume.
+    pub fn playback_volume(&mut self, elem: &str) -> Result<Vec<i32>> {
+        let name = playback_volume(elem);
+        let mut volumes = self.card.load_controls::<Vec<i32>>(&[&name])?;
+        Ok(volumes.pop().unwrap_or_default())
+    }
+
+    /// Gets the minimum and maximum values of the playback volume.
+    ///
+    /// # Errors
+    ///
+    /// * If the playback volume does not exist.
+    pub fn playback_volume_range(&mut self, elem: &str) -> Result<(i32, i32)> {
+        let name = playback_volume(elem);
+        let id = self.card.elem_id::<Vec<i32>>(&name)?;
+        let info = ElemInfo::new(self.card.handle(), &id)?;
+        Ok((info.min(), info.max()))
+    }
+
+    /// Sets the playback volume of all the channels.
+    ///
+    /// # Errors
+    ///
+    /// * If the playback volume does not exist.
+    /// * If it fails to write to the playback volume.
+    pub fn set_playback_volume(&mut self, elem: &str, volume: i32) -> Result<()> {
+        let name = playback_volume(elem);
+        let channels = self.channels::<Vec<i32>>(&name)?;
+        self.card
+            .save_controls::<Vec<i32>>(vec![(&name, vec![volume; channels])])
+    }
+
+    /// Ensures the playback path of the simple mixer element is enabled by turning on its
+    /// playback switch. It's a no-op if the element has no playback switch.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to write to the playback switch.
+    pub fn enable_playback(&mut self, elem: &str) -> Result<()> {
+        if self.has_playback_switch(elem)? && !self.playback_switch(elem)? {
+            self.set_playback_switch(elem, true)?;
+        }
+        Ok(())
+    }
+
+    // Gets the number of value entries of the control.
+    fn channels<E: Elem>(&mut self, control_name: &str) -> Result<usize> {
+        let id = self.card.elem_id::<E>(control_name)?;
+        Ok(ElemInfo::new(self.card.handle(), &id)?.count())
+    }
+}
+
+fn playback_switch(elem: &str) -> String {
+    format!("{} Playback Switch", elem)
+}
+
+fn playback_volume(elem: &str) -> String {
+    format!("{} Playback Volume", elem)
+}
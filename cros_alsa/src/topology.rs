@@ -0,0 +1,190 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! `topology` module provides `Topology`, the resolved card index and mixer control numids of
+//! a sound card. Listing the control elements dominates opening a `Card` on the codecs with
+//! hundreds of controls, so the topology of `Card::topology()` can be persisted and reopened
+//! by `Card::from_topology()` on the next boot, which only checks the card id and the number of
+//! the control elements.
+//!
+//! A topology file starts with the tab separated card id, card index and number of the control
+//! elements, followed by one line per mixer control, which is the tab separated numid, index
+//! and name.
+//!
+//! # Examples
+//!
+//! ```
+//! use cros_alsa::Topology;
+//!
+//! let topology: Topology = "sofcmlmax98390d\t0\t2\n1\t0\tLeft Rdc\n2\t0\tRight Rdc\n"
+//!     .parse()
+//!     .unwrap();
+//! assert_eq!(topology.card_id(), "sofcmlmax98390d");
+//! assert_eq!(topology.numid("Right Rdc", 0), Some(2));
+//! assert_eq!(topology.to_string().parse::<Topology>().unwrap(), topology);
+//! ```
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use remain::sorted;
+
+/// The Result type of cros-alsa::topology.
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[sorted]
+#[derive(Debug)]
+/// Possible errors that can occur in cros-alsa::topology.
+pub enum Error {
+    /// Failed to read or write the topology file.
+    FileIOFailed(String, io::Error),
+    /// The topology has a malformed entry at the line.
+    ParseFailed(usize, &'static str),
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            FileIOFailed(file, e) => write!(f, "{}: {}", file, e),
+            ParseFailed(line, reason) => {
+                write!(f, "invalid topology at line {}: {}", line, reason)
+            }
+        }
+    }
+}
+
+/// `Topology` is the card index and mixer control numids of a sound card.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Topology {
+    card_id: String,
+    index: i32,
+    elem_count: u32,
+    numids: HashMap<(String, u32), u32>,
+}
+
+impl Topology {
+    pub(crate) fn new(
+        card_id: &str,
+        index: i32,
+        elem_count: u32,
+        numids: HashMap<(String, u32), u32>,
+    ) -> Topology {
+        Topology {
+            card_id: card_id.to_owned(),
+            index,
+            elem_count,
+            numids,
+        }
+    }
+
+    /// Returns the sound card id, ex: sofcmlmax98390d.
+    pub fn card_id(&self) -> &str {
+        &self.card_id
+    }
+
+    /// Returns the sound card index, ex: 0 for hw:0.
+    pub fn index(&self) -> i32 {
+        self.index
+    }
+
+    /// Returns the number of the control elements of all interfaces.
+    pub fn elem_count(&self) -> u32 {
+        self.elem_count
+    }
+
+    /// Returns the numid of the mixer control, or None if it's not in the topology.
+    pub fn numid(&self, control_name: &str, index: u32) -> Option<u32> {
+        self.numids.get(&(control_name.to_owned(), index)).copied()
+    }
+
+    pub(crate) fn numids(&self) -> &HashMap<(String, u32), u32> {
+        &self.numids
+    }
+
+    /// Reads a topology file.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to read the file.
+    /// * If the file has a malformed entry.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Topology> {
+        let path = path.as_ref();
+        fs::read_to_string(path)
+            .map_err(|e| Error::FileIOFailed(path.to_string_lossy().to_string(), e))?
+            .parse()
+    }
+
+    /// Saves the topology to a file.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to write the file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        fs::write(path, self.to_string())
+            .map_err(|e| Error::FileIOFailed(path.to_string_lossy().to_string(), e))
+    }
+}
+
+impl fmt::Display for Topology {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}\t{}\t{}", self.card_id, self.index, self.elem_count)?;
+        // Writes the controls in the numid order to keep the file stable.
+        let mut controls: Vec<_> = self.numids.iter().collect();
+        controls.sort_by_key(|(_, numid)| **numid);
+        for ((name, index), numid) in controls {
+            writeln!(f, "{}\t{}\t{}", numid, index, name)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Topology {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Topology> {
+        let mut lines = s.lines().enumerate();
+        let header: Vec<&str> = lines
+            .next()
+            .map(|(_, line)| line.split('\t').collect())
+            .unwrap_or_default();
+        if header.len() != 3 {
+            return Err(Error::ParseFailed(1, "expect the card id, index and count"));
+        }
+        let parse_err = |line, reason| move |_| Error::ParseFailed(line, reason);
+        let mut topology = Topology {
+            card_id: header[0].to_owned(),
+            index: header[1]
+                .parse()
+                .map_err(parse_err(1, "invalid card index"))?,
+            elem_count: header[2].parse().map_err(parse_err(1, "invalid count"))?,
+            numids: HashMap::new(),
+        };
+        for (i, line) in lines {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.splitn(3, '\t').collect();
+            if fields.len() != 3 {
+                return Err(Error::ParseFailed(i + 1, "expect 3 tab separated fields"));
+            }
+            let numid = fields[0]
+                .parse()
+                .map_err(parse_err(i + 1, "invalid numid"))?;
+            let index = fields[1]
+                .parse()
+                .map_err(parse_err(i + 1, "invalid index"))?;
+            topology.numids.insert((fields[2].to_owned(), index), numid);
+        }
+        Ok(topology)
+    }
+}
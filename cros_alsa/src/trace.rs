@@ -0,0 +1,203 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! `trace` module provides `Trace`, the recorded control accesses of a sound card. A `Card`
+//! records its successful reads and writes between `Card::start_recording()` and
+//! `Card::stop_recording()`, and the trace can be saved to a file and replayed by
+//! `FakeCard::from_trace()` of the `fake` feature, ex: to build a regression test from the
+//! calibration of a problematic field device.
+//!
+//! A trace file has one access per line, which is the tab separated access, control name,
+//! element type and values. The lines starting with `#` are comments.
+//!
+//! # Examples
+//!
+//! ```
+//! use cros_alsa::{Trace, TraceAccess};
+//!
+//! let trace: Trace = "read\tLeft Rdc\tinteger\t12000\nwrite\tLeft Rdc\tinteger\t13000\n"
+//!     .parse()
+//!     .unwrap();
+//! assert_eq!(trace.ops()[1].access, TraceAccess::Write);
+//! assert_eq!(trace.ops()[1].values, vec![13000]);
+//! assert_eq!(trace.to_string().parse::<Trace>().unwrap(), trace);
+//! ```
+
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use remain::sorted;
+
+use crate::control_primitive::ElemType;
+
+/// The Result type of cros-alsa::trace.
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[sorted]
+#[derive(Debug)]
+/// Possible errors that can occur in cros-alsa::trace.
+pub enum Error {
+    /// Failed to read or write the trace file.
+    FileIOFailed(String, io::Error),
+    /// The trace has a malformed access at the line.
+    ParseFailed(usize, &'static str),
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            FileIOFailed(file, e) => write!(f, "{}: {}", file, e),
+            ParseFailed(line, reason) => write!(f, "invalid trace at line {}: {}", line, reason),
+        }
+    }
+}
+
+/// The kind of a recorded control access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceAccess {
+    /// The values are read from the control.
+    Read,
+    /// The values are written to the control.
+    Write,
+}
+
+/// A recorded control access. The values are held as i64 like `FakeCard`, and the bytes as one
+/// i64 each.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceOp {
+    /// The kind of the access.
+    pub access: TraceAccess,
+    /// The name of the control.
+    pub control: String,
+    /// The element type of the control.
+    pub elem_type: ElemType,
+    /// The values read or written.
+    pub values: Vec<i64>,
+}
+
+/// `Trace` is the control accesses of a sound card in order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Trace {
+    ops: Vec<TraceOp>,
+}
+
+impl Trace {
+    /// Returns the accesses in order.
+    pub fn ops(&self) -> &[TraceOp] {
+        &self.ops
+    }
+
+    /// Reads a trace file.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to read the file.
+    /// * If the file has a malformed access.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Trace> {
+        let path = path.as_ref();
+        fs::read_to_string(path)
+            .map_err(|e| Error::FileIOFailed(path.to_string_lossy().to_string(), e))?
+            .parse()
+    }
+
+    /// Saves the trace to a file.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to write the file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        fs::write(path, self.to_string())
+            .map_err(|e| Error::FileIOFailed(path.to_string_lossy().to_string(), e))
+    }
+}
+
+impl From<Vec<TraceOp>> for Trace {
+    fn from(ops: Vec<TraceOp>) -> Trace {
+        Trace { ops }
+    }
+}
+
+// The names of the element types in the trace file.
+const ELEM_TYPES: [(ElemType, &str); 7] = [
+    (ElemType::None, "none"),
+    (ElemType::Boolean, "boolean"),
+    (ElemType::Integer, "integer"),
+    (ElemType::Enumerated, "enumerated"),
+    (ElemType::Bytes, "bytes"),
+    (ElemType::IEC958, "iec958"),
+    (ElemType::Integer64, "integer64"),
+];
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for op in &self.ops {
+            let access = match op.access {
+                TraceAccess::Read => "read",
+                TraceAccess::Write => "write",
+            };
+            let elem_type = ELEM_TYPES
+                .iter()
+                .find(|(t, _)| *t == op.elem_type)
+                .map_or("none", |(_, name)| name);
+            let values: Vec<String> = op.values.iter().map(i64::to_string).collect();
+            writeln!(
+                f,
+                "{}\t{}\t{}\t{}",
+                access,
+                op.control,
+                elem_type,
+                values.join(" ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Trace {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Trace> {
+        let mut ops = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |reason| Error::ParseFailed(i + 1, reason);
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 4 {
+                return Err(err("expect 4 tab separated fields"));
+            }
+            let access = match fields[0] {
+                "read" => TraceAccess::Read,
+                "write" => TraceAccess::Write,
+                _ => return Err(err("unknown access")),
+            };
+            let elem_type = ELEM_TYPES
+                .iter()
+                .find(|(_, name)| *name == fields[2])
+                .map(|(t, _)| *t)
+                .ok_or_else(|| err("unknown element type"))?;
+            let values = fields[3]
+                .split_whitespace()
+                .map(i64::from_str)
+                .collect::<std::result::Result<_, _>>()
+                .map_err(|_| err("invalid value"))?;
+            ops.push(TraceOp {
+                access,
+                control: fields[1].to_owned(),
+                elem_type,
+                values,
+            });
+        }
+        Ok(Trace { ops })
+    }
+}
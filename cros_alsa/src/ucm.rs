@@ -0,0 +1,444 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! `ucm` module provides a small parser of the alsa UCM (Use Case Manager) configuration files.
+//! It's used to read the values of the board's UCM config, ex: the PCM of the internal speaker,
+//! instead of duplicating them in other configuration files.
+//!
+//! It supports the subset of the alsa configuration syntax used by UCM: compound nodes in
+//! `{ }`, arrays in `[ ]`, dotted keys like `SectionDevice."Speaker"`, single or double quoted
+//! strings and comments starting with `#`. The devices are also found in the indexed form of
+//! UCM v2, ex: `SectionDevice."Speaker".0`.
+//!
+//! # Examples
+//!
+//! ```
+//! use cros_alsa::Ucm;
+//!
+//! let conf = r#"
+//! SectionDevice."Speaker" {
+//!     Comment "Speaker"
+//!     Value {
+//!         PlaybackPCM "hw:sofcmlmax98390d,0"
+//!     }
+//! }
+//! "#;
+//! let ucm: Ucm = conf.parse().unwrap();
+//! assert_eq!(ucm.playback_pcm("Speaker"), Some("hw:sofcmlmax98390d,0"));
+//! ```
+
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::iter::Peekable;
+use std::path::Path;
+use std::str::{Chars, FromStr};
+
+use remain::sorted;
+
+/// The Result type of cros-alsa::ucm.
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[sorted]
+#[derive(Debug)]
+/// Possible errors that can occur in cros-alsa::ucm.
+pub enum Error {
+    /// Failed to read the UCM file.
+    FileIOFailed(String, io::Error),
+    /// The UCM config has a syntax error at the line.
+    ParseFailed(usize, &'static str),
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            FileIOFailed(file, e) => write!(f, "failed to read {}: {}", file, e),
+            ParseFailed(line, reason) => {
+                write!(f, "failed to parse UCM config at line {}: {}", line, reason)
+            }
+        }
+    }
+}
+
+/// A node of the UCM config.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    /// A string value.
+    Str(String),
+    /// A compound of key and node pairs in the order of the config. The keys of arrays are
+    /// the element indices. A key may appear more than once.
+    Compound(Vec<(String, Node)>),
+}
+
+impl Node {
+    /// Gets the node by the path of keys, or the node itself if the path is empty.
+    /// The duplicated keys are searched in order, and the first match is returned.
+    pub fn node(&self, path: &[&str]) -> Option<&Node> {
+        match (self, path.split_first()) {
+            (_, None) => Some(self),
+            (Node::Compound(entries), Some((key, rest))) => entries
+                .iter()
+                .filter(|(k, _)| k == key)
+                .find_map(|(_, node)| node.node(rest)),
+            (Node::Str(_), Some(_)) => None,
+        }
+    }
+
+    /// Gets the string value of the node by the path of keys.
+    /// The duplicated keys are searched in order, and the first match is returned.
+    pub fn get(&self, path: &[&str]) -> Option<&str> {
+        match (self, path.split_first()) {
+            (Node::Str(s), None) => Some(s),
+            (Node::Compound(entries), Some((key, rest))) => entries
+                .iter()
+                .filter(|(k, _)| k == key)
+                .find_map(|(_, node)| node.get(rest)),
+            _ => None,
+        }
+    }
+}
+
+/// `Ucm` represents a parsed UCM config file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ucm {
+    root: Node,
+}
+
+impl Ucm {
+    /// Parses the UCM config file.
+    ///
+    /// # Errors
+    ///
+    /// * If the file cannot be read.
+    /// * If the file has a syntax error.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Ucm> {
+        let path = path.as_ref();
+        fs::read_to_string(path)
+            .map_err(|e| Error::FileIOFailed(path.to_string_lossy().to_string(), e))?
+            .parse()
+    }
+
+    /// Borrows the root node of the config.
+    pub fn root(&self) -> &Node {
+        &self.root
+    }
+
+    /// Gets the string value by the path of keys, ex: `["SectionVerb", "Value", "FullySpecifiedUCM"]`.
+    pub fn get(&self, path: &[&str]) -> Option<&str> {
+        self.root.get(path)
+    }
+
+    /// Gets the node of the `SectionDevice`, ex: `SectionDevice."Speaker"`. If the device is
+    /// in the indexed form, ex: `SectionDevice."Speaker".0`, it's the node of the first index.
+    pub fn device(&self, device: &str) -> Option<&Node> {
+        let node = self.root.node(&["SectionDevice", device])?;
+        match node {
+            Node::Compound(entries)
+                if !entries.is_empty() && entries.iter().all(|(key, _)| is_index(key)) =>
+            {
+                Some(&entries[0].1)
+            }
+            _ => Some(node),
+        }
+    }
+
+    /// Gets the value in the `Value` section of the `SectionDevice`.
+    pub fn device_value(&self, device: &str, key: &str) -> Option<&str> {
+        self.device(device)?.get(&["Value", key])
+    }
+
+    /// Gets the string values in the `Value` section of the `SectionDevice` in the order of the
+    /// config, or None if the device has no `Value` section.
+    pub fn device_values(&self, device: &str) -> Option<Vec<(&str, &str)>> {
+        match self.device(device)?.node(&["Value"])? {
+            Node::Compound(entries) => Some(
+                entries
+                    .iter()
+                    .filter_map(|(key, node)| match node {
+                        Node::Str(value) => Some((key.as_str(), value.as_str())),
+                        Node::Compound(_) => None,
+                    })
+                    .collect(),
+            ),
+            Node::Str(_) => None,
+        }
+    }
+
+    /// Gets the playback PCM of the `SectionDevice`, ex: `hw:sofcmlmax98390d,0`.
+    pub fn playback_pcm(&self, device: &str) -> Option<&str> {
+        self.device_value(device, "PlaybackPCM")
+    }
+}
+
+impl FromStr for Ucm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Ucm> {
+        let mut parser = Parser {
+            chars: s.chars().peekable(),
+            line: 1,
+        };
+        Ok(Ucm {
+            root: Node::Compound(parser.parse_compound(None)?),
+        })
+    }
+}
+
+// Returns true if the key is the index of a device, ex: 0 of `SectionDevice."Speaker".0`.
+fn is_index(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(|c| c.is_ascii_digit())
+}
+
+// The characters which terminate an unquoted word. The dots separate the keys, but they are
+// allowed in the values, ex: 1.5.
+const KEY_DELIMITERS: &str = "{}[];,=.#'\"";
+const VALUE_DELIMITERS: &str = "{}[];,=#'\"";
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, reason: &'static str) -> Error {
+        Error::ParseFailed(self.line, reason)
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    // Skips the white spaces, comments and separators.
+    fn skip_blank(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            match c {
+                '#' => while !matches!(self.next(), Some('\n') | None) {},
+                ';' | ',' => {
+                    self.next();
+                }
+                c if c.is_whitespace() => {
+                    self.next();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    // Parses the entries until `end` or the end of the input if `end` is None.
+    fn parse_compound(&mut self, end: Option<char>) -> Result<Vec<(String, Node)>> {
+        let mut entries = Vec::new();
+        loop {
+            self.skip_blank();
+            match self.chars.peek() {
+                None if end.is_none() => return Ok(entries),
+                None => return Err(self.error("unexpected end of file")),
+                Some(&c) if Some(c) == end => {
+                    self.next();
+                    return Ok(entries);
+                }
+                Some(_) => {}
+            }
+            let mut keys = self.parse_key()?;
+            self.skip_blank();
+            if self.chars.peek() == Some(&'=') {
+                self.next();
+                self.skip_blank();
+            }
+            let node = self.parse_node()?;
+            // A dotted key `a.b value` is the same as `a { b value }`.
+            let last = keys.pop().ok_or_else(|| self.error("missing key"))?;
+            let entry = keys.into_iter().rev().fold((last, node), |child, key| {
+                (key, Node::Compound(vec![child]))
+            });
+            entries.push(entry);
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Vec<(String, Node)>> {
+        let mut entries = Vec::new();
+        loop {
+            self.skip_blank();
+            match self.chars.peek() {
+                None => return Err(self.error("unexpected end of file")),
+                Some(']') => {
+                    self.next();
+                    return Ok(entries);
+                }
+                Some(_) => {}
+            }
+            let node = self.parse_node()?;
+            entries.push((entries.len().to_string(), node));
+        }
+    }
+
+    fn parse_key(&mut self) -> Result<Vec<String>> {
+        let mut keys = vec![self.parse_str(KEY_DELIMITERS)?];
+        while self.chars.peek() == Some(&'.') {
+            self.next();
+            keys.push(self.parse_str(KEY_DELIMITERS)?);
+        }
+        Ok(keys)
+    }
+
+    fn parse_node(&mut self) -> Result<Node> {
+        match self.chars.peek() {
+            Some('{') => {
+                self.next();
+                Ok(Node::Compound(self.parse_compound(Some('}'))?))
+            }
+            Some('[') => {
+                self.next();
+                Ok(Node::Compound(self.parse_array()?))
+            }
+            _ => Ok(Node::Str(self.parse_str(VALUE_DELIMITERS)?)),
+        }
+    }
+
+    fn parse_str(&mut self, delimiters: &str) -> Result<String> {
+        match self.chars.peek() {
+            Some(&quote) if quote == '"' || quote == '\'' => {
+                self.next();
+                let mut s = String::new();
+                loop {
+                    match self.next() {
+                        None => return Err(self.error("unterminated string")),
+                        Some('\\') => match self.next() {
+                            Some('n') => s.push('\n'),
+                            Some('t') => s.push('\t'),
+                            Some(c) => s.push(c),
+                            None => return Err(self.error("unterminated string")),
+                        },
+                        Some(c) if c == quote => return Ok(s),
+                        Some(c) => s.push(c),
+                    }
+                }
+            }
+            _ => {
+                let mut s = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if c.is_whitespace() || delimiters.contains(c) {
+                        break;
+                    }
+                    s.push(c);
+                    self.next();
+                }
+                if s.is_empty() {
+                    return Err(match self.chars.peek() {
+                        None => self.error("unexpected end of file"),
+                        Some(_) => self.error("unexpected character"),
+                    });
+                }
+                Ok(s)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INDEXED: &str = r#"
+SectionVerb {
+    Value {
+        FullySpecifiedUCM "1"
+    }
+}
+
+SectionDevice."Headphones".0 {
+    Value {
+        PlaybackPCM "hw:sofcmlmax98390d,1"
+    }
+}
+
+SectionDevice."Speaker".0 {
+    Comment "Speaker"
+    EnableSequence [
+        cset "name='Speaker Switch' on"
+    ]
+    Value {
+        PlaybackPCM "hw:sofcmlmax98390d,0"
+        PlaybackMixerElem "Speaker"
+        CalibrationRdcCtrl 'Left Rdc'
+        Nested { Key "ignored" }
+    }
+}
+"#;
+
+    #[test]
+    fn indexed_device() {
+        let ucm: Ucm = INDEXED.parse().unwrap();
+        assert_eq!(ucm.playback_pcm("Speaker"), Some("hw:sofcmlmax98390d,0"));
+        assert_eq!(ucm.playback_pcm("Headphones"), Some("hw:sofcmlmax98390d,1"));
+        assert_eq!(
+            ucm.device("Speaker").unwrap().get(&["Comment"]),
+            Some("Speaker")
+        );
+        assert_eq!(
+            ucm.device_values("Speaker"),
+            Some(vec![
+                ("PlaybackPCM", "hw:sofcmlmax98390d,0"),
+                ("PlaybackMixerElem", "Speaker"),
+                ("CalibrationRdcCtrl", "Left Rdc"),
+            ])
+        );
+        assert_eq!(
+            ucm.get(&["SectionVerb", "Value", "FullySpecifiedUCM"]),
+            Some("1")
+        );
+    }
+
+    #[test]
+    fn device_forms() {
+        let cases = [
+            "SectionDevice.\"Speaker\" { Value { PlaybackPCM \"hw:0,0\" } }",
+            "SectionDevice.\"Speaker\".0 { Value { PlaybackPCM \"hw:0,0\" } }",
+            "SectionDevice { \"Speaker\" { Value { PlaybackPCM \"hw:0,0\" } } }",
+            "SectionDevice.Speaker.0.Value.PlaybackPCM \"hw:0,0\"",
+        ];
+        for conf in &cases {
+            let ucm: Ucm = conf.parse().unwrap();
+            assert_eq!(ucm.playback_pcm("Speaker"), Some("hw:0,0"), "{}", conf);
+        }
+    }
+
+    #[test]
+    fn missing_device_values() {
+        let ucm: Ucm = "SectionDevice.\"Speaker\".0 { Comment \"Speaker\" }"
+            .parse()
+            .unwrap();
+        assert!(ucm.device("Speaker").is_some());
+        assert_eq!(ucm.device_values("Speaker"), None);
+        assert_eq!(ucm.device("Internal Speaker"), None);
+        assert_eq!(ucm.device_values("Internal Speaker"), None);
+        assert_eq!(ucm.playback_pcm("Speaker"), None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let cases = [
+            (
+                "SectionDevice.\"Speaker\".0 {\n",
+                2,
+                "unexpected end of file",
+            ),
+            ("Comment \"Speaker", 1, "unterminated string"),
+            ("\n\n}", 3, "unexpected character"),
+        ];
+        for (conf, line, reason) in &cases {
+            match conf.parse::<Ucm>() {
+                Err(Error::ParseFailed(l, r)) => assert_eq!((l, r), (*line, *reason), "{}", conf),
+                res => panic!("unexpected result of {:?}: {:?}", conf, res),
+            }
+        }
+    }
+}
@@ -0,0 +1,11 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! Feeds arbitrary bytes to the parser of the datastore files in the stateful partition.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = max98390d::parse_datastore(data);
+});
@@ -0,0 +1,15 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! Feeds arbitrary yaml to the parser of the max98390d device settings, including the config
+//! migration and the validation of the thresholds.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use max98390d::DeviceSettings;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(conf) = std::str::from_utf8(data) {
+        let _ = DeviceSettings::from_yaml_str(conf);
+    }
+});
@@ -0,0 +1,188 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It implements the acceptance checks of the calibration values, which decide whether the
+//! measured values are applied, or the datastore or VPD values are kept. The checks are free of
+//! the sound card and CRAS, so that they are built and tested without the `hardware` feature.
+use std::time::Duration;
+
+use log::{debug, info};
+
+use crate::datastore::Datastore;
+use crate::error::{Error, Result};
+use crate::settings::{AmpSettings, CalibThresholds, FactoryLimits};
+use crate::vpd::VPD;
+
+/// The decision of the acceptance checks on the values of a boot time calibration.
+#[derive(Debug, Clone, Copy)]
+pub enum Verdict {
+    /// The measured values are applied and stored.
+    ApplyMeasured,
+    /// The values of the datastore are kept.
+    KeepDatastore(Datastore),
+    /// The VPD values are kept, and the datastore is set to use them.
+    UseVpd,
+}
+
+/// Returns the relative difference of the measured rdc from the rdc in effect, which is the
+/// datastore rdc, or the VPD rdc if there is no calibrated datastore.
+pub fn rdc_diff(rdc: i32, vpd: &VPD, datastore: Option<&Datastore>) -> f32 {
+    // Given that rdc is the inverse of hardware real_rdc, the result equals to transforming
+    // `rdc`s to `real_rdc`s and calculating the relative difference from the `real_rdc`s.
+    let rdc_ref = match datastore {
+        Some(Datastore::DSM { rdc, .. }) => *rdc,
+        Some(Datastore::UseVPD) | None => vpd.dsm_calib_r0,
+    };
+    (rdc - rdc_ref).abs() as f32 / rdc as f32
+}
+
+/// Returns true if the calibration temperature is within the limits of the amp.
+pub fn validate_temperature(amp: &AmpSettings, temp: i32) -> bool {
+    temp < amp.temp_upper_limit && temp > amp.temp_lower_limit
+}
+
+/// Checks whether the speakers have cooled down since the CRAS shutdown, given the unix times
+/// of the last boot time calibration, the CRAS shutdown and now. If less than `cool_down` has
+/// elapsed, the speakers may be over heated.
+///
+/// # Errors
+///
+/// * If the shutdown is before the last run or after now, so the speakers may have been
+///   replaced or used without a clean shutdown.
+/// * If the speakers may be over heated.
+pub fn check_cool_down(
+    last_run: Duration,
+    last_shutdown: Duration,
+    now: Duration,
+    cool_down: Duration,
+) -> Result<()> {
+    if last_shutdown < last_run {
+        return Err(Error::InvalidShutDownTime);
+    }
+    let elapsed = now
+        .checked_sub(last_shutdown)
+        .ok_or(Error::InvalidShutDownTime)?;
+    if elapsed < cool_down {
+        return Err(Error::HotSpeaker);
+    }
+    Ok(())
+}
+
+/// Checks the rdc and ambient temperature measured by a boot time calibration, and decides
+/// which values take effect.
+///
+/// # Errors
+///
+/// * If the temperature is out of the limits and there is no datastore.
+/// * If the rdc differs from the rdc in effect by more than `rdc_diff_upper_limit`.
+pub fn check_calibration(
+    amp: &AmpSettings,
+    thresholds: &CalibThresholds,
+    vpd: &VPD,
+    datastore: Option<Datastore>,
+    rdc: i32,
+    temp: i32,
+) -> Result<Verdict> {
+    let diff = rdc_diff(rdc, vpd, datastore.as_ref());
+    debug!(
+        "{}: rdc_cali: {}, temp_cali: {}, vpd: {:?}, datastore: {:?}, diff: {}",
+        amp.rdc_ctrl, rdc, temp, vpd, datastore, diff
+    );
+
+    if !validate_temperature(amp, temp) {
+        info!("invalid temperature: {}.", temp);
+        return datastore
+            .map(Verdict::KeepDatastore)
+            .ok_or(Error::InvalidTemperature(temp));
+    }
+
+    if diff > thresholds.rdc_diff_upper_limit {
+        Err(Error::LargeCalibrationDiff(rdc, temp))
+    } else if diff < thresholds.rdc_diff_lower_limit {
+        Ok(datastore.map_or(Verdict::UseVpd, Verdict::KeepDatastore))
+    } else {
+        Ok(Verdict::ApplyMeasured)
+    }
+}
+
+/// Checks the rdc and ambient temperature measured by a factory calibration against the
+/// temperature limits of the amp and the factory limits.
+///
+/// # Errors
+///
+/// * If the temperature is out of the limits.
+/// * If the rdc is out of the factory limits.
+pub fn check_factory_calibration(
+    amp: &AmpSettings,
+    limits: &FactoryLimits,
+    rdc: i32,
+    temp: i32,
+) -> Result<()> {
+    if !validate_temperature(amp, temp) {
+        return Err(Error::TemperatureOutOfLimits(temp));
+    }
+    if rdc < limits.rdc_min || rdc > limits.rdc_max {
+        return Err(Error::RdcOutOfFactoryLimits(
+            rdc,
+            limits.rdc_min,
+            limits.rdc_max,
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    fn amp() -> AmpSettings {
+        AmpSettings {
+            temp_lower_limit: -100,
+            temp_upper_limit: 500,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn temperature_limits_are_exclusive() {
+        let cases = [
+            (i32::MIN, false),
+            (-100, false),
+            (-99, true),
+            (0, true),
+            (499, true),
+            (500, false),
+            (i32::MAX, false),
+        ];
+        for (temp, valid) in &cases {
+            assert_eq!(validate_temperature(&amp(), *temp), *valid, "{}", temp);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn out_of_limits_temperature_keeps_datastore(temp: i32, rdc in 1..=i32::MAX) {
+            prop_assume!(!validate_temperature(&amp(), temp));
+            let datastore = Datastore::DSM { rdc: 12000, ambient_temp: 100 };
+            let verdict = check_calibration(
+                &amp(),
+                &CalibThresholds::default(),
+                &VPD::default(),
+                Some(datastore),
+                rdc,
+                temp,
+            );
+            let kept = matches!(
+                verdict,
+                Ok(Verdict::KeepDatastore(Datastore::DSM { rdc: 12000, .. }))
+            );
+            prop_assert!(kept);
+            let thresholds = CalibThresholds::default();
+            let verdict = check_calibration(&amp(), &thresholds, &VPD::default(), None, rdc, temp);
+            let rejected = matches!(verdict, Err(Error::InvalidTemperature(t)) if t == temp);
+            prop_assert!(rejected);
+        }
+    }
+}
@@ -1,35 +1,95 @@
 // Copyright 2020 The Chromium OS Authors. All rights reserved.
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
+use std::backtrace::Backtrace;
 use std::fmt;
-use std::io::Write;
+use std::panic;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Once};
 use std::thread;
-use std::thread::JoinHandle;
+use std::thread::{JoinHandle, ThreadId};
 use std::time::{Duration, Instant};
 
 use audio_streams::SampleFormat;
 use cros_alsa::{Card, IntControl, SwitchControl};
-use libcras::{CrasClient, CrasNodeType};
-use sys_util::{error, info};
+use log::{debug, error, info};
+use utils::{bootstat, faults, metrics, phases, RunOptions};
 
 use crate::{
+    acceptance::{check_calibration, check_factory_calibration, Verdict},
     datastore::Datastore,
     error::{Error, Result},
-    settings::AmpCalibSettings,
+    excitation::Stimulus,
+    playback::open_playback_client,
+    settings::{
+        AmpCalibSettings, AmpSettings, CalibThresholds, DeviceSettings, Excitation, FactoryLimits,
+    },
     vpd::VPD,
 };
 
-const CALI_ERROR_UPPER_LIMIT: f32 = 0.3;
-const CALI_ERROR_LOWER_LIMIT: f32 = 0.03;
-
 const FRAMES_PER_BUFFER: usize = 256;
 const FRAME_RATE: u32 = 48000;
 const NUM_CHANNELS: usize = 2;
 const FORMAT: SampleFormat = SampleFormat::S16LE;
-const DURATION_MS: u32 = 1000;
-const WARM_UP_DURATION_MS: u32 = 300;
+// The thread name of the playback worker, which is used by the panic hook to pick the panics of
+// the workers.
+const WORKER_NAME: &str = "play_zero_worker";
+// The log target of the playback worker.
+const ZERO_PLAYER_TARGET: &str = "max98390d::amp_calibration::zero_player";
+
+const CONTROL_READ_LATENCY_METRIC: &str = "Cras.SoundCardInit.Max98390d.ControlReadLatency";
+const CONTROL_WRITE_LATENCY_METRIC: &str = "Cras.SoundCardInit.Max98390d.ControlWriteLatency";
+const CONTROL_LATENCY_MAX_US: i32 = 1_000_000;
+const CONTROL_LATENCY_BUCKETS: i32 = 50;
+// The control accesses slower than it are logged, since the slow ioctls of some SOF firmware
+// versions are suspected to time out the calibration.
+const SLOW_CONTROL_ACCESS: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy)]
+enum ControlAccess {
+    Read,
+    Write,
+}
+
+// Runs the access of the controls and reports its latency to UMA unless it's a dry run.
+fn timed<T>(
+    dry_run: bool,
+    access: ControlAccess,
+    ctrls: &str,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    if let ControlAccess::Write = access {
+        if !dry_run && faults::fail_control_write() {
+            return Err(Error::InjectedFault(format!("control write of {}", ctrls)));
+        }
+    }
+    let start = Instant::now();
+    let res = f();
+    let latency = start.elapsed();
+    if latency >= SLOW_CONTROL_ACCESS {
+        info!("slow control {:?} of {}: {:?}", access, ctrls, latency);
+    }
+    if !dry_run {
+        let name = match access {
+            ControlAccess::Read => CONTROL_READ_LATENCY_METRIC,
+            ControlAccess::Write => CONTROL_WRITE_LATENCY_METRIC,
+        };
+        metrics::send_histogram(
+            name,
+            latency.as_micros().min(CONTROL_LATENCY_MAX_US as u128) as i32,
+            1,
+            CONTROL_LATENCY_MAX_US,
+            CONTROL_LATENCY_BUCKETS,
+        );
+    }
+    res
+}
+
+// The panic message and backtrace of the playback workers by thread, which are captured by the
+// panic hook and taken when the workers are joined.
+static WORKER_PANICS: Mutex<Vec<(ThreadId, String, String)>> = Mutex::new(Vec::new());
+static INSTALL_PANIC_HOOK: Once = Once::new();
 
 /// Amp volume mode emulation used by set_volume().
 #[derive(PartialEq)]
@@ -42,16 +102,120 @@ pub enum VolumeMode {
     High,
 }
 
+/// The calibration values in effect on an amp channel after sound_card_init, which is reported
+/// to UMA. It tells whether the speaker protection is in place. The values must not be
+/// renumbered.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CalibResult {
+    /// The values measured by this boot are applied.
+    AppliedMeasured = 0,
+    /// The values stored in the datastore are applied.
+    AppliedStored = 1,
+    /// The VPD values are kept.
+    AppliedVpd = 2,
+    /// No values are applied, and the volume is left low.
+    SafeFallback = 3,
+    /// The volume can't be set, so the protection state is unknown.
+    Failed = 4,
+}
+
+impl CalibResult {
+    /// The number of the results.
+    pub const COUNT: i32 = 5;
+
+    /// Returns the name of the result in the event feed.
+    pub fn name(self) -> &'static str {
+        match self {
+            CalibResult::AppliedMeasured => "applied_measured",
+            CalibResult::AppliedStored => "applied_stored",
+            CalibResult::AppliedVpd => "applied_vpd",
+            CalibResult::SafeFallback => "safe_fallback",
+            CalibResult::Failed => "failed",
+        }
+    }
+
+    // Returns the result of applying the datastore.
+    fn of_datastore(datastore: &Datastore) -> CalibResult {
+        match datastore {
+            Datastore::UseVPD => CalibResult::AppliedVpd,
+            Datastore::DSM { .. } => CalibResult::AppliedStored,
+        }
+    }
+}
+
+/// The outcome of the calibration of an amp channel, which is reported to UMA. The values
+/// must not be renumbered.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CalibOutcome {
+    /// The new calibration values are applied.
+    Applied = 0,
+    /// The values in the datastore or VPD are kept.
+    Fallback = 1,
+    /// The calibration values differ too much from the previous values.
+    LargeDiff = 2,
+    /// The calibration or the zero playback is not finished in time.
+    Timeout = 3,
+    /// The calibration fails for the other reasons.
+    Failed = 4,
+}
+
+impl CalibOutcome {
+    /// The number of the outcomes.
+    pub const COUNT: i32 = 5;
+
+    /// Returns the outcome of a calibration result.
+    pub fn of(res: &Result<CalibResult>) -> CalibOutcome {
+        match res {
+            Ok(CalibResult::AppliedMeasured) => CalibOutcome::Applied,
+            Ok(_) => CalibOutcome::Fallback,
+            Err(e) => CalibOutcome::of_error(e),
+        }
+    }
+
+    /// Returns the outcome of a failed calibration.
+    pub fn of_error(err: &Error) -> CalibOutcome {
+        match err {
+            Error::LargeCalibrationDiff(_, _) => CalibOutcome::LargeDiff,
+            Error::CalibrationTimeout | Error::StartPlaybackTimeout => CalibOutcome::Timeout,
+            _ => CalibOutcome::Failed,
+        }
+    }
+}
+
+/// `StreamHealth` is the health of the playback stream of a calibration measurement. It's
+/// reported to UMA to tie the measurement quality to the playback.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct StreamHealth {
+    /// The time from requesting the stream to the start of the measurement.
+    pub start_latency: Duration,
+    /// The underruns of the speaker device during the playback, or None if they are unknown,
+    /// ex: the playback fails.
+    pub underruns: Option<u32>,
+}
+
 /// It implements the amplifier boot time calibration flow.
 pub struct AmpCalibration<'a> {
     card: &'a mut Card,
+    snd_card: &'a str,
     setting: AmpCalibSettings,
+    thresholds: CalibThresholds,
+    excitation: Excitation,
+    opts: &'a RunOptions,
+    // The rdc and ambient temperature of the last calibration measurement.
+    measured: Option<(i32, i32)>,
+    // The playback stream health of the last calibration measurement.
+    stream_health: Option<StreamHealth>,
+    // The rdc and ambient temperature measured by `measure_all()` for the next run.
+    premeasured: Option<(i32, i32)>,
+    // The `Datastore` decided by the last run, which is not saved yet.
+    staged: Option<Datastore>,
 }
 
 impl<'a> fmt::Debug for AmpCalibration<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("AmpCalibration")
-            .field("snd_card_id", &self.card.name())
+            .field("snd_card_id", &self.snd_card)
+            .field("amp_card_id", &self.card.name())
             .field("amp_calib", &self.setting)
             .finish()
     }
@@ -61,8 +225,12 @@ impl<'a> AmpCalibration<'a> {
     /// Creates an `AmpCalibration`.
     /// # Arguments
     ///
-    /// * `card` - `&Card`.
+    /// * `card` - `&Card` of the amp controls.
+    /// * `snd_card` - The sound card name of the playback, which names the datastore.
     /// * `setting` - `AmpCalibSettings`.
+    /// * `thresholds` - `CalibThresholds`.
+    /// * `excitation` - The `Excitation` of the calibration measurement.
+    /// * `opts` - `RunOptions`.
     ///
     /// # Results
     ///
@@ -71,25 +239,49 @@ impl<'a> AmpCalibration<'a> {
     /// # Errors
     ///
     /// * If `Card` creation from sound card name fails.
-    pub fn new(card: &mut Card, setting: AmpCalibSettings) -> Result<AmpCalibration> {
-        let amp = AmpCalibration { card, setting };
+    pub fn new(
+        card: &'a mut Card,
+        snd_card: &'a str,
+        setting: AmpCalibSettings,
+        thresholds: CalibThresholds,
+        excitation: Excitation,
+        opts: &'a RunOptions,
+    ) -> Result<AmpCalibration<'a>> {
+        let amp = AmpCalibration {
+            card,
+            snd_card,
+            setting,
+            thresholds,
+            excitation,
+            opts,
+            measured: None,
+            stream_health: None,
+            premeasured: None,
+            staged: None,
+        };
 
         Ok(amp)
     }
 
     /// Sets card volume control to given VolumeMode.
     pub fn set_volume(&mut self, mode: VolumeMode) -> Result<()> {
-        match mode {
-            VolumeMode::High => self
-                .card
-                .control_by_name::<IntControl>(&self.setting.amp.volume_ctrl)?
-                .set(self.setting.amp.volume_high_limit)?,
-            VolumeMode::Low => self
-                .card
-                .control_by_name::<IntControl>(&self.setting.amp.volume_ctrl)?
-                .set(self.setting.amp.volume_low_limit)?,
+        let volume = match mode {
+            VolumeMode::High => self.setting.amp.volume_high_limit,
+            VolumeMode::Low => self.setting.amp.volume_low_limit,
+        };
+        if self.opts.dry_run {
+            info!(
+                "dry run: skip setting {} to {}",
+                self.setting.amp.volume_ctrl, volume
+            );
+            return Ok(());
         }
-        Ok(())
+        let _span = utils::trace_span!("control write", ctrl = %self.setting.amp.volume_ctrl);
+        let (card, ctrl) = (&mut *self.card, &self.setting.amp.volume_ctrl);
+        timed(self.opts.dry_run, ControlAccess::Write, ctrl, || {
+            card.control_by_name::<IntControl>(ctrl)?.set(volume)?;
+            Ok(())
+        })
     }
 
     /// The implementation of max98390d boot time calibration logic.
@@ -98,10 +290,22 @@ impl<'a> AmpCalibration<'a> {
     ///  * Gets results from `do_calibration`.
     ///  * Decides whether the new calibration result should replace the stored value.
     ///  * Applies a good calibration value.
-    pub fn run(&mut self) -> Result<()> {
-        let vpd = VPD::from_file(&self.setting.rdc_vpd, &self.setting.temp_vpd)?;
+    ///
+    /// The `Datastore` to update is staged instead of saved, so that the caller saves the
+    /// datastores of all the amps in one pass, see `take_staged()`.
+    ///
+    /// # Results
+    ///
+    /// * `CalibResult::AppliedMeasured` if the new calibration values are applied, or
+    ///   `CalibResult::AppliedStored` or `CalibResult::AppliedVpd` if the stored values are
+    ///   kept.
+    pub fn run(&mut self) -> Result<CalibResult> {
+        let vpd = {
+            let _phase = phases::start(self.snd_card, "vpd read");
+            VPD::from_file(&self.setting.rdc_vpd, &self.setting.temp_vpd)?
+        };
         let (rdc_cali, temp_cali) = self.do_calibration()?;
-        let datastore = match Datastore::from_file(self.card.name(), &self.setting.calib_file) {
+        let datastore = match Datastore::from_file(self.snd_card, &self.setting.calib_file) {
             Ok(sci_calib) => Some(sci_calib),
             Err(e) => {
                 info!("failure in Datastore::from_file: {}", e);
@@ -109,162 +313,187 @@ impl<'a> AmpCalibration<'a> {
             }
         };
 
-        // Given that rdc_cali is the inverse of hardware real_rdc, the result of `rdc_diff`
-        // equals to transforming `rdc`s to `real_rdc`s and calculating the relative difference
-        // from the `real_rdc`s.
-        let rdc_diff = |x: i32, x_ref: i32| (x - x_ref).abs() as f32 / x as f32;
-
-        let diff: f32 = match datastore {
-            None => rdc_diff(rdc_cali, vpd.dsm_calib_r0),
-            Some(d) => match d {
-                Datastore::UseVPD => rdc_diff(rdc_cali, vpd.dsm_calib_r0),
-                Datastore::DSM { rdc, .. } => rdc_diff(rdc_cali, rdc),
-            },
-        };
-
-        if !self.validate_temperature(temp_cali) {
-            info!("invalid temperature: {}.", temp_cali);
-            return match datastore {
-                None => Err(Error::InvalidTemperature(temp_cali)),
-                Some(d) => self.apply_datastore(d),
-            };
-        }
-
-        if diff > CALI_ERROR_UPPER_LIMIT {
-            return Err(Error::LargeCalibrationDiff(rdc_cali, temp_cali));
-        } else if diff < CALI_ERROR_LOWER_LIMIT {
-            match datastore {
-                None => Datastore::UseVPD.save(self.card.name(), &self.setting.calib_file)?,
-                Some(d) => self.apply_datastore(d)?,
+        match check_calibration(
+            &self.setting.amp,
+            &self.thresholds,
+            &vpd,
+            datastore,
+            rdc_cali,
+            temp_cali,
+        )? {
+            Verdict::ApplyMeasured => {
+                info!("apply boot time calibration values.");
+                self.set_calib_values(rdc_cali, temp_cali)?;
+                self.stage_datastore(Datastore::DSM {
+                    rdc: rdc_cali,
+                    ambient_temp: temp_cali,
+                });
+                Ok(CalibResult::AppliedMeasured)
+            }
+            Verdict::KeepDatastore(d) => {
+                let result = CalibResult::of_datastore(&d);
+                self.apply_datastore(d)?;
+                Ok(result)
             }
-        } else {
-            info!("apply boot time calibration values.");
-            self.card
-                .control_by_name::<IntControl>(&self.setting.amp.rdc_ctrl)?
-                .set(rdc_cali)?;
-            self.card
-                .control_by_name::<IntControl>(&self.setting.amp.temp_ctrl)?
-                .set(temp_cali)?;
-            Datastore::DSM {
-                rdc: rdc_cali,
-                ambient_temp: temp_cali,
+            Verdict::UseVpd => {
+                self.stage_datastore(Datastore::UseVPD);
+                Ok(CalibResult::AppliedVpd)
             }
-            .save(self.card.name(), &self.setting.calib_file)?;
         }
-        Ok(())
+    }
+
+    /// Takes the `Datastore` staged by the last `run()`, which should be saved by
+    /// `Datastore::save_all()`, or None if the datastore is kept as it is.
+    pub fn take_staged(&mut self) -> Option<Datastore> {
+        self.staged.take()
+    }
+
+    /// Returns the rdc and ambient temperature of the last calibration measurement, or None if
+    /// it has not measured. They are kept even if the calibration fails afterwards.
+    pub fn measured(&self) -> Option<(i32, i32)> {
+        self.measured
+    }
+
+    /// Returns the playback stream health of the last calibration measurement, or None if the
+    /// playback has not started.
+    pub fn stream_health(&self) -> Option<StreamHealth> {
+        self.stream_health
+    }
+
+    /// Runs the calibration measurement of the factory calibration, and checks the values
+    /// against the factory limits. The values are not applied.
+    ///
+    /// # Results
+    ///
+    /// * The calibrated rdc and ambient temperature values.
+    pub fn factory_calibrate(&mut self, limits: &FactoryLimits) -> Result<(i32, i32)> {
+        let (rdc, temp) = self.do_calibration()?;
+        info!(
+            "{}: factory calibration rdc: {}, temp: {}",
+            self.setting.amp.rdc_ctrl, rdc, temp
+        );
+        check_factory_calibration(&self.setting.amp, limits, rdc, temp)?;
+        Ok((rdc, temp))
     }
 
     fn apply_datastore(&mut self, d: Datastore) -> Result<()> {
         info!("apply datastore values.");
         match d {
             Datastore::UseVPD => Ok(()),
-            Datastore::DSM { rdc, ambient_temp } => {
-                self.card
-                    .control_by_name::<IntControl>(&self.setting.amp.rdc_ctrl)?
-                    .set(rdc)?;
-                self.card
-                    .control_by_name::<IntControl>(&self.setting.amp.temp_ctrl)?
-                    .set(ambient_temp)?;
-                Ok(())
-            }
+            Datastore::DSM { rdc, ambient_temp } => self.set_calib_values(rdc, ambient_temp),
+        }
+    }
+
+    fn stage_datastore(&mut self, datastore: Datastore) {
+        if self.opts.dry_run {
+            info!(
+                "dry run: skip saving {:?} to {}",
+                datastore, self.setting.calib_file
+            );
+            return;
         }
+        self.staged = Some(datastore);
     }
 
-    fn validate_temperature(&self, temp: i32) -> bool {
-        temp < self.setting.amp.temp_upper_limit && temp > self.setting.amp.temp_lower_limit
+    fn set_calib_values(&mut self, rdc: i32, ambient_temp: i32) -> Result<()> {
+        if self.opts.dry_run {
+            info!(
+                "dry run: skip setting {} to {} and {} to {}",
+                self.setting.amp.rdc_ctrl, rdc, self.setting.amp.ambient_temp_ctrl, ambient_temp
+            );
+            return Ok(());
+        }
+        let _span = utils::trace_span!("control write", ctrl = %self.setting.amp.rdc_ctrl);
+        let (card, amp) = (&mut *self.card, &self.setting.amp);
+        let ctrls = format!("{}, {}", amp.rdc_ctrl, amp.ambient_temp_ctrl);
+        // Restores the previous values if either write fails, so the amp never runs with a
+        // half-applied calibration.
+        timed(self.opts.dry_run, ControlAccess::Write, &ctrls, || {
+            card.apply_controls::<[i32; 1]>(vec![
+                (&amp.rdc_ctrl, [rdc]),
+                (&amp.ambient_temp_ctrl, [ambient_temp]),
+            ])?;
+            Ok(())
+        })
     }
 
-    /// Triggers the amplifier calibration and reads the calibrated rdc and ambient_temp value
-    /// from the mixer control.
-    /// To get accurate calibration results, the main thread calibrates the amplifier while
-    /// the another thread plays zeros to the speakers.
+    /// Sets the rdc and ambient temperature measured by `measure_all()`, which the next
+    /// `run()` uses instead of measuring the amp by itself.
+    pub fn set_measured(&mut self, rdc: i32, temp: i32) {
+        self.premeasured = Some((rdc, temp));
+    }
+
+    // Runs the calibration measurement of the amp, or takes the values set by
+    // `set_measured()`.
     fn do_calibration(&mut self) -> Result<(i32, i32)> {
-        // The playback worker uses `playback_started` to notify the main thread that playback
-        // of zeros has started.
-        let playback_started = Arc::new((Mutex::new(false), Condvar::new()));
-        // Shares `calib_finished` to the playback worker and uses it to notify the worker when
-        // the calibration is finished.
-        let calib_finished = Arc::new(AtomicBool::new(false));
-        let handle =
-            AmpCalibration::run_play_zero_worker(playback_started.clone(), calib_finished.clone())?;
-
-        // Waits until zero playback starts or timeout.
-        let mut timeout = Duration::from_millis(1000);
-        let (lock, cvar) = &*playback_started;
-        let mut started = lock.lock()?;
-        while !*started {
-            let start_time = Instant::now();
-            started = cvar.wait_timeout(started, timeout)?.0;
-            if *started {
-                break;
-            } else {
-                let elapsed = start_time.elapsed();
-                if elapsed > timeout {
-                    return Err(Error::StartPlaybackTimeout);
-                } else {
-                    // Spurious wakes. Decrements the sleep duration by the amount slept.
-                    timeout -= start_time.elapsed();
-                }
-            }
+        if let Some(values) = self.premeasured.take() {
+            self.measured = Some(values);
+            return Ok(values);
         }
+        let _span = utils::trace_span!("measurement", channel = %self.setting.amp.rdc_ctrl);
+        let mut measurement = Measurement::default();
+        let res = measure(
+            self.card,
+            self.snd_card,
+            &[&self.setting.amp],
+            &self.thresholds,
+            &self.excitation,
+            self.opts,
+            &mut measurement,
+        );
+        self.measured = measurement.values.and_then(|v| v.first().copied());
+        self.stream_health = measurement.stream_health;
+        Ok(res?[0])
+    }
 
-        // Playback of zeros is started, and the main thread can start the calibration.
-        self.card
-            .control_by_name::<SwitchControl>(&self.setting.amp.calib_ctrl)?
-            .on()?;
-        let rdc = self
-            .card
-            .control_by_name::<IntControl>(&self.setting.amp.rdc_ctrl)?
-            .get()?;
-        let temp = self
-            .card
-            .control_by_name::<IntControl>(&self.setting.amp.temp_ctrl)?
-            .get()?;
-        self.card
-            .control_by_name::<SwitchControl>(&self.setting.amp.calib_ctrl)?
-            .off()?;
-        // Notifies the play_zero_worker that the calibration is finished.
-        calib_finished.store(true, Ordering::Relaxed);
-
-        // If play_zero_worker has error during the calibration, returns an error to keep the volume
-        // low to protect the speaker.
+    // Waits for the playback worker and returns the underruns during the playback, or its error
+    // or its panic.
+    fn join_play_zero_worker(handle: JoinHandle<Result<Option<u32>>>) -> Result<Option<u32>> {
+        let id = handle.thread().id();
         match handle.join() {
-            Ok(res) => {
-                if let Err(e) = res {
-                    error!("run_play_zero_worker has error: {}", e);
-                    return Err(e);
+            Ok(res) => res.map_err(|e| {
+                error!(target: ZERO_PLAYER_TARGET, "run_play_zero_worker has error: {}", e);
+                e
+            }),
+            Err(_) => {
+                let err = take_worker_panic(id);
+                if let Error::WorkerPanicked { message, backtrace } = &err {
+                    error!(
+                        target: ZERO_PLAYER_TARGET,
+                        "run_play_zero_worker panicked: {}\n{}",
+                        message,
+                        backtrace
+                    );
                 }
-            }
-            Err(e) => {
-                error!("run_play_zero_worker panics: {:?}", e);
-                return Err(Error::WorkerPanics);
+                Err(err)
             }
         }
-
-        Ok((rdc, temp))
     }
 
     // Creates a thread to play zeros to the internal speakers.
     fn run_play_zero_worker(
-        playback_started: Arc<(Mutex<bool>, Condvar)>,
+        playback_started: Sender<()>,
         calib_finished: Arc<AtomicBool>,
-    ) -> Result<JoinHandle<Result<()>>> {
-        let mut cras_client = CrasClient::new().map_err(Error::CrasClientFailed)?;
-        // TODO(b/155007305): Implement cras_client.wait_node_change and use it here.
-        let node = cras_client
-            .output_nodes()
-            .find(|node| node.node_type == CrasNodeType::CRAS_NODE_TYPE_INTERNAL_SPEAKER)
+        mut stimulus: Stimulus,
+        duration_ms: u32,
+        warm_up_duration_ms: u32,
+    ) -> Result<JoinHandle<Result<Option<u32>>>> {
+        let mut client = open_playback_client()?;
+        let speaker = client
+            .internal_speaker()
             .ok_or(Error::InternalSpeakerNotFound)?;
 
-        let handle = thread::spawn(move || -> Result<()> {
-            let local_buffer = [0u8; FRAMES_PER_BUFFER * NUM_CHANNELS * 2];
-            let iterations = (FRAME_RATE * DURATION_MS) / FRAMES_PER_BUFFER as u32 / 1000;
+        install_panic_hook();
+        let worker = thread::Builder::new().name(WORKER_NAME.to_owned());
+        let handle = worker.spawn(move || -> Result<Option<u32>> {
+            let iterations = (FRAME_RATE * duration_ms) / FRAMES_PER_BUFFER as u32 / 1000;
             let warm_up_iterations =
-                (FRAME_RATE * WARM_UP_DURATION_MS) / FRAMES_PER_BUFFER as u32 / 1000;
+                (FRAME_RATE * warm_up_duration_ms) / FRAMES_PER_BUFFER as u32 / 1000;
 
-            let (_control, mut stream) = cras_client
+            thread::sleep(faults::playback_delay());
+            let (_control, mut stream) = client
                 .new_pinned_playback_stream(
-                    node.iodev_index,
+                    speaker.iodev_index,
                     NUM_CHANNELS,
                     FORMAT,
                     FRAME_RATE,
@@ -272,7 +501,7 @@ impl<'a> AmpCalibration<'a> {
                 )
                 .map_err(|e| Error::NewPlayStreamFailed(e))?;
 
-            // Plays zeros for at most DURATION_MS.
+            // Plays the excitation for at most duration_ms.
             for i in 0..iterations {
                 if calib_finished.load(Ordering::Relaxed) {
                     break;
@@ -280,40 +509,222 @@ impl<'a> AmpCalibration<'a> {
                 let mut buffer = stream
                     .next_playback_buffer()
                     .map_err(|e| Error::NextPlaybackBufferFailed(e))?;
-                let _write_frames = buffer.write(&local_buffer).map_err(Error::PlaybackFailed)?;
+                // The frames are filled in the stream buffer in place, ex: the CRAS shm, without
+                // a copy from a local buffer.
+                buffer.copy_cb(FRAMES_PER_BUFFER * NUM_CHANNELS * 2, |frames| {
+                    stimulus.fill(frames)
+                });
 
                 // Notifies the main thread to start the calibration.
-                // The mute playing time need to be longer than WARM_UP_DURATION_MS to get rdc properly.
+                // The mute playing time need to be longer than warm_up_duration_ms to get rdc properly.
                 if i == warm_up_iterations {
-                    let (lock, cvar) = &*playback_started;
-                    let mut started = lock.lock()?;
-                    *started = true;
-                    cvar.notify_one();
+                    // The main thread is gone if it timed out waiting.
+                    let _ = playback_started.send(());
                 }
-                // The playback_started lock is unlocked here when `started` goes out of scope.
             }
 
             // Returns an error if the calibration is not finished before playback stops.
             if !calib_finished.load(Ordering::Relaxed) {
                 return Err(Error::CalibrationTimeout);
             }
-            Ok(())
+            // The underruns are read while the stream keeps the device open. They are
+            // best-effort and unknown if CRAS fails to report them.
+            let underruns = match &speaker.name {
+                Some(name) => client.underruns(name),
+                None => Some(0),
+            };
+            Ok(underruns)
         });
 
-        Ok(handle)
+        handle.map_err(Error::SpawnWorkerFailed)
     }
 
     /// Skips max98390d boot time calibration when the speaker may be hot.
     ///
     /// If datastore exists, applies the stored value and sets volume to high.
     /// If datastore does not exist, sets volume to low.
-    pub fn hot_speaker_workflow(&mut self) -> Result<()> {
-        if let Ok(sci_calib) = Datastore::from_file(self.card.name(), &self.setting.calib_file) {
+    pub fn hot_speaker_workflow(&mut self) -> Result<CalibResult> {
+        if let Ok(sci_calib) = Datastore::from_file(self.snd_card, &self.setting.calib_file) {
+            let result = CalibResult::of_datastore(&sci_calib);
             self.apply_datastore(sci_calib)?;
             self.set_volume(VolumeMode::High)?;
-            return Ok(());
+            return Ok(result);
         }
         info!("no datastore, set volume low");
-        self.set_volume(VolumeMode::Low)
+        self.set_volume(VolumeMode::Low)?;
+        Ok(CalibResult::SafeFallback)
+    }
+}
+
+// Chains a panic hook which captures the message and the backtrace of the panics of the playback
+// workers, since the payload of a joined thread is an opaque `Box<dyn Any>`.
+/// `Measurement` is the outcome of a calibration measurement. The values are kept even if the
+/// measurement fails afterwards, ex: the playback worker fails after the values are read.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Measurement {
+    /// The rdc and ambient temperature of each amp, or None if they are not read.
+    pub values: Option<Vec<(i32, i32)>>,
+    /// The playback stream health, or None if the playback has not started.
+    pub stream_health: Option<StreamHealth>,
+}
+
+/// Runs one calibration measurement of all the amps of the device, so that their values are
+/// read in one batch during a single playback instead of a playback per amp. The values are
+/// given to the `AmpCalibration` of each amp by `AmpCalibration::set_measured()`.
+///
+/// # Results
+///
+/// * The rdc and ambient temperature of each amp, in the order of `amp_calibrations`.
+pub fn measure_all(
+    card: &mut Card,
+    snd_card: &str,
+    settings: &DeviceSettings,
+    opts: &RunOptions,
+    measurement: &mut Measurement,
+) -> Result<Vec<(i32, i32)>> {
+    let _span = utils::trace_span!("measurement", channel = "all");
+    let amps: Vec<&AmpSettings> = settings.amp_calibrations.iter().map(|s| &s.amp).collect();
+    measure(
+        card,
+        snd_card,
+        &amps,
+        &settings.thresholds,
+        &settings.excitation,
+        opts,
+        measurement,
+    )
+}
+
+// Triggers the calibration of the amps and reads their calibrated rdc and ambient_temp values
+// from the mixer controls. To get accurate calibration results, the main thread calibrates the
+// amps while another thread plays the excitation to the speakers. The calibration controls are
+// toggled in the dry run mode as well since the measurement needs them, but the calibration
+// results are not applied.
+fn measure(
+    card: &mut Card,
+    snd_card: &str,
+    amps: &[&AmpSettings],
+    thresholds: &CalibThresholds,
+    excitation: &Excitation,
+    opts: &RunOptions,
+    measurement: &mut Measurement,
+) -> Result<Vec<(i32, i32)>> {
+    // The playback worker sends to `playback_started` to notify the main thread that playback
+    // of zeros has started. It's dropped if the worker exits early.
+    let (playback_started, started) = mpsc::channel();
+    // Shares `calib_finished` to the playback worker and uses it to notify the worker when the
+    // calibration is finished.
+    let calib_finished = Arc::new(AtomicBool::new(false));
+    let playback_phase = phases::start(snd_card, "playback start");
+    let requested = Instant::now();
+    let handle = AmpCalibration::run_play_zero_worker(
+        playback_started,
+        calib_finished.clone(),
+        Stimulus::new(excitation, FRAME_RATE, NUM_CHANNELS)?,
+        thresholds.playback_duration_ms,
+        thresholds.warm_up_duration_ms,
+    )?;
+
+    // Waits until zero playback starts or timeout.
+    let timeout = Duration::from_millis(thresholds.playback_start_timeout_ms);
+    match started.recv_timeout(timeout) {
+        Ok(()) => {}
+        Err(RecvTimeoutError::Timeout) => return Err(Error::StartPlaybackTimeout),
+        // The worker fails before the playback starts.
+        Err(RecvTimeoutError::Disconnected) => {
+            AmpCalibration::join_play_zero_worker(handle)?;
+            return Err(Error::StartPlaybackTimeout);
+        }
+    }
+
+    // Playback of zeros is started, and the main thread can start the calibration.
+    drop(playback_phase);
+    measurement.stream_health = Some(StreamHealth {
+        start_latency: requested.elapsed(),
+        underruns: None,
+    });
+    if !opts.dry_run {
+        bootstat::mark(bootstat::STREAM_START);
+    }
+    let measurement_phase = phases::start(snd_card, "measurement");
+    let calib_ctrls: Vec<&str> = amps.iter().map(|amp| amp.calib_ctrl.as_str()).collect();
+    debug!(
+        target: ZERO_PLAYER_TARGET,
+        "zero playback started, trigger {}",
+        calib_ctrls.join(", ")
+    );
+    for ctrl in &calib_ctrls {
+        timed(opts.dry_run, ControlAccess::Write, ctrl, || {
+            card.control_by_name::<SwitchControl>(ctrl)?.on()?;
+            Ok(())
+        })?;
+    }
+    // The values of all the amps are read in one batch.
+    let ctrls: Vec<&str> = amps
+        .iter()
+        .flat_map(|amp| [amp.rdc_ctrl.as_str(), amp.ambient_temp_ctrl.as_str()])
+        .collect();
+    let values = timed(opts.dry_run, ControlAccess::Read, &ctrls.join(", "), || {
+        Ok(card.load_controls::<[i32; 1]>(&ctrls)?)
+    })?;
+    for ctrl in &calib_ctrls {
+        timed(opts.dry_run, ControlAccess::Write, ctrl, || {
+            card.control_by_name::<SwitchControl>(ctrl)?.off()?;
+            Ok(())
+        })?;
+    }
+    drop(measurement_phase);
+    let values: Vec<(i32, i32)> = values.chunks(2).map(|v| (v[0][0], v[1][0])).collect();
+    measurement.values = Some(values.clone());
+    // Notifies the play_zero_worker that the calibration is finished.
+    calib_finished.store(true, Ordering::Relaxed);
+
+    // If play_zero_worker has error during the calibration, returns an error to keep the volume
+    // low to protect the speaker.
+    let underruns = AmpCalibration::join_play_zero_worker(handle)?;
+    if let Some(health) = measurement.stream_health.as_mut() {
+        health.underruns = underruns;
+    }
+    Ok(values)
+}
+
+fn install_panic_hook() {
+    INSTALL_PANIC_HOOK.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let current = thread::current();
+            if current.name() == Some(WORKER_NAME) {
+                let payload = info.payload();
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("non-string panic payload");
+                let message = match info.location() {
+                    Some(location) => format!("{} at {}", message, location),
+                    None => message.to_owned(),
+                };
+                let backtrace = Backtrace::force_capture().to_string();
+                if let Ok(mut panics) = WORKER_PANICS.lock() {
+                    panics.push((current.id(), message, backtrace));
+                }
+            }
+            default_hook(info);
+        }));
+    });
+}
+
+// Returns the captured panic of the playback worker.
+fn take_worker_panic(id: ThreadId) -> Error {
+    let captured = WORKER_PANICS.lock().ok().and_then(|mut panics| {
+        let i = panics.iter().position(|(thread, _, _)| *thread == id)?;
+        Some(panics.swap_remove(i))
+    });
+    match captured {
+        Some((_, message, backtrace)) => Error::WorkerPanicked { message, backtrace },
+        None => Error::WorkerPanicked {
+            message: "the panic is not captured".to_owned(),
+            backtrace: String::new(),
+        },
     }
 }
@@ -0,0 +1,196 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It publishes the calibration values in effect to CRAS by the calibration file of the sound
+//! card, ex: /run/sound_card_init/sofcmlmax98390d.calib.json, so that the DSP of CRAS, ex: the
+//! software speaker protection or the EQ selection, can use the values of the device. The file
+//! is replaced after every boot time calibration, including the failed ones:
+//!
+//! ```json
+//! {"sound_card_id": "sofcmlmax98390d", "time": 1600000000,
+//!  "channels": [{"rdc_ctrl": "Left Rdc", "rdc": 27000, "ambient_temp": 1000,
+//!                "provenance": "datastore"}]}
+//! ```
+//!
+//! The provenance is where the values come from: the datastore, the VPD, or none if the amp
+//! runs without the calibration values, ex: the calibration fails on the first boot. The
+//! values are null if they can't be read.
+//!
+//! The internal speaker node of CRAS is also marked as protected by the calibrated speaker
+//! protection if the calibration succeeds, so that CRAS or the UI can apply a different
+//! max-volume policy to the uncalibrated devices. The mark is the state of the CRAS server, so it
+//! doesn't survive the restart of CRAS.
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use cros_alsa::Card;
+use log::info;
+use serde_json::json;
+use utils::clock::clock;
+use utils::run_dir;
+
+use crate::datastore::Datastore;
+use crate::error::Result;
+use crate::playback::open_playback_client;
+use crate::settings::DeviceSettings;
+use crate::status::ChannelStatus;
+
+const CALIB_FILE_EXTENSION: &str = "calib.json";
+
+// Returns the path of the calibration file of the sound card.
+fn calib_file(snd_card: &str) -> PathBuf {
+    run_dir()
+        .join(snd_card)
+        .with_extension(CALIB_FILE_EXTENSION)
+}
+
+/// Writes the calibration values of the amps in effect to the calibration file. The file is
+/// replaced atomically, so CRAS never reads partial values.
+pub fn publish(card: &mut Card, snd_card: &str, settings: &DeviceSettings) -> io::Result<()> {
+    write_calib_file(snd_card, &ChannelStatus::collect(card, snd_card, settings))
+}
+
+// Replaces the calibration file by the values of the channels.
+fn write_calib_file(snd_card: &str, channels: &[ChannelStatus]) -> io::Result<()> {
+    let channels: Vec<_> = channels
+        .iter()
+        .map(|ch| {
+            let provenance = match ch.datastore {
+                Some(Datastore::DSM { .. }) => "datastore",
+                Some(Datastore::UseVPD) => "vpd",
+                None => "none",
+            };
+            json!({
+                "rdc_ctrl": ch.rdc_ctrl,
+                "rdc": ch.rdc,
+                "ambient_temp": ch.ambient_temp,
+                "provenance": provenance,
+            })
+        })
+        .collect();
+    let calib = json!({
+        "sound_card_id": snd_card,
+        "time": clock().now().map_or(0, |t| t.as_secs()),
+        "channels": channels,
+    });
+    let path = calib_file(snd_card);
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, calib.to_string())?;
+    fs::rename(&tmp, &path)
+}
+
+/// Marks the internal speaker node of CRAS as protected by the calibrated speaker protection or
+/// not. It does nothing if CRAS does not have the internal speaker. The client is opened by
+/// `open_playback_client()`, so the mark goes to `FakeCras` if it's in use.
+///
+/// # Errors
+///
+/// * If it fails to connect to CRAS or to send the request.
+pub fn mark_speaker_protected(protected: bool) -> Result<()> {
+    let mut client = open_playback_client()?;
+    let speaker = match client.internal_speaker() {
+        Some(speaker) => speaker,
+        None => {
+            info!("no internal speaker node to mark as protected");
+            return Ok(());
+        }
+    };
+    client.set_speaker_protected(&speaker, protected)?;
+    info!(
+        "marked {} as speaker protected: {}",
+        speaker.name.as_deref().unwrap_or("the internal speaker"),
+        protected
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::sync::Mutex;
+
+    use serde_json::Value;
+
+    use crate::playback::{use_fake_cras, FakeCras};
+
+    const CARD: &str = "sofcmlmax98390d";
+
+    // The tests share the process-wide FakeCras.
+    static FAKE_CRAS: Mutex<()> = Mutex::new(());
+
+    fn channel(rdc_ctrl: &str, datastore: Option<Datastore>) -> ChannelStatus {
+        ChannelStatus {
+            rdc_ctrl: rdc_ctrl.to_owned(),
+            rdc: datastore.as_ref().map(|_| 27000),
+            ambient_temp: datastore.as_ref().map(|_| 1000),
+            datastore,
+            datastore_updated: None,
+            vpd: None,
+        }
+    }
+
+    #[test]
+    fn publishes_the_values_in_effect() {
+        let dir = env::temp_dir().join(format!("cras_calib_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        utils::set_run_dir(dir.clone());
+        let channels = [
+            channel(
+                "Left Rdc",
+                Some(Datastore::DSM {
+                    rdc: 27000,
+                    ambient_temp: 1000,
+                }),
+            ),
+            channel("Right Rdc", Some(Datastore::UseVPD)),
+            channel("Center Rdc", None),
+        ];
+        write_calib_file(CARD, &channels).unwrap();
+
+        let path = calib_file(CARD);
+        assert_eq!(path, dir.join("sofcmlmax98390d.calib.json"));
+        assert!(!path.with_extension("tmp").exists());
+        let calib: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(calib["sound_card_id"], CARD);
+        let provenances: Vec<_> = calib["channels"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|ch| ch["provenance"].as_str().unwrap())
+            .collect();
+        assert_eq!(provenances, ["datastore", "vpd", "none"]);
+        assert_eq!(calib["channels"][0]["rdc"], 27000);
+        assert_eq!(calib["channels"][2]["rdc"], Value::Null);
+
+        // The file is replaced by the next run.
+        write_calib_file(CARD, &channels[2..]).unwrap();
+        let calib: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(calib["channels"].as_array().unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn marks_the_speaker_protected() {
+        let _lock = FAKE_CRAS.lock().unwrap();
+        let cras = FakeCras::new();
+        use_fake_cras(Some(cras.clone()));
+        mark_speaker_protected(true).unwrap();
+        assert_eq!(cras.speaker_protected(), Some(true));
+        mark_speaker_protected(false).unwrap();
+        assert_eq!(cras.speaker_protected(), Some(false));
+        use_fake_cras(None);
+    }
+
+    #[test]
+    fn skips_the_mark_without_speaker() {
+        let _lock = FAKE_CRAS.lock().unwrap();
+        let cras = FakeCras::new().without_speaker();
+        use_fake_cras(Some(cras.clone()));
+        mark_speaker_protected(true).unwrap();
+        assert_eq!(cras.speaker_protected(), None);
+        use_fake_cras(None);
+    }
+}
@@ -1,13 +1,13 @@
 // Copyright 2020 The Chromium OS Authors. All rights reserved.
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
+use std::fmt;
 use std::fs::File;
 use std::io::{prelude::*, BufReader, BufWriter};
-use std::path::PathBuf;
 
-use serde::{Deserialize, Serialize};
-use sys_util::info;
-use utils::DATASTORE_DIR;
+use log::info;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use utils::{datastore_dir, faults};
 
 use crate::error::{Error, Result};
 
@@ -24,33 +24,72 @@ pub enum Datastore {
 impl Datastore {
     /// Creates a `Datastore` and initializes its fields from DATASTORE_DIR/<snd_card>/{file}.
     pub fn from_file(snd_card: &str, file: &str) -> Result<Datastore> {
-        let path = PathBuf::from(DATASTORE_DIR).join(snd_card).join(file);
+        from_yaml_file(snd_card, file)
+    }
+
+    /// Saves a `Datastore` to DATASTORE_DIR/<snd_card>/{file}.
+    pub fn save(&self, snd_card: &str, file: &str) -> Result<()> {
+        save_yaml_file(snd_card, file, self)
+    }
 
-        let io_err = |e| Error::FileIOFailed(path.to_string_lossy().to_string(), e);
-        let parse_err = |e: serde_yaml::Error| {
-            Error::DeserializationFailed(path.to_string_lossy().to_string(), e)
-        };
+    /// Saves the `Datastore`s to their files in DATASTORE_DIR/<snd_card>, and syncs them to
+    /// the storage once after all the writes.
+    pub fn save_all(snd_card: &str, datastores: &[(String, Datastore)]) -> Result<()> {
+        if datastores.is_empty() {
+            return Ok(());
+        }
+        for (file, datastore) in datastores {
+            datastore.save(snd_card, file)?;
+        }
+        let dir = datastore_dir(snd_card);
+        utils::sync_fs(&dir).map_err(|e| Error::FileIOFailed(dir.to_string_lossy().to_string(), e))
+    }
+}
+
+/// `GainOffsets`, which stores the gain normalization offsets of all channels in yaml format.
+/// The offsets are in control steps and are ordered the same as `amp_calibrations`.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct GainOffsets(pub Vec<i32>);
 
-        let reader = BufReader::new(File::open(&path).map_err(io_err)?);
-        let datastore: Datastore = serde_yaml::from_reader(reader).map_err(parse_err)?;
-        Ok(datastore)
+impl GainOffsets {
+    /// Creates a `GainOffsets` and initializes its fields from DATASTORE_DIR/<snd_card>/{file}.
+    pub fn from_file(snd_card: &str, file: &str) -> Result<GainOffsets> {
+        from_yaml_file(snd_card, file)
     }
 
-    /// Saves a `Datastore` to DATASTORE_DIR/<snd_card>/{file}.
+    /// Saves a `GainOffsets` to DATASTORE_DIR/<snd_card>/{file}.
     pub fn save(&self, snd_card: &str, file: &str) -> Result<()> {
-        let path = PathBuf::from(DATASTORE_DIR).join(snd_card).join(file);
-        let io_err = |e| Error::FileIOFailed(path.to_string_lossy().to_string(), e);
-
-        let mut writer = BufWriter::new(File::create(&path).map_err(io_err)?);
-        writer
-            .write(
-                serde_yaml::to_string(self)
-                    .map_err(Error::SerializationFailed)?
-                    .as_bytes(),
-            )
-            .map_err(io_err)?;
-        writer.flush().map_err(io_err)?;
-        info!("update Datastore {}: {:?}", path.to_string_lossy(), self);
-        Ok(())
+        save_yaml_file(snd_card, file, self)
+    }
+}
+
+fn from_yaml_file<T: DeserializeOwned>(snd_card: &str, file: &str) -> Result<T> {
+    let path = datastore_dir(snd_card).join(file);
+
+    let io_err = |e| Error::FileIOFailed(path.to_string_lossy().to_string(), e);
+
+    let reader = BufReader::new(File::open(&path).map_err(io_err)?);
+    from_yaml_reader(&path.to_string_lossy(), reader)
+}
+
+/// Parses the yaml of a datastore file. `name` is the file reported by the errors.
+pub fn from_yaml_reader<T: DeserializeOwned, R: Read>(name: &str, reader: R) -> Result<T> {
+    serde_yaml::from_reader(reader).map_err(|e| Error::CorruptDatastore(name.to_owned(), e))
+}
+
+fn save_yaml_file<T: Serialize + fmt::Debug>(snd_card: &str, file: &str, val: &T) -> Result<()> {
+    let path = datastore_dir(snd_card).join(file);
+    let io_err = |e| Error::FileIOFailed(path.to_string_lossy().to_string(), e);
+
+    let mut yaml = serde_yaml::to_string(val).map_err(Error::SerializationFailed)?;
+    if faults::corrupt_datastore() {
+        // Truncates the yaml, like a write interrupted by a power loss.
+        yaml.truncate(yaml.len() / 2);
+        yaml.push_str("\0{");
     }
+    let mut writer = BufWriter::new(File::create(&path).map_err(io_err)?);
+    writer.write(yaml.as_bytes()).map_err(io_err)?;
+    writer.flush().map_err(io_err)?;
+    info!("update Datastore {}: {:?}", path.to_string_lossy(), val);
+    Ok(())
 }
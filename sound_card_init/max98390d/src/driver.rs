@@ -0,0 +1,1091 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It implements the workflows of max98390d on the sound card and CRAS, which are built with
+//! the `hardware` feature.
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use cros_alsa::{Access, Card, ControlSetBuilder, IntControl, SimpleMixer, Topology};
+use log::{error, info};
+use utils::clock::clock;
+use utils::events::{self, Event};
+use utils::skips::{self, SkipReason};
+use utils::{datastore_dir, metrics, phases, run_time, shutdown_time, RunOptions};
+
+use crate::acceptance::{check_cool_down, check_factory_calibration};
+use crate::amp_calibration::{
+    measure_all, AmpCalibration, CalibOutcome, CalibResult, Measurement, StreamHealth, VolumeMode,
+};
+use crate::cras_calib;
+use crate::datastore::Datastore;
+use crate::error::{Error, Result};
+use crate::gain_normalization::{apply_stored_gain_offsets, normalize_gain};
+use crate::monitor::Monitor;
+use crate::settings::{AmpCalibSettings, DeviceSettings, FactoryLimits};
+use crate::status::{ChannelStatus, LiveStatus};
+use crate::vendor_calib::VendorCalib;
+use crate::vpd::VPD;
+
+const CALIB_OUTCOME_METRIC: &str = "Cras.SoundCardInit.Max98390d.CalibrationOutcome";
+const CALIB_RESULT_METRIC: &str = "Cras.SoundCardInit.Max98390d.CalibrationResult";
+const CALIB_RDC_METRIC: &str = "Cras.SoundCardInit.Max98390d.CalibrationRdc";
+const CALIB_TEMP_METRIC: &str = "Cras.SoundCardInit.Max98390d.CalibrationTemperature";
+const STREAM_START_LATENCY_METRIC: &str = "Cras.SoundCardInit.Max98390d.StreamStartLatency";
+const STREAM_UNDERRUNS_METRIC: &str = "Cras.SoundCardInit.Max98390d.StreamUnderruns";
+const STREAM_START_LATENCY_MAX_MS: i32 = 10_000;
+const STREAM_UNDERRUNS_MAX: i32 = 1000;
+const HISTOGRAM_BUCKETS: i32 = 50;
+// The topology of the amp card in the datastore, which is reused across boots.
+const TOPOLOGY_FILE: &str = "topology";
+
+/// Performs max98390d boot time calibration.
+///
+///
+/// # Arguments
+///
+/// * `snd_card` - The sound card name of the playback, ex: sofcmlmax98390d.
+/// * `conf` - The `DeviceSettings` in yaml format.
+///
+/// # Errors
+///
+/// If any amplifiers fail to complete the calibration.
+pub fn run_max98390d(snd_card: &str, conf: &str) -> Result<()> {
+    run_max98390d_with_card(
+        &mut open_amp_card(snd_card, conf)?,
+        snd_card,
+        conf,
+        &RunOptions::default(),
+    )
+}
+
+/// Opens the sound card of the amp controls, so that the caller can open it before dropping
+/// privileges and pass it to `run_max98390d_with_card()`.
+///
+/// # Arguments
+///
+/// * `snd_card` - The sound card name of the playback, ex: sofcmlmax98390d.
+/// * `conf` - The `DeviceSettings` in yaml format.
+///
+/// # Errors
+///
+/// * If the config is invalid.
+/// * If the sound card can't be opened.
+pub fn open_amp_card(snd_card: &str, conf: &str) -> Result<Card> {
+    let settings = DeviceSettings::from_yaml_str(conf)?;
+    let _phase = phases::start(snd_card, "card open");
+    let _span = utils::trace_span!("card open", snd_card);
+    // The amp controls live on the playback sound card unless `amp_card` is specified.
+    // `amp_card` may also be a substring of the longname or components of the sound card.
+    let pattern = settings.amp_card.as_deref().unwrap_or(snd_card);
+    let topology_file = datastore_dir(snd_card).join(TOPOLOGY_FILE);
+    // Reuses the topology of the last boot to skip listing the control elements.
+    if let Ok(topology) = Topology::from_file(&topology_file) {
+        match Card::from_topology(&topology) {
+            Ok(card) if card_matches(&card, pattern) => return Ok(card),
+            Ok(_) => info!("the topology of {} is not {}", topology.card_id(), pattern),
+            Err(e) => info!("failed to reuse the topology: {}", e),
+        }
+    }
+    let mut card = Card::find(pattern)?;
+    if let Err(e) = card
+        .topology()
+        .map_err(Error::from)
+        .and_then(|t| save_topology(&t, &topology_file))
+    {
+        info!("failed to save the topology: {}", e);
+    }
+    Ok(card)
+}
+
+// Returns true if the sound card is the one `Card::find()` opens by the pattern.
+fn card_matches(card: &Card, pattern: &str) -> bool {
+    let info = card.info();
+    info.id == pattern || info.longname.contains(pattern) || info.components.contains(pattern)
+}
+
+fn save_topology(topology: &Topology, path: &Path) -> Result<()> {
+    let to_err = |e| Error::FileIOFailed(path.to_string_lossy().to_string(), e);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(to_err)?;
+    }
+    Ok(topology.save(path)?)
+}
+
+/// Performs max98390d boot time calibration on the sound card opened by `open_amp_card()`
+/// with the `RunOptions`. The calibration values in effect are published to CRAS afterwards, and
+/// the internal speaker node is marked as protected if the calibration succeeds, see the
+/// `cras_calib` module.
+///
+/// # Errors
+///
+/// If any amplifiers fail to complete the calibration.
+pub fn run_max98390d_with_card(
+    card: &mut Card,
+    snd_card: &str,
+    conf: &str,
+    opts: &RunOptions,
+) -> Result<()> {
+    let settings = DeviceSettings::from_yaml_str(conf)?;
+    let res = calibrate_card(card, snd_card, &settings, opts);
+    // The values in effect are published even if the calibration fails, so CRAS knows which
+    // amps run without the calibration values.
+    if !opts.dry_run {
+        if let Err(e) = cras_calib::publish(card, snd_card, &settings) {
+            error!("failed to publish the calibration values to CRAS: {}", e);
+        }
+        if let Err(e) = cras_calib::mark_speaker_protected(res.is_ok()) {
+            error!("failed to mark the speaker protection in CRAS: {}", e);
+        }
+    }
+    res
+}
+
+// Runs the boot time calibration of all the amps of the card.
+fn calibrate_card(
+    card: &mut Card,
+    snd_card: &str,
+    settings: &DeviceSettings,
+    opts: &RunOptions,
+) -> Result<()> {
+    info!(
+        "amp card: {}, driver: {}, components: {}",
+        card.name(),
+        card.info().driver,
+        card.info().components
+    );
+    // The speaker path must be enabled for the calibration. It's done before the allow-list
+    // is set since the speaker switch is not an amp control.
+    if let Some(elem) = &settings.speaker_mixer {
+        if opts.dry_run {
+            info!("dry run: skip enabling the playback of {}", elem);
+        } else {
+            SimpleMixer::new(card).enable_playback(elem)?;
+        }
+    }
+    // Only the amp controls in the config can be written during the calibration.
+    card.set_allow_list(&amp_controls(settings));
+    // The codecs may silently reject out-of-range calibration values.
+    card.set_verify_writes(true);
+    // Reports all the missing or mismatched amp controls at once before touching the amps.
+    validate_amp_controls(card, settings)?;
+
+    if !Path::new(&settings.dsm_param).exists() {
+        set_all_volume_low(card, snd_card, settings, opts);
+        return Err(Error::MissingDSMParam);
+    }
+
+    // Seeds the datastore from the vendor calibration files on the first time boot.
+    if !run_time::exists(snd_card) {
+        import_all_vendor_calib(snd_card, settings, opts);
+    }
+
+    // The measurement is deferred to a boot on a healthy power state, and the datastore values
+    // stay in effect.
+    if opts.low_power {
+        record_skip(snd_card, SkipReason::PowerState, opts);
+        run_all_hot_speaker_workflow(card, snd_card, settings, opts);
+        return Err(Error::PowerStateDeferred);
+    }
+
+    // Needs to check whether the speakers are over heated if it is not the first time boot.
+    if run_time::exists(snd_card) {
+        if let Err(err) = check_speaker_over_heated(
+            snd_card,
+            Duration::from_secs(settings.thresholds.cool_down_secs),
+        ) {
+            match err {
+                Error::HotSpeaker => {
+                    record_skip(snd_card, SkipReason::HotSpeaker, opts);
+                    run_all_hot_speaker_workflow(card, snd_card, settings, opts)
+                }
+                _ => {
+                    // We cannot assume the speakers are not replaced or not over heated
+                    // when the shutdown time file is invalid; therefore we can not use the
+                    // datastore value anymore and we can not trigger boot time calibration.
+                    record_skip(snd_card, SkipReason::InvalidShutdownTime, opts);
+                    del_all_datastore(snd_card, settings, opts);
+                    set_all_volume_low(card, snd_card, settings, opts);
+                }
+            };
+            return Err(err);
+        };
+    }
+
+    // Locks the calibration controls so that other mixer clients (alsactl restore, UCM) can't
+    // change them during the calibration.
+    let locked = lock_all_calib_controls(card, settings);
+
+    // If some error occurs during the calibration, the iteration will continue running the
+    // calibration for the next amp.
+    let mut staged = Vec::new();
+    let mut errors: Vec<Error> = match batch_measure(card, snd_card, settings, opts) {
+        Some(Err(e)) => {
+            error!("batch measurement error: {}. volume remains low.", e);
+            set_all_volume_low(card, snd_card, settings, opts);
+            vec![e]
+        }
+        measured => settings
+            .amp_calibrations
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                let values = measured
+                    .as_ref()
+                    .and_then(|m| m.as_ref().ok())
+                    .map(|v| v[i]);
+                let (result, res) =
+                    calibrate_amp(card, snd_card, settings, s, values, opts, &mut staged);
+                report_calib_result(snd_card, i, result, opts);
+                res
+            })
+            .enumerate()
+            .filter_map(|(i, res)| res.err().map(|e| Error::of_channel(e, i)))
+            .inspect(|e| error!("calibration error: {}. volume remains low.", e))
+            .collect(),
+    };
+
+    unlock_controls(card, &locked);
+
+    // The datastores of all the amps are written at once, so the storage is synced once per
+    // run instead of once per amp. The applied values stay in effect if the write fails.
+    if !staged.is_empty() {
+        let _phase = phases::start(snd_card, "datastore write");
+        if let Err(e) = Datastore::save_all(snd_card, &staged) {
+            error!("datastore write error: {}", e);
+            errors.push(e);
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(Error::CalibrationFailed(errors));
+    }
+
+    if let Some(gain_norm) = &settings.gain_normalization {
+        normalize_gain(card, snd_card, gain_norm, &settings.amp_calibrations, opts)?;
+    }
+
+    Ok(())
+}
+
+// Measures all the amps in one playback if the config enables `batch_measurement`, or returns
+// None to measure each amp by its own calibration.
+fn batch_measure(
+    card: &mut Card,
+    snd_card: &str,
+    settings: &DeviceSettings,
+    opts: &RunOptions,
+) -> Option<Result<Vec<(i32, i32)>>> {
+    if !settings.batch_measurement {
+        return None;
+    }
+    let mut measurement = Measurement::default();
+    let res = measure_all(card, snd_card, settings, opts, &mut measurement);
+    if !opts.dry_run {
+        // The failed measurement is the outcome of every amp.
+        if let Err(e) = &res {
+            for _ in &settings.amp_calibrations {
+                metrics::send_enum(
+                    CALIB_OUTCOME_METRIC,
+                    CalibOutcome::of_error(e) as i32,
+                    CalibOutcome::COUNT,
+                );
+            }
+        }
+        if let Some(health) = measurement.stream_health {
+            report_stream_health(&health);
+        }
+    }
+    Some(res)
+}
+
+// Calibrates the amp and returns the calibration values in effect. The volume stays low if the
+// calibration fails. The amp is measured by the calibration unless `measured` has its values.
+// The datastore to save for the amp is pushed to `staged`.
+fn calibrate_amp(
+    card: &mut Card,
+    snd_card: &str,
+    settings: &DeviceSettings,
+    setting: &AmpCalibSettings,
+    measured: Option<(i32, i32)>,
+    opts: &RunOptions,
+    staged: &mut Vec<(String, Datastore)>,
+) -> (CalibResult, Result<()>) {
+    let _span = utils::trace_span!("channel calibration", channel = %setting.amp.rdc_ctrl);
+    let mut amp_calib = match AmpCalibration::new(
+        card,
+        snd_card,
+        setting.clone(),
+        settings.thresholds_of(setting),
+        settings.excitation.clone(),
+        opts,
+    ) {
+        Ok(amp_calib) => amp_calib,
+        Err(e) => return (CalibResult::Failed, Err(e)),
+    };
+    if let Err(e) = amp_calib.set_volume(VolumeMode::Low) {
+        return (CalibResult::Failed, Err(e));
+    }
+    if let Some((rdc, temp)) = measured {
+        amp_calib.set_measured(rdc, temp);
+    }
+    let res = amp_calib.run();
+    if let Some(datastore) = amp_calib.take_staged() {
+        staged.push((setting.calib_file.clone(), datastore));
+    }
+    if !opts.dry_run {
+        metrics::send_enum(
+            CALIB_OUTCOME_METRIC,
+            CalibOutcome::of(&res) as i32,
+            CalibOutcome::COUNT,
+        );
+        // The rejected values are reported as well, so the distribution covers them.
+        if let (Some(telemetry), Some((rdc, temp))) = (&settings.telemetry, amp_calib.measured()) {
+            let (rdc, temp) = telemetry.buckets_of(rdc, temp);
+            metrics::send_sparse(CALIB_RDC_METRIC, rdc);
+            metrics::send_sparse(CALIB_TEMP_METRIC, temp);
+        }
+        if let Some(health) = amp_calib.stream_health() {
+            report_stream_health(&health);
+        }
+    }
+    let result = match res {
+        Ok(result) => result,
+        Err(e) => return (CalibResult::SafeFallback, Err(e)),
+    };
+    match amp_calib.set_volume(VolumeMode::High) {
+        Ok(()) => (result, Ok(())),
+        Err(e) => (CalibResult::Failed, Err(e)),
+    }
+}
+
+fn report_stream_health(health: &StreamHealth) {
+    metrics::send_histogram(
+        STREAM_START_LATENCY_METRIC,
+        health
+            .start_latency
+            .as_millis()
+            .min(STREAM_START_LATENCY_MAX_MS as u128) as i32,
+        1,
+        STREAM_START_LATENCY_MAX_MS,
+        HISTOGRAM_BUCKETS,
+    );
+    if let Some(underruns) = health.underruns {
+        metrics::send_histogram(
+            STREAM_UNDERRUNS_METRIC,
+            underruns.min(STREAM_UNDERRUNS_MAX as u32) as i32,
+            1,
+            STREAM_UNDERRUNS_MAX,
+            HISTOGRAM_BUCKETS,
+        );
+    }
+}
+
+fn report_calib_result(snd_card: &str, channel: usize, result: CalibResult, opts: &RunOptions) {
+    if !opts.dry_run {
+        metrics::send_enum(CALIB_RESULT_METRIC, result as i32, CalibResult::COUNT);
+        events::emit(
+            snd_card,
+            Event::ChannelResult {
+                channel,
+                result: result.name().to_owned(),
+            },
+        );
+    }
+}
+
+/// Keeps monitoring the speaker temperature on the sound card calibrated by
+/// `run_max98390d_with_card()`. The volume of a channel is put into protected mode while its
+/// speaker is hot, and the protection changes are emitted as the overheat events of
+/// `snd_card`. It returns immediately if the config has no `monitor` settings.
+///
+/// `reload` is called after every check, and returns the new config if it has changed. The
+/// invalid configs are logged and ignored.
+///
+/// # Errors
+///
+/// * If the config is invalid.
+/// * If any amp with `status_temp_ctrl` is missing.
+pub fn monitor_max98390d(
+    card: &mut Card,
+    snd_card: &str,
+    conf: &str,
+    opts: &RunOptions,
+    reload: &mut dyn FnMut() -> Option<String>,
+) -> Result<()> {
+    let settings = DeviceSettings::from_yaml_str(conf)?;
+    let monitor_settings = match &settings.monitor {
+        Some(monitor_settings) => monitor_settings,
+        None => {
+            info!("no monitor settings, skip runtime monitoring.");
+            return Ok(());
+        }
+    };
+    let mut monitor = Monitor::new(snd_card, monitor_settings, &settings.amp_calibrations, opts)?;
+    let mut interval_secs = monitor_settings.interval_secs;
+    loop {
+        monitor.check(card);
+        clock().sleep(Duration::from_secs(interval_secs));
+        if let Some(conf) = reload() {
+            match reload_monitor(&mut monitor, &conf) {
+                Ok(secs) => {
+                    info!("monitor settings reloaded");
+                    interval_secs = secs;
+                }
+                Err(e) => error!("keep the monitor settings, failed to reload: {}", e),
+            }
+        }
+    }
+}
+
+// Applies the monitor settings of `conf` and returns the new check interval.
+fn reload_monitor(monitor: &mut Monitor, conf: &str) -> Result<u64> {
+    let settings = DeviceSettings::from_yaml_str(conf)?;
+    let monitor_settings = settings
+        .monitor
+        .as_ref()
+        .ok_or(Error::MissingMonitorSettings)?;
+    monitor.reload(monitor_settings, &settings.amp_calibrations)?;
+    Ok(monitor_settings.interval_secs)
+}
+
+/// Performs the factory calibration. All the amps are calibrated and checked against
+/// `factory_limits`, and the values are written to the VPD only if all the amps pass. The
+/// datastore is removed so that the next boot time calibration starts over from the new VPD
+/// values. It's `factory_measure_max98390d()` followed by `factory_apply_max98390d()`.
+///
+/// # Results
+///
+/// * The calibration values of the amp channels as a JSON array.
+///
+/// # Errors
+///
+/// * If the config is invalid or any channel has no `factory_limits`.
+/// * If any amp fails the calibration or the factory limits.
+/// * If it fails to write the VPD.
+pub fn factory_calibrate_max98390d(snd_card: &str, conf: &str) -> Result<serde_json::Value> {
+    let settings = DeviceSettings::from_yaml_str(conf)?;
+    let values = factory_measure(snd_card, conf, &settings)?
+        .into_iter()
+        .enumerate()
+        .map(|(i, (_, res))| res.map_err(|e| e.of_channel(i)))
+        .collect::<Result<Vec<_>>>()?;
+    save_factory_values(snd_card, &settings, &values)?;
+    Ok(settings
+        .amp_calibrations
+        .iter()
+        .zip(&values)
+        .map(|(s, (rdc, ambient_temp))| {
+            serde_json::json!({
+                "rdc_ctrl": s.amp.rdc_ctrl,
+                "rdc": rdc,
+                "ambient_temp": ambient_temp,
+            })
+        })
+        .collect())
+}
+
+/// Runs the measurement of the factory calibration and checks the values against
+/// `factory_limits` without writing the VPD, so that the factory station can track the raw
+/// measurements and decide whether to apply them by `factory_apply_max98390d()`.
+///
+/// # Results
+///
+/// * The measurement of the amp channels as a JSON array. Each channel has the measured `rdc`
+///   and `ambient_temp`, which are null if the measurement fails, and `result` of PASS or FAIL
+///   with the `error` of a failed channel. The values out of the factory limits are kept.
+///
+/// # Errors
+///
+/// * If the config is invalid or any channel has no `factory_limits`.
+/// * If the amp controls mismatch the config.
+pub fn factory_measure_max98390d(snd_card: &str, conf: &str) -> Result<serde_json::Value> {
+    let settings = DeviceSettings::from_yaml_str(conf)?;
+    Ok(settings
+        .amp_calibrations
+        .iter()
+        .zip(factory_measure(snd_card, conf, &settings)?)
+        .map(|(s, (measured, res))| {
+            let (rdc, ambient_temp) = measured.unzip();
+            let mut channel = serde_json::json!({
+                "rdc_ctrl": s.amp.rdc_ctrl,
+                "rdc": rdc,
+                "ambient_temp": ambient_temp,
+                "result": if res.is_ok() { "PASS" } else { "FAIL" },
+            });
+            if let Err(e) = res {
+                channel["error"] = e.to_string().into();
+            }
+            channel
+        })
+        .collect())
+}
+
+/// Checks the rdc and ambient temperature values of the amp channels, ex: measured by
+/// `factory_measure_max98390d()`, against `factory_limits`, and writes them to the VPD. The
+/// datastore is removed so that the next boot time calibration starts over from the new VPD
+/// values.
+///
+/// # Errors
+///
+/// * If the config is invalid or any channel has no `factory_limits`.
+/// * If the number of the values mismatches the amp channels.
+/// * If any value is out of the factory limits.
+/// * If it fails to write the VPD.
+pub fn factory_apply_max98390d(snd_card: &str, conf: &str, values: &[(i32, i32)]) -> Result<()> {
+    let settings = DeviceSettings::from_yaml_str(conf)?;
+    let limits = factory_limits(&settings)?;
+    if values.len() != limits.len() {
+        return Err(Error::FactoryValuesMismatch(values.len(), limits.len()));
+    }
+    for (i, ((s, limits), (rdc, ambient_temp))) in settings
+        .amp_calibrations
+        .iter()
+        .zip(limits)
+        .zip(values)
+        .enumerate()
+    {
+        check_factory_calibration(&s.amp, limits, *rdc, *ambient_temp)
+            .map_err(|e| e.of_channel(i))?;
+    }
+    save_factory_values(snd_card, &settings, values)
+}
+
+fn factory_limits(settings: &DeviceSettings) -> Result<Vec<&FactoryLimits>> {
+    settings
+        .amp_calibrations
+        .iter()
+        .map(|s| settings.factory_limits_of(s))
+        .collect::<Option<Vec<_>>>()
+        .ok_or(Error::MissingFactoryLimits)
+}
+
+// Measures all the amp channels against the factory limits. Each channel has the measured
+// values, which are kept even if they are out of the limits, and the result of the channel.
+#[allow(clippy::type_complexity)]
+fn factory_measure(
+    snd_card: &str,
+    conf: &str,
+    settings: &DeviceSettings,
+) -> Result<Vec<(Option<(i32, i32)>, Result<(i32, i32)>)>> {
+    let limits = factory_limits(settings)?;
+    let mut card = open_amp_card(snd_card, conf)?;
+    card.set_allow_list(&amp_controls(settings));
+    validate_amp_controls(&mut card, settings)?;
+    if !Path::new(&settings.dsm_param).exists() {
+        return Err(Error::MissingDSMParam);
+    }
+
+    let opts = RunOptions::default();
+    let mut results = Vec::new();
+    for (s, limits) in settings.amp_calibrations.iter().zip(limits) {
+        let mut measured = None;
+        let res = AmpCalibration::new(
+            &mut card,
+            snd_card,
+            s.clone(),
+            settings.thresholds_of(s),
+            settings.excitation.clone(),
+            &opts,
+        )
+        .and_then(|mut amp_calib| {
+            amp_calib.set_volume(VolumeMode::Low)?;
+            let res = amp_calib.factory_calibrate(limits);
+            measured = amp_calib.measured();
+            res
+        });
+        results.push((measured, res));
+    }
+    Ok(results)
+}
+
+// Writes the factory calibration values of the amp channels to the VPD and removes the
+// datastore.
+fn save_factory_values(
+    snd_card: &str,
+    settings: &DeviceSettings,
+    values: &[(i32, i32)],
+) -> Result<()> {
+    for (s, (rdc, ambient_temp)) in settings.amp_calibrations.iter().zip(values) {
+        VPD {
+            dsm_calib_r0: *rdc,
+            dsm_calib_temp: *ambient_temp,
+        }
+        .save(&s.rdc_vpd, &s.temp_vpd)?;
+    }
+    for file in datastore_files(settings) {
+        remove_datastore(snd_card, file)?;
+    }
+    Ok(())
+}
+
+/// Puts the volume of all the amps into protected mode. It's used to restore a safe state when
+/// sound_card_init is aborted, so it keeps going if an amp fails.
+///
+/// # Errors
+///
+/// * If the config is invalid.
+/// * If the volume of any amp can't be set.
+pub fn set_safe_state_max98390d(card: &mut Card, conf: &str) -> Result<()> {
+    let settings = DeviceSettings::from_yaml_str(conf)?;
+    let mut res = Ok(());
+    for (i, s) in settings.amp_calibrations.iter().enumerate() {
+        if let Err(e) = set_volume_low(card, s) {
+            error!("failed to set {} low: {}.", s.amp.volume_ctrl, e);
+            res = Err(e.of_channel(i));
+        }
+    }
+    res
+}
+
+fn set_volume_low(card: &mut Card, setting: &AmpCalibSettings) -> Result<()> {
+    card.control_by_name::<IntControl>(&setting.amp.volume_ctrl)?
+        .set(setting.amp.volume_low_limit)?;
+    Ok(())
+}
+
+/// Validates the config and the amp controls of the sound card without touching the amps.
+///
+/// # Errors
+///
+/// * If the config is invalid.
+/// * If any amp control is missing or mismatches the config.
+/// * If dsm_param does not exist.
+pub fn validate_max98390d(snd_card: &str, conf: &str) -> Result<()> {
+    let settings = DeviceSettings::from_yaml_str(conf)?;
+    let mut card = open_amp_card(snd_card, conf)?;
+    validate_amp_controls(&mut card, &settings)?;
+    if !Path::new(&settings.dsm_param).exists() {
+        return Err(Error::MissingDSMParam);
+    }
+    Ok(())
+}
+
+/// Reports the current, stored and factory calibration values of each amp channel.
+///
+/// # Errors
+///
+/// * If the config is invalid.
+/// * If the sound card can't be opened.
+pub fn show_max98390d(snd_card: &str, conf: &str) -> Result<String> {
+    let settings = DeviceSettings::from_yaml_str(conf)?;
+    let mut card = open_amp_card(snd_card, conf)?;
+    Ok(ChannelStatus::collect(&mut card, snd_card, &settings)
+        .iter()
+        .map(|status| status.to_string())
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// Reports the speaker temperature and the volume protection state of each amp channel on the
+/// sound card opened by `open_amp_card()`. It's cheap enough to be called every second.
+///
+/// # Errors
+///
+/// * If the config is invalid.
+pub fn live_status_max98390d(card: &mut Card, conf: &str) -> Result<String> {
+    let settings = DeviceSettings::from_yaml_str(conf)?;
+    Ok(LiveStatus::collect(card, &settings.amp_calibrations)
+        .iter()
+        .map(|status| status.to_string())
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// Reports the state returned by `show_max98390d()` as a JSON array of the amp channels.
+///
+/// # Errors
+///
+/// * If the config is invalid.
+/// * If the sound card can't be opened.
+pub fn show_max98390d_json(snd_card: &str, conf: &str) -> Result<serde_json::Value> {
+    let mut card = open_amp_card(snd_card, conf)?;
+    snapshot_max98390d(&mut card, snd_card, conf)
+}
+
+/// Returns the calibration state of all the amp channels as a JSON array on the sound card
+/// opened by `open_amp_card()`, which snapshots the amp controls and the datastore.
+///
+/// # Errors
+///
+/// * If the config is invalid.
+pub fn snapshot_max98390d(
+    card: &mut Card,
+    snd_card: &str,
+    conf: &str,
+) -> Result<serde_json::Value> {
+    let settings = DeviceSettings::from_yaml_str(conf)?;
+    Ok(ChannelStatus::collect(card, snd_card, &settings)
+        .iter()
+        .map(|status| status.to_json())
+        .collect())
+}
+
+/// Removes the stored calibration values of the channel, or of all the channels if `channel`
+/// is None, so the next boot time calibration of the channels starts over from the VPD
+/// values. The gain offsets depend on all the channels and are always removed.
+///
+/// # Errors
+///
+/// * If the config is invalid.
+/// * If the channel does not exist.
+/// * If it fails to remove any datastore.
+pub fn reset_max98390d(snd_card: &str, conf: &str, channel: Option<usize>) -> Result<()> {
+    let settings = DeviceSettings::from_yaml_str(conf)?;
+    let files = match channel {
+        None => datastore_files(&settings),
+        Some(channel) => {
+            let s = settings
+                .amp_calibrations
+                .get(channel)
+                .ok_or_else(|| Error::InvalidChannel(channel, settings.amp_calibrations.len()))?;
+            let mut files = vec![s.calib_file.as_str()];
+            files.extend(
+                settings
+                    .gain_normalization
+                    .as_ref()
+                    .map(|gain_norm| gain_norm.offset_file.as_str()),
+            );
+            files
+        }
+    };
+    for file in files {
+        remove_datastore(snd_card, file)?;
+        info!("removed datastore {}", file);
+    }
+    Ok(())
+}
+
+/// Checks that the calibration can run without touching the amps or the datastore. It opens
+/// the sound card, validates and reads the amp controls, and reads the VPD values and the
+/// datastore directory. It returns the checks as a JSON array, whose `result` is PASS or FAIL.
+///
+/// # Errors
+///
+/// * If the config is invalid.
+pub fn self_test_max98390d(snd_card: &str, conf: &str) -> Result<serde_json::Value> {
+    let settings = DeviceSettings::from_yaml_str(conf)?;
+    let mut checks = Vec::new();
+    match open_amp_card(snd_card, conf) {
+        Ok(mut card) => {
+            checks.push(self_test_check("open card", None, || Ok(())));
+            checks.push(self_test_check("amp controls", None, || {
+                validate_amp_controls(&mut card, &settings)?;
+                card.load_controls::<[i32; 1]>(&amp_int_controls(&settings))?;
+                Ok(())
+            }));
+        }
+        Err(e) => checks.push(self_test_check("open card", None, || Err(e))),
+    }
+    checks.push(self_test_check("dsm_param", None, || {
+        if !Path::new(&settings.dsm_param).exists() {
+            return Err(Error::MissingDSMParam);
+        }
+        Ok(())
+    }));
+    for (i, s) in settings.amp_calibrations.iter().enumerate() {
+        checks.push(self_test_check("vpd", Some(i), || {
+            VPD::from_file(&s.rdc_vpd, &s.temp_vpd)?;
+            Ok(())
+        }));
+    }
+    checks.push(self_test_check("datastore", None, || {
+        let dir = datastore_dir(snd_card);
+        let io_err = |e| Error::FileIOFailed(dir.to_string_lossy().to_string(), e);
+        fs::read_dir(&dir).map_err(io_err)?;
+        if fs::metadata(&dir).map_err(io_err)?.permissions().readonly() {
+            return Err(Error::FileIOFailed(
+                dir.to_string_lossy().to_string(),
+                io::Error::from(io::ErrorKind::PermissionDenied),
+            ));
+        }
+        Ok(())
+    }));
+    Ok(serde_json::Value::Array(checks))
+}
+
+/// Checks the health of the speakers on demand, which is the backend of the speaker check
+/// routine of cros_healthd. It runs the checks of `self_test_max98390d()`, and checks the live
+/// readouts of each amp channel: the runtime controls can be read, and the speaker is cooler
+/// than the `hot_temp` of the monitor settings. It returns the checks and the live readouts of
+/// the channels in JSON.
+///
+/// # Errors
+///
+/// * If the config is invalid.
+pub fn speaker_check_max98390d(snd_card: &str, conf: &str) -> Result<serde_json::Value> {
+    let settings = DeviceSettings::from_yaml_str(conf)?;
+    let mut checks = match self_test_max98390d(snd_card, conf)? {
+        serde_json::Value::Array(checks) => checks,
+        _ => Vec::new(),
+    };
+    // The failure to open the card is already a failed check of the self test.
+    let mut card = match open_amp_card(snd_card, conf) {
+        Ok(card) => card,
+        Err(_) => return Ok(serde_json::json!({ "checks": checks, "live": [] })),
+    };
+    let live = LiveStatus::collect(&mut card, &settings.amp_calibrations);
+    for (i, (s, status)) in settings.amp_calibrations.iter().zip(&live).enumerate() {
+        checks.push(self_test_check("live readout", Some(i), || {
+            let mut ctrls = vec![s.amp.volume_ctrl.as_str()];
+            ctrls.extend(s.amp.status_temp_ctrl.as_deref());
+            card.load_controls::<[i32; 1]>(&ctrls)?;
+            Ok(())
+        }));
+        if let (Some(monitor), Some(temp)) = (&settings.monitor, status.temp) {
+            checks.push(self_test_check("speaker temperature", Some(i), || {
+                if temp >= monitor.hot_temp {
+                    return Err(Error::SpeakerOverheated(temp, monitor.hot_temp));
+                }
+                Ok(())
+            }));
+        }
+    }
+    Ok(serde_json::json!({
+        "checks": checks,
+        "live": live.iter().map(LiveStatus::to_json).collect::<Vec<_>>(),
+    }))
+}
+
+// Runs a check of the self test and returns its record.
+fn self_test_check(
+    name: &str,
+    channel: Option<usize>,
+    check: impl FnOnce() -> Result<()>,
+) -> serde_json::Value {
+    let mut record = serde_json::json!({ "check": name });
+    if let Some(channel) = channel {
+        record["channel"] = channel.into();
+    }
+    match check() {
+        Ok(()) => record["result"] = "PASS".into(),
+        Err(e) => {
+            record["result"] = "FAIL".into();
+            record["error"] = e.to_string().into();
+            record["error_code"] = e.code().into();
+        }
+    }
+    record
+}
+
+// Returns the integer amp controls read by the self test.
+fn amp_int_controls(settings: &DeviceSettings) -> Vec<&str> {
+    settings
+        .amp_calibrations
+        .iter()
+        .flat_map(|s| [s.amp.rdc_ctrl.as_str(), s.amp.ambient_temp_ctrl.as_str()])
+        .collect()
+}
+
+fn amp_controls(settings: &DeviceSettings) -> Vec<&str> {
+    let mut controls = Vec::new();
+    for s in &settings.amp_calibrations {
+        controls.extend_from_slice(&[
+            s.amp.rdc_ctrl.as_str(),
+            s.amp.ambient_temp_ctrl.as_str(),
+            s.amp.calib_ctrl.as_str(),
+            s.amp.volume_ctrl.as_str(),
+        ]);
+        controls.extend(s.amp.gain_ctrl.as_deref());
+        controls.extend(s.amp.status_temp_ctrl.as_deref());
+    }
+    controls
+}
+
+fn validate_amp_controls(card: &mut Card, settings: &DeviceSettings) -> Result<()> {
+    let mut builder = ControlSetBuilder::new(card);
+    for s in &settings.amp_calibrations {
+        builder.control::<[i32; 1]>(&s.amp.rdc_ctrl, Access::ReadWrite);
+        builder.control::<[i32; 1]>(&s.amp.ambient_temp_ctrl, Access::ReadWrite);
+        builder.control::<[bool; 1]>(&s.amp.calib_ctrl, Access::ReadWrite);
+        builder.control::<[i32; 1]>(&s.amp.volume_ctrl, Access::ReadWrite);
+        if let Some(gain_ctrl) = &s.amp.gain_ctrl {
+            builder.control::<[i32; 1]>(gain_ctrl, Access::ReadWrite);
+        }
+        if let Some(status_temp_ctrl) = &s.amp.status_temp_ctrl {
+            builder.control::<[i32; 1]>(status_temp_ctrl, Access::ReadOnly);
+        }
+    }
+    Ok(builder.build()?)
+}
+
+// The locks are best-effort. The calibration still runs if a control can't be locked.
+// Returns the names of the locked controls.
+fn lock_all_calib_controls(card: &mut Card, settings: &DeviceSettings) -> Vec<String> {
+    let mut locked = Vec::new();
+    for s in &settings.amp_calibrations {
+        for ctrl in &[&s.amp.rdc_ctrl, &s.amp.ambient_temp_ctrl, &s.amp.calib_ctrl] {
+            match card.lock_control(ctrl) {
+                Ok(()) => locked.push((*ctrl).clone()),
+                Err(e) => error!("failed to lock {}: {}.", ctrl, e),
+            }
+        }
+    }
+    locked
+}
+
+fn unlock_controls(card: &mut Card, controls: &[String]) {
+    for ctrl in controls {
+        if let Err(e) = card.unlock_control(ctrl) {
+            error!("failed to unlock {}: {}.", ctrl, e);
+        }
+    }
+}
+
+fn del_all_datastore(snd_card: &str, settings: &DeviceSettings, opts: &RunOptions) {
+    for file in datastore_files(settings) {
+        if opts.dry_run {
+            info!("dry run: skip removing datastore {}", file);
+            continue;
+        }
+        if let Err(e) = remove_datastore(snd_card, file) {
+            error!("failed to remove datastore: {}.", e);
+        }
+    }
+}
+
+// Returns the datastore files of the calibration values and the gain offsets.
+fn datastore_files(settings: &DeviceSettings) -> Vec<&str> {
+    let mut files: Vec<&str> = settings
+        .amp_calibrations
+        .iter()
+        .map(|s| s.calib_file.as_str())
+        .collect();
+    files.extend(
+        settings
+            .gain_normalization
+            .as_ref()
+            .map(|gain_norm| gain_norm.offset_file.as_str()),
+    );
+    files
+}
+
+// Removes the datastore file. It's not an error if the file does not exist.
+fn remove_datastore(snd_card: &str, file: &str) -> Result<()> {
+    let path = datastore_dir(snd_card).join(file);
+    match fs::remove_file(&path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            Err(Error::FileIOFailed(path.to_string_lossy().to_string(), e))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn import_all_vendor_calib(snd_card: &str, settings: &DeviceSettings, opts: &RunOptions) {
+    for s in &settings.amp_calibrations {
+        let vendor_calib_file = match &s.vendor_calib_file {
+            Some(file) => file,
+            None => continue,
+        };
+        // Never overrides an existing datastore.
+        if Datastore::from_file(snd_card, &s.calib_file).is_ok() {
+            continue;
+        }
+        let res = VendorCalib::from_file(vendor_calib_file).and_then(|calib| {
+            let datastore = Datastore::from(calib);
+            if opts.dry_run {
+                info!(
+                    "dry run: skip importing {:?} to {}",
+                    datastore, s.calib_file
+                );
+                return Ok(());
+            }
+            datastore.save(snd_card, &s.calib_file)
+        });
+        if let Err(e) = res {
+            error!("failed to import vendor calibration: {}.", e);
+        }
+    }
+}
+
+fn run_all_hot_speaker_workflow(
+    card: &mut Card,
+    snd_card: &str,
+    settings: &DeviceSettings,
+    opts: &RunOptions,
+) {
+    for (i, s) in settings.amp_calibrations.iter().enumerate() {
+        let mut amp_calib = match AmpCalibration::new(
+            card,
+            snd_card,
+            s.clone(),
+            settings.thresholds_of(s),
+            settings.excitation.clone(),
+            opts,
+        ) {
+            Ok(amp) => amp,
+            Err(e) => {
+                error!("{}.", e.of_channel(i));
+                report_calib_result(snd_card, i, CalibResult::Failed, opts);
+                continue;
+            }
+        };
+        match amp_calib.hot_speaker_workflow() {
+            Ok(result) => report_calib_result(snd_card, i, result, opts),
+            Err(e) => {
+                error!("failed to run hot_speaker_workflow: {}.", e.of_channel(i));
+                report_calib_result(snd_card, i, CalibResult::Failed, opts);
+            }
+        }
+    }
+    if let Some(gain_norm) = &settings.gain_normalization {
+        if let Err(e) =
+            apply_stored_gain_offsets(card, snd_card, gain_norm, &settings.amp_calibrations, opts)
+        {
+            error!("failed to apply gain offsets: {}.", e);
+        }
+    }
+}
+
+fn set_all_volume_low(
+    card: &mut Card,
+    snd_card: &str,
+    settings: &DeviceSettings,
+    opts: &RunOptions,
+) {
+    for (i, s) in settings.amp_calibrations.iter().enumerate() {
+        let mut amp_calib = match AmpCalibration::new(
+            card,
+            snd_card,
+            s.clone(),
+            settings.thresholds_of(s),
+            settings.excitation.clone(),
+            opts,
+        ) {
+            Ok(amp) => amp,
+            Err(e) => {
+                error!("{}.", e.of_channel(i));
+                report_calib_result(snd_card, i, CalibResult::Failed, opts);
+                continue;
+            }
+        };
+        match amp_calib.set_volume(VolumeMode::Low) {
+            Ok(()) => report_calib_result(snd_card, i, CalibResult::SafeFallback, opts),
+            Err(e) => {
+                error!("failed to set volume to low: {}.", e.of_channel(i));
+                report_calib_result(snd_card, i, CalibResult::Failed, opts);
+            }
+        }
+    }
+}
+
+// Counts the skip of the boot time calibration unless it's a dry run.
+fn record_skip(snd_card: &str, reason: SkipReason, opts: &RunOptions) {
+    if opts.dry_run {
+        return;
+    }
+    if let Err(e) = skips::record(snd_card, reason) {
+        error!("failed to record the calibration skip {}: {}", reason, e);
+    }
+}
+
+// If (Current time - the latest CRAS shutdown time) < cool_down_time, we assume that
+// the speakers may be over heated.
+fn check_speaker_over_heated(snd_card: &str, cool_down_time: Duration) -> Result<()> {
+    let last_run = run_time::from_file(snd_card).map_err(Error::ReadTimestampFailed)?;
+    let last_shutdown = shutdown_time::from_file().map_err(Error::ReadTimestampFailed)?;
+    let now = clock().now().map_err(Error::SystemTimeError)?;
+    check_cool_down(last_run, last_shutdown, now, cool_down_time)
+}
@@ -5,73 +5,454 @@ use std::error;
 use std::fmt;
 use std::io;
 use std::num::ParseIntError;
-use std::sync::PoisonError;
 use std::time;
 
 use remain::sorted;
+use serde::{Serialize, Serializer};
+use utils::error::ErrorReport;
+use utils::ExitCode;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+// The error of the CRAS streams, which is `libcras::BoxError`.
+pub type BoxError = Box<dyn error::Error + Send + Sync>;
+
 #[sorted]
 #[derive(Debug)]
 pub enum Error {
+    #[cfg(feature = "hardware")]
     AlsaCardError(cros_alsa::CardError),
+    #[cfg(feature = "hardware")]
     AlsaControlError(cros_alsa::ControlError),
-    CalibrationFailed,
+    CalibrationFailed(Vec<Error>),
     CalibrationTimeout,
+    Channel(usize, Box<Error>),
+    CorruptDatastore(String, serde_yaml::Error),
+    #[cfg(feature = "hardware")]
     CrasClientFailed(libcras::Error),
+    CrasConnectRefused(io::Error),
+    CrasProtocolMismatch(io::Error),
+    CrasSocketMissing(io::Error),
+    CrasTimeout(io::Error),
     DeserializationFailed(String, serde_yaml::Error),
+    FactoryValuesMismatch(usize, usize),
     FileIOFailed(String, io::Error),
     HotSpeaker,
+    InjectedFault(String),
     InternalSpeakerNotFound,
+    InvalidChannel(usize, usize),
     InvalidDatastore,
+    InvalidExcitation(String),
+    InvalidMonitorSettings,
+    InvalidRdc(i32),
     InvalidShutDownTime,
     InvalidTemperature(i32),
+    InvalidThresholds(String),
+    InvalidVendorCalib(String),
     LargeCalibrationDiff(i32, i32),
     MissingDSMParam,
-    MutexPoisonError,
-    NewPlayStreamFailed(libcras::BoxError),
-    NextPlaybackBufferFailed(libcras::BoxError),
+    MissingFactoryLimits,
+    MissingGainControl(String),
+    MissingMonitorSettings,
+    MissingStatusTempControl(String),
+    NewPlayStreamFailed(BoxError),
+    NextPlaybackBufferFailed(BoxError),
     PlaybackFailed(io::Error),
+    PowerStateDeferred,
+    RdcOutOfFactoryLimits(i32, i32, i32),
     ReadTimestampFailed(utils::error::Error),
     SerializationFailed(serde_yaml::Error),
+    SpawnWorkerFailed(io::Error),
+    SpeakerOverheated(i32, i32),
     StartPlaybackTimeout,
     SystemTimeError(time::SystemTimeError),
+    TemperatureOutOfLimits(i32),
+    #[cfg(feature = "hardware")]
+    TopologyFailed(cros_alsa::TopologyError),
+    UnsupportedConfigVersion(u32),
+    VendorCalibParseFailed(String, ParseIntError),
     VPDParseFailed(String, ParseIntError),
-    WorkerPanics,
+    VPDWriteFailed(String),
+    WorkerPanicked {
+        message: String,
+        backtrace: String,
+    },
 }
 
+#[cfg(feature = "hardware")]
 impl From<cros_alsa::CardError> for Error {
     fn from(err: cros_alsa::CardError) -> Error {
         Error::AlsaCardError(err)
     }
 }
 
+#[cfg(feature = "hardware")]
 impl From<cros_alsa::ControlError> for Error {
     fn from(err: cros_alsa::ControlError) -> Error {
         Error::AlsaControlError(err)
     }
 }
 
-impl<T> From<PoisonError<T>> for Error {
-    fn from(_: PoisonError<T>) -> Error {
-        Error::MutexPoisonError
+#[cfg(feature = "hardware")]
+impl From<cros_alsa::TopologyError> for Error {
+    fn from(err: cros_alsa::TopologyError) -> Error {
+        Error::TopologyFailed(err)
+    }
+}
+
+// The connection errors are split by the cause, so that CRAS starting late at boot can be told
+// apart from a broken CRAS.
+#[cfg(feature = "hardware")]
+impl From<libcras::Error> for Error {
+    fn from(err: libcras::Error) -> Error {
+        match err {
+            libcras::Error::IoError(e) => match e.kind() {
+                io::ErrorKind::NotFound => Error::CrasSocketMissing(e),
+                io::ErrorKind::ConnectionRefused => Error::CrasConnectRefused(e),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => Error::CrasTimeout(e),
+                // The server state of a different CRAS version, see `CrasServerState::try_new`.
+                io::ErrorKind::InvalidData => Error::CrasProtocolMismatch(e),
+                _ => Error::CrasClientFailed(libcras::Error::IoError(e)),
+            },
+            _ => Error::CrasClientFailed(err),
+        }
+    }
+}
+
+impl Error {
+    /// Returns the `ExitCode` of the error category. The code of a failed calibration is the
+    /// code of the error of the first failed amp.
+    pub fn exit_code(&self) -> ExitCode {
+        use Error::*;
+        match self {
+            #[cfg(feature = "hardware")]
+            AlsaCardError(cros_alsa::CardError::CardNotFound(_)) => ExitCode::SoundCardUnavailable,
+            #[cfg(feature = "hardware")]
+            AlsaCardError(cros_alsa::CardError::InvalidControlSet(_)) => ExitCode::InvalidConfig,
+            CalibrationFailed(errors) => {
+                errors.first().map_or(ExitCode::Failure, |e| e.exit_code())
+            }
+            Channel(_, e) => e.exit_code(),
+            CorruptDatastore(_, _) | InvalidDatastore => ExitCode::CorruptDatastore,
+            #[cfg(feature = "hardware")]
+            CrasClientFailed(_) => ExitCode::CrasUnavailable,
+            CrasConnectRefused(_)
+            | CrasProtocolMismatch(_)
+            | CrasSocketMissing(_)
+            | CrasTimeout(_)
+            | InternalSpeakerNotFound
+            | NewPlayStreamFailed(_)
+            | NextPlaybackBufferFailed(_) => ExitCode::CrasUnavailable,
+            FactoryValuesMismatch(_, _) | InvalidChannel(_, _) => ExitCode::InvalidArgs,
+            DeserializationFailed(_, _)
+            | InvalidExcitation(_)
+            | InvalidMonitorSettings
+            | InvalidThresholds(_)
+            | MissingDSMParam
+            | MissingFactoryLimits
+            | MissingGainControl(_)
+            | MissingMonitorSettings
+            | MissingStatusTempControl(_)
+            | UnsupportedConfigVersion(_) => ExitCode::InvalidConfig,
+            InvalidRdc(_)
+            | InvalidTemperature(_)
+            | LargeCalibrationDiff(_, _)
+            | RdcOutOfFactoryLimits(_, _, _)
+            | TemperatureOutOfLimits(_) => ExitCode::CalibrationRejected,
+            _ => ExitCode::Failure,
+        }
+    }
+}
+
+impl Error {
+    /// Returns the stable code of the error, which identifies the failure mode in the logs,
+    /// the last run outcome and the UMA metrics. The max98390d errors use 200 to 299. The
+    /// codes are persisted and reported, so they must not be renumbered or reused, and new
+    /// errors take the next free code. The code of a failed calibration is the code of the
+    /// error of the first failed amp.
+    pub fn code(&self) -> u32 {
+        use Error::*;
+        match self {
+            #[cfg(feature = "hardware")]
+            AlsaCardError(_) => 200,
+            #[cfg(feature = "hardware")]
+            AlsaControlError(_) => 201,
+            CalibrationFailed(errors) => errors.first().map_or(202, |e| e.code()),
+            CalibrationTimeout => 203,
+            Channel(_, e) => e.code(),
+            CorruptDatastore(_, _) => 204,
+            #[cfg(feature = "hardware")]
+            CrasClientFailed(_) => 205,
+            DeserializationFailed(_, _) => 206,
+            FileIOFailed(_, _) => 207,
+            HotSpeaker => 208,
+            InternalSpeakerNotFound => 209,
+            InvalidChannel(_, _) => 210,
+            InvalidDatastore => 211,
+            InvalidExcitation(_) => 212,
+            InvalidMonitorSettings => 213,
+            InvalidRdc(_) => 214,
+            InvalidShutDownTime => 215,
+            InvalidTemperature(_) => 216,
+            InvalidThresholds(_) => 217,
+            InvalidVendorCalib(_) => 218,
+            LargeCalibrationDiff(_, _) => 219,
+            MissingDSMParam => 220,
+            MissingFactoryLimits => 221,
+            MissingGainControl(_) => 222,
+            MissingMonitorSettings => 223,
+            MissingStatusTempControl(_) => 224,
+            // 225 was MutexPoisonError, which is removed.
+            NewPlayStreamFailed(_) => 226,
+            NextPlaybackBufferFailed(_) => 227,
+            PlaybackFailed(_) => 228,
+            RdcOutOfFactoryLimits(_, _, _) => 229,
+            ReadTimestampFailed(_) => 230,
+            SerializationFailed(_) => 231,
+            StartPlaybackTimeout => 232,
+            SystemTimeError(_) => 233,
+            TemperatureOutOfLimits(_) => 234,
+            UnsupportedConfigVersion(_) => 235,
+            VendorCalibParseFailed(_, _) => 236,
+            VPDParseFailed(_, _) => 237,
+            VPDWriteFailed(_) => 238,
+            WorkerPanicked { .. } => 239,
+            SpawnWorkerFailed(_) => 240,
+            CrasConnectRefused(_) => 241,
+            CrasProtocolMismatch(_) => 242,
+            CrasSocketMissing(_) => 243,
+            CrasTimeout(_) => 244,
+            InjectedFault(_) => 245,
+            #[cfg(feature = "hardware")]
+            TopologyFailed(_) => 246,
+            PowerStateDeferred => 247,
+            SpeakerOverheated(_, _) => 248,
+            FactoryValuesMismatch(_, _) => 249,
+        }
     }
 }
 
-impl error::Error for Error {}
+impl Error {
+    /// Returns true if the error may go away by retrying, ex: CRAS is not up yet or the sound
+    /// card is being re-enumerated. The errors of the config, the datastore and the
+    /// calibration values are permanent, and so are a mismatched or broken CRAS. A failed
+    /// calibration is transient only if every amp failed for a transient reason.
+    pub fn is_transient(&self) -> bool {
+        use Error::*;
+        match self {
+            #[cfg(feature = "hardware")]
+            AlsaCardError(e) => e.is_enodev(),
+            #[cfg(feature = "hardware")]
+            AlsaControlError(e) => e.is_enodev(),
+            CalibrationFailed(errors) => {
+                !errors.is_empty() && errors.iter().all(Error::is_transient)
+            }
+            Channel(_, e) => e.is_transient(),
+            CalibrationTimeout
+            | CrasConnectRefused(_)
+            | CrasSocketMissing(_)
+            | CrasTimeout(_)
+            | InternalSpeakerNotFound
+            | NewPlayStreamFailed(_)
+            | NextPlaybackBufferFailed(_)
+            | PlaybackFailed(_)
+            | StartPlaybackTimeout => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the D-Bus error name of the error, which the IPC callers match on instead of the
+    /// message, ex: `org.chromium.SoundCardInit.Error.CrasUnavailable`. The names are part of
+    /// the IPC interface and must not be renamed. The name of a failed calibration is the name
+    /// of the error of the first failed amp.
+    pub fn dbus_error_name(&self) -> &'static str {
+        use Error::*;
+        match self {
+            CalibrationFailed(errors) => errors
+                .first()
+                .map_or("org.chromium.SoundCardInit.Error.Failed", |e| {
+                    e.dbus_error_name()
+                }),
+            Channel(_, e) => e.dbus_error_name(),
+            #[cfg(feature = "hardware")]
+            AlsaCardError(cros_alsa::CardError::CardNotFound(_)) => {
+                "org.chromium.SoundCardInit.Error.SoundCardUnavailable"
+            }
+            CalibrationTimeout | StartPlaybackTimeout | CrasTimeout(_) => {
+                "org.chromium.SoundCardInit.Error.Timeout"
+            }
+            #[cfg(feature = "hardware")]
+            CrasClientFailed(_) => "org.chromium.SoundCardInit.Error.CrasUnavailable",
+            CrasConnectRefused(_)
+            | CrasSocketMissing(_)
+            | InternalSpeakerNotFound
+            | NewPlayStreamFailed(_)
+            | NextPlaybackBufferFailed(_) => "org.chromium.SoundCardInit.Error.CrasUnavailable",
+            CrasProtocolMismatch(_) => "org.chromium.SoundCardInit.Error.CrasProtocolMismatch",
+            CorruptDatastore(_, _) | InvalidDatastore => {
+                "org.chromium.SoundCardInit.Error.CorruptDatastore"
+            }
+            HotSpeaker => "org.chromium.SoundCardInit.Error.HotSpeaker",
+            PowerStateDeferred => "org.chromium.SoundCardInit.Error.PowerState",
+            FactoryValuesMismatch(_, _) | InvalidChannel(_, _) => {
+                "org.chromium.SoundCardInit.Error.InvalidArgument"
+            }
+            InvalidRdc(_)
+            | InvalidTemperature(_)
+            | LargeCalibrationDiff(_, _)
+            | RdcOutOfFactoryLimits(_, _, _)
+            | TemperatureOutOfLimits(_) => "org.chromium.SoundCardInit.Error.CalibrationRejected",
+            MissingDSMParam => "org.chromium.SoundCardInit.Error.MissingDsmParam",
+            VPDParseFailed(_, _) | VPDWriteFailed(_) => "org.chromium.SoundCardInit.Error.Vpd",
+            _ if self.exit_code() == ExitCode::InvalidConfig => {
+                "org.chromium.SoundCardInit.Error.InvalidConfig"
+            }
+            _ => "org.chromium.SoundCardInit.Error.Failed",
+        }
+    }
+
+    /// Returns the actionable remediation of the error for the user, or None if there is
+    /// nothing to do but report it. The hint of a failed calibration is the hint of the error
+    /// of the first failed amp.
+    pub fn hint(&self) -> Option<&'static str> {
+        use Error::*;
+        match self {
+            #[cfg(feature = "hardware")]
+            AlsaCardError(cros_alsa::CardError::CardNotFound(_)) => {
+                Some("check that the sound card driver is loaded")
+            }
+            CalibrationFailed(errors) => errors.first().and_then(Error::hint),
+            Channel(_, e) => e.hint(),
+            CorruptDatastore(_, _) | InvalidDatastore => {
+                Some("run `sound_card_init reset --all` to remove the corrupt datastore")
+            }
+            CrasConnectRefused(_) | CrasSocketMissing(_) => {
+                Some("cras is not running, check `status cras`")
+            }
+            CrasProtocolMismatch(_) => {
+                Some("sound_card_init and cras are from different builds, update the image")
+            }
+            HotSpeaker => Some("the speakers were used recently, reboot after they cool down"),
+            InternalSpeakerNotFound => Some("check the internal speaker node in the ucm config"),
+            LargeCalibrationDiff(_, _) => Some(concat!(
+                "run `sound_card_init reset --all` after a speaker replacement to calibrate from ",
+                "the vpd values again",
+            )),
+            MissingDSMParam => Some("check dsm_param.bin in the firmware package"),
+            MissingFactoryLimits => Some("add factory_limits to the config"),
+            PowerStateDeferred => Some("reboot on ac power after the device cools down"),
+            SpeakerOverheated(_, _) => Some("let the speakers cool down and check again"),
+            RdcOutOfFactoryLimits(_, _, _) => {
+                Some("check the speaker connection, the speaker may be damaged or unplugged")
+            }
+            VPDParseFailed(_, _) => {
+                Some("run `sound_card_init factory-calibrate` to write the vpd values")
+            }
+            _ if self.exit_code() == ExitCode::InvalidConfig => {
+                Some("run `sound_card_init --check-config` on the config directory")
+            }
+            _ => None,
+        }
+    }
+
+    /// Attaches the index of the amp channel in the config to the error.
+    pub fn of_channel(self, channel: usize) -> Error {
+        Error::Channel(channel, Box::new(self))
+    }
+
+    /// Returns the structured form of the error. The channel is moved into the context of the
+    /// error of the channel, and a failed calibration has the errors of all the failed amps.
+    pub fn report(&self) -> ErrorReport {
+        use Error::*;
+        match self {
+            Channel(channel, e) => {
+                let mut report = e.report();
+                report.context.channel = Some(*channel);
+                report
+            }
+            _ => {
+                let mut report = ErrorReport::new(self, self.code());
+                report.hint = self.hint().map(str::to_owned);
+                if let CalibrationFailed(errors) = self {
+                    report.errors = errors.iter().map(Error::report).collect();
+                }
+                report
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    // The wrapped errors are the sources, so that the callers can walk the causes. The first
+    // failure is the source of `CalibrationFailed`.
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        use Error::*;
+        match self {
+            #[cfg(feature = "hardware")]
+            AlsaCardError(e) => Some(e),
+            #[cfg(feature = "hardware")]
+            AlsaControlError(e) => Some(e),
+            CalibrationFailed(errors) => errors.first().map(|e| e as &(dyn error::Error + 'static)),
+            Channel(_, e) => Some(e.as_ref()),
+            CorruptDatastore(_, e) | DeserializationFailed(_, e) | SerializationFailed(e) => {
+                Some(e)
+            }
+            #[cfg(feature = "hardware")]
+            CrasClientFailed(e) => Some(e),
+            CrasConnectRefused(e)
+            | CrasProtocolMismatch(e)
+            | CrasSocketMissing(e)
+            | CrasTimeout(e) => Some(e),
+            FileIOFailed(_, e) | PlaybackFailed(e) | SpawnWorkerFailed(e) => Some(e),
+            NewPlayStreamFailed(e) | NextPlaybackBufferFailed(e) => Some(e.as_ref()),
+            ReadTimestampFailed(e) => Some(e),
+            SystemTimeError(e) => Some(e),
+            #[cfg(feature = "hardware")]
+            TopologyFailed(e) => Some(e),
+            VendorCalibParseFailed(_, e) | VPDParseFailed(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        self.report().serialize(serializer)
+    }
+}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use Error::*;
         match self {
+            #[cfg(feature = "hardware")]
             AlsaCardError(e) => write!(f, "{}", e),
+            #[cfg(feature = "hardware")]
             AlsaControlError(e) => write!(f, "{}", e),
-            CalibrationFailed => write!(f, "amp calibration failed"),
+            CalibrationFailed(errors) => {
+                write!(f, "amp calibration failed")?;
+                for (i, e) in errors.iter().enumerate() {
+                    write!(f, "{} {}", if i == 0 { ":" } else { ";" }, e)?;
+                }
+                Ok(())
+            }
+            Channel(channel, e) => write!(f, "channel {}: {}", channel, e),
             CalibrationTimeout => write!(f, "calibration is not finished in time"),
+            CorruptDatastore(file, e) => write!(f, "corrupt datastore {}: {}", file, e),
+            #[cfg(feature = "hardware")]
             CrasClientFailed(e) => write!(f, "failed to create cras client: {}", e),
+            CrasConnectRefused(e) => write!(f, "cras refused the connection: {}", e),
+            CrasProtocolMismatch(e) => write!(f, "cras protocol mismatch: {}", e),
+            CrasSocketMissing(e) => write!(f, "cras socket is missing, cras is not up yet: {}", e),
+            CrasTimeout(e) => write!(f, "cras does not respond in time: {}", e),
             DeserializationFailed(file, e) => write!(f, "failed to parse {}: {}", file, e),
+            FactoryValuesMismatch(given, count) => write!(
+                f,
+                "{} factory calibration values are given, the amp has {} channels",
+                given, count
+            ),
             FileIOFailed(file, e) => write!(f, "{}: {}", file, e),
+            InjectedFault(fault) => write!(f, "injected fault: {}", fault),
             InvalidShutDownTime => write!(f, "invalid shutdown time"),
             InternalSpeakerNotFound => write!(f, "internal speaker is not found in cras"),
             InvalidTemperature(temp) => write!(
@@ -79,24 +460,162 @@ impl fmt::Display for Error {
                 "invalid calibration temperature: {}, and there is no datastore",
                 temp
             ),
+            InvalidThresholds(e) => write!(f, "invalid thresholds: {}", e),
+            InvalidVendorCalib(file) => write!(f, "invalid vendor calibration file: {}", file),
+            InvalidChannel(channel, count) => {
+                write!(
+                    f,
+                    "invalid channel: {}, the amp has {} channels",
+                    channel, count
+                )
+            }
             InvalidDatastore => write!(f, "invalid datastore format"),
+            InvalidExcitation(e) => write!(f, "invalid excitation: {}", e),
+            InvalidMonitorSettings => write!(
+                f,
+                concat!(
+                    "invalid monitor settings: interval_secs and temp_millicelsius must be ",
+                    "positive and cool_temp must be lower than hot_temp",
+                )
+            ),
+            InvalidRdc(rdc) => write!(f, "invalid rdc value: {}", rdc),
             HotSpeaker => write!(f, "skip boot time calibration as the speakers may be hot"),
+            PowerStateDeferred => write!(
+                f,
+                "skip boot time calibration on a low battery or thermal throttling"
+            ),
             LargeCalibrationDiff(rdc, temp) => write!(
                 f,
                 "calibration difference is too large, rdc: {}, temp: {}",
                 rdc, temp
             ),
             MissingDSMParam => write!(f, "missing dsm_param.bin"),
-            MutexPoisonError => write!(f, "mutex is poisoned"),
+            MissingFactoryLimits => write!(f, "factory calibration requires factory_limits"),
+            MissingMonitorSettings => write!(f, "the reloaded config has no monitor settings"),
+            MissingGainControl(rdc_ctrl) => write!(
+                f,
+                "gain normalization requires gain_ctrl of the amp with rdc_ctrl: {}",
+                rdc_ctrl
+            ),
+            MissingStatusTempControl(rdc_ctrl) => write!(
+                f,
+                "runtime monitor requires status_temp_ctrl of the amp with rdc_ctrl: {}",
+                rdc_ctrl
+            ),
             NewPlayStreamFailed(e) => write!(f, "{}", e),
             NextPlaybackBufferFailed(e) => write!(f, "{}", e),
             PlaybackFailed(e) => write!(f, "{}", e),
+            RdcOutOfFactoryLimits(rdc, min, max) => write!(
+                f,
+                "rdc {} is out of the factory limits [{}, {}]",
+                rdc, min, max
+            ),
             ReadTimestampFailed(e) => write!(f, "{}", e),
             SerializationFailed(e) => write!(f, "failed to serialize yaml: {}", e),
+            SpawnWorkerFailed(e) => write!(f, "failed to spawn run_play_zero_worker: {}", e),
+            SpeakerOverheated(temp, hot_temp) => write!(
+                f,
+                "speaker temperature {} is above the hot temperature {}",
+                temp, hot_temp
+            ),
             StartPlaybackTimeout => write!(f, "playback is not started in time"),
             SystemTimeError(e) => write!(f, "{}", e),
+            TemperatureOutOfLimits(temp) => {
+                write!(f, "calibration temperature {} is out of the limits", temp)
+            }
+            #[cfg(feature = "hardware")]
+            TopologyFailed(e) => write!(f, "{}", e),
+            UnsupportedConfigVersion(version) => {
+                write!(f, "unsupported config_version: {}", version)
+            }
+            VendorCalibParseFailed(file, e) => {
+                write!(f, "failed to parse vendor calibration file {}: {}", file, e)
+            }
             VPDParseFailed(file, e) => write!(f, "failed to parse vpd {}: {}", file, e),
-            WorkerPanics => write!(f, "run_play_zero_worker panics"),
+            VPDWriteFailed(e) => write!(f, "failed to write vpd: {}", e),
+            WorkerPanicked { message, .. } => {
+                write!(f, "run_play_zero_worker panicked: {}", message)
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    // The errors of the variants which don't depend on the `hardware` feature, so the snapshot
+    // is the same on all the builds.
+    fn errors() -> Vec<Error> {
+        use Error::*;
+        let os_err = || io::Error::new(io::ErrorKind::NotFound, "No such file or directory");
+        let yaml_err = || serde_yaml::from_str::<Vec<i32>>("{").unwrap_err();
+        let parse_err = || "0x".parse::<i32>().unwrap_err();
+        vec![
+            CalibrationFailed(vec![InvalidRdc(0), HotSpeaker]),
+            CalibrationTimeout,
+            Channel(1, Box::new(InvalidTemperature(90))),
+            CorruptDatastore("calib_0".to_owned(), yaml_err()),
+            CrasConnectRefused(os_err()),
+            CrasProtocolMismatch(os_err()),
+            CrasSocketMissing(os_err()),
+            CrasTimeout(os_err()),
+            DeserializationFailed("dsm_param".to_owned(), yaml_err()),
+            FactoryValuesMismatch(1, 2),
+            FileIOFailed("/var/lib/sound_card_init/calib_0".to_owned(), os_err()),
+            HotSpeaker,
+            InjectedFault("calibration".to_owned()),
+            InternalSpeakerNotFound,
+            InvalidChannel(2, 2),
+            InvalidDatastore,
+            InvalidExcitation("unknown waveform".to_owned()),
+            InvalidMonitorSettings,
+            InvalidRdc(0),
+            InvalidShutDownTime,
+            InvalidTemperature(90),
+            InvalidThresholds("rdc_diff_lower_limit".to_owned()),
+            InvalidVendorCalib("vendor.bin".to_owned()),
+            LargeCalibrationDiff(27000, 1000),
+            MissingDSMParam,
+            MissingFactoryLimits,
+            MissingGainControl("Left Rdc".to_owned()),
+            MissingMonitorSettings,
+            MissingStatusTempControl("Left Rdc".to_owned()),
+            NewPlayStreamFailed("no stream".into()),
+            NextPlaybackBufferFailed("no buffer".into()),
+            PlaybackFailed(os_err()),
+            PowerStateDeferred,
+            RdcOutOfFactoryLimits(30000, 20000, 28000),
+            ReadTimestampFailed(utils::error::Error::InvalidLogSpec("x".to_owned())),
+            SerializationFailed(yaml_err()),
+            SpawnWorkerFailed(os_err()),
+            SpeakerOverheated(120, 100),
+            StartPlaybackTimeout,
+            SystemTimeError(
+                UNIX_EPOCH
+                    .duration_since(UNIX_EPOCH + Duration::from_secs(1))
+                    .unwrap_err(),
+            ),
+            TemperatureOutOfLimits(90),
+            UnsupportedConfigVersion(3),
+            VendorCalibParseFailed("vendor.bin".to_owned(), parse_err()),
+            VPDParseFailed("dsm_calib_r0_0".to_owned(), parse_err()),
+            VPDWriteFailed("vpd exit status: 1".to_owned()),
+            WorkerPanicked {
+                message: "index out of bounds".to_owned(),
+                backtrace: String::new(),
+            },
+        ]
+    }
+
+    #[test]
+    fn display_strings() {
+        let lines: Vec<_> = errors()
+            .iter()
+            .map(|e| format!("{} {:?}: {}", e.code(), e.exit_code(), e))
+            .collect();
+        insta::assert_snapshot!(lines.join("\n"));
+    }
+}
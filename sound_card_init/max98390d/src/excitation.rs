@@ -0,0 +1,106 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It generates the excitation played to the speakers during the calibration measurement.
+use std::f64::consts::PI;
+use std::fs;
+
+use crate::error::{Error, Result};
+use crate::settings::Excitation;
+
+// The bytes of a S16LE sample.
+const SAMPLE_BYTES: usize = 2;
+
+/// `Stimulus` generates the frames of an `Excitation` in S16LE.
+pub struct Stimulus {
+    source: Source,
+    num_channels: usize,
+    // The position of the next frame.
+    frame: usize,
+}
+
+enum Source {
+    Silence,
+    // The phase increment per frame in radians and the peak sample value.
+    Sine { step: f64, amplitude: f64 },
+    // The interleaved frames of the file, which are played in a loop.
+    Pcm(Vec<u8>),
+}
+
+impl Stimulus {
+    /// Creates the `Stimulus` of the playback format. The file of `Excitation::File` is read
+    /// up front.
+    ///
+    /// # Errors
+    ///
+    /// * If the sine frequency is not below the Nyquist frequency or the level is above 0 dBFS.
+    /// * If the file can't be read, or is empty or has a partial frame.
+    pub fn new(excitation: &Excitation, frame_rate: u32, num_channels: usize) -> Result<Self> {
+        let source = match excitation {
+            Excitation::Silence => Source::Silence,
+            Excitation::Sine {
+                frequency_hz,
+                level_dbfs,
+            } => {
+                if *frequency_hz <= 0.0 || *frequency_hz >= frame_rate as f32 / 2.0 {
+                    return Err(Error::InvalidExcitation(format!(
+                        "sine frequency {} Hz",
+                        frequency_hz
+                    )));
+                }
+                if *level_dbfs > 0.0 {
+                    return Err(Error::InvalidExcitation(format!(
+                        "sine level {} dBFS",
+                        level_dbfs
+                    )));
+                }
+                Source::Sine {
+                    step: 2.0 * PI * f64::from(*frequency_hz) / f64::from(frame_rate),
+                    amplitude: 10f64.powf(f64::from(*level_dbfs) / 20.0) * f64::from(i16::MAX),
+                }
+            }
+            Excitation::File { path } => {
+                let pcm = fs::read(path).map_err(|e| Error::FileIOFailed(path.clone(), e))?;
+                if pcm.is_empty() || pcm.len() % (SAMPLE_BYTES * num_channels) != 0 {
+                    return Err(Error::InvalidExcitation(format!(
+                        "{} is not S16LE with {} channels",
+                        path, num_channels
+                    )));
+                }
+                Source::Pcm(pcm)
+            }
+        };
+        Ok(Stimulus {
+            source,
+            num_channels,
+            frame: 0,
+        })
+    }
+
+    /// Fills `buffer` with the next frames. The length of `buffer` is a multiple of the frame
+    /// size.
+    pub fn fill(&mut self, buffer: &mut [u8]) {
+        let frame_bytes = SAMPLE_BYTES * self.num_channels;
+        let frames = buffer.len() / frame_bytes;
+        match &self.source {
+            Source::Silence => buffer.iter_mut().for_each(|b| *b = 0),
+            Source::Sine { step, amplitude } => {
+                for (i, frame) in buffer.chunks_exact_mut(frame_bytes).enumerate() {
+                    let n = (self.frame + i) as f64;
+                    let sample = ((n * step).sin() * amplitude).round() as i16;
+                    for ch in frame.chunks_exact_mut(SAMPLE_BYTES) {
+                        ch.copy_from_slice(&sample.to_le_bytes());
+                    }
+                }
+            }
+            Source::Pcm(pcm) => {
+                let total = pcm.len() / frame_bytes;
+                for (i, frame) in buffer.chunks_exact_mut(frame_bytes).enumerate() {
+                    let start = (self.frame + i) % total * frame_bytes;
+                    frame.copy_from_slice(&pcm[start..start + frame_bytes]);
+                }
+            }
+        }
+        self.frame += frames;
+    }
+}
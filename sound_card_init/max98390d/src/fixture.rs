@@ -0,0 +1,298 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It implements `DatastoreFixture` of the `fake` feature, which puts the datastore of a sound
+//! card into the given state before a test, ex: the first channel calibrated, the second
+//! channel corrupt and no gain offsets. The files are written under `datastore_dir()`, so the
+//! tests usually set a temporary directory by `utils::set_datastore_dir()` first.
+//!
+//! ```ignore
+//! DatastoreFixture::new("sofcmlmax98390d")
+//!     .with_channels(&settings, &[FileState::Values(12000, 30), FileState::Corrupt])
+//!     .with_run_time(Duration::from_secs(1_600_000_000))
+//!     .build()?;
+//! ```
+use std::error;
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+use utils::{datastore_dir, run_time};
+
+use crate::datastore::{Datastore, GainOffsets};
+use crate::error::{Error, Result};
+use crate::settings::DeviceSettings;
+
+/// The state of a datastore file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileState {
+    /// The file does not exist, ex: the channel is never calibrated.
+    Missing,
+    /// The file uses the VPD values.
+    UseVPD,
+    /// The file has the calibrated rdc and ambient temperature.
+    Values(i32, i32),
+    /// The file is a truncated yaml, like a write interrupted by a power loss.
+    Corrupt,
+    /// The file is empty.
+    Empty,
+    /// The file has the contents, ex: a valid yaml of another type.
+    Contents(String),
+}
+
+/// `DatastoreFixture` builds the datastore files of a sound card.
+#[derive(Debug)]
+pub struct DatastoreFixture {
+    snd_card: String,
+    files: Vec<(String, FileState)>,
+    run_time: Option<Duration>,
+}
+
+impl DatastoreFixture {
+    /// Creates a `DatastoreFixture` which leaves the datastore of the sound card as it is.
+    pub fn new(snd_card: &str) -> Self {
+        DatastoreFixture {
+            snd_card: snd_card.to_owned(),
+            files: Vec::new(),
+            run_time: None,
+        }
+    }
+
+    /// Sets the state of the calibration file of each channel of the settings, in the order of
+    /// `amp_calibrations`. The channels without a state are left as they are, ex: to leave the
+    /// second channel of a partially calibrated device.
+    pub fn with_channels(mut self, settings: &DeviceSettings, states: &[FileState]) -> Self {
+        for (setting, state) in settings.amp_calibrations.iter().zip(states) {
+            self.files.push((setting.calib_file.clone(), state.clone()));
+        }
+        self
+    }
+
+    /// Sets the state of a datastore file.
+    pub fn with_file(mut self, file: &str, state: FileState) -> Self {
+        self.files.push((file.to_owned(), state));
+        self
+    }
+
+    /// Writes the gain normalization offsets to the file.
+    pub fn with_gain_offsets(mut self, file: &str, offsets: &[i32]) -> Self {
+        let yaml = serde_yaml::to_string(&GainOffsets(offsets.to_vec())).unwrap_or_default();
+        self.files
+            .push((file.to_owned(), FileState::Contents(yaml)));
+        self
+    }
+
+    /// Writes the run time file of the sound card, which is the unix time of the last boot
+    /// time calibration.
+    pub fn with_run_time(mut self, time: Duration) -> Self {
+        self.run_time = Some(time);
+        self
+    }
+
+    /// Creates the datastore directory and puts the files into their states.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to create the directory, or to write or remove a file.
+    pub fn build(self) -> std::result::Result<(), Box<dyn error::Error>> {
+        let dir = datastore_dir(&self.snd_card);
+        let io_err = |path: &str, e| Error::FileIOFailed(path.to_owned(), e);
+        fs::create_dir_all(&dir).map_err(|e| io_err(&dir.to_string_lossy(), e))?;
+        for (file, state) in &self.files {
+            let path = dir.join(file);
+            let path_str = path.to_string_lossy().to_string();
+            let contents = match state {
+                FileState::Missing => match fs::remove_file(&path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        return Err(Box::new(io_err(&path_str, e)))
+                    }
+                    _ => continue,
+                },
+                FileState::UseVPD => to_yaml(&Datastore::UseVPD)?,
+                FileState::Values(rdc, ambient_temp) => to_yaml(&Datastore::DSM {
+                    rdc: *rdc,
+                    ambient_temp: *ambient_temp,
+                })?,
+                FileState::Corrupt => {
+                    let mut yaml = to_yaml(&Datastore::DSM {
+                        rdc: 0,
+                        ambient_temp: 0,
+                    })?;
+                    yaml.truncate(yaml.len() / 2);
+                    yaml.push_str("\0{");
+                    yaml
+                }
+                FileState::Empty => String::new(),
+                FileState::Contents(contents) => contents.clone(),
+            };
+            fs::write(&path, contents).map_err(|e| io_err(&path_str, e))?;
+        }
+        if let Some(time) = self.run_time {
+            run_time::to_file(&self.snd_card, time)?;
+        }
+        Ok(())
+    }
+}
+
+fn to_yaml(datastore: &Datastore) -> Result<String> {
+    serde_yaml::to_string(datastore).map_err(Error::SerializationFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::path::PathBuf;
+
+    use crate::settings::AmpCalibSettings;
+
+    // Sets the datastore directory of the test process. Each test uses its own sound card, so
+    // the tests don't share the files.
+    fn datastore_root() -> PathBuf {
+        let root = env::temp_dir().join(format!("datastore_fixture_{}", std::process::id()));
+        utils::set_datastore_dir(root.clone());
+        root
+    }
+
+    fn settings(files: &[&str]) -> DeviceSettings {
+        DeviceSettings {
+            amp_calibrations: files
+                .iter()
+                .map(|file| AmpCalibSettings {
+                    calib_file: (*file).to_owned(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn loads_the_channel_states() {
+        let root = datastore_root();
+        let snd_card = "fixture_load";
+        let settings = settings(&["calib_0", "calib_1", "calib_2", "calib_3", "calib_4"]);
+        DatastoreFixture::new(snd_card)
+            .with_channels(
+                &settings,
+                &[
+                    FileState::Values(12000, 30),
+                    FileState::UseVPD,
+                    FileState::Missing,
+                    FileState::Empty,
+                    FileState::Contents("[1, 2]".to_owned()),
+                ],
+            )
+            .build()
+            .unwrap();
+        assert_eq!(datastore_dir(snd_card), root.join(snd_card));
+
+        assert!(matches!(
+            Datastore::from_file(snd_card, "calib_0"),
+            Ok(Datastore::DSM {
+                rdc: 12000,
+                ambient_temp: 30
+            })
+        ));
+        assert!(matches!(
+            Datastore::from_file(snd_card, "calib_1"),
+            Ok(Datastore::UseVPD)
+        ));
+        match Datastore::from_file(snd_card, "calib_2") {
+            Err(Error::FileIOFailed(_, e)) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        for file in &["calib_3", "calib_4"] {
+            assert!(matches!(
+                Datastore::from_file(snd_card, file),
+                Err(Error::CorruptDatastore(..))
+            ));
+        }
+    }
+
+    #[test]
+    fn corrupt_datastore_is_replaced_by_save() {
+        datastore_root();
+        let snd_card = "fixture_corrupt";
+        let settings = settings(&["calib_0", "calib_1"]);
+        DatastoreFixture::new(snd_card)
+            .with_channels(&settings, &[FileState::Corrupt, FileState::Corrupt])
+            .build()
+            .unwrap();
+        for file in &["calib_0", "calib_1"] {
+            assert!(matches!(
+                Datastore::from_file(snd_card, file),
+                Err(Error::CorruptDatastore(..))
+            ));
+        }
+
+        let saved = [
+            (
+                "calib_0".to_owned(),
+                Datastore::DSM {
+                    rdc: 27000,
+                    ambient_temp: 1000,
+                },
+            ),
+            ("calib_1".to_owned(), Datastore::UseVPD),
+        ];
+        Datastore::save_all(snd_card, &saved).unwrap();
+        assert!(matches!(
+            Datastore::from_file(snd_card, "calib_0"),
+            Ok(Datastore::DSM {
+                rdc: 27000,
+                ambient_temp: 1000
+            })
+        ));
+        assert!(matches!(
+            Datastore::from_file(snd_card, "calib_1"),
+            Ok(Datastore::UseVPD)
+        ));
+    }
+
+    #[test]
+    fn missing_removes_the_saved_file() {
+        datastore_root();
+        let snd_card = "fixture_missing";
+        let settings = settings(&["calib_0"]);
+        DatastoreFixture::new(snd_card)
+            .with_channels(&settings, &[FileState::Values(12000, 30)])
+            .build()
+            .unwrap();
+        assert!(Datastore::from_file(snd_card, "calib_0").is_ok());
+        // The channels without a state are left as they are.
+        DatastoreFixture::new(snd_card)
+            .with_channels(&settings, &[])
+            .build()
+            .unwrap();
+        assert!(Datastore::from_file(snd_card, "calib_0").is_ok());
+        DatastoreFixture::new(snd_card)
+            .with_channels(&settings, &[FileState::Missing])
+            .build()
+            .unwrap();
+        assert!(Datastore::from_file(snd_card, "calib_0").is_err());
+        // Removing a missing file is not an error.
+        DatastoreFixture::new(snd_card)
+            .with_file("calib_0", FileState::Missing)
+            .build()
+            .unwrap();
+    }
+
+    #[test]
+    fn writes_the_gain_offsets_and_run_time() {
+        datastore_root();
+        let snd_card = "fixture_offsets";
+        let run_time = Duration::from_secs(1_600_000_000);
+        DatastoreFixture::new(snd_card)
+            .with_gain_offsets("gain_offsets", &[0, 2])
+            .with_run_time(run_time)
+            .build()
+            .unwrap();
+        assert_eq!(
+            GainOffsets::from_file(snd_card, "gain_offsets").unwrap(),
+            GainOffsets(vec![0, 2])
+        );
+        assert_eq!(utils::run_time::from_file(snd_card).unwrap(), run_time);
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It implements the post-calibration gain normalization, which adjusts the amp digital gain
+//! to normalize the loudness between channels based on the calibrated speaker impedance.
+use cros_alsa::{Card, IntControl};
+use log::info;
+use utils::RunOptions;
+
+use crate::datastore::GainOffsets;
+use crate::error::{Error, Result};
+use crate::settings::{AmpCalibSettings, GainNormalizationSettings};
+
+/// Computes the gain offsets from the current rdc values and applies them.
+/// The offsets are saved to the datastore so that they can be reapplied when the
+/// calibration is skipped.
+pub fn normalize_gain(
+    card: &mut Card,
+    snd_card: &str,
+    setting: &GainNormalizationSettings,
+    amp_calibrations: &[AmpCalibSettings],
+    opts: &RunOptions,
+) -> Result<()> {
+    let rdcs = amp_calibrations
+        .iter()
+        .map(|s| Ok(card.control_by_name::<IntControl>(&s.amp.rdc_ctrl)?.get()?))
+        .collect::<Result<Vec<i32>>>()?;
+    let offsets = GainOffsets(gain_offsets(&rdcs, setting)?);
+    info!("gain normalization offsets: {:?}", offsets);
+    apply_gain_offsets(card, setting, amp_calibrations, &offsets, opts)?;
+    if opts.dry_run {
+        info!(
+            "dry run: skip saving gain offsets to {}",
+            setting.offset_file
+        );
+        return Ok(());
+    }
+    offsets.save(snd_card, &setting.offset_file)
+}
+
+/// Applies the gain offsets stored in the datastore.
+pub fn apply_stored_gain_offsets(
+    card: &mut Card,
+    snd_card: &str,
+    setting: &GainNormalizationSettings,
+    amp_calibrations: &[AmpCalibSettings],
+    opts: &RunOptions,
+) -> Result<()> {
+    let offsets = GainOffsets::from_file(snd_card, &setting.offset_file)?;
+    apply_gain_offsets(card, setting, amp_calibrations, &offsets, opts)
+}
+
+fn apply_gain_offsets(
+    card: &mut Card,
+    setting: &GainNormalizationSettings,
+    amp_calibrations: &[AmpCalibSettings],
+    offsets: &GainOffsets,
+    opts: &RunOptions,
+) -> Result<()> {
+    if offsets.0.len() != amp_calibrations.len() {
+        return Err(Error::InvalidDatastore);
+    }
+    for (s, offset) in amp_calibrations.iter().zip(offsets.0.iter()) {
+        let gain_ctrl = s
+            .amp
+            .gain_ctrl
+            .as_ref()
+            .ok_or_else(|| Error::MissingGainControl(s.amp.rdc_ctrl.clone()))?;
+        let gain = setting.gain_default - offset;
+        if opts.dry_run {
+            info!("dry run: skip setting {} to {}", gain_ctrl, gain);
+            continue;
+        }
+        card.control_by_name::<IntControl>(gain_ctrl)?.set(gain)?;
+    }
+    Ok(())
+}
+
+// The output power at a given voltage is inversely proportional to the speaker impedance,
+// and rdc is the inverse of the impedance. Therefore, the channel with the smallest rdc is
+// the quietest one, and the other channels are attenuated by 10 * log10(rdc / min_rdc) dB.
+fn gain_offsets(rdcs: &[i32], setting: &GainNormalizationSettings) -> Result<Vec<i32>> {
+    if let Some(rdc) = rdcs.iter().find(|&&rdc| rdc <= 0) {
+        return Err(Error::InvalidRdc(*rdc));
+    }
+    let min_rdc = match rdcs.iter().min() {
+        Some(rdc) => *rdc as f32,
+        None => return Ok(Vec::new()),
+    };
+    Ok(rdcs
+        .iter()
+        .map(|&rdc| {
+            let attenuation_db = 10.0 * (rdc as f32 / min_rdc).log10();
+            ((attenuation_db / setting.gain_step_db).round() as i32).min(setting.max_offset)
+        })
+        .collect())
+}
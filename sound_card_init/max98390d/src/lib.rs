@@ -3,151 +3,119 @@
 // found in the LICENSE file.
 //! `max98390d` crate implements the required initialization workflows.
 //! It currently supports boot time calibration for max98390d.
+//!
+//! The workflows on the sound card and CRAS need the `hardware` feature, which is on by
+//! default. The config, datastore and acceptance checks build without it, so that they are
+//! tested on a host without the ALSA and CRAS libraries by
+//! `cargo test --no-default-features`. The fakes of the `fake` feature are always built for the
+//! tests, so the plain `cargo test` also runs the tests on them.
 #![deny(missing_docs)]
+// Without the `hardware` feature, the pure modules are only used by their tests.
+#![cfg_attr(not(feature = "hardware"), allow(dead_code))]
+mod acceptance;
+#[cfg(feature = "hardware")]
 mod amp_calibration;
+#[cfg(feature = "hardware")]
+mod cras_calib;
 mod datastore;
+#[cfg(feature = "hardware")]
+mod driver;
 mod error;
+mod excitation;
+#[cfg(any(test, feature = "fake"))]
+mod fixture;
+#[cfg(feature = "hardware")]
+mod gain_normalization;
+#[cfg(feature = "hardware")]
+mod monitor;
+#[cfg(feature = "hardware")]
+mod playback;
 mod settings;
+#[cfg(feature = "hardware")]
+mod status;
+mod vendor_calib;
 mod vpd;
 
-use std::fs;
-use std::path::{Path, PathBuf};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-
-use cros_alsa::Card;
-use sys_util::error;
-use utils::{run_time, shutdown_time, DATASTORE_DIR};
-
-use crate::amp_calibration::{AmpCalibration, VolumeMode};
+use utils::error::ErrorReport;
+use utils::ExitCode;
+
+pub use crate::acceptance::check_cool_down;
+use crate::datastore::{from_yaml_reader, Datastore, GainOffsets};
+#[cfg(feature = "hardware")]
+pub use crate::driver::{
+    factory_apply_max98390d, factory_calibrate_max98390d, factory_measure_max98390d,
+    live_status_max98390d, monitor_max98390d, open_amp_card, reset_max98390d, run_max98390d,
+    run_max98390d_with_card, self_test_max98390d, set_safe_state_max98390d, show_max98390d,
+    show_max98390d_json, snapshot_max98390d, speaker_check_max98390d, validate_max98390d,
+};
 use crate::error::{Error, Result};
-use crate::settings::DeviceSettings;
-
-const SPEAKER_COOL_DOWN_TIME: Duration = Duration::from_secs(180);
-
-/// Performs max98390d boot time calibration.
-///
-///
-/// # Arguments
-///
-/// * `snd_card` - The sound card name, ex: sofcmlmax98390d.
-/// * `conf` - The `DeviceSettings` in yaml format.
-///
-/// # Errors
-///
-/// If any amplifiers fail to complete the calibration.
-pub fn run_max98390d(snd_card: &str, conf: &str) -> Result<()> {
-    let settings = DeviceSettings::from_yaml_str(conf)?;
-    let mut card = Card::new(snd_card)?;
-
-    if !Path::new(&settings.dsm_param).exists() {
-        set_all_volume_low(&mut card, &settings);
-        return Err(Error::MissingDSMParam);
-    }
-
-    // Needs to check whether the speakers are over heated if it is not the first time boot.
-    if run_time::exists(snd_card) {
-        if let Err(err) = check_speaker_over_heated(snd_card, SPEAKER_COOL_DOWN_TIME) {
-            match err {
-                Error::HotSpeaker => run_all_hot_speaker_workflow(&mut card, &settings),
-                _ => {
-                    // We cannot assume the speakers are not replaced or not over heated
-                    // when the shutdown time file is invalid; therefore we can not use the datastore
-                    // value anymore and we can not trigger boot time calibration.
-                    del_all_datastore(snd_card, &settings);
-                    set_all_volume_low(&mut card, &settings);
-                }
-            };
-            return Err(err);
-        };
-    }
-
-    // If some error occurs during the calibration, the iteration will continue running the
-    // calibration for the next amp.
-    let results: Vec<Result<()>> = settings
-        .amp_calibrations
-        .into_iter()
-        .map(|s| {
-            let mut amp_calib = AmpCalibration::new(&mut card, s)?;
-            amp_calib.set_volume(VolumeMode::Low)?;
-            amp_calib.run()?;
-            amp_calib.set_volume(VolumeMode::High)?;
-            Ok(())
-        })
-        .filter_map(|res| res.err())
-        .map(|e| {
-            error!("calibration error: {}. volume remains low.", e);
-            Err(e)
-        })
-        .collect();
-
-    if !results.is_empty() {
-        return Err(Error::CalibrationFailed);
-    }
-
-    Ok(())
+#[cfg(any(test, feature = "fake"))]
+pub use crate::fixture::{DatastoreFixture, FileState};
+#[cfg(all(feature = "hardware", any(test, feature = "fake")))]
+pub use crate::playback::{use_fake_cras, FakeCras};
+#[cfg(feature = "hardware")]
+pub use crate::playback::{PlaybackClient, Speaker};
+pub use crate::settings::DeviceSettings;
+
+/// Returns the `ExitCode` of the errors returned by the max98390d functions, or None if `err`
+/// is not a max98390d error.
+pub fn exit_code(err: &(dyn std::error::Error + 'static)) -> Option<ExitCode> {
+    err.downcast_ref::<Error>().map(Error::exit_code)
 }
 
-fn del_all_datastore(snd_card: &str, settings: &DeviceSettings) {
-    for s in &settings.amp_calibrations {
-        if let Err(e) = fs::remove_file(
-            PathBuf::from(DATASTORE_DIR)
-                .join(snd_card)
-                .join(&s.calib_file),
-        ) {
-            error!("failed to remove datastore: {}.", e);
-        }
-    }
+/// Returns the stable code of the errors returned by the max98390d functions, or None if `err`
+/// is not a max98390d error.
+pub fn error_code(err: &(dyn std::error::Error + 'static)) -> Option<u32> {
+    err.downcast_ref::<Error>().map(Error::code)
 }
 
-fn run_all_hot_speaker_workflow(card: &mut Card, settings: &DeviceSettings) {
-    for s in &settings.amp_calibrations {
-        let mut amp_calib = match AmpCalibration::new(card, s.clone()) {
-            Ok(amp) => amp,
-            Err(e) => {
-                error!("{}.", e);
-                continue;
-            }
-        };
-        if let Err(e) = amp_calib.hot_speaker_workflow() {
-            error!("failed to run hot_speaker_workflow: {}.", e);
-        }
-    }
+/// Returns the structured form of the errors returned by the max98390d functions, or None if
+/// `err` is not a max98390d error.
+pub fn error_report(err: &(dyn std::error::Error + 'static)) -> Option<ErrorReport> {
+    err.downcast_ref::<Error>().map(Error::report)
 }
 
-fn set_all_volume_low(card: &mut Card, settings: &DeviceSettings) {
-    for s in &settings.amp_calibrations {
-        let mut amp_calib = match AmpCalibration::new(card, s.clone()) {
-            Ok(amp) => amp,
-            Err(e) => {
-                error!("{}.", e);
-                continue;
-            }
-        };
-        if let Err(e) = amp_calib.set_volume(VolumeMode::Low) {
-            error!("failed to set volume to low: {}.", e);
-        }
-    }
+/// Returns the remediation hint of the errors returned by the max98390d functions, or None if
+/// `err` is not a max98390d error or has no hint.
+pub fn error_hint(err: &(dyn std::error::Error + 'static)) -> Option<&'static str> {
+    err.downcast_ref::<Error>().and_then(Error::hint)
 }
 
-// If (Current time - the latest CRAS shutdown time) < cool_down_time, we assume that
-// the speakers may be over heated.
-fn check_speaker_over_heated(snd_card: &str, cool_down_time: Duration) -> Result<()> {
-    let last_run = run_time::from_file(snd_card).map_err(Error::ReadTimestampFailed)?;
-    let last_shutdown = shutdown_time::from_file().map_err(Error::ReadTimestampFailed)?;
-    if last_shutdown < last_run {
-        return Err(Error::InvalidShutDownTime);
-    }
+/// Returns true if `err` is a transient max98390d error, see `Error::is_transient()`.
+pub fn is_transient(err: &(dyn std::error::Error + 'static)) -> bool {
+    err.downcast_ref::<Error>().is_some_and(Error::is_transient)
+}
 
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map_err(Error::SystemTimeError)?;
+/// Returns true if `err` is the max98390d error of the speakers which may be over heated, see
+/// `check_cool_down()`.
+pub fn is_hot_speaker(err: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(err.downcast_ref::<Error>(), Some(Error::HotSpeaker))
+}
 
-    let elapsed = now
-        .checked_sub(last_shutdown)
-        .ok_or(Error::InvalidShutDownTime)?;
+/// Returns the settings in effect for the config, including the defaults of the omitted
+/// fields.
+///
+/// # Errors
+///
+/// * If the config is invalid.
+pub fn effective_config_max98390d(conf: &str) -> Result<serde_yaml::Value> {
+    let settings = DeviceSettings::from_yaml_str(conf)?;
+    serde_yaml::to_value(&settings).map_err(Error::SerializationFailed)
+}
 
-    if elapsed < cool_down_time {
-        return Err(Error::HotSpeaker);
-    }
-    Ok(())
+/// Parses the data of the datastore files, ex: calib_0, as each kind of datastore. It's the
+/// parser of the stateful data which runs as root at boot, and is exposed for the fuzzers.
+///
+/// # Errors
+///
+/// * If the data is not a valid datastore. The error of the calibration datastore is returned
+///   if it's neither kind of datastore.
+pub fn parse_datastore(data: &[u8]) -> Result<()> {
+    from_yaml_reader::<Datastore, _>("datastore", data)
+        .map(drop)
+        .or_else(|e| {
+            from_yaml_reader::<GainOffsets, _>("datastore", data)
+                .map(drop)
+                .map_err(|_| e)
+        })
 }
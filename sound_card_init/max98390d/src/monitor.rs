@@ -0,0 +1,301 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It implements the runtime monitor of the daemon mode, which protects the speakers by
+//! limiting the volume while they are hot.
+//!
+//! The platform health services are notified of the overheat events by the event file of the
+//! sound card, ex: /run/sound_card_init/sofcmlmax98390d.overheat.json, which is replaced on
+//! every protection change:
+//!
+//! ```json
+//! {"sound_card_id": "sofcmlmax98390d", "time": 1600000000, "overheated": true,
+//!  "channels": [{"temp_ctrl": "Left Temp", "temp": 80, "hot_temp": 70, "protected": true}]}
+//! ```
+//!
+//! The events are also reported to UMA.
+//!
+//! If the monitor settings have `temp_millicelsius`, the speaker temperatures are also
+//! published after every check to the hwmon-style feed of the sound card, ex:
+//! /run/sound_card_init/sofcmlmax98390d/hwmon, so that the platform thermal stack can read them
+//! like a hwmon device of the kernel. The directory has the `name` of the feed, and for each
+//! channel N counted from 1, `tempN_input` and `tempN_crit` in millidegrees Celsius and
+//! `tempN_label`. The `tempN_input` of a channel whose temperature can't be read is removed.
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use cros_alsa::{Card, IntControl};
+use log::{error, info};
+use serde_json::json;
+use utils::clock::clock;
+use utils::{metrics, run_dir, RunOptions};
+
+use crate::error::{Error, Result};
+use crate::settings::{to_millicelsius, AmpCalibSettings, MonitorSettings};
+
+const OVERHEAT_EVENT_METRIC: &str = "Cras.SoundCardInit.Max98390d.OverheatEvent";
+const OVERHEAT_TEMP_METRIC: &str = "Cras.SoundCardInit.Max98390d.OverheatTemperature";
+const OVERHEAT_EVENT_EXTENSION: &str = "overheat.json";
+const HWMON_DIR: &str = "hwmon";
+const HWMON_NAME: &str = "speaker";
+
+/// The overheat events of an amp channel, which are reported to UMA. The values must not be
+/// renumbered.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum OverheatEvent {
+    /// The speaker is hot, and the volume is put into protected mode.
+    Overheated = 0,
+    /// The speaker cools down, and the volume is restored.
+    CooledDown = 1,
+}
+
+impl OverheatEvent {
+    // The number of the events.
+    const COUNT: i32 = 2;
+}
+
+// The runtime state of an amp channel.
+struct Channel {
+    temp_ctrl: String,
+    volume_ctrl: String,
+    volume_low_limit: i32,
+    // The volume before the protection, or None if the channel is not protected.
+    saved_volume: Option<i32>,
+    // The speaker temperature of the last check.
+    temp: Option<i32>,
+}
+
+/// `Monitor` checks the speaker temperature of the amp channels and applies the protection.
+pub struct Monitor {
+    snd_card: String,
+    hot_temp: i32,
+    cool_temp: i32,
+    // The millidegrees Celsius of a unit of the temperature, if the hwmon feed is published.
+    temp_millicelsius: Option<i32>,
+    dry_run: bool,
+    channels: Vec<Channel>,
+}
+
+impl Monitor {
+    /// Creates a `Monitor` of the amp channels of the sound card.
+    ///
+    /// # Errors
+    ///
+    /// * If the `MonitorSettings` is invalid.
+    /// * If any amp has no `status_temp_ctrl`.
+    pub fn new(
+        snd_card: &str,
+        setting: &MonitorSettings,
+        amp_calibrations: &[AmpCalibSettings],
+        opts: &RunOptions,
+    ) -> Result<Self> {
+        if setting.interval_secs == 0
+            || setting.cool_temp >= setting.hot_temp
+            || matches!(setting.temp_millicelsius, Some(scale) if scale <= 0)
+        {
+            return Err(Error::InvalidMonitorSettings);
+        }
+        let channels =
+            amp_calibrations
+                .iter()
+                .map(|s| {
+                    Ok(Channel {
+                        temp_ctrl: s.amp.status_temp_ctrl.clone().ok_or_else(|| {
+                            Error::MissingStatusTempControl(s.amp.rdc_ctrl.clone())
+                        })?,
+                        volume_ctrl: s.amp.volume_ctrl.clone(),
+                        volume_low_limit: s.amp.volume_low_limit,
+                        saved_volume: None,
+                        temp: None,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+        Ok(Monitor {
+            snd_card: snd_card.to_owned(),
+            hot_temp: setting.hot_temp,
+            cool_temp: setting.cool_temp,
+            temp_millicelsius: setting.temp_millicelsius,
+            dry_run: opts.dry_run,
+            channels,
+        })
+    }
+
+    /// Applies the reloaded settings. The protection state of the channels is kept, so a
+    /// channel protected by the old settings is restored by the new `cool_temp`.
+    ///
+    /// # Errors
+    ///
+    /// * If the reloaded settings are invalid, which leaves the current settings in effect.
+    pub fn reload(
+        &mut self,
+        setting: &MonitorSettings,
+        amp_calibrations: &[AmpCalibSettings],
+    ) -> Result<()> {
+        let opts = RunOptions {
+            dry_run: self.dry_run,
+            ..Default::default()
+        };
+        let mut monitor = Monitor::new(&self.snd_card, setting, amp_calibrations, &opts)?;
+        for ch in &mut monitor.channels {
+            ch.saved_volume = self
+                .channels
+                .iter()
+                .find(|old| old.volume_ctrl == ch.volume_ctrl)
+                .and_then(|old| old.saved_volume);
+        }
+        *self = monitor;
+        Ok(())
+    }
+
+    /// Checks the speaker temperature of all the channels once. The volume of a channel is
+    /// put into protected mode when its speaker is hotter than `hot_temp`, and is restored
+    /// when the speaker cools down below `cool_temp`. The errors are logged, and the channel
+    /// is checked again next time. The protection changes are emitted as overheat events
+    /// unless it's a dry run.
+    pub fn check(&mut self, card: &mut Card) {
+        let (hot_temp, cool_temp, dry_run) = (self.hot_temp, self.cool_temp, self.dry_run);
+        let mut changed = false;
+        for ch in &mut self.channels {
+            match ch.check(card, hot_temp, cool_temp, dry_run) {
+                Ok(Some(event)) if !dry_run => {
+                    metrics::send_enum(OVERHEAT_EVENT_METRIC, event as i32, OverheatEvent::COUNT);
+                    if let (OverheatEvent::Overheated, Some(temp)) = (event, ch.temp) {
+                        metrics::send_sparse(OVERHEAT_TEMP_METRIC, temp);
+                    }
+                    changed = true;
+                }
+                Ok(_) => (),
+                Err(e) => error!("failed to monitor {}: {}.", ch.temp_ctrl, e),
+            }
+        }
+        if changed {
+            if let Err(e) = self.write_event_file() {
+                error!("failed to write the overheat event: {}", e);
+            }
+        }
+        if let (Some(scale), false) = (self.temp_millicelsius, dry_run) {
+            if let Err(e) = self.write_hwmon(scale) {
+                error!("failed to publish the hwmon feed: {}", e);
+            }
+        }
+    }
+
+    // Returns the path of the overheat event file of the sound card.
+    fn event_file(snd_card: &str) -> PathBuf {
+        run_dir()
+            .join(snd_card)
+            .with_extension(OVERHEAT_EVENT_EXTENSION)
+    }
+
+    // Returns the directory of the hwmon feed of the sound card.
+    fn hwmon_dir(snd_card: &str) -> PathBuf {
+        run_dir().join(snd_card).join(HWMON_DIR)
+    }
+
+    // Replaces the attributes of the hwmon feed atomically, so the readers never see a partial
+    // value.
+    fn write_hwmon(&self, scale: i32) -> io::Result<()> {
+        let dir = Monitor::hwmon_dir(&self.snd_card);
+        // The upstart job pre-creates the directory, which is created here for the runs
+        // without it, ex: a manual run of the daemon mode.
+        fs::create_dir_all(&dir)?;
+        let write = |attr: &str, value: String| {
+            let path = dir.join(attr);
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, format!("{}\n", value))?;
+            fs::rename(&tmp, &path)
+        };
+        write("name", HWMON_NAME.to_owned())?;
+        for (i, ch) in self.channels.iter().enumerate() {
+            let n = i + 1;
+            write(&format!("temp{}_label", n), ch.temp_ctrl.clone())?;
+            write(
+                &format!("temp{}_crit", n),
+                to_millicelsius(self.hot_temp, scale).to_string(),
+            )?;
+            let input = format!("temp{}_input", n);
+            match ch.temp {
+                Some(temp) => write(&input, to_millicelsius(temp, scale).to_string())?,
+                None => match fs::remove_file(dir.join(&input)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => (),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // Replaces the event file atomically, so the readers never see a partial event.
+    fn write_event_file(&self) -> io::Result<()> {
+        let time = clock().now().map_or(0, |t| t.as_secs());
+        let event = json!({
+            "sound_card_id": self.snd_card,
+            "time": time,
+            "overheated": self.channels.iter().any(|ch| ch.saved_volume.is_some()),
+            "channels": self.channels.iter().map(|ch| json!({
+                "temp_ctrl": ch.temp_ctrl,
+                "temp": ch.temp,
+                "hot_temp": self.hot_temp,
+                "protected": ch.saved_volume.is_some(),
+            })).collect::<Vec<_>>(),
+        });
+        let path = Monitor::event_file(&self.snd_card);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, event.to_string())?;
+        fs::rename(&tmp, &path)
+    }
+}
+
+impl Channel {
+    fn check(
+        &mut self,
+        card: &mut Card,
+        hot_temp: i32,
+        cool_temp: i32,
+        dry_run: bool,
+    ) -> Result<Option<OverheatEvent>> {
+        // A failed read must not leave a stale temperature in the feed.
+        self.temp = None;
+        let temp = card.control_by_name::<IntControl>(&self.temp_ctrl)?.get()?;
+        self.temp = Some(temp);
+        match self.saved_volume {
+            None if temp > hot_temp => {
+                let volume = card
+                    .control_by_name::<IntControl>(&self.volume_ctrl)?
+                    .get()?;
+                // The volume is already limited, ex: the boot time calibration failed.
+                if volume <= self.volume_low_limit {
+                    return Ok(None);
+                }
+                info!(
+                    "{}: {} is hot, limit {} to {}",
+                    self.temp_ctrl, temp, self.volume_ctrl, self.volume_low_limit
+                );
+                self.set_volume(card, self.volume_low_limit, dry_run)?;
+                self.saved_volume = Some(volume);
+                Ok(Some(OverheatEvent::Overheated))
+            }
+            Some(volume) if temp < cool_temp => {
+                info!(
+                    "{}: {} cools down, restore {} to {}",
+                    self.temp_ctrl, temp, self.volume_ctrl, volume
+                );
+                self.set_volume(card, volume, dry_run)?;
+                self.saved_volume = None;
+                Ok(Some(OverheatEvent::CooledDown))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn set_volume(&self, card: &mut Card, volume: i32, dry_run: bool) -> Result<()> {
+        if dry_run {
+            info!("dry run: skip setting {} to {}", self.volume_ctrl, volume);
+            return Ok(());
+        }
+        card.control_by_name::<IntControl>(&self.volume_ctrl)?
+            .set(volume)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,275 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It abstracts the parts of the CRAS client used by the playback worker of the calibration and
+//! by the speaker protection mark, so that they can be replaced by `FakeCras` of the `fake`
+//! feature, ex: to exercise the stream start timeout and the buffer failures without CRAS.
+use audio_streams::{PlaybackBufferStream, SampleFormat, StreamControl};
+use libcras::{BoxError, CrasClient, CrasNodeType};
+
+use crate::error::Result;
+
+/// The internal speaker device of CRAS.
+#[derive(Debug, Clone)]
+pub struct Speaker {
+    /// The iodev index of the internal speaker node.
+    pub iodev_index: u32,
+    /// The ionode index of the internal speaker node.
+    pub ionode_index: u32,
+    /// The device name, which matches the debug info of the device.
+    pub name: Option<String>,
+}
+
+/// `PlaybackClient` is the part of the CRAS client used by the playback worker and the speaker
+/// protection mark.
+pub trait PlaybackClient: Send {
+    /// Returns the internal speaker, or None if CRAS does not have it.
+    fn internal_speaker(&mut self) -> Option<Speaker>;
+
+    /// Creates a playback stream pinned to the device.
+    #[allow(clippy::type_complexity)]
+    fn new_pinned_playback_stream(
+        &mut self,
+        device_index: u32,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn PlaybackBufferStream>), BoxError>;
+
+    /// Returns the underruns of the device, or None if CRAS fails to report them.
+    fn underruns(&mut self, device: &str) -> Option<u32>;
+
+    /// Marks the speaker node as protected by the calibrated speaker protection or not.
+    fn set_speaker_protected(&mut self, speaker: &Speaker, protected: bool) -> Result<()>;
+}
+
+impl PlaybackClient for CrasClient<'static> {
+    fn internal_speaker(&mut self) -> Option<Speaker> {
+        // TODO(b/155007305): Implement cras_client.wait_node_change and use it here.
+        let node = self
+            .output_nodes()
+            .find(|node| node.node_type == CrasNodeType::CRAS_NODE_TYPE_INTERNAL_SPEAKER)?;
+        let name = self
+            .output_devices()
+            .find(|device| device.index == node.iodev_index)
+            .map(|device| device.name);
+        Some(Speaker {
+            iodev_index: node.iodev_index,
+            ionode_index: node.ionode_index,
+            name,
+        })
+    }
+
+    fn new_pinned_playback_stream(
+        &mut self,
+        device_index: u32,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+    ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn PlaybackBufferStream>), BoxError>
+    {
+        CrasClient::new_pinned_playback_stream(
+            self,
+            device_index,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size,
+        )
+    }
+
+    fn underruns(&mut self, device: &str) -> Option<u32> {
+        let info = self.get_audio_debug_info().ok()?;
+        Some(
+            info.devices
+                .iter()
+                .filter(|d| d.dev_name == device)
+                .map(|d| d.num_underruns)
+                .sum(),
+        )
+    }
+
+    fn set_speaker_protected(&mut self, speaker: &Speaker, protected: bool) -> Result<()> {
+        Ok(self.set_node_speaker_protected(speaker.iodev_index, speaker.ionode_index, protected)?)
+    }
+}
+
+/// Opens the CRAS client of the calibration, which is `FakeCras` if it's set by
+/// `use_fake_cras()`.
+///
+/// # Errors
+///
+/// * If it fails to connect to CRAS.
+pub fn open_playback_client() -> Result<Box<dyn PlaybackClient>> {
+    #[cfg(any(test, feature = "fake"))]
+    if let Some(fake) = fake::current() {
+        return Ok(Box::new(fake));
+    }
+    Ok(Box::new(CrasClient::new()?))
+}
+
+#[cfg(any(test, feature = "fake"))]
+pub use self::fake::{use_fake_cras, FakeCras};
+
+#[cfg(any(test, feature = "fake"))]
+mod fake {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use audio_streams::{
+        NoopStream, NoopStreamControl, PlaybackBuffer, PlaybackBufferStream, SampleFormat,
+        StreamControl,
+    };
+    use libcras::BoxError;
+
+    use super::{PlaybackClient, Speaker};
+    use crate::error::Result;
+
+    // The fake used by the calibrations instead of CRAS.
+    static FAKE_CRAS: Mutex<Option<FakeCras>> = Mutex::new(None);
+
+    /// Makes the calibrations of the process play to the fake instead of CRAS, or to CRAS
+    /// again if it's None.
+    pub fn use_fake_cras(fake: Option<FakeCras>) {
+        if let Ok(mut current) = FAKE_CRAS.lock() {
+            *current = fake;
+        }
+    }
+
+    pub(super) fn current() -> Option<FakeCras> {
+        FAKE_CRAS.lock().ok().and_then(|current| current.clone())
+    }
+
+    /// `FakeCras` is a scripted CRAS client. Its streams take the time of real streams, ex:
+    /// a buffer of 256 frames at 48kHz takes 5ms, so the calibration timeouts apply as well.
+    #[derive(Debug, Clone)]
+    pub struct FakeCras {
+        speaker: Option<Speaker>,
+        start_delay: Duration,
+        stream_error: Option<String>,
+        failed_buffer: Option<usize>,
+        underruns: Option<u32>,
+        // The last mark of the speaker protection, which is shared by the clones opened by the
+        // calibrations.
+        protected: Arc<Mutex<Option<bool>>>,
+    }
+
+    impl Default for FakeCras {
+        fn default() -> Self {
+            FakeCras {
+                speaker: Some(Speaker {
+                    iodev_index: 0,
+                    ionode_index: 0,
+                    name: Some("Speaker".to_owned()),
+                }),
+                start_delay: Duration::ZERO,
+                stream_error: None,
+                failed_buffer: None,
+                underruns: Some(0),
+                protected: Arc::new(Mutex::new(None)),
+            }
+        }
+    }
+
+    impl FakeCras {
+        /// Creates a `FakeCras` of a working internal speaker without underruns.
+        pub fn new() -> Self {
+            Default::default()
+        }
+
+        /// Removes the internal speaker.
+        pub fn without_speaker(mut self) -> Self {
+            self.speaker = None;
+            self
+        }
+
+        /// Delays the stream creation, ex: to time out the stream start.
+        pub fn with_start_delay(mut self, delay: Duration) -> Self {
+            self.start_delay = delay;
+            self
+        }
+
+        /// Fails the stream creation with the message.
+        pub fn with_stream_error(mut self, message: &str) -> Self {
+            self.stream_error = Some(message.to_owned());
+            self
+        }
+
+        /// Fails to get the buffer of the index, which starts from 0.
+        pub fn with_buffer_error_at(mut self, index: usize) -> Self {
+            self.failed_buffer = Some(index);
+            self
+        }
+
+        /// Sets the reported underruns, or reports none if it's None.
+        pub fn with_underruns(mut self, underruns: Option<u32>) -> Self {
+            self.underruns = underruns;
+            self
+        }
+
+        /// Returns the last mark of the speaker protection, or None if the speaker is never
+        /// marked.
+        pub fn speaker_protected(&self) -> Option<bool> {
+            self.protected.lock().ok().and_then(|protected| *protected)
+        }
+    }
+
+    impl PlaybackClient for FakeCras {
+        fn internal_speaker(&mut self) -> Option<Speaker> {
+            self.speaker.clone()
+        }
+
+        fn new_pinned_playback_stream(
+            &mut self,
+            _device_index: u32,
+            num_channels: usize,
+            format: SampleFormat,
+            frame_rate: u32,
+            buffer_size: usize,
+        ) -> std::result::Result<(Box<dyn StreamControl>, Box<dyn PlaybackBufferStream>), BoxError>
+        {
+            thread::sleep(self.start_delay);
+            if let Some(message) = &self.stream_error {
+                return Err(message.clone().into());
+            }
+            let stream = FakeStream {
+                inner: NoopStream::new(num_channels, format, frame_rate, buffer_size),
+                buffers: 0,
+                failed_buffer: self.failed_buffer,
+            };
+            Ok((Box::new(NoopStreamControl::new()), Box::new(stream)))
+        }
+
+        fn underruns(&mut self, _device: &str) -> Option<u32> {
+            self.underruns
+        }
+
+        fn set_speaker_protected(&mut self, _speaker: &Speaker, protected: bool) -> Result<()> {
+            if let Ok(mut current) = self.protected.lock() {
+                *current = Some(protected);
+            }
+            Ok(())
+        }
+    }
+
+    // The stream of `FakeCras`, which fails to get the buffer of the index.
+    struct FakeStream {
+        inner: NoopStream,
+        buffers: usize,
+        failed_buffer: Option<usize>,
+    }
+
+    impl PlaybackBufferStream for FakeStream {
+        fn next_playback_buffer(&mut self) -> std::result::Result<PlaybackBuffer<'_>, BoxError> {
+            let index = self.buffers;
+            self.buffers += 1;
+            if self.failed_buffer == Some(index) {
+                return Err(format!("fake buffer failure at {}", index).into());
+            }
+            self.inner.next_playback_buffer()
+        }
+    }
+}
@@ -3,21 +3,247 @@
 // found in the LICENSE file.
 use std::string::String;
 
-use serde::Deserialize;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
 
 use crate::error::{Error, Result};
 
+/// The version of the config format. The fields renamed since an older version keep working
+/// for one more version with a deprecation warning.
+pub const CONFIG_VERSION: u32 = 2;
+
+// The version of the configs without `config_version`.
+const FIRST_CONFIG_VERSION: u32 = 1;
+
+// The fields of `AmpSettings` renamed by each version: (version, old name, new name).
+const RENAMED_AMP_FIELDS: [(u32, &str, &str); 1] = [(2, "temp_ctrl", "ambient_temp_ctrl")];
+
 /// `DeviceSettings` includes the settings of max98390. It currently includes:
+/// * the version of the config format.
 /// * the settings of amplifier calibration.
 /// * the path of dsm_param.
-#[derive(Debug, Default, PartialEq, Deserialize, Clone)]
+/// * the optional settings of post-calibration gain normalization.
+/// * the optional sound card of the amp controls.
+/// * the optional simple mixer element of the speaker path.
+/// * the optional settings of the runtime monitor.
+/// * the optional limits of the factory calibration.
+/// * the calibration thresholds, which default to the values tuned for max98390d.
+/// * the excitation of the calibration measurement, which defaults to silence.
+/// * the optional settings of the fleet telemetry.
+/// * whether all the amps are measured in one playback.
+///
+/// The unknown fields are rejected to catch the typos in the config.
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct DeviceSettings {
+    /// The version of the config format. The older configs are migrated to `CONFIG_VERSION`.
+    #[serde(default = "first_config_version")]
+    pub config_version: u32,
+    /// The amp driver selected by the config, ex: max98390d. It's parsed by sound_card_init.
+    #[serde(default, skip_serializing)]
+    pub amp: Option<String>,
+    /// The timeout of waiting for the sound card in seconds. It's parsed by sound_card_init.
+    #[serde(default, skip_serializing)]
+    pub card_wait_timeout_secs: Option<u64>,
+    /// The calibration settings of each amp channel.
     pub amp_calibrations: Vec<AmpCalibSettings>,
+    /// The path of the dsm_param.bin to apply before the calibration.
     pub dsm_param: String,
+    /// The sound card which exposes the amp controls, ex: sofcmlmax98390d. It is only needed
+    /// when the amps live on a different sound card than the playback. A substring of the
+    /// sound card longname or components is also accepted, ex: MAX98390.
+    #[serde(default)]
+    pub amp_card: Option<String>,
+    /// The settings of the post-calibration gain normalization.
+    #[serde(default)]
+    pub gain_normalization: Option<GainNormalizationSettings>,
+    /// The simple mixer element of the speaker path, ex: Speaker. Its playback switch is
+    /// turned on before the calibration if it's muted.
+    #[serde(default)]
+    pub speaker_mixer: Option<String>,
+    /// The settings of the runtime monitor.
+    #[serde(default)]
+    pub monitor: Option<MonitorSettings>,
+    /// The limits of the factory calibration.
+    #[serde(default)]
+    pub factory_limits: Option<FactoryLimits>,
+    /// The thresholds of the boot time calibration.
+    #[serde(default)]
+    pub thresholds: CalibThresholds,
+    /// The excitation played during the calibration measurement. It's played for
+    /// `playback_duration_ms` of the thresholds at most.
+    #[serde(default)]
+    pub excitation: Excitation,
+    /// The settings of the fleet telemetry. The measured values are not reported without it.
+    #[serde(default)]
+    pub telemetry: Option<TelemetrySettings>,
+    /// Measures all the amps in one playback, and reads their values in one batch, instead of
+    /// a playback per amp. It shortens the boot time calibration of the boards with many amps.
+    #[serde(default)]
+    pub batch_measurement: bool,
+}
+
+/// `TelemetrySettings` enables reporting the rdc and ambient temperature measured by the boot
+/// time calibration to UMA, so that the acceptance ranges can be tuned from the distribution of
+/// the fleet. The values are reported as the lower bounds of their buckets, and no device
+/// identifier is attached.
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TelemetrySettings {
+    /// The bucket width of rdc in the unit of `rdc_ctrl`.
+    pub rdc_bucket: i32,
+    /// The bucket width of the ambient temperature in the unit of `ambient_temp_ctrl`.
+    pub temp_bucket: i32,
 }
 
-/// `AmpCalibSettings` includes the settings needed for amplifier calibration.
-#[derive(Debug, Default, PartialEq, Deserialize, Clone)]
+impl TelemetrySettings {
+    /// Returns the lower bounds of the buckets of the rdc and the ambient temperature.
+    pub fn buckets_of(&self, rdc: i32, temp: i32) -> (i32, i32) {
+        (
+            bucket_of(rdc, self.rdc_bucket),
+            bucket_of(temp, self.temp_bucket),
+        )
+    }
+
+    // Checks that the bucket widths are positive.
+    fn validate(&self) -> Result<()> {
+        if self.rdc_bucket <= 0 || self.temp_bucket <= 0 {
+            return Err(Error::InvalidThresholds(
+                "telemetry bucket widths must be positive".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// `Excitation` is the stimulus played to the speakers during the calibration measurement,
+/// ex:
+///
+/// ```yaml
+/// excitation:
+///   type: sine
+///   frequency_hz: 20
+///   level_dbfs: -40
+/// ```
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Excitation {
+    /// Plays zeros.
+    #[default]
+    Silence,
+    /// Plays a sine wave on all the channels.
+    Sine {
+        /// The frequency in Hz, which is below the Nyquist frequency.
+        frequency_hz: f32,
+        /// The peak level in dBFS, which is at most 0.
+        level_dbfs: f32,
+    },
+    /// Plays the raw S16LE 48 kHz stereo PCM file in a loop.
+    File {
+        /// The path of the PCM file.
+        path: String,
+    },
+}
+
+/// `CalibThresholds` includes the thresholds of the boot time calibration flow. The omitted
+/// fields keep their defaults.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CalibThresholds {
+    /// The time since the CRAS shutdown after which the speakers are considered cool, so the
+    /// calibration can run, in seconds.
+    pub cool_down_secs: u64,
+    /// The relative rdc difference from the stored value above which the calibration values
+    /// are rejected.
+    pub rdc_diff_upper_limit: f32,
+    /// The relative rdc difference from the stored value below which the stored values are
+    /// kept.
+    pub rdc_diff_lower_limit: f32,
+    /// The time to wait for the zero playback to start in milliseconds.
+    pub playback_start_timeout_ms: u64,
+    /// The maximum duration of the zero playback in milliseconds.
+    pub playback_duration_ms: u32,
+    /// The zero playback time before the calibration starts in milliseconds, which lets
+    /// the speakers settle.
+    pub warm_up_duration_ms: u32,
+}
+
+impl Default for CalibThresholds {
+    fn default() -> Self {
+        CalibThresholds {
+            cool_down_secs: 180,
+            rdc_diff_upper_limit: 0.3,
+            rdc_diff_lower_limit: 0.03,
+            playback_start_timeout_ms: 1000,
+            playback_duration_ms: 1000,
+            warm_up_duration_ms: 300,
+        }
+    }
+}
+
+/// `FactoryLimits` includes the limits which the factory calibration values must be within.
+/// The rdc limits are in the unit of `rdc_ctrl`, and the temperature limits of the amps are
+/// also applied.
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FactoryLimits {
+    /// The lower limit of a valid rdc value.
+    pub rdc_min: i32,
+    /// The upper limit of a valid rdc value.
+    pub rdc_max: i32,
+}
+
+/// `MonitorSettings` includes the settings of the runtime monitor, which keeps checking the
+/// speaker temperature after the boot time calibration in the daemon mode.
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MonitorSettings {
+    /// The interval between two checks in seconds.
+    pub interval_secs: u64,
+    /// The speaker temperature above which the volume is put into protected mode.
+    pub hot_temp: i32,
+    /// The speaker temperature below which the volume is restored. It's lower than
+    /// `hot_temp` so that the volume does not toggle around a single threshold.
+    pub cool_temp: i32,
+    /// The millidegrees Celsius of a unit of the speaker temperature. If it's set, the speaker
+    /// temperatures are published to the hwmon-style feed for the platform thermal stack.
+    #[serde(default)]
+    pub temp_millicelsius: Option<i32>,
+}
+
+/// Converts a speaker temperature to millidegrees Celsius by the positive `temp_millicelsius`
+/// of the `MonitorSettings`. The result saturates at the range of i32, so the temperatures out
+/// of the range are still published as the hottest or the coldest ones.
+pub fn to_millicelsius(temp: i32, temp_millicelsius: i32) -> i32 {
+    temp.saturating_mul(temp_millicelsius)
+}
+
+// Returns the lower bound of the bucket of the value, which saturates at i32::MIN if the bound
+// is out of the range.
+fn bucket_of(value: i32, width: i32) -> i32 {
+    value.saturating_sub(value.rem_euclid(width))
+}
+
+/// `GainNormalizationSettings` includes the settings needed to normalize the loudness between
+/// channels after the calibration.
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GainNormalizationSettings {
+    /// File to store the gain offsets.
+    pub offset_file: String,
+    /// The gain control value of a channel without attenuation.
+    pub gain_default: i32,
+    /// The gain change of a control step in dB.
+    pub gain_step_db: f32,
+    /// The maximum number of steps a channel can be attenuated.
+    pub max_offset: i32,
+}
+
+/// `AmpCalibSettings` includes the settings needed for amplifier calibration. The optional
+/// limits of a channel override the limits of the device, ex: for the asymmetric speakers.
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct AmpCalibSettings {
     /// `AmpSettings`.
     pub amp: AmpSettings,
@@ -27,15 +253,28 @@ pub struct AmpCalibSettings {
     pub temp_vpd: String,
     /// File to store the boot time calibration values.
     pub calib_file: String,
+    /// The per-device vendor calibration file used to seed the datastore on first boot.
+    #[serde(default)]
+    pub vendor_calib_file: Option<String>,
+    /// Overrides `rdc_diff_upper_limit` of the `CalibThresholds` of the device.
+    #[serde(default)]
+    pub rdc_diff_upper_limit: Option<f32>,
+    /// Overrides `rdc_diff_lower_limit` of the `CalibThresholds` of the device.
+    #[serde(default)]
+    pub rdc_diff_lower_limit: Option<f32>,
+    /// Overrides the `FactoryLimits` of the device.
+    #[serde(default)]
+    pub factory_limits: Option<FactoryLimits>,
 }
 
 /// `AmpSettings` represents mixer control names and amp params needed for amplifier calibration.
-#[derive(Debug, Default, PartialEq, Deserialize, Clone)]
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct AmpSettings {
     // Mixer control to get/set rdc value.
     pub rdc_ctrl: String,
-    // Mixer control to get/set ambient temperature value.
-    pub temp_ctrl: String,
+    // Mixer control to get/set ambient temperature value. It's `temp_ctrl` before version 2.
+    pub ambient_temp_ctrl: String,
     // Mixer control to trigger calibration.
     pub calib_ctrl: String,
     // Mixer control to adjust volume.
@@ -48,13 +287,254 @@ pub struct AmpSettings {
     pub temp_upper_limit: i32,
     // The lower limit of a valid temperature value.
     pub temp_lower_limit: i32,
+    // Mixer control to adjust digital gain. It is required by gain normalization.
+    #[serde(default)]
+    pub gain_ctrl: Option<String>,
+    // Mixer control to read the speaker temperature estimated by the amp at runtime, in the
+    // same unit as the thresholds of `MonitorSettings`. It is required by the runtime monitor.
+    #[serde(default)]
+    pub status_temp_ctrl: Option<String>,
 }
 
 impl DeviceSettings {
+    /// Returns the `CalibThresholds` of the channel, which are the thresholds of the device
+    /// overridden by the channel.
+    pub fn thresholds_of(&self, setting: &AmpCalibSettings) -> CalibThresholds {
+        let mut thresholds = self.thresholds.clone();
+        if let Some(limit) = setting.rdc_diff_upper_limit {
+            thresholds.rdc_diff_upper_limit = limit;
+        }
+        if let Some(limit) = setting.rdc_diff_lower_limit {
+            thresholds.rdc_diff_lower_limit = limit;
+        }
+        thresholds
+    }
+
+    /// Returns the `FactoryLimits` of the channel, or None if neither the channel nor the
+    /// device has them.
+    pub fn factory_limits_of<'a>(
+        &'a self,
+        setting: &'a AmpCalibSettings,
+    ) -> Option<&'a FactoryLimits> {
+        setting
+            .factory_limits
+            .as_ref()
+            .or(self.factory_limits.as_ref())
+    }
+
     /// Creates a `DeviceSettings` from a yaml str.
+    ///
+    /// # Errors
+    ///
+    /// * If the config is malformed. The error has the path of the invalid field, ex:
+    ///   DeviceSettings.amp_calibrations[0].amp.volume_low_limit.
     pub fn from_yaml_str(conf: &str) -> Result<DeviceSettings> {
-        let settings: DeviceSettings = serde_yaml::from_str(conf)
-            .map_err(|e| Error::DeserializationFailed("DeviceSettings".to_owned(), e))?;
+        let migrated = migrate(conf)?;
+        let conf = migrated.as_deref().unwrap_or(conf);
+        let settings: DeviceSettings = serde_yaml::from_str(conf).map_err(|e| {
+            let path = match e.location().map(|loc| field_path(conf, loc.line())) {
+                Some(path) if !path.is_empty() => format!("DeviceSettings.{}", path),
+                _ => "DeviceSettings".to_owned(),
+            };
+            Error::DeserializationFailed(path, e)
+        })?;
+        for s in &settings.amp_calibrations {
+            settings.thresholds_of(s).validate()?;
+        }
+        if let Some(telemetry) = &settings.telemetry {
+            telemetry.validate()?;
+        }
         Ok(settings)
     }
 }
+
+impl CalibThresholds {
+    // Checks that the timeouts and the durations are usable, and the rdc difference limits
+    // are ordered.
+    fn validate(&self) -> Result<()> {
+        let invalid = |e: &str| Err(Error::InvalidThresholds(e.to_owned()));
+        if self.playback_start_timeout_ms == 0 {
+            return invalid("playback_start_timeout_ms must be positive");
+        }
+        if self.warm_up_duration_ms >= self.playback_duration_ms {
+            return invalid("warm_up_duration_ms must be less than playback_duration_ms");
+        }
+        if self.rdc_diff_lower_limit < 0.0 || self.rdc_diff_lower_limit > self.rdc_diff_upper_limit
+        {
+            return invalid("rdc_diff_lower_limit must be within 0 and rdc_diff_upper_limit");
+        }
+        Ok(())
+    }
+}
+
+fn first_config_version() -> u32 {
+    FIRST_CONFIG_VERSION
+}
+
+// Renames the deprecated fields of the older config versions. It's None if the config needs
+// no migration, which keeps the parse errors pointing to the lines of the config.
+fn migrate(conf: &str) -> Result<Option<String>> {
+    // The malformed configs are reported by the parser.
+    let mut value: Value = match serde_yaml::from_str(conf) {
+        Ok(value) => value,
+        Err(_) => return Ok(None),
+    };
+    let settings = match value.as_mapping_mut() {
+        Some(settings) => settings,
+        None => return Ok(None),
+    };
+    let version_key = Value::from("config_version");
+    let version = match settings.get(&version_key).and_then(Value::as_u64) {
+        Some(version) => version as u32,
+        None => FIRST_CONFIG_VERSION,
+    };
+    if version > CONFIG_VERSION {
+        return Err(Error::UnsupportedConfigVersion(version));
+    }
+    if version == CONFIG_VERSION {
+        return Ok(None);
+    }
+    let calibs = settings
+        .get_mut(&Value::from("amp_calibrations"))
+        .and_then(Value::as_sequence_mut);
+    for (i, calib) in calibs.into_iter().flatten().enumerate() {
+        let amp = match calib
+            .as_mapping_mut()
+            .and_then(|calib| calib.get_mut(&Value::from("amp")))
+            .and_then(Value::as_mapping_mut)
+        {
+            Some(amp) => amp,
+            None => continue,
+        };
+        for (since, old, new) in RENAMED_AMP_FIELDS.iter() {
+            if version >= *since {
+                continue;
+            }
+            if let Some(field) = amp.remove(&Value::from(*old)) {
+                warn!(
+                    "DeviceSettings.amp_calibrations[{}].amp.{} is deprecated since \
+                     config_version {}, use {} instead",
+                    i, old, since, new
+                );
+                amp.insert(Value::from(*new), field);
+            }
+        }
+    }
+    settings.insert(version_key, Value::from(CONFIG_VERSION));
+    serde_yaml::to_string(&value)
+        .map(Some)
+        .map_err(Error::SerializationFailed)
+}
+
+// A node on the field path.
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+// Returns the field path of the yaml line, ex: amp_calibrations[0].amp.rdc_ctrl, which is
+// resolved by the indentation of the keys and the list items above the line. It's only
+// used by the diagnostics, so it does not handle the flow style and the multiline scalars.
+fn field_path(conf: &str, line: usize) -> String {
+    // The indentation and the segment of the enclosing nodes.
+    let mut stack: Vec<(usize, Segment)> = Vec::new();
+    for text in conf.lines().take(line) {
+        let mut rest = text.trim_start();
+        if rest.is_empty() || rest.starts_with('#') {
+            continue;
+        }
+        let mut indent = text.len() - rest.len();
+        while rest == "-" || rest.starts_with("- ") {
+            while matches!(stack.last(), Some((i, _)) if *i > indent) {
+                stack.pop();
+            }
+            match stack.last_mut() {
+                Some((i, Segment::Index(idx))) if *i == indent => *idx += 1,
+                _ => stack.push((indent, Segment::Index(0))),
+            }
+            let item = rest[1..].trim_start();
+            indent += rest.len() - item.len();
+            rest = item;
+        }
+        if let Some((key, _)) = rest.split_once(':') {
+            while matches!(stack.last(), Some((i, _)) if *i >= indent) {
+                stack.pop();
+            }
+            stack.push((indent, Segment::Key(key.trim().to_owned())));
+        }
+    }
+    let mut path = String::new();
+    for (_, seg) in &stack {
+        match seg {
+            Segment::Key(key) if path.is_empty() => path.push_str(key),
+            Segment::Key(key) => path.push_str(&format!(".{}", key)),
+            Segment::Index(idx) => path.push_str(&format!("[{}]", idx)),
+        }
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn millicelsius_is_monotonic(a: i32, b: i32, scale in 1..=100_000i32) {
+            let (low, high) = if a <= b { (a, b) } else { (b, a) };
+            prop_assert!(to_millicelsius(low, scale) <= to_millicelsius(high, scale));
+        }
+
+        #[test]
+        fn millicelsius_round_trips(temp: i32, scale in 1..=100_000i32) {
+            match temp.checked_mul(scale) {
+                Some(_) => prop_assert_eq!(to_millicelsius(temp, scale) / scale, temp),
+                None if temp > 0 => prop_assert_eq!(to_millicelsius(temp, scale), i32::MAX),
+                None => prop_assert_eq!(to_millicelsius(temp, scale), i32::MIN),
+            }
+        }
+
+        #[test]
+        fn temp_bucket_contains_temp(temp: i32, width in 1..=10_000i32) {
+            let telemetry = TelemetrySettings { rdc_bucket: width, temp_bucket: width };
+            let (_, bucket) = telemetry.buckets_of(0, temp);
+            prop_assert!(bucket <= temp);
+            if bucket != i32::MIN {
+                prop_assert_eq!(bucket.rem_euclid(width), 0);
+                prop_assert!(temp - bucket < width);
+            }
+        }
+
+        #[test]
+        fn temp_buckets_are_monotonic(a: i32, b: i32, width in 1..=10_000i32) {
+            let telemetry = TelemetrySettings { rdc_bucket: width, temp_bucket: width };
+            let (low, high) = if a <= b { (a, b) } else { (b, a) };
+            prop_assert!(telemetry.buckets_of(0, low).1 <= telemetry.buckets_of(0, high).1);
+        }
+    }
+
+    #[test]
+    fn edge_temperatures() {
+        let telemetry = TelemetrySettings {
+            rdc_bucket: 1000,
+            temp_bucket: 3,
+        };
+        let cases = [
+            (0, 0),
+            (2, 0),
+            (-1, -3),
+            (-3, -3),
+            (i32::MAX, i32::MAX - 1),
+            (i32::MIN + 2, i32::MIN + 2),
+            (i32::MIN, i32::MIN),
+        ];
+        for (temp, bucket) in &cases {
+            assert_eq!(telemetry.buckets_of(0, *temp).1, *bucket, "{}", temp);
+        }
+        assert_eq!(to_millicelsius(-40, 1000), -40_000);
+        assert_eq!(to_millicelsius(i32::MAX, 2), i32::MAX);
+        assert_eq!(to_millicelsius(i32::MIN, 2), i32::MIN);
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It collects the calibration state and the live state of the amps for the `show` command and
+//! the speaker check.
+use std::fmt;
+use std::fs;
+use std::time::{Duration, UNIX_EPOCH};
+
+use cros_alsa::Card;
+use serde_json::{json, Value};
+use utils::datastore_dir;
+
+use crate::datastore::Datastore;
+use crate::settings::{AmpCalibSettings, DeviceSettings};
+use crate::vpd::VPD;
+
+/// `ChannelStatus` represents the calibration state of an amp channel.
+#[derive(Debug)]
+pub struct ChannelStatus {
+    /// The rdc control, which names the channel.
+    pub rdc_ctrl: String,
+    /// The current rdc control value.
+    pub rdc: Option<i32>,
+    /// The current ambient temperature control value.
+    pub ambient_temp: Option<i32>,
+    /// The calibration values stored in the datastore.
+    pub datastore: Option<Datastore>,
+    /// The unix time when the datastore was last updated.
+    pub datastore_updated: Option<Duration>,
+    /// The factory calibration values in VPD.
+    pub vpd: Option<VPD>,
+}
+
+/// `LiveStatus` represents the runtime state of an amp channel.
+#[derive(Debug)]
+pub struct LiveStatus {
+    /// The rdc control, which names the channel.
+    pub rdc_ctrl: String,
+    /// The speaker temperature read from `status_temp_ctrl`.
+    pub temp: Option<i32>,
+    /// The current volume control value.
+    pub volume: Option<i32>,
+    /// The volume is in protected mode.
+    pub protected: Option<bool>,
+}
+
+// Reads an integer control. It's None if the control can't be read.
+fn read(card: &mut Card, ctrl: &str) -> Option<i32> {
+    card.load_controls::<[i32; 1]>(&[ctrl])
+        .ok()
+        .map(|values| values[0][0])
+}
+
+impl ChannelStatus {
+    /// Collects the state of all the amp channels. The values which can't be read are None.
+    pub fn collect(card: &mut Card, snd_card: &str, settings: &DeviceSettings) -> Vec<Self> {
+        settings
+            .amp_calibrations
+            .iter()
+            .map(|s| ChannelStatus {
+                rdc_ctrl: s.amp.rdc_ctrl.clone(),
+                rdc: read(card, &s.amp.rdc_ctrl),
+                ambient_temp: read(card, &s.amp.ambient_temp_ctrl),
+                datastore: Datastore::from_file(snd_card, &s.calib_file).ok(),
+                datastore_updated: modified_time(snd_card, &s.calib_file),
+                vpd: VPD::from_file(&s.rdc_vpd, &s.temp_vpd).ok(),
+            })
+            .collect()
+    }
+
+    /// Returns the state in JSON. The provenance is where the calibration values applied at
+    /// boot come from: the datastore, the VPD, or none if there is no datastore yet. The fields
+    /// follow `CalibData` of sound_card_init/proto/speaker_status.proto.
+    pub fn to_json(&self) -> Value {
+        let (provenance, stored) = match &self.datastore {
+            Some(Datastore::DSM { rdc, ambient_temp }) => (
+                "datastore",
+                json!({ "rdc": rdc, "ambient_temp": ambient_temp }),
+            ),
+            Some(Datastore::UseVPD) => ("vpd", Value::Null),
+            None => ("none", Value::Null),
+        };
+        json!({
+            "rdc_ctrl": self.rdc_ctrl,
+            "current": { "rdc": self.rdc, "ambient_temp": self.ambient_temp },
+            "provenance": provenance,
+            "datastore": stored,
+            "datastore_updated": self.datastore_updated.map(|t| t.as_secs()),
+            "vpd": self.vpd.as_ref().map(|vpd| json!({
+                "rdc": vpd.dsm_calib_r0,
+                "ambient_temp": vpd.dsm_calib_temp,
+            })),
+        })
+    }
+}
+
+impl LiveStatus {
+    /// Collects the runtime state of all the amp channels. The values which can't be read are
+    /// None.
+    pub fn collect(card: &mut Card, amp_calibrations: &[AmpCalibSettings]) -> Vec<Self> {
+        amp_calibrations
+            .iter()
+            .map(|s| {
+                let volume = read(card, &s.amp.volume_ctrl);
+                LiveStatus {
+                    rdc_ctrl: s.amp.rdc_ctrl.clone(),
+                    temp: s
+                        .amp
+                        .status_temp_ctrl
+                        .as_ref()
+                        .and_then(|ctrl| read(card, ctrl)),
+                    volume,
+                    protected: volume.map(|v| v <= s.amp.volume_low_limit),
+                }
+            })
+            .collect()
+    }
+}
+
+impl LiveStatus {
+    /// Returns the runtime state in JSON. The values which can't be read are null.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "rdc_ctrl": self.rdc_ctrl,
+            "temp": self.temp,
+            "volume": self.volume,
+            "protected": self.protected,
+        })
+    }
+}
+
+impl fmt::Display for LiveStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = |v: Option<i32>| v.map_or("unknown".to_owned(), |v| v.to_string());
+        let protection = match self.protected {
+            Some(true) => "protected",
+            Some(false) => "normal",
+            None => "unknown",
+        };
+        write!(
+            f,
+            "{}: temp: {}, volume: {} ({})",
+            self.rdc_ctrl,
+            value(self.temp),
+            value(self.volume),
+            protection
+        )
+    }
+}
+
+fn modified_time(snd_card: &str, file: &str) -> Option<Duration> {
+    fs::metadata(datastore_dir(snd_card).join(file))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+}
+
+impl fmt::Display for ChannelStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = |v: Option<i32>| v.map_or("unknown".to_owned(), |v| v.to_string());
+        writeln!(f, "{}:", self.rdc_ctrl)?;
+        writeln!(
+            f,
+            "  current: rdc: {}, ambient_temp: {}",
+            value(self.rdc),
+            value(self.ambient_temp)
+        )?;
+        match &self.datastore {
+            Some(Datastore::DSM { rdc, ambient_temp }) => writeln!(
+                f,
+                "  datastore: rdc: {}, ambient_temp: {}",
+                rdc, ambient_temp
+            )?,
+            Some(Datastore::UseVPD) => writeln!(f, "  datastore: use VPD")?,
+            None => writeln!(f, "  datastore: none")?,
+        }
+        match &self.vpd {
+            Some(vpd) => write!(
+                f,
+                "  vpd: rdc: {}, ambient_temp: {}",
+                vpd.dsm_calib_r0, vpd.dsm_calib_temp
+            ),
+            None => write!(f, "  vpd: unavailable"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channels() -> Vec<ChannelStatus> {
+        vec![
+            ChannelStatus {
+                rdc_ctrl: "Left Rdc".to_owned(),
+                rdc: Some(27000),
+                ambient_temp: Some(1000),
+                datastore: Some(Datastore::DSM {
+                    rdc: 27000,
+                    ambient_temp: 1000,
+                }),
+                datastore_updated: Some(Duration::from_secs(1_600_000_000)),
+                vpd: Some(VPD {
+                    dsm_calib_r0: 26500,
+                    dsm_calib_temp: 980,
+                }),
+            },
+            ChannelStatus {
+                rdc_ctrl: "Right Rdc".to_owned(),
+                rdc: Some(26500),
+                ambient_temp: Some(980),
+                datastore: Some(Datastore::UseVPD),
+                datastore_updated: Some(Duration::from_secs(1_500_000_000)),
+                vpd: Some(VPD {
+                    dsm_calib_r0: 26500,
+                    dsm_calib_temp: 980,
+                }),
+            },
+            ChannelStatus {
+                rdc_ctrl: "Center Rdc".to_owned(),
+                rdc: None,
+                ambient_temp: None,
+                datastore: None,
+                datastore_updated: None,
+                vpd: None,
+            },
+        ]
+    }
+
+    fn live() -> Vec<LiveStatus> {
+        vec![
+            LiveStatus {
+                rdc_ctrl: "Left Rdc".to_owned(),
+                temp: Some(45),
+                volume: Some(138),
+                protected: Some(false),
+            },
+            LiveStatus {
+                rdc_ctrl: "Right Rdc".to_owned(),
+                temp: Some(101),
+                volume: Some(100),
+                protected: Some(true),
+            },
+            LiveStatus {
+                rdc_ctrl: "Center Rdc".to_owned(),
+                temp: None,
+                volume: None,
+                protected: None,
+            },
+        ]
+    }
+
+    fn join<T: fmt::Display>(statuses: &[T]) -> String {
+        statuses
+            .iter()
+            .map(|status| status.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn show() {
+        insta::assert_snapshot!(join(&channels()));
+    }
+
+    #[test]
+    fn show_json() {
+        let json: Value = channels().iter().map(ChannelStatus::to_json).collect();
+        insta::assert_snapshot!(serde_json::to_string_pretty(&json).unwrap());
+    }
+
+    #[test]
+    fn live_status() {
+        insta::assert_snapshot!(join(&live()));
+    }
+
+    #[test]
+    fn live_status_json() {
+        let json: Value = live().iter().map(LiveStatus::to_json).collect();
+        insta::assert_snapshot!(serde_json::to_string_pretty(&json).unwrap());
+    }
+}
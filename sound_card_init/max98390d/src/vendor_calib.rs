@@ -0,0 +1,92 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+use std::fs::File;
+use std::io::{prelude::*, BufReader};
+
+use crate::datastore::Datastore;
+use crate::error::{Error, Result};
+
+// The keys of the calibration values in the vendor calibration file.
+const RDC_KEY: &str = "dsm_calib_r0";
+const TEMP_KEY: &str = "dsm_calib_temp";
+
+/// `VendorCalib`, which represents the calibration values in the per-device vendor
+/// calibration file shipped by ODMs.
+///
+/// The vendor calibration file consists of `key=value` lines. Lines starting with `#` are
+/// comments, and the values are either decimal or `0x` prefixed hexadecimal integers, ex:
+///
+/// ```text
+/// # max98390d left channel
+/// dsm_calib_r0=0x1a2b3
+/// dsm_calib_temp=2048
+/// ```
+#[derive(Default, Debug)]
+pub struct VendorCalib {
+    /// dsm_calib_r0 is (11 / 3) / actual_rdc * 2^20.
+    pub dsm_calib_r0: i32,
+    /// dsm_calib_temp is actual_temp * 2^12 / 100.
+    pub dsm_calib_temp: i32,
+}
+
+impl VendorCalib {
+    /// Creates a `VendorCalib` and initializes its fields from the given vendor calibration file.
+    ///
+    /// # Errors
+    ///
+    /// * If the file cannot be read.
+    /// * If the value of a key is not a valid integer.
+    /// * If any calibration value is missing in the file.
+    pub fn from_file(file: &str) -> Result<VendorCalib> {
+        let io_err = |e| Error::FileIOFailed(file.to_owned(), e);
+        let reader = BufReader::new(File::open(file).map_err(io_err)?);
+
+        let mut rdc = None;
+        let mut temp = None;
+        for line in reader.lines() {
+            let line = line.map_err(io_err)?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut kv = line.splitn(2, '=');
+            let key = kv.next().unwrap_or_default().trim();
+            let value = match kv.next() {
+                Some(v) => v.trim(),
+                None => return Err(Error::InvalidVendorCalib(file.to_owned())),
+            };
+            match key {
+                RDC_KEY => rdc = Some(parse_int(file, value)?),
+                TEMP_KEY => temp = Some(parse_int(file, value)?),
+                // Ignores the vendor specific values which are not used by the calibration.
+                _ => continue,
+            }
+        }
+
+        match (rdc, temp) {
+            (Some(dsm_calib_r0), Some(dsm_calib_temp)) => Ok(VendorCalib {
+                dsm_calib_r0,
+                dsm_calib_temp,
+            }),
+            _ => Err(Error::InvalidVendorCalib(file.to_owned())),
+        }
+    }
+}
+
+impl From<VendorCalib> for Datastore {
+    fn from(calib: VendorCalib) -> Datastore {
+        Datastore::DSM {
+            rdc: calib.dsm_calib_r0,
+            ambient_temp: calib.dsm_calib_temp,
+        }
+    }
+}
+
+fn parse_int(file: &str, value: &str) -> Result<i32> {
+    let res = match value.strip_prefix("0x") {
+        Some(hex) => i32::from_str_radix(hex, 16),
+        None => value.parse::<i32>(),
+    };
+    res.map_err(|e| Error::VendorCalibParseFailed(file.to_owned(), e))
+}
@@ -4,11 +4,16 @@
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufReader;
+use std::num::ParseIntError;
 use std::path::PathBuf;
+use std::process::Command;
 
 use crate::error::{Error, Result};
 
 const VPD_DIR: &str = "/sys/firmware/vpd/ro/vpdfile";
+// The utility to write the VPD, and the partition of the factory calibration values.
+const VPD_UTIL: &str = "vpd";
+const VPD_PARTITION: &str = "RO_VPD";
 
 /// `VPD`, which represents the amplifier factory calibration values.
 #[derive(Default, Debug)]
@@ -27,6 +32,23 @@ impl VPD {
         vpd.dsm_calib_temp = read_vpd_files(temp_file)?;
         Ok(vpd)
     }
+
+    /// Writes the values to the given VPD keys, which are the names of the VPD files. The VPD
+    /// files are updated on the next boot.
+    pub fn save(&self, rdc_key: &str, temp_key: &str) -> Result<()> {
+        let status = Command::new(VPD_UTIL)
+            .args(["-i", VPD_PARTITION])
+            .arg("-s")
+            .arg(format!("{}={}", rdc_key, self.dsm_calib_r0))
+            .arg("-s")
+            .arg(format!("{}={}", temp_key, self.dsm_calib_temp))
+            .status()
+            .map_err(|e| Error::VPDWriteFailed(e.to_string()))?;
+        if !status.success() {
+            return Err(Error::VPDWriteFailed(format!("{} {}", VPD_UTIL, status)));
+        }
+        Ok(())
+    }
 }
 
 fn read_vpd_files(file: &str) -> Result<i32> {
@@ -35,6 +57,50 @@ fn read_vpd_files(file: &str) -> Result<i32> {
     let mut reader = BufReader::new(File::open(&path).map_err(io_err)?);
     let mut line = String::new();
     reader.read_line(&mut line).map_err(io_err)?;
-    line.parse::<i32>()
-        .map_err(|e| Error::VPDParseFailed(path.to_string_lossy().to_string(), e))
+    parse_vpd_value(&line).map_err(|e| Error::VPDParseFailed(path.to_string_lossy().to_string(), e))
+}
+
+// Parses a VPD value in the formats written by the factories: decimal or `0x` hex, with the
+// surrounding whitespace. The `vpd` utility keeps the trailing newline of the values written
+// from files, and some factory scripts pad the values or write them in hex.
+fn parse_vpd_value(value: &str) -> std::result::Result<i32, ParseIntError> {
+    let value = value.trim();
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => i32::from_str_radix(hex, 16),
+        None => value.parse::<i32>(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_factory_formats() {
+        let cases = [
+            ("13000", Some(13000)),
+            ("-1700", Some(-1700)),
+            ("13000\n", Some(13000)),
+            ("  13000  ", Some(13000)),
+            ("\t13000\r\n", Some(13000)),
+            ("0x32c8", Some(13000)),
+            ("0X32C8", Some(13000)),
+            ("0x32c8\n", Some(13000)),
+            ("0x7fffffff", Some(i32::MAX)),
+            ("-2147483648", Some(i32::MIN)),
+            ("1.3e4", None),
+            ("", None),
+            ("0x", None),
+            ("-0x32c8", None),
+            ("13 000", None),
+            ("0x80000000", None),
+            ("2147483648", None),
+        ];
+        for (input, expected) in &cases {
+            assert_eq!(parse_vpd_value(input).ok(), *expected, "{:?}", input);
+        }
+    }
 }
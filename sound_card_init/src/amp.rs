@@ -0,0 +1,566 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It defines the `Amp` trait and the amplifiers supported by sound_card_init.
+use std::error;
+
+use cros_alsa::Card;
+use log::{error, info};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use serde_yaml::Mapping;
+use utils::RunOptions;
+
+use max98390d::{
+    effective_config_max98390d, factory_apply_max98390d, factory_calibrate_max98390d,
+    factory_measure_max98390d, live_status_max98390d, monitor_max98390d, open_amp_card,
+    reset_max98390d, run_max98390d_with_card, self_test_max98390d, set_safe_state_max98390d,
+    show_max98390d, show_max98390d_json, snapshot_max98390d, speaker_check_max98390d,
+    validate_max98390d, DeviceSettings,
+};
+
+#[cfg(feature = "fake-amp")]
+use crate::fake_amp::FakeAmp;
+#[cfg(any(test, feature = "mock-amp"))]
+use crate::mock_amp::MockAmp;
+use crate::{Error, Result};
+
+/// The amplifier types supported by sound_card_init. The values are reported to UMA and must
+/// not be renumbered.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AmpType {
+    /// Maxim max98390d smart amps.
+    Max98390d = 0,
+    /// Boards without smart amps.
+    NoAmp = 1,
+    /// The scripted amp of the `mock-amp` feature, which is never reported from the field.
+    #[cfg(any(test, feature = "mock-amp"))]
+    Mock = 2,
+    /// The simulated amp of the `fake-amp` feature for the VM images, which is never reported
+    /// from the field.
+    #[cfg(feature = "fake-amp")]
+    Fake = 3,
+}
+
+/// `AmpDriver` registers the driver of an `AmpType`.
+struct AmpDriver {
+    amp_type: AmpType,
+    // The driver name used by `--amp` and the `amp` field of the config. It's also the key of
+    // the driver section in the config.
+    name: &'static str,
+    // Creates the `Amp` from the driver config, which is parsed into the config type of the
+    // driver.
+    new: NewAmp,
+}
+
+// Creates the `Amp` of a sound card from the driver config.
+type NewAmp = fn(&str, &str) -> std::result::Result<Box<dyn Amp>, Box<dyn error::Error>>;
+
+// The registry of the amp drivers.
+const AMP_DRIVERS: &[AmpDriver] = &[
+    AmpDriver {
+        amp_type: AmpType::Max98390d,
+        name: "max98390d",
+        new: Max98390d::from_config,
+    },
+    AmpDriver {
+        amp_type: AmpType::NoAmp,
+        name: "none",
+        new: NoAmp::from_config,
+    },
+    #[cfg(any(test, feature = "mock-amp"))]
+    AmpDriver {
+        amp_type: AmpType::Mock,
+        name: "mock",
+        new: MockAmp::from_config,
+    },
+    #[cfg(feature = "fake-amp")]
+    AmpDriver {
+        amp_type: AmpType::Fake,
+        name: "fake",
+        new: FakeAmp::from_config,
+    },
+];
+
+impl AmpType {
+    /// The number of the amplifier types reported to UMA, which excludes `Mock` and `Fake`.
+    pub const COUNT: i32 = 2;
+
+    /// Returns the `AmpType` of the name used by the `amp` field of the config, ex: max98390d.
+    pub fn from_name(name: &str) -> Option<AmpType> {
+        AMP_DRIVERS
+            .iter()
+            .find(|driver| driver.name == name)
+            .map(|driver| driver.amp_type)
+    }
+
+    /// Returns the driver name, ex: max98390d.
+    pub fn name(self) -> &'static str {
+        self.driver().name
+    }
+
+    fn driver(self) -> &'static AmpDriver {
+        // Every `AmpType` is registered.
+        AMP_DRIVERS
+            .iter()
+            .find(|driver| driver.amp_type == self)
+            .expect("unregistered amp type")
+    }
+
+    /// Returns the `AmpType` of the speaker amp name in cros_config, ex: MAX98390.
+    pub fn from_speaker_amp(name: &str) -> Option<AmpType> {
+        if name.to_lowercase().starts_with("max98390") {
+            return Some(AmpType::Max98390d);
+        }
+        None
+    }
+}
+
+/// The amp selection in CONF_DIR/<sound_card_id>.yaml. The config of the driver is either the
+/// section named by the driver, ex: `max98390d:`, or the whole config for the configs without
+/// driver sections.
+#[derive(Debug, Default, Deserialize)]
+struct AmpConfig {
+    #[serde(default)]
+    amp: Option<String>,
+    #[serde(flatten)]
+    sections: Mapping,
+}
+
+/// `SafeState` puts the amplifiers into a safe state when sound_card_init is aborted.
+pub trait SafeState {
+    /// Puts the amplifiers into the safe state, ex: the protected volume.
+    fn apply(&mut self) -> std::result::Result<(), Box<dyn error::Error>>;
+}
+
+/// Opens the `SafeState` of the amplifiers. It's called in the watchdog thread, so that the
+/// watchdog has its own sound card handles.
+pub type SafeStateOpener =
+    Box<dyn FnOnce() -> std::result::Result<Box<dyn SafeState>, Box<dyn error::Error>> + Send>;
+
+// The `SafeState` of the amplifiers without a safe state to restore.
+struct NoSafeState;
+
+impl SafeState for NoSafeState {
+    fn apply(&mut self) -> std::result::Result<(), Box<dyn error::Error>> {
+        Ok(())
+    }
+}
+
+/// It defines the required functions of the amplifiers supported by sound_card_init.
+pub trait Amp {
+    /// Returns the type of the amplifiers.
+    fn amp_type(&self) -> AmpType;
+
+    /// Opens the sound card handles needed by the calibration. It's called before
+    /// sound_card_init drops its privileges.
+    fn open_card(&mut self) -> std::result::Result<(), Box<dyn error::Error>> {
+        Ok(())
+    }
+
+    /// Performs the boot time calibration of the amplifiers with the `RunOptions`.
+    fn boot_time_calibration(
+        &mut self,
+        opts: &RunOptions,
+    ) -> std::result::Result<(), Box<dyn error::Error>>;
+
+    /// Returns the `SafeStateOpener` of the amplifiers. It's called before sound_card_init
+    /// drops its privileges.
+    fn safe_state_opener(&self) -> SafeStateOpener {
+        Box::new(|| Ok(Box::new(NoSafeState)))
+    }
+
+    /// Keeps monitoring the amplifiers after the boot time calibration in the daemon mode.
+    /// It returns when there is nothing to monitor. `reload` returns the new driver config if
+    /// the config has changed, see `driver_config()`.
+    fn monitor(
+        &mut self,
+        _opts: &RunOptions,
+        _reload: &mut dyn FnMut() -> Option<String>,
+    ) -> std::result::Result<(), Box<dyn error::Error>> {
+        Ok(())
+    }
+
+    /// Validates the config against the sound card without touching the amplifiers.
+    fn validate(&mut self) -> std::result::Result<(), Box<dyn error::Error>>;
+
+    /// Reports the calibration state of the amplifiers.
+    fn show(&mut self) -> std::result::Result<String, Box<dyn error::Error>>;
+
+    /// Reports the runtime state of the amplifiers, ex: the speaker temperature. It's called
+    /// repeatedly by `show --watch`.
+    fn live_status(&mut self) -> std::result::Result<String, Box<dyn error::Error>>;
+
+    /// Reports the calibration state of the amplifier channels as a JSON array.
+    fn show_json(&mut self) -> std::result::Result<Value, Box<dyn error::Error>>;
+
+    /// Snapshots the amp controls and the stored calibration values on the sound card handles
+    /// opened by `open_card()` for the anomaly report. It's Null if there is nothing to
+    /// snapshot.
+    fn snapshot(&mut self) -> std::result::Result<Value, Box<dyn error::Error>> {
+        Ok(Value::Null)
+    }
+
+    /// Returns the number of the amplifier channels in the config.
+    fn channel_count(&self) -> std::result::Result<usize, Box<dyn error::Error>> {
+        Ok(0)
+    }
+
+    /// Returns the driver config in effect, including the defaults of the omitted fields. It's
+    /// Null if the driver has no config.
+    fn effective_config(
+        &mut self,
+    ) -> std::result::Result<serde_yaml::Value, Box<dyn error::Error>> {
+        Ok(serde_yaml::Value::Null)
+    }
+
+    /// Removes the stored calibration values of the channel, or of all the channels if
+    /// `channel` is None.
+    fn reset(&mut self, channel: Option<usize>) -> std::result::Result<(), Box<dyn error::Error>>;
+
+    /// Checks that the calibration can run without writing the amplifiers or the datastore, and
+    /// returns the checks as a JSON array. Each check has `result` of PASS or FAIL.
+    fn self_test(&mut self) -> std::result::Result<Value, Box<dyn error::Error>>;
+
+    /// Checks the health of the speakers on demand for the speaker check routine of
+    /// cros_healthd. It returns the `checks` like `self_test()`, and the `live` readouts of the
+    /// amplifier channels as a JSON array.
+    fn speaker_check(&mut self) -> std::result::Result<Value, Box<dyn error::Error>> {
+        Ok(json!({ "checks": self.self_test()?, "live": [] }))
+    }
+
+    /// Performs the factory calibration and returns the calibration values of the amplifier
+    /// channels as a JSON array.
+    fn factory_calibrate(&mut self) -> std::result::Result<Value, Box<dyn error::Error>>;
+
+    /// Runs the measurement of the factory calibration without writing the values, and returns
+    /// the measurement of the amplifier channels as a JSON array. Each channel has `result` of
+    /// PASS or FAIL.
+    fn factory_measure(&mut self) -> std::result::Result<Value, Box<dyn error::Error>> {
+        Ok(json!([]))
+    }
+
+    /// Writes the factory calibration values, the rdc and ambient temperature of each
+    /// amplifier channel, after checking them against the factory limits.
+    fn factory_apply(
+        &mut self,
+        _values: &[(i32, i32)],
+    ) -> std::result::Result<(), Box<dyn error::Error>> {
+        Ok(())
+    }
+}
+
+/// Creates the `Amp` of the sound card.
+///
+/// The amp is selected by `amp_override`, by the `amp` field of the config, or by `board_amp`
+/// from cros_config in order. If none is specified, the amp is selected by the sound card
+/// name or the codecs of the sound card.
+///
+/// # Errors
+///
+/// * If the config is not valid yaml.
+/// * If the amp in the config is unknown.
+/// * If the config of the driver is invalid.
+/// * If the sound card cannot be opened.
+/// * If the sound card is not supported.
+pub fn new_amp(
+    snd_card: &str,
+    conf: &str,
+    amp_override: Option<AmpType>,
+    board_amp: Option<AmpType>,
+) -> std::result::Result<Box<dyn Amp>, Box<dyn error::Error>> {
+    let amp_conf: AmpConfig = serde_yaml::from_str(conf).map_err(Error::ParseConfigFailed)?;
+    let amp_type = match amp_override.or(conf_amp(&amp_conf)?).or(board_amp) {
+        Some(amp_type) => amp_type,
+        None => detect_amp(snd_card)?,
+    };
+    if amp_override.is_some() {
+        info!("amp is overridden to {:?}", amp_type);
+    }
+
+    (amp_type.driver().new)(snd_card, &section(&amp_conf, conf, amp_type)?)
+}
+
+/// Checks the config without the sound card, ex: in the ebuild. The configs without `amp` are
+/// checked as max98390d configs, which is the only driver with a config.
+///
+/// # Errors
+///
+/// * If the config is not valid yaml.
+/// * If the amp in the config is unknown.
+/// * If the config of the driver is invalid.
+pub fn check_config(snd_card: &str, conf: &str) -> std::result::Result<(), Box<dyn error::Error>> {
+    let amp_conf: AmpConfig = serde_yaml::from_str(conf).map_err(Error::ParseConfigFailed)?;
+    let amp_type = conf_amp(&amp_conf)?.unwrap_or(AmpType::Max98390d);
+    (amp_type.driver().new)(snd_card, &section(&amp_conf, conf, amp_type)?)?;
+    Ok(())
+}
+
+// Returns the amp selected by the config.
+fn conf_amp(amp_conf: &AmpConfig) -> Result<Option<AmpType>> {
+    match &amp_conf.amp {
+        Some(name) => AmpType::from_name(name)
+            .map(Some)
+            .ok_or_else(|| Error::UnknownAmp(name.clone())),
+        None => Ok(None),
+    }
+}
+
+/// Returns the config of the amp driver, which is either the section named by the driver or
+/// the whole config.
+///
+/// # Errors
+///
+/// * If the config is not valid yaml.
+pub fn driver_config(conf: &str, amp_type: AmpType) -> Result<String> {
+    let amp_conf: AmpConfig = serde_yaml::from_str(conf).map_err(Error::ParseConfigFailed)?;
+    section(&amp_conf, conf, amp_type)
+}
+
+// The whole config is returned as is, so that the parse errors keep pointing to its lines.
+fn section(amp_conf: &AmpConfig, conf: &str, amp_type: AmpType) -> Result<String> {
+    match amp_conf
+        .sections
+        .get(&serde_yaml::Value::from(amp_type.driver().name))
+    {
+        Some(section) => serde_yaml::to_string(section).map_err(Error::ParseConfigFailed),
+        None => Ok(conf.to_owned()),
+    }
+}
+
+// Selects the amp by the sound card name, or by the codecs in the components of the sound
+// card, which distinguishes the variants of the same board, ex: max98390 and max98396.
+fn detect_amp(snd_card: &str) -> Result<AmpType> {
+    if snd_card == "sofcmlmax98390d" {
+        return Ok(AmpType::Max98390d);
+    }
+    let card = Card::new(snd_card).map_err(Error::OpenCardFailed)?;
+    let info = card.info();
+    info!(
+        "sound card driver: {}, components: {}",
+        info.driver, info.components
+    );
+    if info.components.to_lowercase().contains("max98390") {
+        return Ok(AmpType::Max98390d);
+    }
+    Err(Error::UnsupportedSoundCard(snd_card.to_owned()))
+}
+
+// The `SafeState` of max98390d, which sets the volume of all the amps low.
+struct Max98390dSafeState {
+    conf: String,
+    card: Card,
+}
+
+impl SafeState for Max98390dSafeState {
+    fn apply(&mut self) -> std::result::Result<(), Box<dyn error::Error>> {
+        set_safe_state_max98390d(&mut self.card, &self.conf)?;
+        Ok(())
+    }
+}
+
+/// `Max98390d` performs the boot time calibration of max98390d.
+struct Max98390d {
+    snd_card: String,
+    conf: String,
+    // The amp sound card opened by `open_card()`.
+    card: Option<Card>,
+}
+
+impl Max98390d {
+    // The config is parsed into `DeviceSettings` up front, so that a malformed config fails
+    // before any command runs.
+    fn from_config(
+        snd_card: &str,
+        conf: &str,
+    ) -> std::result::Result<Box<dyn Amp>, Box<dyn error::Error>> {
+        DeviceSettings::from_yaml_str(conf)?;
+        Ok(Box::new(Max98390d {
+            snd_card: snd_card.to_owned(),
+            conf: conf.to_owned(),
+            card: None,
+        }))
+    }
+}
+
+impl Amp for Max98390d {
+    fn amp_type(&self) -> AmpType {
+        AmpType::Max98390d
+    }
+
+    fn open_card(&mut self) -> std::result::Result<(), Box<dyn error::Error>> {
+        self.card = Some(open_amp_card(&self.snd_card, &self.conf)?);
+        Ok(())
+    }
+
+    fn boot_time_calibration(
+        &mut self,
+        opts: &RunOptions,
+    ) -> std::result::Result<(), Box<dyn error::Error>> {
+        if self.card.is_none() {
+            self.card = Some(open_amp_card(&self.snd_card, &self.conf)?);
+        }
+        if let Some(card) = self.card.as_mut() {
+            if opts.trace_file.is_some() {
+                card.start_recording();
+            }
+            let res = run_max98390d_with_card(card, &self.snd_card, &self.conf, opts);
+            // The trace of a failed calibration is the most useful one, so it's saved anyway.
+            if let Some(file) = &opts.trace_file {
+                match card.stop_recording().save(file) {
+                    Ok(()) => info!("recorded the control accesses to {}", file.display()),
+                    Err(e) => error!("failed to save the trace: {}", e),
+                }
+            }
+            res?;
+        }
+        info!("run_max98390d() finished successfully.");
+        Ok(())
+    }
+
+    fn snapshot(&mut self) -> std::result::Result<Value, Box<dyn error::Error>> {
+        Ok(match self.card.as_mut() {
+            Some(card) => snapshot_max98390d(card, &self.snd_card, &self.conf)?,
+            None => Value::Null,
+        })
+    }
+
+    fn safe_state_opener(&self) -> SafeStateOpener {
+        let snd_card = self.snd_card.clone();
+        let conf = self.conf.clone();
+        Box::new(move || {
+            let card = open_amp_card(&snd_card, &conf)?;
+            Ok(Box::new(Max98390dSafeState { conf, card }) as Box<dyn SafeState>)
+        })
+    }
+
+    fn monitor(
+        &mut self,
+        opts: &RunOptions,
+        reload: &mut dyn FnMut() -> Option<String>,
+    ) -> std::result::Result<(), Box<dyn error::Error>> {
+        // The card opened before dropping privileges is reused.
+        if let Some(card) = self.card.as_mut() {
+            monitor_max98390d(card, &self.snd_card, &self.conf, opts, reload)?;
+        }
+        Ok(())
+    }
+
+    fn validate(&mut self) -> std::result::Result<(), Box<dyn error::Error>> {
+        Ok(validate_max98390d(&self.snd_card, &self.conf)?)
+    }
+
+    fn show(&mut self) -> std::result::Result<String, Box<dyn error::Error>> {
+        Ok(show_max98390d(&self.snd_card, &self.conf)?)
+    }
+
+    fn live_status(&mut self) -> std::result::Result<String, Box<dyn error::Error>> {
+        // Keeps the card open between the refreshes.
+        if self.card.is_none() {
+            self.card = Some(open_amp_card(&self.snd_card, &self.conf)?);
+        }
+        match self.card.as_mut() {
+            Some(card) => Ok(live_status_max98390d(card, &self.conf)?),
+            None => Ok(String::new()),
+        }
+    }
+
+    fn show_json(&mut self) -> std::result::Result<Value, Box<dyn error::Error>> {
+        Ok(show_max98390d_json(&self.snd_card, &self.conf)?)
+    }
+
+    fn effective_config(
+        &mut self,
+    ) -> std::result::Result<serde_yaml::Value, Box<dyn error::Error>> {
+        Ok(effective_config_max98390d(&self.conf)?)
+    }
+
+    fn channel_count(&self) -> std::result::Result<usize, Box<dyn error::Error>> {
+        Ok(DeviceSettings::from_yaml_str(&self.conf)?
+            .amp_calibrations
+            .len())
+    }
+
+    fn reset(&mut self, channel: Option<usize>) -> std::result::Result<(), Box<dyn error::Error>> {
+        Ok(reset_max98390d(&self.snd_card, &self.conf, channel)?)
+    }
+
+    fn self_test(&mut self) -> std::result::Result<Value, Box<dyn error::Error>> {
+        Ok(self_test_max98390d(&self.snd_card, &self.conf)?)
+    }
+
+    fn speaker_check(&mut self) -> std::result::Result<Value, Box<dyn error::Error>> {
+        Ok(speaker_check_max98390d(&self.snd_card, &self.conf)?)
+    }
+
+    fn factory_calibrate(&mut self) -> std::result::Result<Value, Box<dyn error::Error>> {
+        Ok(factory_calibrate_max98390d(&self.snd_card, &self.conf)?)
+    }
+
+    fn factory_measure(&mut self) -> std::result::Result<Value, Box<dyn error::Error>> {
+        Ok(factory_measure_max98390d(&self.snd_card, &self.conf)?)
+    }
+
+    fn factory_apply(
+        &mut self,
+        values: &[(i32, i32)],
+    ) -> std::result::Result<(), Box<dyn error::Error>> {
+        Ok(factory_apply_max98390d(&self.snd_card, &self.conf, values)?)
+    }
+}
+
+/// `NoAmp` is used by the boards without smart amps. There is no calibration needed, and
+/// sound_card_init only performs its other duties.
+struct NoAmp;
+
+impl NoAmp {
+    // The boards without smart amps have nothing to configure.
+    fn from_config(
+        _snd_card: &str,
+        _conf: &str,
+    ) -> std::result::Result<Box<dyn Amp>, Box<dyn error::Error>> {
+        Ok(Box::new(NoAmp))
+    }
+}
+
+impl Amp for NoAmp {
+    fn amp_type(&self) -> AmpType {
+        AmpType::NoAmp
+    }
+
+    fn boot_time_calibration(
+        &mut self,
+        _opts: &RunOptions,
+    ) -> std::result::Result<(), Box<dyn error::Error>> {
+        info!("no smart amp, skip boot time calibration.");
+        Ok(())
+    }
+
+    fn validate(&mut self) -> std::result::Result<(), Box<dyn error::Error>> {
+        Ok(())
+    }
+
+    fn show(&mut self) -> std::result::Result<String, Box<dyn error::Error>> {
+        Ok("no smart amp".to_owned())
+    }
+
+    fn live_status(&mut self) -> std::result::Result<String, Box<dyn error::Error>> {
+        Ok("no smart amp".to_owned())
+    }
+
+    fn show_json(&mut self) -> std::result::Result<Value, Box<dyn error::Error>> {
+        Ok(json!([]))
+    }
+
+    fn reset(&mut self, _channel: Option<usize>) -> std::result::Result<(), Box<dyn error::Error>> {
+        Ok(())
+    }
+
+    fn self_test(&mut self) -> std::result::Result<Value, Box<dyn error::Error>> {
+        Ok(json!([]))
+    }
+
+    fn factory_calibrate(&mut self) -> std::result::Result<Value, Box<dyn error::Error>> {
+        Ok(json!([]))
+    }
+}
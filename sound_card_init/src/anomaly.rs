@@ -0,0 +1,81 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It writes an anomaly report to the crash report spool when the boot time calibration keeps
+//! failing, so that the failures surface through the feedback reports.
+use std::error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::SystemTime;
+
+use serde_json::{json, Value};
+
+/// The crash report spool collected by crash_sender.
+pub const CRASH_SPOOL_DIR: &str = "/var/spool/crash";
+/// The number of the consecutive failed boot time calibrations which triggers the report.
+pub const FAILURE_THRESHOLD: u32 = 3;
+const EXEC_NAME: &str = "sound_card_init";
+
+/// The alerts raised by the anomaly reports.
+#[derive(Debug, Clone, Copy)]
+pub enum Alert {
+    /// The boot time calibration failed `FAILURE_THRESHOLD` times in a row.
+    RepeatedFailures,
+    /// The boot time calibration is disabled and the amps are left in the safe state.
+    SafeMode,
+}
+
+impl Alert {
+    fn name(self) -> &'static str {
+        match self {
+            Alert::RepeatedFailures => "repeated_failures",
+            Alert::SafeMode => "safe_mode",
+        }
+    }
+}
+
+/// Writes the anomaly report of the alert of the sound card, which has the structured error, the
+/// number of the consecutive failures and the snapshot of the amp controls and the datastore. It
+/// returns the path of the report.
+///
+/// # Errors
+///
+/// * If the report can't be written.
+pub fn write_report(
+    snd_card: &str,
+    alert: Alert,
+    err: &(dyn error::Error + 'static),
+    failures: u32,
+    snapshot: Value,
+) -> io::Result<PathBuf> {
+    let report = json!({
+        "sound_card_id": snd_card,
+        "alert": alert.name(),
+        "failures": failures,
+        "error": crate::error::error_report(err),
+        "snapshot": snapshot,
+    });
+
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |t| t.as_secs());
+    let base = format!("{}.{}.{}.{}", EXEC_NAME, snd_card, time, process::id());
+    let spool = Path::new(CRASH_SPOOL_DIR);
+    let log = spool.join(&base).with_extension("log");
+    fs::write(&log, report.to_string())?;
+    // crash_sender only picks up the report after the meta file is complete, so it's written
+    // last.
+    let meta = format!(
+        "exec_name={}\nsig={}: {}: {}\npayload={}\ndone=1\n",
+        EXEC_NAME,
+        snd_card,
+        alert.name(),
+        err,
+        log.file_name()
+            .map_or_else(String::new, |name| name.to_string_lossy().to_string()),
+    );
+    fs::write(spool.join(&base).with_extension("meta"), meta)?;
+    Ok(log)
+}
@@ -0,0 +1,436 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It parses the command line of sound_card_init: the command, see `Command`, and its options,
+//! see `Args`.
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+use getopts::{Matches, Options};
+use utils::logger::LogSpec;
+use utils::RunOptions;
+
+use crate::amp::AmpType;
+use crate::error::{Error, Result};
+
+/// The default directory of the configs.
+pub const CONF_DIR: &str = "/etc/sound_card_init";
+
+/// The commands of sound_card_init.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Command {
+    BootTimeCalibration,
+    Validate,
+    Show,
+    ShowConfig,
+    Reset,
+    SelfTest,
+    FactoryCalibrate,
+    SpeakerCheck,
+    Dump,
+}
+
+pub const COMMANDS: [Command; 9] = [
+    Command::BootTimeCalibration,
+    Command::Validate,
+    Command::Show,
+    Command::ShowConfig,
+    Command::Reset,
+    Command::SelfTest,
+    Command::FactoryCalibrate,
+    Command::SpeakerCheck,
+    Command::Dump,
+];
+
+impl Command {
+    pub fn name(self) -> &'static str {
+        match self {
+            Command::BootTimeCalibration => "boot_time_calibration",
+            Command::Validate => "validate",
+            Command::Show => "show",
+            Command::ShowConfig => "show-config",
+            Command::Reset => "reset",
+            Command::SelfTest => "self-test",
+            Command::FactoryCalibrate => "factory-calibrate",
+            Command::SpeakerCheck => "speaker-check",
+            Command::Dump => "dump",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Command::BootTimeCalibration => "run the boot time calibration (default)",
+            Command::Validate => "validate the config against the sound card",
+            Command::Show => "show the calibration state of the amps",
+            Command::ShowConfig => "print the config in effect for the sound card",
+            Command::Reset => "remove the stored calibration values",
+            Command::SelfTest => "check that the calibration can run without touching the amps",
+            Command::FactoryCalibrate => "calibrate the amps and write the values to VPD",
+            Command::SpeakerCheck => "check the speaker health for cros_healthd",
+            Command::Dump => "print the diagnostic archive for the feedback reports",
+        }
+    }
+
+    // The commands which write the amps or the datastore are serialized by the sound card
+    // lock. `show --watch` runs indefinitely and must not block them.
+    pub fn locks_card(self) -> bool {
+        !matches!(
+            self,
+            Command::Validate | Command::Show | Command::ShowConfig | Command::Dump
+        )
+    }
+
+    pub fn from_name(name: &str) -> Option<Command> {
+        COMMANDS.iter().copied().find(|cmd| cmd.name() == name)
+    }
+
+    // The options supported by the command.
+    pub fn options(self) -> Options {
+        let mut opts = Options::new();
+        opts.optopt("", "id", "sound card id", "ID");
+        if self == Command::BootTimeCalibration {
+            opts.optopt(
+                "",
+                "user",
+                "drop privileges to the user after opening the sound card",
+                "USER",
+            );
+            opts.optflag(
+                "",
+                "dry-run",
+                "log the calibration results without applying them",
+            );
+            opts.optopt(
+                "",
+                "record-trace",
+                "record the control accesses of the calibration to the file",
+                "FILE",
+            );
+            opts.optflag(
+                "",
+                "daemon",
+                "keep monitoring the amps after the calibration",
+            );
+        }
+        if self == Command::Reset {
+            opts.optopt("", "channel", "reset the channel", "N");
+            opts.optflag("", "all", "reset all the channels");
+            opts.optflag("", "force", "reset without confirmation");
+        }
+        if self == Command::FactoryCalibrate {
+            opts.optflag(
+                "",
+                "measure-only",
+                "measure without writing the values to VPD",
+            );
+            opts.optopt(
+                "",
+                "apply",
+                "write the values to VPD without measuring, ex: 27000:1000,27100:1000",
+                "VALUES",
+            );
+        }
+        if self == Command::Show {
+            opts.optflag("", "json", "print the state in JSON");
+            opts.optflag(
+                "",
+                "watch",
+                "refresh the live state of the amps every second",
+            );
+        }
+        opts.optopt(
+            "",
+            "amp",
+            "force the amp driver, ex: max98390d or none",
+            "AMP",
+        );
+        opts.optopt(
+            "",
+            "conf",
+            "use the config file, relative to the config directory",
+            "FILE",
+        );
+        opts.optopt(
+            "",
+            "log-level",
+            "log levels, ex: info,max98390d::amp_calibration=debug",
+            "SPEC",
+        );
+        opts.optflag("", "log-stderr", "also write the logs to stderr");
+        opts.optopt(
+            "",
+            "config-dir",
+            "read the configs from the directory",
+            "DIR",
+        );
+        opts.optopt(
+            "",
+            "datastore-dir",
+            "use the directory as the datastore",
+            "DIR",
+        );
+        opts.optflag("", "time-phases", "print the duration of each phase");
+        opts.optopt("", "timeout", "bound the total execution time", "SECS");
+        // The modes replace the command, so they are options of the default command only.
+        if self == Command::BootTimeCalibration {
+            opts.optopt(
+                "",
+                "check-config",
+                "check the configs in the directory",
+                "DIR",
+            );
+            opts.optflag("", "dbus-service", "serve the D-Bus interface");
+            opts.optopt(
+                "",
+                "control-socket",
+                "serve the control protocol on the socket",
+                "PATH",
+            );
+            opts.optopt(
+                "",
+                "factory-service",
+                "serve the factory calibration on the loopback port",
+                "PORT",
+            );
+        }
+        opts.optflag("h", "help", "print help menu");
+        opts
+    }
+}
+
+/// The steps of `factory-calibrate`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FactoryStep {
+    /// Measures and writes the values to VPD.
+    Calibrate,
+    /// Measures without writing the values.
+    Measure,
+    /// Writes the rdc and ambient temperature of each channel without measuring.
+    Apply(Vec<(i32, i32)>),
+}
+
+// Parses the factory calibration values of `--apply`, ex: 27000:1000,27100:1000.
+fn parse_factory_values(values: &str) -> Result<Vec<(i32, i32)>> {
+    values
+        .split(',')
+        .map(|value| {
+            value
+                .split_once(':')
+                .and_then(|(rdc, temp)| Some((rdc.trim().parse().ok()?, temp.trim().parse().ok()?)))
+                .ok_or_else(|| Error::InvalidFactoryValues(values.to_owned()))
+        })
+        .collect()
+}
+
+/// The command and the options of the command line.
+pub struct Args {
+    pub command: Command,
+    pub sound_card_id: Option<String>,
+    pub amp: Option<AmpType>,
+    pub conf_file: Option<String>,
+    pub user: Option<String>,
+    pub run_options: RunOptions,
+    pub json: bool,
+    pub watch: bool,
+    pub daemon: bool,
+    pub reset_channel: Option<usize>,
+    pub force: bool,
+    pub factory_step: FactoryStep,
+    pub log_spec: LogSpec,
+    pub log_stderr: bool,
+    pub timeout: Option<Duration>,
+    pub time_phases: bool,
+    pub config_dir: PathBuf,
+    pub datastore_dir: Option<PathBuf>,
+    pub check_config: Option<PathBuf>,
+    pub dbus_service: bool,
+    pub control_socket: Option<PathBuf>,
+    pub factory_service: Option<u16>,
+}
+
+fn print_usage(command: Command) {
+    let mut brief = "Usage: sound_card_init [command] [options]\n\nCommands:\n".to_owned();
+    for cmd in COMMANDS.iter() {
+        brief.push_str(&format!("    {:24}{}\n", cmd.name(), cmd.description()));
+    }
+    brief.push_str(&format!("\nOptions of {}:", command.name()));
+    print!("{}", command.options().usage(&brief));
+}
+
+// The options of the other commands are not defined, and `Matches` panics on the undefined
+// options, so they are read as absent.
+fn opt_present(matches: &Matches, name: &str) -> bool {
+    matches.opts_present(&[name.to_owned()])
+}
+
+fn opt_str(matches: &Matches, name: &str) -> Option<String> {
+    if opt_present(matches, name) {
+        matches.opt_str(name)
+    } else {
+        None
+    }
+}
+
+/// Parses the command line arguments.
+pub fn parse_args() -> Result<Args> {
+    parse_argv(env::args().skip(1).collect())
+}
+
+/// Parses the arguments without the program name.
+pub fn parse_argv(argv: Vec<String>) -> Result<Args> {
+    // The boot time calibration is run if no command is given, which keeps the old
+    // invocations working.
+    let (command, argv) = match argv.split_first() {
+        Some((name, rest)) if !name.starts_with('-') => match Command::from_name(name) {
+            Some(command) => (command, rest),
+            None => {
+                print_usage(Command::BootTimeCalibration);
+                return Err(Error::UnknownCommand(name.to_owned()));
+            }
+        },
+        _ => (Command::BootTimeCalibration, &argv[..]),
+    };
+
+    let opts = command.options();
+    let matches = opts.parse(argv).map_err(|e| {
+        print_usage(command);
+        Error::ParseArgsFailed(e)
+    })?;
+
+    if opt_present(&matches, "h") {
+        print_usage(command);
+        process::exit(0);
+    }
+
+    let sound_card_id = opt_str(&matches, "id");
+    // A daemon monitors a single sound card, a config file is for a single sound card, and the
+    // trace of the cards would be written to the same file at once.
+    if sound_card_id.is_none()
+        && ["daemon", "conf", "record-trace"]
+            .iter()
+            .any(|name| opt_present(&matches, name))
+    {
+        print_usage(command);
+        return Err(Error::MissingOption("id".to_owned()));
+    }
+
+    let reset_channel = match opt_str(&matches, "channel") {
+        Some(channel) => Some(
+            channel
+                .parse()
+                .map_err(|_| Error::InvalidChannel(channel))?,
+        ),
+        None => None,
+    };
+    // `reset` must be explicit about the channels to reset.
+    if command == Command::Reset {
+        match (reset_channel.is_some(), opt_present(&matches, "all")) {
+            (true, true) => {
+                return Err(Error::ConflictingOptions(
+                    "channel".to_owned(),
+                    "all".to_owned(),
+                ))
+            }
+            (false, false) => {
+                print_usage(command);
+                return Err(Error::MissingOption("channel or all".to_owned()));
+            }
+            _ => (),
+        }
+    }
+
+    let factory_step = match (
+        opt_present(&matches, "measure-only"),
+        opt_str(&matches, "apply"),
+    ) {
+        (true, Some(_)) => {
+            return Err(Error::ConflictingOptions(
+                "measure-only".to_owned(),
+                "apply".to_owned(),
+            ))
+        }
+        (true, None) => FactoryStep::Measure,
+        (false, Some(values)) => FactoryStep::Apply(parse_factory_values(&values)?),
+        (false, None) => FactoryStep::Calibrate,
+    };
+
+    Ok(Args {
+        command,
+        sound_card_id,
+        amp: match opt_str(&matches, "amp") {
+            Some(name) => Some(AmpType::from_name(&name).ok_or(Error::UnknownAmp(name))?),
+            None => None,
+        },
+        conf_file: opt_str(&matches, "conf"),
+        user: opt_str(&matches, "user"),
+        run_options: RunOptions {
+            dry_run: opt_present(&matches, "dry-run"),
+            trace_file: opt_str(&matches, "record-trace").map(PathBuf::from),
+            low_power: false,
+        },
+        json: opt_present(&matches, "json"),
+        watch: opt_present(&matches, "watch"),
+        daemon: opt_present(&matches, "daemon"),
+        reset_channel,
+        force: opt_present(&matches, "force"),
+        factory_step,
+        log_spec: match opt_str(&matches, "log-level") {
+            Some(spec) => spec.parse().map_err(Error::ParseLogLevelFailed)?,
+            None => LogSpec::default(),
+        },
+        log_stderr: opt_present(&matches, "log-stderr"),
+        timeout: match opt_str(&matches, "timeout") {
+            Some(secs) => Some(Duration::from_secs(
+                secs.parse().map_err(|_| Error::InvalidTimeout(secs))?,
+            )),
+            None => None,
+        },
+        time_phases: opt_present(&matches, "time-phases"),
+        config_dir: matches
+            .opt_str("config-dir")
+            .map_or_else(|| PathBuf::from(CONF_DIR), PathBuf::from),
+        datastore_dir: opt_str(&matches, "datastore-dir").map(PathBuf::from),
+        check_config: opt_str(&matches, "check-config").map(PathBuf::from),
+        dbus_service: opt_present(&matches, "dbus-service"),
+        control_socket: opt_str(&matches, "control-socket").map(PathBuf::from),
+        factory_service: match opt_str(&matches, "factory-service") {
+            Some(port) => Some(port.parse().map_err(|_| Error::InvalidPort(port))?),
+            None => None,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn record_trace_requires_id() {
+        match parse_argv(argv(&[
+            "boot_time_calibration",
+            "--record-trace=/tmp/trace",
+        ])) {
+            Err(Error::MissingOption(option)) => assert_eq!(option, "id"),
+            res => panic!(
+                "unexpected result: {:?}",
+                res.map(|args| args.run_options.trace_file)
+            ),
+        }
+        let args = parse_argv(argv(&[
+            "boot_time_calibration",
+            "--id=sofcmlmax98390d",
+            "--record-trace=/tmp/trace",
+        ]))
+        .unwrap();
+        assert_eq!(
+            args.run_options.trace_file,
+            Some(PathBuf::from("/tmp/trace"))
+        );
+    }
+}
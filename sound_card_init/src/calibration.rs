@@ -0,0 +1,300 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It implements `boot_time_calibration`. The calibration is retried on the transient errors,
+//! and the deferred phase does the bookkeeping of the outcome: the metrics, the last run
+//! outcome, the anomaly report, the diagnostic snapshot, the status summary, the state file and
+//! the events. Once it fails `SAFE_MODE_THRESHOLD` times in a row for a permanent reason, the
+//! amps are left in the safe state instead.
+use std::error;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use serde_json::json;
+use utils::clock::clock;
+use utils::events::{self, Event};
+use utils::{bootstat, last_run, metrics, phases, run_time, RunOptions};
+
+use crate::amp::{Amp, AmpType};
+use crate::anomaly;
+use crate::args::{Args, Command};
+use crate::commands::Critical;
+use crate::diagnostics;
+use crate::error::{error_code, is_transient, Error};
+use crate::power;
+use crate::run_state::{RunState, State};
+use crate::show::write_status_summary;
+
+const AMP_TYPE_METRIC: &str = "Cras.SoundCardInit.AmpType";
+const CALIB_DURATION_METRIC: &str = "Cras.SoundCardInit.BootTimeCalibrationDuration";
+const CALIB_DURATION_MAX_MS: i32 = 60_000;
+const CALIB_DURATION_BUCKETS: i32 = 50;
+// The prefix of the duration metrics of the phases, ex:
+// Cras.SoundCardInit.BootTimeCalibrationPhase.VpdRead.
+const CALIB_PHASE_METRIC: &str = "Cras.SoundCardInit.BootTimeCalibrationPhase";
+const CALIB_ERROR_METRIC: &str = "Cras.SoundCardInit.BootTimeCalibrationError";
+// The retries of the boot time calibration, each of which reconnects to CRAS.
+const CALIB_RECONNECTS_METRIC: &str = "Cras.SoundCardInit.BootTimeCalibrationCrasReconnects";
+// The error codes are below 300, see `error_code()`.
+const ERROR_CODE_MAX: i32 = 300;
+/// The boot time calibration is disabled after failing this many times in a row for a permanent
+/// reason, and the amps are left in the safe state until `reset --all`. It stops the
+/// calibrate-fail loop on every boot.
+pub const SAFE_MODE_THRESHOLD: u32 = 5;
+// The boot time calibration is retried on the transient errors.
+const CALIB_ATTEMPTS: u32 = 3;
+const CALIB_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// The measurements of a boot time calibration, which are reported to UMA by the deferred phase.
+pub struct CalibStats {
+    amp_type: AmpType,
+    duration: Duration,
+    attempts: u32,
+}
+
+/// Runs the boot time calibration on the `Amp`, which is retried on the transient errors. It
+/// sets the `CalibStats` unless it's a dry run.
+pub fn calibrate(
+    args: &Args,
+    snd_card: &str,
+    amp: &mut dyn Amp,
+    stats: &mut Option<CalibStats>,
+) -> std::result::Result<(), Box<dyn error::Error>> {
+    if !args.run_options.dry_run {
+        bootstat::mark(bootstat::CALIBRATION_START);
+    }
+    // The suspend is deferred until the calibration finishes.
+    let _suspend_delay =
+        power::SuspendDelay::register(&format!("sound_card_init calibration of {}", snd_card));
+    let opts = RunOptions {
+        low_power: power::defer_calibration(),
+        ..args.run_options.clone()
+    };
+    let start = clock().monotonic();
+    let mut attempt = 1;
+    let res = loop {
+        match amp.boot_time_calibration(&opts) {
+            Err(e) if attempt < CALIB_ATTEMPTS && is_transient(e.as_ref()) => {
+                warn!(
+                    "boot time calibration attempt {} failed: {}, retry in {:?}",
+                    attempt, e, CALIB_RETRY_DELAY
+                );
+                attempt += 1;
+                clock().sleep(CALIB_RETRY_DELAY);
+            }
+            res => break res,
+        }
+    };
+    if !args.run_options.dry_run {
+        bootstat::mark(bootstat::CALIBRATION_END);
+        *stats = Some(CalibStats {
+            amp_type: amp.amp_type(),
+            duration: clock().monotonic().saturating_duration_since(start),
+            attempts: attempt,
+        });
+    }
+    res
+}
+
+// Reports which amp driver ran the boot time calibration, how long it took, how long each
+// phase took and how many times it's retried.
+fn report_calibration_metrics(snd_card: &str, stats: &CalibStats) {
+    metrics::send_enum(AMP_TYPE_METRIC, stats.amp_type as i32, AmpType::COUNT);
+    send_duration(CALIB_DURATION_METRIC, stats.duration);
+    metrics::send_enum(
+        CALIB_RECONNECTS_METRIC,
+        (stats.attempts - 1) as i32,
+        CALIB_ATTEMPTS as i32,
+    );
+    for (phase, duration) in phases::totals_of(snd_card) {
+        // The phase names are turned into the metric names, ex: vpd read to VpdRead.
+        let name: String = phase
+            .split_whitespace()
+            .map(|word| {
+                let mut chars = word.chars();
+                chars.next().map_or_else(String::new, |first| {
+                    first.to_uppercase().chain(chars).collect()
+                })
+            })
+            .collect();
+        send_duration(&format!("{}.{}", CALIB_PHASE_METRIC, name), duration);
+    }
+}
+
+fn send_duration(name: &str, duration: Duration) {
+    metrics::send_histogram(
+        name,
+        duration.as_millis().min(CALIB_DURATION_MAX_MS as u128) as i32,
+        1,
+        CALIB_DURATION_MAX_MS,
+        CALIB_DURATION_BUCKETS,
+    );
+}
+
+/// Records the run time and the outcome of the boot time calibration, which are read by the
+/// next boot time calibration and `show`. The code of the error is reported to UMA. It returns
+/// the recorded outcome.
+pub fn record_run(
+    snd_card: &str,
+    error: Option<&(dyn error::Error + 'static)>,
+) -> Option<last_run::LastRun> {
+    if let Err(e) = run_time::now_to_file(snd_card) {
+        error!("failed to create sound_card_init run time file: {}", e);
+    }
+    let error = error.map(|e| last_run::RunError {
+        message: e.to_string(),
+        code: error_code(e),
+        transient: is_transient(e),
+    });
+    if let Some(e) = &error {
+        metrics::send_enum(CALIB_ERROR_METRIC, e.code as i32, ERROR_CODE_MAX);
+    }
+    last_run::now_to_file(snd_card, error)
+        .map_err(|e| error!("failed to save sound_card_init last run outcome: {}", e))
+        .ok()
+}
+
+// Writes the anomaly report once the boot time calibration fails `FAILURE_THRESHOLD` times
+// in a row, and raises the safe mode alert once it fails `SAFE_MODE_THRESHOLD` times in a row
+// for a permanent reason.
+fn report_anomaly(
+    snd_card: &str,
+    err: &(dyn error::Error + 'static),
+    last_run: &last_run::LastRun,
+    amp: Option<&mut Box<dyn Amp>>,
+) {
+    let alert = if last_run.fatal_failures == SAFE_MODE_THRESHOLD {
+        error!(
+            "boot time calibration of {} is disabled after {} failures in a row",
+            snd_card, last_run.fatal_failures
+        );
+        anomaly::Alert::SafeMode
+    } else if last_run.failures == anomaly::FAILURE_THRESHOLD {
+        anomaly::Alert::RepeatedFailures
+    } else {
+        return;
+    };
+    match anomaly::write_report(snd_card, alert, err, last_run.failures, amp_snapshot(amp)) {
+        Ok(path) => info!("wrote anomaly report {}", path.display()),
+        Err(e) => error!("failed to write anomaly report: {}", e),
+    }
+}
+
+// Writes the diagnostic snapshot if the boot time calibration fails for a permanent reason.
+// The transient errors are already retried and don't need a snapshot.
+fn write_diagnostics(
+    snd_card: &str,
+    err: &(dyn error::Error + 'static),
+    amp: Option<&mut Box<dyn Amp>>,
+) {
+    if is_transient(err) {
+        return;
+    }
+    match diagnostics::write_snapshot(snd_card, err, amp_snapshot(amp)) {
+        Ok(path) => info!("wrote diagnostic snapshot {}", path.display()),
+        Err(e) => error!("failed to write diagnostic snapshot: {}", e),
+    }
+}
+
+// Returns the snapshot of the amp controls and the datastore, or the error of the snapshot.
+fn amp_snapshot(amp: Option<&mut Box<dyn Amp>>) -> serde_json::Value {
+    match amp.map(|amp| amp.snapshot()) {
+        Some(Ok(snapshot)) => snapshot,
+        Some(Err(e)) => json!({ "error": e.to_string() }),
+        None => serde_json::Value::Null,
+    }
+}
+
+/// Returns the number of the consecutive permanent failures if the boot time calibration is in
+/// safe mode. The dry run still calibrates, so that the failure can be diagnosed.
+pub fn safe_mode_failures(args: &Args, snd_card: &str) -> Option<u32> {
+    if args.command != Command::BootTimeCalibration || args.run_options.dry_run {
+        return None;
+    }
+    last_run::from_file(snd_card)
+        .ok()
+        .map(|run| run.fatal_failures)
+        .filter(|failures| *failures >= SAFE_MODE_THRESHOLD)
+}
+
+/// Puts the amps into the safe state instead of calibrating them.
+pub fn enter_safe_mode(
+    amp: &mut dyn Amp,
+    failures: u32,
+) -> std::result::Result<(), Box<dyn error::Error>> {
+    amp.safe_state_opener()()?.apply()?;
+    Err(Box::new(Error::SafeMode(failures)))
+}
+
+/// Returns the state of the sound card after the critical phase, which is published to the state
+/// file.
+pub fn run_state(critical: &Critical) -> RunState {
+    RunState {
+        state: if critical.res.is_ok() {
+            State::Calibrated
+        } else {
+            State::Failed
+        },
+        exit_code: critical.code,
+        error_code: critical
+            .res
+            .as_ref()
+            .err()
+            .map_or(0, |e| error_code(e.as_ref())),
+        safe_mode: critical.safe_mode.is_some(),
+    }
+}
+
+/// Updates the state file of the sound card.
+pub fn write_run_state(snd_card: &str, state: &RunState) {
+    if let Err(e) = state.write(snd_card) {
+        error!("failed to write the state file: {}", e);
+    }
+}
+
+/// Runs the deferred phase of the boot time calibration, which only does the bookkeeping: the
+/// metrics, the last run outcome, the anomaly report, the diagnostic snapshot, the status
+/// summary, the state file and the events of the outcome. Its failures are logged, and never
+/// change the `ExitCode`.
+pub fn run_deferred(args: &Args, snd_card: &str, critical: &mut Critical) {
+    // The dry run must not change the state seen by the next boot time calibration.
+    if args.run_options.dry_run {
+        return;
+    }
+    if let Some(stats) = &critical.calib_stats {
+        report_calibration_metrics(snd_card, stats);
+    }
+    // The failure counts are frozen in safe mode.
+    if critical.safe_mode.is_none() {
+        let last_run = record_run(snd_card, critical.res.as_ref().err().map(|e| e.as_ref()));
+        if let Err(e) = &critical.res {
+            if let Some(last_run) = &last_run {
+                report_anomaly(snd_card, e.as_ref(), last_run, critical.amp.as_mut());
+            }
+            write_diagnostics(snd_card, e.as_ref(), critical.amp.as_mut());
+        }
+    }
+    write_status_summary(
+        snd_card,
+        critical
+            .amp
+            .as_mut()
+            .map(|amp| amp.as_mut() as &mut dyn Amp),
+    );
+    write_run_state(snd_card, &run_state(critical));
+    if let Err(e) = &critical.res {
+        events::emit(
+            snd_card,
+            Event::Error {
+                error_code: error_code(e.as_ref()),
+                error: e.to_string(),
+            },
+        );
+    }
+    events::emit(
+        snd_card,
+        Event::RunEnd {
+            exit_code: critical.code as i32,
+        },
+    );
+}
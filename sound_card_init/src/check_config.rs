@@ -0,0 +1,83 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It implements `--check-config`, which checks every config in a directory without the sound
+//! cards, ex: in the ebuild.
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::error;
+use utils::ExitCode;
+
+use crate::amp;
+use crate::config;
+use crate::error::Error;
+use crate::topology::{self, Topology};
+
+/// Checks the configs in `dir` and prints the result of each config. The file stem is the
+/// sound card id, and `${model}` and `${ucm.<name>}` are kept as is since there is no model or
+/// UCM config.
+pub fn run(dir: &Path) -> ExitCode {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            let e = Error::OpenConfigFailed(dir.to_string_lossy().to_string(), e);
+            error!("{}", e);
+            return e.exit_code();
+        }
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            matches!(
+                path.extension().and_then(|ext| ext.to_str()),
+                Some("yaml") | Some("json")
+            )
+        })
+        .collect();
+    files.sort();
+    let placeholders = |path: &Path| config::Placeholders {
+        card: path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        model: Some("${model}".to_owned()),
+        ucm: Some(config::UcmValues::Unexpanded),
+    };
+    let bases: Vec<PathBuf> = files
+        .iter()
+        .filter_map(|path| config::chain(path, &placeholders(path)).ok())
+        .flat_map(|chain| chain.into_iter().skip(1))
+        .filter_map(|base| fs::canonicalize(base).ok())
+        .collect();
+
+    let (mut checked, mut invalid) = (0, 0);
+    for path in &files {
+        if fs::canonicalize(path).is_ok_and(|path| bases.contains(&path)) {
+            continue;
+        }
+        let placeholders = placeholders(path);
+        let res = if path.file_name() == Some(OsStr::new(topology::TOPOLOGY_FILE)) {
+            Topology::load(dir).map(|_| ()).map_err(|e| e.into())
+        } else {
+            config::load(path, &placeholders)
+                .and_then(|conf| config::card_wait_timeout(&conf).map(|_| conf))
+                .map_err(|e| e.into())
+                .and_then(|conf| amp::check_config(&placeholders.card, &conf))
+        };
+        checked += 1;
+        match res {
+            Ok(()) => println!("{}: ok", path.display()),
+            Err(e) => {
+                invalid += 1;
+                println!("{}: {}", path.display(), e);
+            }
+        }
+    }
+    println!("{} configs checked, {} invalid", checked, invalid);
+    if invalid > 0 {
+        return ExitCode::InvalidConfig;
+    }
+    ExitCode::Success
+}
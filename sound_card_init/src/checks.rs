@@ -0,0 +1,86 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It implements `self-test` and `speaker-check`, which print a JSON record of the checks of
+//! the amps for the lab tests and cros_healthd.
+use std::error;
+
+use serde_json::json;
+
+use crate::amp::Amp;
+use crate::error::{error_code, error_hint, Error};
+
+/// Checks that the calibration can run without writing the amps or the datastore, and prints
+/// the record of the checks.
+pub fn self_test(
+    snd_card: &str,
+    amp: &mut dyn Amp,
+) -> std::result::Result<(), Box<dyn error::Error>> {
+    // The lab tests parse the report from stdout.
+    let res = amp.self_test();
+    let failed = res.as_ref().map_or(0, failed_checks);
+    let record = match &res {
+        Ok(checks) => json!({
+            "sound_card_id": snd_card,
+            "result": if failed == 0 { "PASS" } else { "FAIL" },
+            "checks": checks,
+        }),
+        Err(e) => json!({
+            "sound_card_id": snd_card,
+            "result": "FAIL",
+            "error": e.to_string(),
+            "error_code": error_code(e.as_ref()),
+            "hint": error_hint(e.as_ref()),
+        }),
+    };
+    println!("{}", record);
+    res?;
+    if failed > 0 {
+        return Err(Box::new(Error::SelfTestFailed(failed)));
+    }
+    Ok(())
+}
+
+/// Checks the speaker health for the speaker check routine of cros_healthd, and prints the
+/// record of the verdict.
+pub fn speaker_check(
+    snd_card: &str,
+    amp: &mut dyn Amp,
+) -> std::result::Result<(), Box<dyn error::Error>> {
+    // The healthd executor parses the verdict from stdout.
+    let res = amp.speaker_check();
+    let failed = res
+        .as_ref()
+        .map_or(0, |report| failed_checks(&report["checks"]));
+    let record = match &res {
+        Ok(report) => json!({
+            "sound_card_id": snd_card,
+            "verdict": if failed == 0 { "passed" } else { "failed" },
+            "checks": report["checks"],
+            "live": report["live"],
+        }),
+        Err(e) => json!({
+            "sound_card_id": snd_card,
+            "verdict": "error",
+            "error": e.to_string(),
+            "error_code": error_code(e.as_ref()),
+            "hint": error_hint(e.as_ref()),
+        }),
+    };
+    println!("{}", record);
+    res?;
+    if failed > 0 {
+        return Err(Box::new(Error::SpeakerCheckFailed(failed)));
+    }
+    Ok(())
+}
+
+/// Returns the number of the checks of a JSON array whose `result` is not PASS.
+pub fn failed_checks(checks: &serde_json::Value) -> usize {
+    checks.as_array().map_or(0, |checks| {
+        checks
+            .iter()
+            .filter(|check| check["result"] != "PASS")
+            .count()
+    })
+}
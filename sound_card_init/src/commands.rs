@@ -0,0 +1,221 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It runs a command on a sound card. The command runs in the critical phase, after which the
+//! speakers are protected, and the boot time calibration also runs its deferred phase.
+use std::error;
+
+use log::{error, info};
+use utils::events::{self, Event};
+use utils::skips::{self, SkipReason};
+use utils::{phases, readiness, ExitCode};
+
+use crate::amp::{self, Amp};
+use crate::args::{Args, Command};
+use crate::calibration::{
+    self, enter_safe_mode, run_deferred, run_state, safe_mode_failures, write_run_state, CalibStats,
+};
+use crate::checks;
+use crate::config;
+use crate::cros_config::BoardConfig;
+use crate::dbus_service;
+use crate::diagnostics;
+use crate::error::{error_code, error_hint, exit_code, Error};
+use crate::factory_calibration::factory_calibrate;
+use crate::reset::reset;
+use crate::run_state::{RunState, State};
+use crate::show;
+use crate::sound_cards::config_source;
+use crate::topology::CardTopology;
+use crate::watchdog::Watchdog;
+
+/// Runs the command on the `Amp` of the sound card. The boot time calibration sets the
+/// `CalibStats` unless it's a dry run.
+fn run_command(
+    args: &Args,
+    snd_card: &str,
+    amp: &mut dyn Amp,
+    stats: &mut Option<CalibStats>,
+) -> std::result::Result<(), Box<dyn error::Error>> {
+    match args.command {
+        Command::BootTimeCalibration => calibration::calibrate(args, snd_card, amp, stats),
+        Command::Validate => amp.validate(),
+        Command::Show => show::show(args, snd_card, amp),
+        Command::ShowConfig => show::show_config(amp),
+        Command::Reset => reset(snd_card, amp, args.reset_channel),
+        Command::SelfTest => checks::self_test(snd_card, amp),
+        // `run()` prints the archive before the critical phase, see `dump()`.
+        Command::Dump => Ok(()),
+        Command::SpeakerCheck => checks::speaker_check(snd_card, amp),
+        Command::FactoryCalibrate => factory_calibrate(&args.factory_step, snd_card, amp),
+    }
+}
+
+/// Prints the phase durations recorded by `--time-phases`.
+pub fn print_phase_times() {
+    for t in phases::take() {
+        println!(
+            "{}: {}: {:.1} ms",
+            t.snd_card,
+            t.phase,
+            t.duration.as_secs_f64() * 1000.0
+        );
+    }
+}
+
+/// The outcome of the critical phase of a run, which is all the deferred phase gets from it.
+pub struct Critical {
+    pub res: std::result::Result<(), Box<dyn error::Error>>,
+    pub code: ExitCode,
+    // The consecutive permanent failures if it's in safe mode.
+    pub safe_mode: Option<u32>,
+    // None if the amp can't be initialized.
+    pub amp: Option<Box<dyn Amp>>,
+    pub calib_stats: Option<CalibStats>,
+}
+
+// Runs the critical phase: the command on the `Amp` created by `init_amp()`, or the safe state
+// in safe mode. For the boot time calibration, the volume is either calibrated or left low once
+// it returns, so the speakers are protected even if the calibration fails.
+fn run_critical(
+    args: &Args,
+    snd_card: &str,
+    init: std::result::Result<Box<dyn Amp>, Box<dyn error::Error>>,
+) -> Critical {
+    let mut amp = None;
+    let mut calib_stats = None;
+    let safe_mode = safe_mode_failures(args, snd_card);
+    if args.command == Command::BootTimeCalibration && !args.run_options.dry_run {
+        events::emit(
+            snd_card,
+            Event::RunStart {
+                command: args.command.name().to_owned(),
+            },
+        );
+    }
+    // The errors carry the sound card, so that the recorded outcome and the anomaly report tell
+    // which sound card failed.
+    let res = init
+        .and_then(|new| {
+            let amp = amp.insert(new).as_mut();
+            match safe_mode {
+                Some(failures) => {
+                    if let Err(e) = skips::record(snd_card, SkipReason::SafeMode) {
+                        error!("failed to record the calibration skip: {}", e);
+                    }
+                    enter_safe_mode(amp, failures)
+                }
+                None => run_command(args, snd_card, amp, &mut calib_stats),
+            }
+        })
+        .map_err(|e| {
+            Box::new(Error::SoundCardFailed(snd_card.to_owned(), e)) as Box<dyn error::Error>
+        });
+    let code = match &res {
+        Ok(()) => ExitCode::Success,
+        Err(e) => {
+            error!(
+                "sound_card_init {} {} (error code {})",
+                args.command.name(),
+                e,
+                error_code(e.as_ref())
+            );
+            if let Some(hint) = error_hint(e.as_ref()) {
+                error!("hint: {}", hint);
+            }
+            exit_code(e.as_ref())
+        }
+    };
+    Critical {
+        res,
+        code,
+        safe_mode,
+        amp,
+        calib_stats,
+    }
+}
+
+/// Runs the command and returns the `ExitCode`. The boot time calibration is split into
+/// `run_critical()`, after which the watchdog is finished and the readiness is notified, and
+/// `run_deferred()`, so that the services waiting for the speaker protection don't wait for the
+/// bookkeeping. The `Watchdog` is dropped before entering the daemon mode, which reloads the
+/// config on change.
+pub fn run(
+    args: &Args,
+    board: &BoardConfig,
+    card: Option<&CardTopology>,
+    snd_card: &str,
+    init: std::result::Result<Box<dyn Amp>, Box<dyn error::Error>>,
+    watchdog: Option<Watchdog>,
+) -> ExitCode {
+    if args.command == Command::Dump {
+        return dump(snd_card, init);
+    }
+    let mut critical = run_critical(args, snd_card, init);
+    if let Some(watchdog) = &watchdog {
+        watchdog.finish(snd_card);
+    }
+    if args.command != Command::BootTimeCalibration {
+        return critical.code;
+    }
+    if let Err(e) = readiness::notify_ready(snd_card) {
+        error!("{}", e);
+    }
+
+    run_deferred(args, snd_card, &mut critical);
+
+    // The monitor also runs after a failed calibration, which leaves the volume low.
+    let code = critical.code;
+    let state = run_state(&critical);
+    if let (true, Some(amp)) = (args.daemon, critical.amp.as_mut()) {
+        info!("enter daemon mode");
+        drop(watchdog);
+        let amp_type = amp.amp_type();
+        let (conf_file, placeholders) = config_source(args, board, card, snd_card);
+        let mut watcher = config::Watcher::new(&conf_file, placeholders);
+        // The state file is refreshed after every check, so its time tells the daemon is alive.
+        let monitoring = RunState {
+            state: State::Monitoring,
+            ..state
+        };
+        let mut reload = || {
+            if !args.run_options.dry_run {
+                write_run_state(snd_card, &monitoring);
+            }
+            match watcher
+                .reload()?
+                .and_then(|conf| amp::driver_config(&conf, amp_type))
+            {
+                Ok(conf) => {
+                    info!("reload config {}", conf_file.display());
+                    Some(conf)
+                }
+                Err(e) => {
+                    error!("failed to reload config: {}", e);
+                    None
+                }
+            }
+        };
+        if let Err(e) = amp.monitor(&args.run_options, &mut reload) {
+            error!("sound_card_init monitor: {}", e);
+            return exit_code(e.as_ref());
+        }
+    }
+    code
+}
+
+// Prints the diagnostic archive of the sound card. The archive is printed even if the `Amp`
+// can't be created, since a broken config or sound card is what it's for.
+fn dump(
+    snd_card: &str,
+    init: std::result::Result<Box<dyn Amp>, Box<dyn error::Error>>,
+) -> ExitCode {
+    // debugd passes the sound card id of the caller, which must not escape the directories.
+    if let Err(e) = dbus_service::check_sound_card(snd_card) {
+        error!("{}", e);
+        return ExitCode::InvalidArgs;
+    }
+    let controls = init.and_then(|mut amp| amp.show_json());
+    println!("{}", diagnostics::dump(snd_card, controls));
+    ExitCode::Success
+}
@@ -0,0 +1,336 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It loads the configs, which may inherit a base config by `extends`, ex:
+//!
+//! ```yaml
+//! extends: base.yaml
+//! factory_limits:
+//!   rdc_min: 27000
+//! ```
+//!
+//! The base config is relative to the directory of the config. The maps are merged
+//! recursively, and the other values of the config replace the values of the base,
+//! including the lists.
+//!
+//! The `${card}` and `${model}` placeholders in the configs are expanded to the sound card id
+//! and the model name from cros_config, so that the cards and the models of a board can share
+//! one config, ex: `dsm_param: /opt/google/dsm/${model}/dsm_param.bin`. The `${ucm.<name>}`
+//! placeholders are expanded to the values of the internal speaker device in the UCM config of
+//! the sound card, UCM_DIR/<sound_card_id>/HiFi.conf, so that the configs refer to the facts UCM
+//! already encodes instead of repeating them. The values are the `Value` block of the `Speaker`
+//! device, including the calibration hints of the board, ex:
+//!
+//! ```text
+//! SectionDevice."Speaker".0 {
+//!     Value {
+//!         PlaybackPCM "hw:sofcmlmax98390d,0"
+//!         PlaybackMixerElem "Speaker"
+//!         CalibrationRdcCtrl "Left Rdc"
+//!     }
+//! }
+//! ```
+//!
+//! The value of the example is used by `speaker_mixer: ${ucm.PlaybackMixerElem}`. The UCM
+//! config is parsed by `cros_alsa::Ucm`, which does not follow the includes and the conditions.
+//!
+//! The timeout of waiting for the sound card is set by `card_wait_timeout_secs`, and the other
+//! fields are parsed by the amp driver.
+//!
+//! The configs with the `.json` extension are parsed as JSON, and may extend or be extended
+//! by the YAML configs.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use cros_alsa::{Ucm, UcmError};
+use serde::Deserialize;
+use serde_yaml::Value;
+
+use crate::{Error, Result};
+
+const EXTENDS: &str = "extends";
+
+/// The directory of the UCM configs.
+pub const UCM_DIR: &str = "/usr/share/alsa/ucm";
+// The verb file of the sound card, which has the devices.
+const VERB_FILE: &str = "HiFi.conf";
+// The names of the internal speaker device.
+const SPEAKER_DEVICES: [&str; 2] = ["Speaker", "Internal Speaker"];
+
+/// The values of a UCM device, or the placeholders kept as they are, ex: for `--check-config`
+/// without the UCM configs.
+#[derive(Debug, Clone)]
+pub enum UcmValues {
+    /// The values of the device by name.
+    Device(BTreeMap<String, String>),
+    /// The placeholders are expanded to themselves.
+    Unexpanded,
+}
+
+impl UcmValues {
+    /// Returns the value of the name, or None if the device has no such value.
+    pub fn get(&self, name: &str) -> Option<String> {
+        match self {
+            UcmValues::Device(values) => values.get(name).cloned(),
+            UcmValues::Unexpanded => Some(format!("${{ucm.{}}}", name)),
+        }
+    }
+}
+
+/// Reads the values of the internal speaker device of the sound card, or None if the sound
+/// card has no UCM config or no speaker device.
+///
+/// # Errors
+///
+/// * If the UCM config can't be read or parsed.
+pub fn speaker_values(snd_card: &str) -> Result<Option<UcmValues>> {
+    let path = Path::new(UCM_DIR).join(snd_card).join(VERB_FILE);
+    let ucm = match Ucm::from_file(&path) {
+        Ok(ucm) => ucm,
+        Err(UcmError::FileIOFailed(_, e)) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(None)
+        }
+        Err(UcmError::FileIOFailed(file, e)) => return Err(Error::OpenConfigFailed(file, e)),
+        Err(UcmError::ParseFailed(line, reason)) => {
+            return Err(Error::ParseUcmFailed(
+                path.to_string_lossy().to_string(),
+                line,
+                reason,
+            ))
+        }
+    };
+    Ok(SPEAKER_DEVICES
+        .iter()
+        .find_map(|device| ucm.device_values(device))
+        .map(|values| {
+            UcmValues::Device(
+                values
+                    .into_iter()
+                    .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                    .collect(),
+            )
+        }))
+}
+
+/// `Placeholders` holds the values of the placeholders in the configs.
+#[derive(Debug, Clone, Default)]
+pub struct Placeholders {
+    /// The sound card id, ex: sofcmlmax98390d.
+    pub card: String,
+    /// The model name, or None if cros_config is unavailable.
+    pub model: Option<String>,
+    /// The values of the speaker device in the UCM config, or None if there is no UCM config.
+    pub ucm: Option<UcmValues>,
+}
+
+impl Placeholders {
+    // Expands the placeholders in the text of a config, which keeps the lines of the config.
+    fn expand(&self, path: &Path, conf: &str) -> Result<String> {
+        let mut expanded = String::with_capacity(conf.len());
+        let mut rest = conf;
+        while let Some(start) = rest.find("${") {
+            expanded.push_str(&rest[..start]);
+            let end = rest[start..]
+                .find('}')
+                .map(|end| start + end)
+                .ok_or_else(|| self.unresolved(path, &rest[start..]))?;
+            let value = match &rest[start + 2..end] {
+                "card" => Some(self.card.clone()),
+                "model" => self.model.clone(),
+                name => name
+                    .strip_prefix("ucm.")
+                    .and_then(|name| self.ucm.as_ref()?.get(name)),
+            };
+            expanded.push_str(&value.ok_or_else(|| self.unresolved(path, &rest[start..=end]))?);
+            rest = &rest[end + 1..];
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    fn unresolved(&self, path: &Path, placeholder: &str) -> Error {
+        Error::UnresolvedPlaceholder(
+            path.to_string_lossy().to_string(),
+            placeholder.lines().next().unwrap_or_default().to_owned(),
+        )
+    }
+}
+
+// The fields of the config parsed by sound_card_init.
+#[derive(Debug, Default, Deserialize)]
+struct Settings {
+    #[serde(default)]
+    card_wait_timeout_secs: Option<u64>,
+}
+
+/// Returns the timeout of waiting for the sound card, or None if the config does not set it.
+///
+/// # Errors
+///
+/// * If the config is not valid yaml.
+/// * If the timeout is zero.
+pub fn card_wait_timeout(conf: &str) -> Result<Option<Duration>> {
+    let settings: Settings = serde_yaml::from_str(conf).map_err(Error::ParseConfigFailed)?;
+    match settings.card_wait_timeout_secs {
+        Some(0) => Err(Error::InvalidCardWaitTimeout),
+        secs => Ok(secs.map(Duration::from_secs)),
+    }
+}
+
+/// Reads the config and resolves its `extends` chain. The config is returned as is if it does
+/// not extend a base config, so that the parse errors keep pointing to its lines.
+///
+/// # Errors
+///
+/// * If any config in the chain can't be read or parsed.
+/// * If any config has an unknown placeholder, `${model}` without a model name, or a
+///   `${ucm.<name>}` which the UCM config does not have.
+/// * If the chain has a cycle.
+pub fn load(path: &Path, placeholders: &Placeholders) -> Result<String> {
+    load_chain(path, placeholders).map(|(conf, _)| conf)
+}
+
+/// Returns the config and the base configs of its `extends` chain.
+///
+/// # Errors
+///
+/// * The same as `load()`.
+pub fn chain(path: &Path, placeholders: &Placeholders) -> Result<Vec<PathBuf>> {
+    load_chain(path, placeholders).map(|(_, chain)| chain)
+}
+
+/// `Watcher` reloads a config when any config in its `extends` chain is modified.
+pub struct Watcher {
+    path: PathBuf,
+    placeholders: Placeholders,
+    // The configs of the chain and their modified times when they were last loaded.
+    chain: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl Watcher {
+    /// Creates a `Watcher` of the config loaded by `load(path, &placeholders)`.
+    pub fn new(path: &Path, placeholders: Placeholders) -> Self {
+        let chain = match load_chain(path, &placeholders) {
+            Ok((_, chain)) => chain,
+            Err(_) => vec![path.to_path_buf()],
+        };
+        Watcher {
+            path: path.to_path_buf(),
+            placeholders,
+            chain: stamp(chain),
+        }
+    }
+
+    /// Returns the reloaded config, or None if no config in the chain has been modified
+    /// since the last load.
+    ///
+    /// # Errors
+    ///
+    /// * If the modified config can't be loaded. It's reloaded again after the next
+    ///   modification.
+    pub fn reload(&mut self) -> Option<Result<String>> {
+        if self.chain.iter().all(|(p, t)| modified(p) == *t) {
+            return None;
+        }
+        let res = load_chain(&self.path, &self.placeholders);
+        let chain = match &res {
+            Ok((_, chain)) => chain.clone(),
+            Err(_) => self.chain.iter().map(|(p, _)| p.clone()).collect(),
+        };
+        self.chain = stamp(chain);
+        Some(res.map(|(conf, _)| conf))
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn stamp(chain: Vec<PathBuf>) -> Vec<(PathBuf, Option<SystemTime>)> {
+    chain
+        .into_iter()
+        .map(|path| {
+            let time = modified(&path);
+            (path, time)
+        })
+        .collect()
+}
+
+// Loads the config and returns the configs of its chain along with it.
+fn load_chain(path: &Path, placeholders: &Placeholders) -> Result<(String, Vec<PathBuf>)> {
+    let mut chain = vec![path.to_path_buf()];
+    let conf = read(path, placeholders)?;
+    let value: Value = serde_yaml::from_str(&conf).map_err(Error::ParseConfigFailed)?;
+    if base_of(&value).is_none() {
+        return Ok((conf, chain));
+    }
+    let merged = resolve(path, value, placeholders, &mut chain)?;
+    let conf = serde_yaml::to_string(&merged).map_err(Error::ParseConfigFailed)?;
+    Ok((conf, chain))
+}
+
+// Reads the config in YAML. The JSON configs are converted to YAML.
+fn read(path: &Path, placeholders: &Placeholders) -> Result<String> {
+    let conf = fs::read_to_string(path)
+        .map_err(|e| Error::OpenConfigFailed(path.to_string_lossy().to_string(), e))?;
+    let conf = placeholders.expand(path, &conf)?;
+    if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+        return Ok(conf);
+    }
+    let value: Value = serde_json::from_str(&conf)
+        .map_err(|e| Error::ParseJsonConfigFailed(path.to_string_lossy().to_string(), e))?;
+    serde_yaml::to_string(&value).map_err(Error::ParseConfigFailed)
+}
+
+fn base_of(value: &Value) -> Option<&str> {
+    value
+        .as_mapping()
+        .and_then(|map| map.get(&Value::from(EXTENDS)))
+        .and_then(Value::as_str)
+}
+
+// Merges the config at `path` into its base configs. `chain` holds the configs being
+// resolved to detect cycles.
+fn resolve(
+    path: &Path,
+    mut value: Value,
+    placeholders: &Placeholders,
+    chain: &mut Vec<PathBuf>,
+) -> Result<Value> {
+    let base = match base_of(&value) {
+        Some(base) => path.parent().unwrap_or_else(|| Path::new("")).join(base),
+        None => return Ok(value),
+    };
+    if chain.contains(&base) {
+        return Err(Error::ConfigExtendsCycle(
+            base.to_string_lossy().to_string(),
+        ));
+    }
+    if let Some(map) = value.as_mapping_mut() {
+        map.remove(&Value::from(EXTENDS));
+    }
+    chain.push(base.clone());
+    let base_value =
+        serde_yaml::from_str(&read(&base, placeholders)?).map_err(Error::ParseConfigFailed)?;
+    let base_value = resolve(&base, base_value, placeholders, chain)?;
+    Ok(merge(base_value, value))
+}
+
+fn merge(base: Value, value: Value) -> Value {
+    match (base, value) {
+        (Value::Mapping(mut base), Value::Mapping(map)) => {
+            for (k, v) in map {
+                let merged = match base.remove(&k) {
+                    Some(b) => merge(b, v),
+                    None => v,
+                };
+                base.insert(k, merged);
+            }
+            Value::Mapping(base)
+        }
+        (_, value) => value,
+    }
+}
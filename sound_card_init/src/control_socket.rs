@@ -0,0 +1,342 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It implements the control protocol of `--control-socket`, which lets the factory test
+//! frameworks that can't link Rust or use D-Bus drive the calibration over a Unix domain
+//! socket.
+//!
+//! Each request and response is a frame of a 4-byte big-endian length followed by a JSON object
+//! of that many bytes. A connection may send any number of requests, and each request gets one
+//! response:
+//!
+//! ```text
+//! -> {"method": "status", "sound_card_id": "sofcmlmax98390d"}
+//! <- {"ok": true, "result": {"sound_card_id": "sofcmlmax98390d", ...}}
+//! ```
+//!
+//! The methods are:
+//!
+//! * `status` - The status of `show --json`, which is `SpeakerStatus` of
+//!   proto/speaker_status.proto.
+//! * `start_measurement` - Starts `factory-calibrate` on the sound card in the background.
+//! * `results` - The state of the latest measurement of the sound card: `none`, `running`, or
+//!   `done` with the `exit_code` and the `factory-calibrate` record as the `record`.
+//!
+//! A failed request gets `{"ok": false, "error": "..."}`. Like the D-Bus service, the commands
+//! run sound_card_init in a child process, so that they take the sound card lock. The framing
+//! is shared with the `factory_service` module.
+//!
+//! The commands write the VPD and the datastore, so the socket is created with mode 0600, and
+//! the connections are only accepted from root and the user of the server by `SO_PEERCRED`.
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+use libc::{c_void, socklen_t, ucred};
+use log::{error, info};
+use remain::sorted;
+use serde_json::{json, Value};
+use utils::ExitCode;
+
+use crate::dbus_service::{self, check_sound_card, run_self};
+
+// The upper bound of the frame length, which keeps a bad client from exhausting the memory.
+const MAX_FRAME_LEN: u32 = 1 << 20;
+// The umask of binding the socket, which leaves the socket accessible by its owner only.
+const SOCKET_UMASK: libc::mode_t = 0o177;
+
+/// The errors of the control socket.
+#[sorted]
+#[derive(Debug)]
+pub enum Error {
+    BindFailed(PathBuf, io::Error),
+    CommandFailed(dbus_service::Error),
+    FrameTooLarge(u32),
+    InvalidRequest(String),
+    MeasurementRunning(String),
+    PeerRejected(u32),
+    SocketFailed(io::Error),
+    UnknownMethod(String),
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            BindFailed(path, e) => write!(f, "failed to bind {}: {}", path.display(), e),
+            CommandFailed(e) => write!(f, "{}", e),
+            FrameTooLarge(len) => write!(f, "frame of {} bytes exceeds {}", len, MAX_FRAME_LEN),
+            InvalidRequest(e) => write!(f, "invalid request: {}", e),
+            MeasurementRunning(snd_card) => write!(f, "measurement of {} is running", snd_card),
+            PeerRejected(uid) => write!(f, "connection of uid {} is rejected", uid),
+            SocketFailed(e) => write!(f, "socket failure: {}", e),
+            UnknownMethod(method) => write!(f, "unknown method: {}", method),
+        }
+    }
+}
+
+impl From<dbus_service::Error> for Error {
+    fn from(err: dbus_service::Error) -> Error {
+        Error::CommandFailed(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// The latest measurement of a sound card.
+enum Measurement {
+    Running,
+    Done(i32, Value),
+}
+
+type Measurements = Arc<Mutex<HashMap<String, Measurement>>>;
+
+// Locks the measurements. The map is still consistent if a handler panics while holding the
+// lock, since every update is a single insert, so the poisoning is ignored.
+fn lock(measurements: &Measurements) -> MutexGuard<'_, HashMap<String, Measurement>> {
+    measurements.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Serves the control protocol on the socket until it fails. The stale socket file is replaced.
+/// The child processes read the configs from `config_dir`.
+pub fn serve(config_dir: &Path, socket: &Path) -> Result<()> {
+    match fs::remove_file(socket) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            return Err(Error::BindFailed(socket.to_path_buf(), e))
+        }
+        _ => (),
+    }
+    let listener = bind(socket).map_err(|e| Error::BindFailed(socket.to_path_buf(), e))?;
+    info!("serving the control protocol on {}", socket.display());
+    let measurements: Measurements = Default::default();
+    for stream in listener.incoming() {
+        let stream = stream.map_err(Error::SocketFailed)?;
+        if let Err(e) = check_peer(&stream) {
+            error!("control connection: {}", e);
+            continue;
+        }
+        let config_dir = config_dir.to_path_buf();
+        let measurements = measurements.clone();
+        // A slow client never blocks the others.
+        thread::spawn(move || {
+            let handle = |request: &Value| handle_request(&config_dir, &measurements, request);
+            if let Err(e) = serve_connection(stream, handle) {
+                error!("control connection: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+// Binds the socket with mode 0600. The umask is set around the bind, so that the socket is
+// never accessible by the others. No other thread runs at the time.
+fn bind(socket: &Path) -> io::Result<UnixListener> {
+    // Safe because umask() has no pointer arguments and always succeeds.
+    let umask = unsafe { libc::umask(SOCKET_UMASK) };
+    let listener = UnixListener::bind(socket);
+    // Safe because umask() has no pointer arguments and always succeeds.
+    unsafe { libc::umask(umask) };
+    listener
+}
+
+// Accepts the connections of root and the user of the server only.
+fn check_peer(stream: &UnixStream) -> Result<()> {
+    // Safe because ucred is a plain C struct which can be zero initialized.
+    let mut cred: ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<ucred>() as socklen_t;
+    // Safe because cred and len are valid, and len is the size of cred.
+    let rc = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut ucred as *mut c_void,
+            &mut len,
+        )
+    };
+    if rc < 0 {
+        return Err(Error::SocketFailed(io::Error::last_os_error()));
+    }
+    // Safe because it has no arguments.
+    if cred.uid != 0 && cred.uid != unsafe { libc::geteuid() } {
+        return Err(Error::PeerRejected(cred.uid));
+    }
+    Ok(())
+}
+
+/// Runs `--control-socket` on the socket and returns the exit code of sound_card_init.
+pub fn run(config_dir: &Path, socket: &Path) -> ExitCode {
+    match serve(config_dir, socket) {
+        Ok(()) => ExitCode::Success,
+        Err(e) => {
+            error!("sound_card_init control socket: {}", e);
+            ExitCode::Failure
+        }
+    }
+}
+
+// Reads a frame, or returns None at the end of the connection.
+fn read_frame<S: Read>(stream: &mut S) -> Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        res => res.map_err(Error::SocketFailed)?,
+    }
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(Error::FrameTooLarge(len));
+    }
+    let mut frame = vec![0u8; len as usize];
+    stream.read_exact(&mut frame).map_err(Error::SocketFailed)?;
+    Ok(Some(frame))
+}
+
+fn write_frame<S: Write>(stream: &mut S, frame: &[u8]) -> Result<()> {
+    stream
+        .write_all(&(frame.len() as u32).to_be_bytes())
+        .and_then(|_| stream.write_all(frame))
+        .map_err(Error::SocketFailed)
+}
+
+/// Serves the requests of a connection until it ends. Each request is handled by `handle`,
+/// which returns the result of the response.
+pub(crate) fn serve_connection<S: Read + Write>(
+    mut stream: S,
+    handle: impl Fn(&Value) -> Result<Value>,
+) -> Result<()> {
+    while let Some(frame) = read_frame(&mut stream)? {
+        let response = match serde_json::from_slice(&frame)
+            .map_err(|e| Error::InvalidRequest(e.to_string()))
+            .and_then(|request| handle(&request))
+        {
+            Ok(result) => json!({ "ok": true, "result": result }),
+            Err(e) => {
+                error!("control request: {}", e);
+                json!({ "ok": false, "error": e.to_string() })
+            }
+        };
+        write_frame(&mut stream, response.to_string().as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Returns the string field of the request.
+///
+/// # Errors
+///
+/// * If the request has no such string field.
+pub(crate) fn field<'a>(request: &'a Value, name: &str) -> Result<&'a str> {
+    request[name]
+        .as_str()
+        .ok_or_else(|| Error::InvalidRequest(format!("missing {}", name)))
+}
+
+/// Returns the exit code and the record printed by a child process. The record is kept as a
+/// string if it's not JSON, ex: the command line is rejected.
+pub(crate) fn command_record(output: &Output) -> (i32, Value) {
+    (
+        output.status.code().unwrap_or(ExitCode::Failure as i32),
+        serde_json::from_slice(&output.stdout).unwrap_or_else(|_| {
+            Value::String(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        }),
+    )
+}
+
+// Returns the result of a request.
+fn handle_request(
+    config_dir: &Path,
+    measurements: &Measurements,
+    request: &Value,
+) -> Result<Value> {
+    let method = field(request, "method")?;
+    let snd_card = field(request, "sound_card_id")?;
+    info!("control request {}({})", method, snd_card);
+    match method {
+        "status" => status(config_dir, snd_card),
+        "start_measurement" => start_measurement(config_dir, measurements, snd_card),
+        "results" => Ok(results(measurements, snd_card)),
+        _ => Err(Error::UnknownMethod(method.to_owned())),
+    }
+}
+
+fn status(config_dir: &Path, snd_card: &str) -> Result<Value> {
+    let status = dbus_service::status(config_dir, snd_card)?;
+    Ok(serde_json::from_str(&status).unwrap_or(Value::String(status)))
+}
+
+fn start_measurement(
+    config_dir: &Path,
+    measurements: &Measurements,
+    snd_card: &str,
+) -> Result<Value> {
+    check_sound_card(snd_card)?;
+    let mut guard = lock(measurements);
+    if let Some(Measurement::Running) = guard.get(snd_card) {
+        return Err(Error::MeasurementRunning(snd_card.to_owned()));
+    }
+    guard.insert(snd_card.to_owned(), Measurement::Running);
+    drop(guard);
+
+    let (config_dir, snd_card) = (config_dir.to_path_buf(), snd_card.to_owned());
+    let measurements = measurements.clone();
+    thread::spawn(move || {
+        let done = match run_self(&config_dir, &snd_card, &["factory-calibrate"]) {
+            Ok(output) => {
+                let (exit_code, record) = command_record(&output);
+                Measurement::Done(exit_code, record)
+            }
+            Err(e) => {
+                error!("failed to measure {}: {}", snd_card, e);
+                Measurement::Done(ExitCode::Failure as i32, Value::Null)
+            }
+        };
+        lock(&measurements).insert(snd_card, done);
+    });
+    Ok(json!({ "started": true }))
+}
+
+fn results(measurements: &Measurements, snd_card: &str) -> Value {
+    match lock(measurements).get(snd_card) {
+        None => json!({ "state": "none" }),
+        Some(Measurement::Running) => json!({ "state": "running" }),
+        Some(Measurement::Done(exit_code, record)) => json!({
+            "state": "done",
+            "exit_code": exit_code,
+            "record": record,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn binds_owner_only_socket() {
+        let socket = env::temp_dir().join(format!("control_socket_{}", process::id()));
+        let _ = fs::remove_file(&socket);
+        let listener = bind(&socket).unwrap();
+        let mode = fs::metadata(&socket).unwrap().permissions().mode();
+        let stream = UnixStream::connect(&socket).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        fs::remove_file(&socket).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(check_peer(&stream).is_ok());
+        assert!(check_peer(&accepted).is_ok());
+    }
+}
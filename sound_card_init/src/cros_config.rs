@@ -0,0 +1,67 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It queries the audio configuration of the board from cros_config, so that the unibuild
+//! variants with different speakers share one image without per-variant upstart args.
+use std::process::Command;
+
+use log::{debug, info};
+
+const CROS_CONFIG: &str = "cros_config";
+const ROOT_PATH: &str = "/";
+// The model name, ex: helios.
+const NAME_PROPERTY: &str = "name";
+const AUDIO_PATH: &str = "/audio/main";
+// The config filename in CONF_DIR, ex: sofcmlmax98390d.yaml.
+const CONF_FILE_PROPERTY: &str = "sound-card-init-conf";
+// The speaker amp of the board, ex: MAX98390.
+const SPEAKER_AMP_PROPERTY: &str = "speaker-amp";
+
+/// `BoardConfig` is the audio configuration of the board. The properties not set in
+/// cros_config are None.
+#[derive(Debug, Default)]
+pub struct BoardConfig {
+    /// The config filename in the config directory.
+    pub conf_file: Option<String>,
+    /// The speaker amp name.
+    pub speaker_amp: Option<String>,
+    /// The model name, it's used by the `${model}` placeholder of the configs.
+    pub model: Option<String>,
+}
+
+impl BoardConfig {
+    /// Queries the `BoardConfig` from cros_config. It's empty if cros_config is unavailable,
+    /// ex: on the non-unibuild boards.
+    pub fn query() -> Self {
+        let board = BoardConfig {
+            conf_file: get(AUDIO_PATH, CONF_FILE_PROPERTY),
+            speaker_amp: get(AUDIO_PATH, SPEAKER_AMP_PROPERTY),
+            model: model(),
+        };
+        info!("cros_config: {:?}", board);
+        board
+    }
+}
+
+/// Queries the model name from cros_config. It's None if cros_config is unavailable.
+pub fn model() -> Option<String> {
+    get(ROOT_PATH, NAME_PROPERTY)
+}
+
+// Returns the property of `path`, or None if it's not set.
+fn get(path: &str, property: &str) -> Option<String> {
+    match Command::new(CROS_CONFIG).args([path, property]).output() {
+        Ok(output) if output.status.success() => {
+            let value = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+            Some(value).filter(|v| !v.is_empty())
+        }
+        Ok(output) => {
+            debug!("{} {} {}: {}", CROS_CONFIG, path, property, output.status);
+            None
+        }
+        Err(e) => {
+            debug!("failed to run {}: {}", CROS_CONFIG, e);
+            None
+        }
+    }
+}
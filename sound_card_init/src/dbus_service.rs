@@ -0,0 +1,243 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It implements the D-Bus service `org.chromium.SoundCardInit` of `--dbus-service`, which lets
+//! the diagnostics UIs and the autotests query and run the speaker protection without shelling
+//! out. The service is only built with the `dbus` feature, which links libdbus. The object
+//! `/org/chromium/SoundCardInit` has the methods:
+//!
+//! * `GetStatus(s sound_card_id) -> (s status)` - The status of `show --json`, which includes
+//!   the calibration values of each channel and the outcome of the last boot time calibration.
+//!   It's `SpeakerStatus` of proto/speaker_status.proto in the JSON mapping.
+//! * `Validate(s sound_card_id) -> (i exit_code)` - Runs `validate` and returns its exit code.
+//! * `SpeakerCheck(s sound_card_id) -> (s verdict)` - Runs `speaker-check` and returns its JSON
+//!   record, which is the result of the speaker check routine of cros_healthd.
+//! * `Recalibrate(s sound_card_id)` - Starts the sound_card_init upstart job of the sound card,
+//!   which runs the boot time calibration in its sandbox. It returns once the job is started,
+//!   and the outcome is reported by `GetStatus`.
+//!
+//! The methods run sound_card_init on the sound card in a child process, so that they take the
+//! sound card lock like the command line, and a failed call never takes the service down. The
+//! child processes are shared with the `control_socket` module.
+#![cfg_attr(not(feature = "dbus"), allow(dead_code))]
+
+use std::env;
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Output};
+
+use log::error;
+use remain::sorted;
+use utils::ExitCode;
+
+/// The well-known name of the service.
+pub const SERVICE_NAME: &str = "org.chromium.SoundCardInit";
+/// The object path of the service.
+pub const OBJECT_PATH: &str = "/org/chromium/SoundCardInit";
+/// The interface of the methods.
+pub const INTERFACE: &str = "org.chromium.SoundCardInit";
+
+// The upstart job which runs the boot time calibration.
+const CALIBRATION_JOB: &str = "sound_card_init";
+
+/// The errors of the D-Bus service.
+#[sorted]
+#[derive(Debug)]
+pub enum Error {
+    CommandFailed(String, Option<i32>),
+    #[cfg(feature = "dbus")]
+    DbusFailed(String),
+    InvalidSoundCard(String),
+    SpawnFailed(String, io::Error),
+    #[cfg(not(feature = "dbus"))]
+    Unsupported,
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            CommandFailed(cmd, Some(code)) => write!(f, "{} exited with code {}", cmd, code),
+            CommandFailed(cmd, None) => write!(f, "{} is killed by a signal", cmd),
+            #[cfg(feature = "dbus")]
+            DbusFailed(e) => write!(f, "D-Bus failure: {}", e),
+            InvalidSoundCard(snd_card) => write!(f, "invalid sound card id: {}", snd_card),
+            SpawnFailed(cmd, e) => write!(f, "failed to run {}: {}", cmd, e),
+            #[cfg(not(feature = "dbus"))]
+            Unsupported => write!(f, "sound_card_init is built without the dbus feature"),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Serves the methods on the system bus until the connection fails. The child processes read
+/// the configs from `config_dir`.
+pub fn serve(config_dir: &Path) -> Result<()> {
+    imp::serve(config_dir)
+}
+
+/// Runs `--dbus-service` and returns the exit code of sound_card_init.
+pub fn run(config_dir: &Path) -> ExitCode {
+    match serve(config_dir) {
+        Ok(()) => ExitCode::Success,
+        Err(e) => {
+            error!("sound_card_init D-Bus service: {}", e);
+            ExitCode::Failure
+        }
+    }
+}
+
+// The sound card ids are checked like the upstart job, since they are passed to the child
+// processes and the job.
+pub(crate) fn check_sound_card(snd_card: &str) -> Result<()> {
+    if snd_card.is_empty() || !snd_card.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(Error::InvalidSoundCard(snd_card.to_owned()));
+    }
+    Ok(())
+}
+
+// Runs sound_card_init with the command and its options on the sound card.
+pub(crate) fn run_self(config_dir: &Path, snd_card: &str, args: &[&str]) -> Result<Output> {
+    check_sound_card(snd_card)?;
+    let name = format!("sound_card_init {}", args.join(" "));
+    let exe = env::current_exe().map_err(|e| Error::SpawnFailed(name.clone(), e))?;
+    Command::new(exe)
+        .args(args)
+        .arg(format!("--id={}", snd_card))
+        .arg(format!("--config-dir={}", config_dir.display()))
+        .output()
+        .map_err(|e| Error::SpawnFailed(name, e))
+}
+
+// Returns the status of `show --json`.
+pub(crate) fn status(config_dir: &Path, snd_card: &str) -> Result<String> {
+    let output = run_self(config_dir, snd_card, &["show", "--json"])?;
+    if !output.status.success() {
+        return Err(Error::CommandFailed(
+            "sound_card_init show".to_owned(),
+            output.status.code(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+// Returns the exit code of `validate`.
+fn validate(config_dir: &Path, snd_card: &str) -> Result<i32> {
+    let output = run_self(config_dir, snd_card, &["validate"])?;
+    Ok(output.status.code().unwrap_or(ExitCode::Failure as i32))
+}
+
+// Returns the record of `speaker-check`, which tells the verdict regardless of the exit code.
+fn speaker_check(config_dir: &Path, snd_card: &str) -> Result<String> {
+    let output = run_self(config_dir, snd_card, &["speaker-check"])?;
+    let record = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    if record.is_empty() {
+        return Err(Error::CommandFailed(
+            "sound_card_init speaker-check".to_owned(),
+            output.status.code(),
+        ));
+    }
+    Ok(record)
+}
+
+// Starts the upstart job of the boot time calibration of the sound card.
+fn recalibrate(snd_card: &str) -> Result<()> {
+    check_sound_card(snd_card)?;
+    let name = format!("initctl start {}", CALIBRATION_JOB);
+    let status = Command::new("initctl")
+        .args(["start", CALIBRATION_JOB])
+        .arg(format!("SOUND_CARD_ID={}", snd_card))
+        .status()
+        .map_err(|e| Error::SpawnFailed(name.clone(), e))?;
+    if !status.success() {
+        return Err(Error::CommandFailed(name, status.code()));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "dbus")]
+mod imp {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use dbus::blocking::Connection;
+    use dbus::channel::{MatchingReceiver, Sender};
+    use dbus::message::MatchRule;
+    use dbus::{Message, MethodErr};
+    use log::{error, info};
+
+    use super::{recalibrate, speaker_check, status, validate, Error, Result};
+    use super::{INTERFACE, OBJECT_PATH, SERVICE_NAME};
+
+    // The timeout of each round of processing the incoming messages.
+    const PROCESS_TIMEOUT: Duration = Duration::from_secs(1);
+
+    pub fn serve(config_dir: &Path) -> Result<()> {
+        let dbus_err = |e: dbus::Error| Error::DbusFailed(e.to_string());
+        let conn = Connection::new_system().map_err(dbus_err)?;
+        conn.request_name(SERVICE_NAME, false, true, false)
+            .map_err(dbus_err)?;
+        info!("serving {} on the system bus", SERVICE_NAME);
+
+        let config_dir = config_dir.to_path_buf();
+        conn.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |msg, conn| {
+                let reply = handle(&config_dir, &msg);
+                if conn.send(reply).is_err() {
+                    error!("failed to send the reply of {:?}", msg.member());
+                }
+                true
+            }),
+        );
+        loop {
+            conn.process(PROCESS_TIMEOUT).map_err(dbus_err)?;
+        }
+    }
+
+    // Returns the reply of a method call.
+    fn handle(config_dir: &Path, msg: &Message) -> Message {
+        if msg.path().as_deref() != Some(OBJECT_PATH)
+            || msg.interface().as_deref() != Some(INTERFACE)
+        {
+            return MethodErr::no_interface(&msg.interface().as_deref().unwrap_or_default())
+                .to_message(msg);
+        }
+        let member = msg.member();
+        let member = member.as_deref().unwrap_or_default();
+        let snd_card: String = match msg.read1() {
+            Ok(snd_card) => snd_card,
+            Err(e) => return MethodErr::from(e).to_message(msg),
+        };
+        info!("D-Bus call {}({})", member, snd_card);
+        let reply = match member {
+            "GetStatus" => status(config_dir, &snd_card).map(|s| msg.method_return().append1(s)),
+            "Validate" => validate(config_dir, &snd_card).map(|c| msg.method_return().append1(c)),
+            "SpeakerCheck" => {
+                speaker_check(config_dir, &snd_card).map(|s| msg.method_return().append1(s))
+            }
+            "Recalibrate" => recalibrate(&snd_card).map(|()| msg.method_return()),
+            _ => return MethodErr::no_method(&member).to_message(msg),
+        };
+        reply.unwrap_or_else(|e| {
+            error!("D-Bus call {}({}): {}", member, snd_card, e);
+            MethodErr::failed(&e).to_message(msg)
+        })
+    }
+}
+
+#[cfg(not(feature = "dbus"))]
+mod imp {
+    use std::path::Path;
+
+    use super::{Error, Result};
+
+    pub fn serve(_config_dir: &Path) -> Result<()> {
+        Err(Error::Unsupported)
+    }
+}
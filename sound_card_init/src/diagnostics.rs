@@ -0,0 +1,244 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It dumps a diagnostic snapshot of the sound card when the boot time calibration fails for a
+//! permanent reason, so that a one-off field failure can be debugged after the fact. The
+//! snapshot has the structured error, the amp controls, the datastore and the VPD values of the
+//! channels, and the recent log records. Only the latest `MAX_SNAPSHOTS` of a sound card are
+//! kept.
+//!
+//! It also keeps the status summary of each sound card, ex: sofcmlmax98390d.status.json, which
+//! is the `show --json` output of the latest run. The feedback reports collect it to include the
+//! speaker protection state without running sound_card_init.
+//!
+//! The diagnostic archive of `dump()` bundles the files above with the datastore, the recent run
+//! logs and the events of a sound card, so that debugd can attach everything needed to triage a
+//! speaker protection issue to the feedback reports by one call of `sound_card_init dump`.
+use std::error;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde_json::{json, Map, Value};
+use utils::events::EVENTS_FILE;
+use utils::logger;
+
+use crate::run_state;
+
+/// The directory of the diagnostic snapshots.
+pub const DIAGNOSTICS_DIR: &str = "/var/log/sound_card_init";
+// The number of the snapshots kept for a sound card.
+const MAX_SNAPSHOTS: usize = 5;
+const SNAPSHOT_EXTENSION: &str = "json";
+const STATUS_EXTENSION: &str = "status.json";
+/// The total size of the files in the diagnostic archive.
+pub const MAX_DUMP_BYTES: usize = 512 * 1024;
+// The size of a file in the diagnostic archive, of which the tail is kept.
+const MAX_DUMP_FILE_BYTES: usize = 64 * 1024;
+// The number of the latest run logs in the diagnostic archive.
+const DUMP_RUN_LOGS: usize = 3;
+
+/// Writes the diagnostic snapshot of the sound card, and removes its oldest snapshots beyond
+/// `MAX_SNAPSHOTS`. It returns the path of the snapshot.
+///
+/// # Errors
+///
+/// * If the snapshot can't be written.
+pub fn write_snapshot(
+    snd_card: &str,
+    err: &(dyn error::Error + 'static),
+    channels: Value,
+) -> io::Result<PathBuf> {
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |t| t.as_secs());
+    let snapshot = json!({
+        "sound_card_id": snd_card,
+        "run_id": logger::run_id(),
+        "time": time,
+        "error": crate::error::error_report(err),
+        "channels": channels,
+        "log": logger::recent(),
+    });
+    let dir = Path::new(DIAGNOSTICS_DIR);
+    let path = dir
+        .join(format!("{}.{}", snd_card, time))
+        .with_extension(SNAPSHOT_EXTENSION);
+    fs::write(&path, snapshot.to_string())?;
+    remove_old_snapshots(dir, snd_card)?;
+    Ok(path)
+}
+
+/// Replaces the status summary of the sound card atomically, so the collectors never read a
+/// partial summary. It returns the path of the summary.
+///
+/// # Errors
+///
+/// * If the summary can't be written.
+pub fn write_status(snd_card: &str, status: &Value) -> io::Result<PathBuf> {
+    let path = Path::new(DIAGNOSTICS_DIR)
+        .join(snd_card)
+        .with_extension(STATUS_EXTENSION);
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, status.to_string())?;
+    fs::rename(&tmp, &path)?;
+    Ok(path)
+}
+
+// The snapshots are named <sound_card_id>.<unix time>.json, and the sound card ids have no dots.
+fn remove_old_snapshots(dir: &Path, snd_card: &str) -> io::Result<()> {
+    let mut snapshots: Vec<(u64, PathBuf)> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension() == Some(OsStr::new(SNAPSHOT_EXTENSION)))
+        .filter_map(|path| {
+            let stem = path.file_stem()?.to_str()?;
+            let (card, time) = stem.split_once('.')?;
+            if card != snd_card {
+                return None;
+            }
+            Some((time.parse().ok()?, path))
+        })
+        .collect();
+    snapshots.sort();
+    for (_, path) in snapshots.iter().rev().skip(MAX_SNAPSHOTS) {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Returns the diagnostic archive of the sound card with the snapshot of the amp controls, or of
+/// the error if they can't be read. The archive has the files of the sound card by path: the
+/// status summary, the state file, ex: /run/sound_card_init/sofcmlmax98390d.status, the
+/// datastore, the latest snapshot, the recent run logs and the events of the sound card.
+///
+/// The archive is bounded and sanitized: only the tail of each file up to `MAX_DUMP_FILE_BYTES`
+/// is kept, the files beyond `MAX_DUMP_BYTES` in total are dropped, and the control characters
+/// are removed. The truncated and dropped files are listed in `truncated`.
+pub fn dump(snd_card: &str, controls: Result<Value, Box<dyn error::Error>>) -> Value {
+    let mut archive = Archive {
+        files: Map::new(),
+        truncated: Vec::new(),
+        remaining: MAX_DUMP_BYTES,
+    };
+    let dir = Path::new(DIAGNOSTICS_DIR);
+    archive.add_file(&dir.join(snd_card).with_extension(STATUS_EXTENSION));
+    archive.add_file(&run_state::state_file(snd_card));
+    for path in sorted_files(&utils::datastore_dir(snd_card)) {
+        archive.add_file(&path);
+    }
+    if let Some(path) = latest_snapshot(dir, snd_card) {
+        archive.add_file(&path);
+    }
+    let mut run_logs: Vec<PathBuf> = sorted_files(dir)
+        .into_iter()
+        .filter(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .is_some_and(|name| name.starts_with("run.") && name.ends_with(".log"))
+        })
+        .collect();
+    run_logs.sort_by_key(|path| fs::metadata(path).and_then(|m| m.modified()).ok());
+    for path in run_logs.iter().rev().take(DUMP_RUN_LOGS) {
+        archive.add_file(path);
+    }
+    // The event feed is shared by the sound cards.
+    if let Ok(events) = fs::read_to_string(EVENTS_FILE) {
+        let events: String = events
+            .lines()
+            .filter(|line| {
+                serde_json::from_str::<Value>(line).is_ok_and(|e| e["sound_card_id"] == snd_card)
+            })
+            .map(|line| format!("{}\n", line))
+            .collect();
+        archive.add(EVENTS_FILE, &events);
+    }
+
+    json!({
+        "sound_card_id": snd_card,
+        "time": SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs()),
+        "controls": controls.unwrap_or_else(|e| json!({ "error": e.to_string() })),
+        "files": archive.files,
+        "truncated": archive.truncated,
+    })
+}
+
+// The files of the diagnostic archive within its size bound.
+struct Archive {
+    files: Map<String, Value>,
+    truncated: Vec<String>,
+    remaining: usize,
+}
+
+impl Archive {
+    // Adds the file if it can be read.
+    fn add_file(&mut self, path: &Path) {
+        if let Ok(content) = fs::read(path) {
+            self.add(&path.to_string_lossy(), &String::from_utf8_lossy(&content));
+        }
+    }
+
+    fn add(&mut self, name: &str, content: &str) {
+        let content = sanitize(content);
+        let kept = tail(&content, MAX_DUMP_FILE_BYTES.min(self.remaining));
+        if kept.len() < content.len() {
+            self.truncated.push(name.to_owned());
+        }
+        if kept.is_empty() && !content.is_empty() {
+            return;
+        }
+        self.remaining -= kept.len();
+        self.files.insert(name.to_owned(), kept.into());
+    }
+}
+
+// Removes the control characters except the newlines and the tabs, so that a corrupt file can't
+// garble the feedback report.
+fn sanitize(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+        .collect()
+}
+
+// Returns the last `max` bytes of the text at a character boundary.
+fn tail(text: &str, max: usize) -> &str {
+    let mut start = text.len().saturating_sub(max);
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    &text[start..]
+}
+
+// Returns the regular files of the directory by name, or none if it can't be read.
+fn sorted_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.is_file())
+                .collect()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+// Returns the latest snapshot of the sound card, see `remove_old_snapshots()` for the names.
+fn latest_snapshot(dir: &Path, snd_card: &str) -> Option<PathBuf> {
+    sorted_files(dir)
+        .into_iter()
+        .filter(|path| path.extension() == Some(OsStr::new(SNAPSHOT_EXTENSION)))
+        .filter_map(|path| {
+            let stem = path.file_stem()?.to_str()?;
+            let (card, time) = stem.split_once('.')?;
+            if card != snd_card {
+                return None;
+            }
+            Some((time.parse::<u64>().ok()?, path))
+        })
+        .max()
+        .map(|(_, path)| path)
+}
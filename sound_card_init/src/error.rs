@@ -0,0 +1,403 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It defines the errors of sound_card_init, and classifies the errors of all the crates by
+//! their `ExitCode`, stable code, remediation hint and structured report.
+use std::error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use remain::sorted;
+use serde::{Serialize, Serializer};
+use utils::error::ErrorReport;
+use utils::ExitCode;
+
+#[cfg(any(test, feature = "mock-amp"))]
+use crate::mock_amp;
+
+/// The result of sound_card_init.
+pub type Result<T> = std::result::Result<T, Error>;
+// The code of the errors of the other crates, ex: libcras.
+const UNKNOWN_ERROR_CODE: u32 = 0;
+
+/// The errors of sound_card_init.
+#[sorted]
+#[derive(Debug)]
+pub enum Error {
+    ConfigExtendsCycle(String),
+    ConflictingOptions(String, String),
+    DropPrivilegesFailed(String, io::Error),
+    DuplicateCard(String),
+    FactoryMeasureFailed(usize),
+    InvalidCardWaitTimeout,
+    InvalidChannel(String),
+    InvalidFactoryValues(String),
+    InvalidPort(String),
+    InvalidTimeout(String),
+    MissingOption(String),
+    NoInternalSoundCard,
+    OpenCardFailed(cros_alsa::CardError),
+    OpenConfigFailed(String, io::Error),
+    ParseArgsFailed(getopts::Fail),
+    ParseConfigFailed(serde_yaml::Error),
+    ParseJsonConfigFailed(String, serde_json::Error),
+    ParseLogLevelFailed(utils::error::Error),
+    ParseUcmFailed(String, usize, &'static str),
+    ResetCancelled,
+    SafeMode(u32),
+    SandboxFailed(io::Error),
+    SelfTestFailed(usize),
+    SoundCardFailed(String, Box<dyn error::Error>),
+    SpeakerCheckFailed(usize),
+    TopologyMismatch(String, usize, usize),
+    UnknownAmp(String),
+    UnknownCommand(String),
+    UnknownUser(String),
+    UnresolvedPlaceholder(String, String),
+    UnsupportedSoundCard(String),
+    WatchdogTimeout(Duration),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> ExitCode {
+        use Error::*;
+        match self {
+            ConflictingOptions(_, _)
+            | InvalidChannel(_)
+            | InvalidFactoryValues(_)
+            | InvalidPort(_)
+            | InvalidTimeout(_)
+            | MissingOption(_)
+            | ParseArgsFailed(_)
+            | ParseLogLevelFailed(_)
+            | UnknownAmp(_)
+            | UnknownCommand(_) => ExitCode::InvalidArgs,
+            NoInternalSoundCard | OpenCardFailed(_) => ExitCode::SoundCardUnavailable,
+            ConfigExtendsCycle(_)
+            | OpenConfigFailed(_, _)
+            | DuplicateCard(_)
+            | InvalidCardWaitTimeout
+            | ParseConfigFailed(_)
+            | ParseJsonConfigFailed(_, _)
+            | ParseUcmFailed(_, _, _)
+            | TopologyMismatch(_, _, _)
+            | UnresolvedPlaceholder(_, _) => ExitCode::InvalidConfig,
+            UnsupportedSoundCard(_) => ExitCode::UnsupportedSoundCard,
+            FactoryMeasureFailed(_) => ExitCode::CalibrationRejected,
+            DropPrivilegesFailed(_, _)
+            | ResetCancelled
+            | SafeMode(_)
+            | SandboxFailed(_)
+            | SelfTestFailed(_)
+            | SpeakerCheckFailed(_)
+            | UnknownUser(_) => ExitCode::Failure,
+            SoundCardFailed(_, e) => exit_code(e.as_ref()),
+            WatchdogTimeout(_) => ExitCode::Timeout,
+        }
+    }
+
+    pub fn is_transient(&self) -> bool {
+        use Error::*;
+        match self {
+            OpenCardFailed(e) => e.is_enodev(),
+            SoundCardFailed(_, e) => is_transient(e.as_ref()),
+            _ => false,
+        }
+    }
+
+    pub fn hint(&self) -> Option<&'static str> {
+        use Error::*;
+        match self {
+            NoInternalSoundCard => Some("give the sound card by --id"),
+            SafeMode(_) => Some(concat!(
+                "run `sound_card_init reset --all` after fixing the speakers to re-enable the ",
+                "calibration",
+            )),
+            SoundCardFailed(_, e) => error_hint(e.as_ref()),
+            UnsupportedSoundCard(_) => Some("force the amp driver by --amp for a prototype"),
+            _ if self.exit_code() == ExitCode::InvalidConfig => {
+                Some("run `sound_card_init --check-config` on the config directory")
+            }
+            _ => None,
+        }
+    }
+
+    // The sound card is moved into the context of the error of the sound card.
+    pub fn report(&self) -> ErrorReport {
+        match self {
+            Error::SoundCardFailed(snd_card, e) => {
+                let mut report = error_report(e.as_ref());
+                report.context.sound_card = Some(snd_card.clone());
+                report
+            }
+            _ => {
+                let mut report = ErrorReport::new(self, self.code());
+                report.hint = self.hint().map(str::to_owned);
+                report
+            }
+        }
+    }
+
+    // Returns the stable code of the error. The sound_card_init errors use 1 to 99, and the
+    // codes must not be renumbered or reused, see `error_code()`.
+    pub fn code(&self) -> u32 {
+        use Error::*;
+        match self {
+            ConfigExtendsCycle(_) => 1,
+            ConflictingOptions(_, _) => 2,
+            DropPrivilegesFailed(_, _) => 3,
+            DuplicateCard(_) => 4,
+            InvalidCardWaitTimeout => 5,
+            InvalidChannel(_) => 6,
+            InvalidTimeout(_) => 7,
+            MissingOption(_) => 8,
+            NoInternalSoundCard => 9,
+            OpenCardFailed(_) => 10,
+            OpenConfigFailed(_, _) => 11,
+            ParseArgsFailed(_) => 12,
+            ParseConfigFailed(_) => 13,
+            ParseJsonConfigFailed(_, _) => 14,
+            ParseLogLevelFailed(_) => 15,
+            ResetCancelled => 16,
+            SandboxFailed(_) => 17,
+            SoundCardFailed(_, e) => error_code(e.as_ref()),
+            TopologyMismatch(_, _, _) => 18,
+            UnknownAmp(_) => 19,
+            UnknownCommand(_) => 20,
+            UnknownUser(_) => 21,
+            UnresolvedPlaceholder(_, _) => 22,
+            UnsupportedSoundCard(_) => 23,
+            WatchdogTimeout(_) => 24,
+            SafeMode(_) => 25,
+            SelfTestFailed(_) => 26,
+            ParseUcmFailed(_, _, _) => 27,
+            SpeakerCheckFailed(_) => 28,
+            InvalidFactoryValues(_) => 29,
+            FactoryMeasureFailed(_) => 30,
+            InvalidPort(_) => 31,
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        use Error::*;
+        match self {
+            DropPrivilegesFailed(_, e) | OpenConfigFailed(_, e) | SandboxFailed(e) => Some(e),
+            OpenCardFailed(e) => Some(e),
+            ParseArgsFailed(e) => Some(e),
+            ParseConfigFailed(e) => Some(e),
+            ParseJsonConfigFailed(_, e) => Some(e),
+            ParseLogLevelFailed(e) => Some(e),
+            SoundCardFailed(_, e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        self.report().serialize(serializer)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            ConfigExtendsCycle(file) => write!(f, "config extends itself through {}", file),
+            ConflictingOptions(a, b) => write!(f, "conflicting options: {} and {}", a, b),
+            DuplicateCard(snd_card) => write!(f, "duplicate sound card in topology: {}", snd_card),
+            DropPrivilegesFailed(user, e) => {
+                write!(f, "failed to drop privileges to {}: {}", user, e)
+            }
+            InvalidCardWaitTimeout => write!(f, "card_wait_timeout_secs must be positive"),
+            FactoryMeasureFailed(failed) => {
+                write!(f, "{} channels failed the factory limits", failed)
+            }
+            InvalidChannel(channel) => write!(f, "invalid channel: {}", channel),
+            InvalidFactoryValues(values) => {
+                write!(f, "invalid factory calibration values: {}", values)
+            }
+            InvalidPort(port) => write!(f, "invalid port: {}", port),
+            InvalidTimeout(timeout) => write!(f, "invalid timeout: {}", timeout),
+            MissingOption(option) => write!(f, "missing required option: {}", option),
+            NoInternalSoundCard => write!(f, "no internal sound card has a config"),
+            OpenCardFailed(e) => write!(f, "failed to open sound card: {}", e),
+            OpenConfigFailed(file, e) => write!(f, "failed to open file {}: {}", file, e),
+            ParseArgsFailed(e) => write!(f, "parse_args failed: {}", e),
+            ParseConfigFailed(e) => write!(f, "failed to parse config: {}", e),
+            ParseJsonConfigFailed(file, e) => write!(f, "failed to parse {}: {}", file, e),
+            ParseLogLevelFailed(e) => write!(f, "{}", e),
+            ParseUcmFailed(file, line, reason) => {
+                write!(
+                    f,
+                    "invalid UCM config {} at line {}: {}",
+                    file, line, reason
+                )
+            }
+            ResetCancelled => write!(f, "reset is cancelled"),
+            SafeMode(failures) => write!(
+                f,
+                concat!(
+                    "boot time calibration is disabled after {} failures in a row, the amps are ",
+                    "left in the safe state until reset --all",
+                ),
+                failures
+            ),
+            SandboxFailed(e) => write!(f, "failed to set up the sandbox: {}", e),
+            SelfTestFailed(failed) => write!(f, "{} self test checks failed", failed),
+            SoundCardFailed(snd_card, e) => write!(f, "{}: {}", snd_card, e),
+            SpeakerCheckFailed(failed) => write!(f, "{} speaker checks failed", failed),
+            TopologyMismatch(snd_card, declared, count) => write!(
+                f,
+                "topology declares {} channels of {}, but the config has {}",
+                declared, snd_card, count
+            ),
+            UnknownAmp(name) => write!(f, "unknown amp: {}", name),
+            UnknownCommand(name) => write!(f, "unknown command: {}", name),
+            UnknownUser(user) => write!(f, "unknown user: {}", user),
+            UnresolvedPlaceholder(file, placeholder) => {
+                write!(f, "unresolved placeholder in {}: {}", file, placeholder)
+            }
+            UnsupportedSoundCard(name) => write!(f, "unsupported sound card: {}", name),
+            WatchdogTimeout(timeout) => write!(f, "timed out after {:?}", timeout),
+        }
+    }
+}
+
+/// Returns the `ExitCode` of the error category. The uncategorized transient errors are
+/// `ExitCode::Transient`.
+pub fn exit_code(err: &(dyn error::Error + 'static)) -> ExitCode {
+    let code = if let Some(e) = err.downcast_ref::<Error>() {
+        e.exit_code()
+    } else if let Some(e) = err.downcast_ref::<utils::error::Error>() {
+        e.exit_code()
+    } else {
+        max98390d::exit_code(err).unwrap_or(ExitCode::Failure)
+    };
+    match code {
+        ExitCode::Failure if is_transient(err) => ExitCode::Transient,
+        code => code,
+    }
+}
+
+/// Returns the structured form of the error, see `ErrorReport`.
+pub fn error_report(err: &(dyn error::Error + 'static)) -> ErrorReport {
+    if let Some(e) = err.downcast_ref::<Error>() {
+        return e.report();
+    }
+    if let Some(e) = err.downcast_ref::<utils::error::Error>() {
+        return ErrorReport::new(e, e.code());
+    }
+    max98390d::error_report(err).unwrap_or_else(|| ErrorReport::new(err, UNKNOWN_ERROR_CODE))
+}
+
+/// Returns the remediation hint of the error, see `max98390d::Error::hint()`.
+pub fn error_hint(err: &(dyn error::Error + 'static)) -> Option<&'static str> {
+    if let Some(e) = err.downcast_ref::<Error>() {
+        return e.hint();
+    }
+    max98390d::error_hint(err)
+}
+
+/// Returns true if the error may go away by retrying the command.
+pub fn is_transient(err: &(dyn error::Error + 'static)) -> bool {
+    if let Some(e) = err.downcast_ref::<Error>() {
+        return e.is_transient();
+    }
+    #[cfg(any(test, feature = "mock-amp"))]
+    if let Some(e) = err.downcast_ref::<mock_amp::Error>() {
+        return e.is_transient();
+    }
+    max98390d::is_transient(err)
+}
+
+/// Returns the stable code of the error, which identifies the failure mode in the logs, the last
+/// run outcome and the UMA metrics. The codes are split by crate: 1 to 99 for sound_card_init,
+/// 100 to 199 for utils and 200 to 299 for max98390d.
+pub fn error_code(err: &(dyn error::Error + 'static)) -> u32 {
+    if let Some(e) = err.downcast_ref::<Error>() {
+        return e.code();
+    }
+    if let Some(e) = err.downcast_ref::<utils::error::Error>() {
+        return e.code();
+    }
+    max98390d::error_code(err).unwrap_or(UNKNOWN_ERROR_CODE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io;
+    use std::time::Duration;
+
+    fn errors() -> Vec<Error> {
+        use Error::*;
+        let os_err = || io::Error::new(io::ErrorKind::PermissionDenied, "Permission denied");
+        let cool_down = max98390d::check_cool_down(
+            Duration::from_secs(100),
+            Duration::from_secs(200),
+            Duration::from_secs(210),
+            Duration::from_secs(60),
+        )
+        .unwrap_err();
+        vec![
+            ConfigExtendsCycle("sofcmlmax98390d.yaml".to_owned()),
+            ConflictingOptions("--dry-run".to_owned(), "--force".to_owned()),
+            DropPrivilegesFailed("sound_card_init".to_owned(), os_err()),
+            DuplicateCard("sofcmlmax98390d".to_owned()),
+            FactoryMeasureFailed(1),
+            InvalidCardWaitTimeout,
+            InvalidChannel("2".to_owned()),
+            InvalidFactoryValues("27000".to_owned()),
+            InvalidPort("0".to_owned()),
+            InvalidTimeout("-1".to_owned()),
+            MissingOption("--id".to_owned()),
+            NoInternalSoundCard,
+            OpenConfigFailed("sofcmlmax98390d.yaml".to_owned(), os_err()),
+            ParseArgsFailed(getopts::Fail::UnrecognizedOption("x".to_owned())),
+            ParseConfigFailed(serde_yaml::from_str::<Vec<i32>>("{").unwrap_err()),
+            ParseJsonConfigFailed(
+                "topology.json".to_owned(),
+                serde_json::from_str::<Vec<i32>>("{").unwrap_err(),
+            ),
+            ParseLogLevelFailed(utils::error::Error::InvalidLogSpec("x".to_owned())),
+            ParseUcmFailed("HiFi.conf".to_owned(), 3, "unbalanced braces"),
+            ResetCancelled,
+            SafeMode(3),
+            SandboxFailed(os_err()),
+            SelfTestFailed(2),
+            SoundCardFailed("sofcmlmax98390d".to_owned(), Box::new(cool_down)),
+            SpeakerCheckFailed(1),
+            TopologyMismatch("sofcmlmax98390d".to_owned(), 4, 2),
+            UnknownAmp("max98373".to_owned()),
+            UnknownCommand("calibrate".to_owned()),
+            UnknownUser("sound_card_init".to_owned()),
+            UnresolvedPlaceholder("sofcmlmax98390d.yaml".to_owned(), "${SKU}".to_owned()),
+            UnsupportedSoundCard("sofrt5682".to_owned()),
+            WatchdogTimeout(Duration::from_secs(30)),
+        ]
+    }
+
+    #[test]
+    fn error_display_strings() {
+        let lines: Vec<_> = errors()
+            .iter()
+            .map(|e| format!("{} {:?}: {}", e.code(), e.exit_code(), e))
+            .collect();
+        insta::assert_snapshot!(lines.join("\n"));
+    }
+
+    #[test]
+    fn error_reports() {
+        let reports: Vec<_> = errors()
+            .iter()
+            .filter(|e| matches!(e, Error::NoInternalSoundCard | Error::SoundCardFailed(..)))
+            .map(|e| serde_json::to_value(e).unwrap())
+            .collect();
+        insta::assert_snapshot!(serde_json::to_string_pretty(&reports).unwrap());
+    }
+}
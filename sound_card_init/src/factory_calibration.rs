@@ -0,0 +1,56 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It implements `factory-calibrate`, which calibrates the amps against the factory limits and
+//! writes the values to VPD, or runs the measurement or the VPD write alone.
+use std::error;
+
+use serde_json::json;
+
+use crate::amp::Amp;
+use crate::args::FactoryStep;
+use crate::checks::failed_checks;
+use crate::error::{error_code, error_hint, Error};
+
+/// Runs the step of `factory-calibrate`, and prints the record of the channels.
+pub fn factory_calibrate(
+    step: &FactoryStep,
+    snd_card: &str,
+    amp: &mut dyn Amp,
+) -> std::result::Result<(), Box<dyn error::Error>> {
+    // The factory test harnesses parse the record from stdout.
+    let res = match step {
+        FactoryStep::Calibrate => amp.factory_calibrate(),
+        FactoryStep::Measure => amp.factory_measure(),
+        FactoryStep::Apply(values) => amp.factory_apply(values).map(|_| {
+            values
+                .iter()
+                .map(|(rdc, ambient_temp)| json!({ "rdc": rdc, "ambient_temp": ambient_temp }))
+                .collect()
+        }),
+    };
+    let failed = res.as_ref().map_or(0, |channels| match step {
+        FactoryStep::Measure => failed_checks(channels),
+        _ => 0,
+    });
+    let record = match &res {
+        Ok(channels) => json!({
+            "sound_card_id": snd_card,
+            "result": if failed == 0 { "PASS" } else { "FAIL" },
+            "channels": channels,
+        }),
+        Err(e) => json!({
+            "sound_card_id": snd_card,
+            "result": "FAIL",
+            "error": e.to_string(),
+            "error_code": error_code(e.as_ref()),
+            "hint": error_hint(e.as_ref()),
+        }),
+    };
+    println!("{}", record);
+    res?;
+    if failed > 0 {
+        return Err(Box::new(Error::FactoryMeasureFailed(failed)));
+    }
+    Ok(())
+}
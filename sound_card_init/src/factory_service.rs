@@ -0,0 +1,166 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It implements `--factory-service`, the remote factory calibration mode of the
+//! `factory-service` feature. It serves the steps of the factory calibration on a TCP port of
+//! the loopback interface, so that the factory station software can orchestrate a multi-step
+//! calibration through a port forward of the device, and pull the raw measurements for the
+//! statistical process control.
+//!
+//! The framing and the responses are the ones of the `control_socket` module:
+//!
+//! ```text
+//! -> {"method": "measure", "sound_card_id": "sofcmlmax98390d"}
+//! <- {"ok": true, "result": {"exit_code": 0, "record": {"result": "PASS", "channels": [...]}}}
+//! ```
+//!
+//! The methods are:
+//!
+//! * `measure` - Runs `factory-calibrate --measure-only`. The record has the measured values of
+//!   each channel, including the ones out of the factory limits.
+//! * `apply` - Runs `factory-calibrate --apply` with the `values` of the request, ex:
+//!   `"values": [{"rdc": 27000, "ambient_temp": 1000}]`, which writes them to the VPD.
+//! * `readback` - Runs `show --json`, which has the values in the amp controls and the VPD.
+//!
+//! The result has the `exit_code` and the `record` printed by the command. The commands run
+//! sound_card_init in a child process, so that the steps of a sound card are serialized by the
+//! sound card lock.
+#![cfg_attr(not(feature = "factory-service"), allow(dead_code))]
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use log::{error, info};
+use remain::sorted;
+use serde_json::{json, Value};
+use utils::ExitCode;
+
+use crate::control_socket;
+use crate::dbus_service::run_self;
+
+/// The errors of the factory service.
+#[sorted]
+#[derive(Debug)]
+pub enum Error {
+    BindFailed(u16, io::Error),
+    SocketFailed(io::Error),
+    #[cfg(not(feature = "factory-service"))]
+    Unsupported,
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            BindFailed(port, e) => write!(f, "failed to bind port {}: {}", port, e),
+            SocketFailed(e) => write!(f, "socket failure: {}", e),
+            #[cfg(not(feature = "factory-service"))]
+            Unsupported => write!(
+                f,
+                "sound_card_init is built without the factory-service feature"
+            ),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Serves the factory calibration on the port of the loopback interface until it fails. The
+/// child processes read the configs from `config_dir`.
+pub fn serve(config_dir: &Path, port: u16) -> Result<()> {
+    imp::serve(config_dir, port)
+}
+
+/// Runs `--factory-service` on the port and returns the exit code of sound_card_init.
+pub fn run(config_dir: &Path, port: u16) -> ExitCode {
+    match serve(config_dir, port) {
+        Ok(()) => ExitCode::Success,
+        Err(e) => {
+            error!("sound_card_init factory service: {}", e);
+            ExitCode::Failure
+        }
+    }
+}
+
+// Returns the options of `factory-calibrate --apply` from the `values` of the request.
+fn apply_option(values: &Value) -> control_socket::Result<String> {
+    let invalid = || control_socket::Error::InvalidRequest("invalid values".to_owned());
+    let values = values
+        .as_array()
+        .filter(|values| !values.is_empty())
+        .ok_or_else(invalid)?
+        .iter()
+        .map(
+            |value| match (value["rdc"].as_i64(), value["ambient_temp"].as_i64()) {
+                (Some(rdc), Some(temp)) => Ok(format!("{}:{}", rdc, temp)),
+                _ => Err(invalid()),
+            },
+        )
+        .collect::<control_socket::Result<Vec<_>>>()?;
+    Ok(format!("--apply={}", values.join(",")))
+}
+
+// Returns the result of a request.
+fn handle_request(config_dir: &Path, request: &Value) -> control_socket::Result<Value> {
+    let method = control_socket::field(request, "method")?;
+    let snd_card = control_socket::field(request, "sound_card_id")?;
+    info!("factory request {}({})", method, snd_card);
+    let apply;
+    let args: &[&str] = match method {
+        "measure" => &["factory-calibrate", "--measure-only"],
+        "apply" => {
+            apply = apply_option(&request["values"])?;
+            &["factory-calibrate", &apply]
+        }
+        "readback" => &["show", "--json"],
+        _ => return Err(control_socket::Error::UnknownMethod(method.to_owned())),
+    };
+    let output = run_self(config_dir, snd_card, args)?;
+    let (exit_code, record) = control_socket::command_record(&output);
+    Ok(json!({ "exit_code": exit_code, "record": record }))
+}
+
+#[cfg(feature = "factory-service")]
+mod imp {
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::path::Path;
+    use std::thread;
+
+    use log::{error, info};
+
+    use super::{handle_request, Error, Result};
+    use crate::control_socket;
+
+    pub fn serve(config_dir: &Path, port: u16) -> Result<()> {
+        // The service is never exposed beyond the device.
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+            .map_err(|e| Error::BindFailed(port, e))?;
+        info!("serving the factory calibration on port {}", port);
+        for stream in listener.incoming() {
+            let stream = stream.map_err(Error::SocketFailed)?;
+            let config_dir = config_dir.to_path_buf();
+            thread::spawn(move || {
+                let handle = |request: &_| handle_request(&config_dir, request);
+                if let Err(e) = control_socket::serve_connection(stream, handle) {
+                    error!("factory connection: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "factory-service"))]
+mod imp {
+    use std::path::Path;
+
+    use super::{Error, Result};
+
+    pub fn serve(_config_dir: &Path, _port: u16) -> Result<()> {
+        Err(Error::Unsupported)
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It implements `FakeAmp`, the amp driver of the `fake-amp` feature for the VM images, ex:
+//! betty, which have no smart amps. Unlike `MockAmp`, it needs no script, and runs the whole
+//! boot time calibration of sound_card_init with plausible values, so that the integration
+//! tests see the same datastore, status and last run outcome as on a device. It's selected by
+//! `--amp=fake` or `amp: fake`, and the `fake` section of the config is optional:
+//!
+//! ```yaml
+//! amp: fake
+//! fake:
+//!   channels: 2
+//!   rdc: 12000
+//!   ambient_temp: 25
+//! ```
+//!
+//! Each calibration takes `calibration_ms`, and measures `rdc` with a jitter of up to
+//! `RDC_JITTER_PERCENT` and `ambient_temp` as is. Like max98390d, the measured rdc is rejected
+//! if it differs from the stored value by more than `RDC_DIFF_LIMIT_PERCENT`, and the stored
+//! values are kept in the datastore across the boots.
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use log::info;
+use remain::sorted;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use utils::clock::clock;
+use utils::{datastore_dir, RunOptions};
+
+use crate::amp::{Amp, AmpType};
+
+// The max jitter of the measured rdc.
+const RDC_JITTER_PERCENT: i32 = 1;
+// The max difference of the measured rdc from the stored one.
+const RDC_DIFF_LIMIT_PERCENT: i32 = 5;
+
+/// The errors of `FakeAmp`.
+#[sorted]
+#[derive(Debug)]
+pub enum Error {
+    DatastoreFailed(PathBuf, io::Error),
+    InvalidConfig(&'static str),
+    LargeCalibrationDiff(usize, i32, i32),
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            DatastoreFailed(path, e) => write!(f, "{}: {}", path.display(), e),
+            InvalidConfig(reason) => write!(f, "invalid fake amp config: {}", reason),
+            LargeCalibrationDiff(ch, rdc, stored) => write!(
+                f,
+                "channel {}: rdc {} differs too much from the stored {}",
+                ch, rdc, stored
+            ),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct FakeConfig {
+    channels: usize,
+    rdc: i32,
+    ambient_temp: i32,
+    calibration_ms: u64,
+}
+
+impl Default for FakeConfig {
+    fn default() -> Self {
+        FakeConfig {
+            channels: 2,
+            rdc: 12000,
+            ambient_temp: 25,
+            calibration_ms: 300,
+        }
+    }
+}
+
+// The stored calibration values of a channel.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Calibration {
+    rdc: i32,
+    ambient_temp: i32,
+}
+
+/// `FakeAmp` is an amp which calibrates to plausible values without the hardware.
+pub struct FakeAmp {
+    snd_card: String,
+    config: FakeConfig,
+}
+
+impl FakeAmp {
+    /// Creates the `FakeAmp` of the config, which is the defaults if it's empty.
+    pub fn from_config(
+        snd_card: &str,
+        conf: &str,
+    ) -> std::result::Result<Box<dyn Amp>, Box<dyn error::Error>> {
+        // The whole config is given if there is no `fake` section.
+        let config: FakeConfig =
+            serde_yaml::from_str::<Option<FakeConfig>>(conf)?.unwrap_or_default();
+        if config.channels == 0 {
+            return Err(Box::new(Error::InvalidConfig("channels must be positive")));
+        }
+        if config.rdc <= 0 {
+            return Err(Box::new(Error::InvalidConfig("rdc must be positive")));
+        }
+        Ok(Box::new(FakeAmp {
+            snd_card: snd_card.to_owned(),
+            config,
+        }))
+    }
+
+    fn calib_file(&self, ch: usize) -> PathBuf {
+        datastore_dir(&self.snd_card).join(format!("fake_calib_{}", ch))
+    }
+
+    fn stored(&self, ch: usize) -> Option<Calibration> {
+        let yaml = fs::read_to_string(self.calib_file(ch)).ok()?;
+        serde_yaml::from_str(&yaml).ok()
+    }
+
+    fn store(&self, ch: usize, calib: Calibration) -> Result<()> {
+        let dir = datastore_dir(&self.snd_card);
+        fs::create_dir_all(&dir).map_err(|e| Error::DatastoreFailed(dir, e))?;
+        let path = self.calib_file(ch);
+        let yaml = serde_yaml::to_string(&calib).unwrap_or_default();
+        fs::write(&path, yaml).map_err(|e| Error::DatastoreFailed(path, e))
+    }
+
+    // Measures the rdc of the channel with a jitter, which varies by the boot and the channel.
+    fn measure(&self, ch: usize) -> Calibration {
+        let seed = clock()
+            .now()
+            .map_or(0, |now| now.subsec_nanos() as i32)
+            .wrapping_add(ch as i32 * 7919);
+        let jitter = self.config.rdc * RDC_JITTER_PERCENT / 100;
+        let offset = if jitter > 0 {
+            seed.rem_euclid(2 * jitter + 1) - jitter
+        } else {
+            0
+        };
+        Calibration {
+            rdc: self.config.rdc + offset,
+            ambient_temp: self.config.ambient_temp,
+        }
+    }
+
+    fn status(&self, ch: usize) -> Value {
+        let stored = self.stored(ch);
+        json!({
+            "rdc": stored.map(|c| c.rdc),
+            "ambient_temp": stored.map(|c| c.ambient_temp),
+            "volume": if stored.is_some() { "high" } else { "low" },
+        })
+    }
+}
+
+impl Amp for FakeAmp {
+    fn amp_type(&self) -> AmpType {
+        AmpType::Fake
+    }
+
+    fn boot_time_calibration(
+        &mut self,
+        opts: &RunOptions,
+    ) -> std::result::Result<(), Box<dyn error::Error>> {
+        // Takes the time of the playback and the measurement.
+        clock().sleep(Duration::from_millis(self.config.calibration_ms));
+        for ch in 0..self.config.channels {
+            let calib = self.measure(ch);
+            if let Some(stored) = self.stored(ch) {
+                let diff = (calib.rdc - stored.rdc).abs();
+                if diff * 100 > stored.rdc * RDC_DIFF_LIMIT_PERCENT {
+                    return Err(Box::new(Error::LargeCalibrationDiff(
+                        ch, calib.rdc, stored.rdc,
+                    )));
+                }
+            }
+            if opts.dry_run {
+                info!("dry run: skip applying channel {}: {:?}", ch, calib);
+                continue;
+            }
+            self.store(ch, calib)?;
+            info!("channel {}: applied {:?}", ch, calib);
+        }
+        Ok(())
+    }
+
+    fn validate(&mut self) -> std::result::Result<(), Box<dyn error::Error>> {
+        Ok(())
+    }
+
+    fn show(&mut self) -> std::result::Result<String, Box<dyn error::Error>> {
+        Ok((0..self.config.channels)
+            .map(|ch| format!("channel {}: {}", ch, self.status(ch)))
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+
+    fn live_status(&mut self) -> std::result::Result<String, Box<dyn error::Error>> {
+        self.show()
+    }
+
+    fn show_json(&mut self) -> std::result::Result<Value, Box<dyn error::Error>> {
+        Ok(Value::Array(
+            (0..self.config.channels)
+                .map(|ch| self.status(ch))
+                .collect(),
+        ))
+    }
+
+    fn channel_count(&self) -> std::result::Result<usize, Box<dyn error::Error>> {
+        Ok(self.config.channels)
+    }
+
+    fn reset(&mut self, channel: Option<usize>) -> std::result::Result<(), Box<dyn error::Error>> {
+        for ch in 0..self.config.channels {
+            if channel.is_none() || channel == Some(ch) {
+                let path = self.calib_file(ch);
+                match fs::remove_file(&path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                        return Err(Box::new(Error::DatastoreFailed(path, e)))
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn self_test(&mut self) -> std::result::Result<Value, Box<dyn error::Error>> {
+        let dir = datastore_dir(&self.snd_card);
+        let datastore = match fs::metadata(&dir) {
+            Ok(meta) if !meta.permissions().readonly() => json!({
+                "check": "datastore",
+                "result": "PASS",
+            }),
+            Ok(_) => json!({
+                "check": "datastore",
+                "result": "FAIL",
+                "error": format!("{} is read-only", dir.display()),
+            }),
+            Err(e) => json!({
+                "check": "datastore",
+                "result": "FAIL",
+                "error": Error::DatastoreFailed(dir, e).to_string(),
+            }),
+        };
+        Ok(json!([datastore]))
+    }
+
+    fn factory_calibrate(&mut self) -> std::result::Result<Value, Box<dyn error::Error>> {
+        Ok(Value::Array(
+            (0..self.config.channels)
+                .map(|ch| {
+                    let calib = self.measure(ch);
+                    json!({ "rdc": calib.rdc, "ambient_temp": calib.ambient_temp })
+                })
+                .collect(),
+        ))
+    }
+}
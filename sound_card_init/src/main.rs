@@ -1,134 +1,396 @@
 // Copyright 2020 The Chromium OS Authors. All rights reserved.
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
-//!  `sound_card_init` is an user space binary to perform sound card initialization during boot time.
+//!  `sound_card_init` is an user space binary to perform sound card initialization during boot
+//!  time.
 //!
 //!
+//!  # Usage
+//!
+//!  `sound_card_init [command] [--id=<sound_card_id>] [options]`
+//!
+//!  # Commands
+//!
+//!  * `boot_time_calibration` - Runs the boot time calibration. It's the default command. It's
+//!    retried a few times if it fails for a transient reason, ex: CRAS is not up yet. Once it
+//!    fails on several boots in a row for a permanent reason, it's disabled and the amps are
+//!    left in the safe state until `reset --all`. With the `dbus` feature, the measurement is
+//!    skipped on a low battery or thermal throttling, and the suspends are deferred until it
+//!    finishes, see the `power` module.
+//!  * `validate` - Validates the config against the sound card without touching the amps.
+//!  * `show` - Shows the calibration state of the amps.
+//!  * `show-config` - Prints the config in effect for the sound card, after resolving `extends`
+//!    and the placeholders, applying `amp`, and filling in the defaults.
+//!  * `reset` - Removes the stored calibration values of a channel or all the channels, ex:
+//!    after a speaker or amp replacement. It asks for confirmation unless `force` is given.
+//!    Resetting all the channels also starts the failure counts of the boot time calibration
+//!    over.
+//!  * `self-test` - Checks that the calibration can run without writing the amps or the
+//!    datastore: the sound card opens, the amp controls exist and can be read, and the VPD
+//!    values and the datastore directory can be read. It prints a JSON record with `result` of
+//!    PASS or FAIL and the result of each check.
+//!  * `factory-calibrate` - Calibrates the amps against the factory limits and writes the values
+//!    to VPD. It prints a JSON record with `result` of PASS or FAIL. The measurement and the VPD
+//!    write can be run as separate steps by `measure-only` and `apply`.
+//!  * `speaker-check` - Checks the speaker health for the speaker check routine of cros_healthd.
+//!    It runs the checks of `self-test` and checks the live readouts of the amps, ex: the
+//!    speaker temperature. It prints a JSON record with `verdict` of passed, failed or error,
+//!    the `checks`, and the `live` readouts of each channel.
+//!  * `dump` - Prints the diagnostic archive of the sound card for the feedback reports, which is
+//!    invoked through debugd. It's a JSON record of the amp controls and the files of
+//!    sound_card_init: the status, the datastore, the recent run logs and the events. The
+//!    archive is bounded and sanitized, see `diagnostics::dump()`. It's printed even if the
+//!    config or the sound card is broken.
+//!
 //!  # Arguments
 //!
-//!  * `sound_card_id` - The sound card name, ex: sofcmlmax98390d.
+//!  * `sound_card_id` - The sound card name, ex: sofcmlmax98390d. If it's not given, the
+//!    command runs concurrently on every internal sound card which has a config, and the USB
+//!    sound cards are skipped. It's required by `daemon`.
+//!  * `user` - The optional user to drop privileges to after opening the sound card. It's only
+//!    supported by `boot_time_calibration`.
+//!  * `channel` - The index of the channel in the config to `reset`.
+//!  * `all` - Resets all the channels.
+//!  * `force` - Resets without confirmation.
+//!  * `measure-only` - Runs the measurement of `factory-calibrate` without writing the VPD. The
+//!    record has the measured values and the `result` of each channel, including the values out
+//!    of the factory limits, so that the factory station can track them.
+//!  * `apply` - Writes the values to VPD by `factory-calibrate` without measuring, ex:
+//!    `--apply=27000:1000,27100:1000` for the rdc and ambient temperature of each channel. The
+//!    values are checked against the factory limits first.
+//!  * `json` - Prints the state of `show` in JSON, which includes the calibration values, their
+//!    provenance and timestamps of each channel, and the outcome of the last boot time calibration.
+//!    The same state is kept in /var/log/sound_card_init/<sound_card_id>.status.json, which is
+//!    updated by `boot_time_calibration` and `reset`. The schema of the state is `SpeakerStatus`
+//!    of proto/speaker_status.proto, which is shared with the consumers in Chrome.
+//!  * `watch` - Refreshes the speaker temperature and the protection state of `show` every
+//!    second until it's interrupted.
+//!  * `dry-run` - Runs `boot_time_calibration` without writing the amp controls and the
+//!    datastore, and logs what would have been applied.
+//!  * `record-trace` - Records the control accesses of `boot_time_calibration` to the file, so
+//!    that the capture of a problematic device can be replayed against `cros_alsa::FakeCard`.
+//!    It requires `sound_card_id`, since the captures of the sound cards would overwrite each
+//!    other.
+//!  * `daemon` - Keeps running after `boot_time_calibration` to monitor the amps and apply the
+//!    runtime speaker protection. The monitor settings are reloaded when the config changes.
+//!    The state of the sound card in /run/sound_card_init/<sound_card_id>.status is refreshed
+//!    after every check.
+//!  * `amp` - Forces the amp driver regardless of the config and the sound card, ex:
+//!    `--amp=max98390d`. It's for bringing up the prototypes whose sound card names are not
+//!    final yet.
+//!  * `conf` - Uses the config file instead of the one named by the topology, cros_config, or the
+//!    sound card, ex: `--conf=sofcmlmax98390d-test.yaml`. A relative path is in the config
+//!    directory. It's used with `sound_card_id`, since the sound cards have different configs.
+//!  * `log-level` - The log levels of the modules, ex: `info,max98390d::amp_calibration=debug`.
+//!    It's `info` by default. The logs of `boot_time_calibration` after the privileges are
+//!    dropped are also written to a size capped run log in /var/log/sound_card_init.
+//!  * `log-stderr` - Also writes the logs to stderr.
+//!  * `config-dir` - Reads the configs from the directory instead of CONF_DIR, ex: to iterate
+//!    on the thresholds with local configs on a test image.
+//!  * `datastore-dir` - Uses the directory instead of /var/lib/sound_card_init as the datastore.
+//!  * `time-phases` - Prints the duration of each phase, ex: the sound card open, the VPD read,
+//!    the playback start, the measurement and the datastore write.
+//!  * `check-config` - Checks every config in the directory without the sound cards and prints
+//!    the problems, ex: `--check-config=files/` in the ebuild. The base configs are checked
+//!    through the configs which extend them. It exits with code 3 if any config is invalid.
+//!  * `timeout` - Bounds the total execution time in seconds, so that a wedged ALSA ioctl can't
+//!    hang the boot. On expiry, the volume of the unfinished amps is set low, the timeout is
+//!    recorded as the outcome of `boot_time_calibration`, and it exits with code 9.
+//!  * `dbus-service` - Serves `org.chromium.SoundCardInit` on the system bus instead of running
+//!    a command, see the `dbus_service` module. It needs the `dbus` feature.
+//!  * `control-socket` - Serves the control protocol on the Unix domain socket instead of
+//!    running a command, ex: `--control-socket=/run/sound_card_init/control`, which lets the
+//!    factory test frameworks query the status and run the measurements, see the
+//!    `control_socket` module.
+//!  * `factory-service` - Serves the steps of `factory-calibrate` on the TCP port of the loopback
+//!    interface instead of running a command, ex: `--factory-service=8730`, so that the factory
+//!    station software can orchestrate the calibration and pull the raw measurements, see the
+//!    `factory_service` module. It needs the `factory-service` feature.
+//!
+//!  The modes `check-config`, `dbus-service`, `control-socket` and `factory-service` replace the
+//!  command, so they are only accepted without a command.
+//!
+//!  # Exit codes
 //!
-//!  Given the `sound_card_id`, this binary parses the CONF_DIR/<sound_card_id>.yaml to perform per sound card initialization.
-//!  The upstart job of `sound_card_init` is started by the udev event specified in /lib/udev/rules.d/99-sound_card_init.rules.
+//!  * 0 - Success.
+//!  * 1 - Uncategorized failure.
+//!  * 2 - Invalid command line arguments.
+//!  * 3 - The config is missing, malformed or does not match the sound card.
+//!  * 4 - The sound card is not supported.
+//!  * 5 - The sound card is not available.
+//!  * 6 - CRAS or its internal speaker node is not available.
+//!  * 7 - The calibration values are rejected by the sanity checks.
+//!  * 8 - The datastore is corrupt.
+//!  * 9 - The command does not finish before the timeout.
+//!  * 10 - Uncategorized failure which may go away by retrying.
+//!
+//!  Given the `sound_card_id`, this binary parses the CONF_DIR/<sound_card_id>.yaml, or the
+//!  CONF_DIR/<sound_card_id>.json in JSON, to perform per sound card initialization.
+//!  A config may inherit a base config by `extends` and use the `${card}` and `${model}`
+//!  placeholders, see the `config` module.
+//!  On unibuild images, the config filename is given by `/audio/main sound-card-init-conf` of
+//!  cros_config instead, unless it's overridden by `conf`.
+//!  The amp is selected by the optional `amp` field of the config, ex: `amp: none` for the boards
+//!  without smart amps, or by `/audio/main speaker-amp` of cros_config.
+//!  The config of the amp driver may be put in the section named by the driver, ex:
+//!  `max98390d:`, and it's parsed into the config type of the driver. Otherwise, the whole config
+//!  is parsed by the driver.
+//!  A board may declare its sound cards, their amps and channels in CONF_DIR/topology.yaml, see
+//!  the `topology` module.
+//!  The upstart job of `sound_card_init` is started by the udev event specified in
+//!  /lib/udev/rules.d/99-sound_card_init.rules.
 #![deny(missing_docs)]
-use std::env;
-use std::error;
-use std::fmt;
-use std::fs;
-use std::io;
-use std::path::PathBuf;
-use std::process;
-use std::string::String;
 
-use getopts::Options;
-use remain::sorted;
-use sys_util::{error, info, syslog};
+mod amp;
+mod anomaly;
+mod args;
+mod calibration;
+mod check_config;
+mod checks;
+mod commands;
+mod config;
+mod control_socket;
+mod cros_config;
+mod dbus_service;
+mod diagnostics;
+mod error;
+mod factory_calibration;
+mod factory_service;
+#[cfg(feature = "fake-amp")]
+mod fake_amp;
+#[cfg(any(test, feature = "mock-amp"))]
+mod mock_amp;
+mod power;
+mod privilege;
+mod reset;
+mod run_state;
+mod sandbox;
+mod show;
+mod sound_cards;
+mod topology;
+mod watchdog;
 
-use max98390d::run_max98390d;
-use utils::run_time;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{mpsc, Barrier};
+use std::thread;
 
-type Result<T> = std::result::Result<T, Error>;
-const CONF_DIR: &str = "/etc/sound_card_init";
+use log::{error, info, warn};
+use utils::lock::CardLock;
+use utils::logger::{self, LogSpec};
+use utils::{bootstat, metrics, ExitCode};
 
-#[derive(Default)]
-struct Args {
-    pub sound_card_id: String,
-}
+use crate::amp::SafeStateOpener;
+use crate::args::{parse_args, Command};
+use crate::commands::print_phase_times;
+use crate::cros_config::BoardConfig;
+use crate::error::{Error, Result};
+use crate::privilege::drop_privileges;
+use crate::sandbox::confine;
+use crate::sound_cards::{init_amp, internal_sound_cards};
+use crate::topology::Topology;
+use crate::watchdog::Watchdog;
 
-#[sorted]
-#[derive(Debug)]
-enum Error {
-    MissingOption(String),
-    OpenConfigFailed(String, io::Error),
-    ParseArgsFailed(getopts::Fail),
-    UnsupportedSoundCard(String),
-}
+fn main() {
+    let args = parse_args();
+    // The parse errors are logged with the default log levels.
+    let (log_spec, log_stderr) = match &args {
+        Ok(args) => (args.log_spec.clone(), args.log_stderr),
+        Err(_) => (LogSpec::default(), false),
+    };
+    logger::init(log_spec, log_stderr).expect("failed to initialize logger");
+    let trace = utils::trace::init();
+    let args = match args {
+        Ok(args) => args,
+        Err(e) => {
+            error!("failed to parse arguments: {}", e);
+            process::exit(e.exit_code() as i32);
+        }
+    };
 
-impl error::Error for Error {}
+    if let Some(dir) = &args.check_config {
+        process::exit(check_config::run(dir) as i32);
+    }
+    if args.dbus_service {
+        process::exit(dbus_service::run(&args.config_dir) as i32);
+    }
+    if let Some(socket) = &args.control_socket {
+        process::exit(control_socket::run(&args.config_dir, socket) as i32);
+    }
+    if let Some(port) = args.factory_service {
+        process::exit(factory_service::run(&args.config_dir, port) as i32);
+    }
+    if let Some(dir) = &args.datastore_dir {
+        info!("datastore dir: {}", dir.display());
+        utils::set_datastore_dir(dir.clone());
+    }
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use Error::*;
-        match self {
-            MissingOption(option) => write!(f, "missing required option: {}", option),
-            OpenConfigFailed(file, e) => write!(f, "failed to open file {}: {}", file, e),
-            ParseArgsFailed(e) => write!(f, "parse_args failed: {}", e),
-            UnsupportedSoundCard(name) => write!(f, "unsupported sound card: {}", name),
+    let topology = match Topology::load(&args.config_dir) {
+        Ok(topology) => topology,
+        Err(e) => {
+            error!("failed to load the topology: {}", e);
+            process::exit(e.exit_code() as i32);
+        }
+    };
+    if let Some(topology) = &topology {
+        for card in &topology.cards {
+            info!("topology: {} is the {:?} card", card.id, card.role);
         }
     }
-}
 
-fn print_usage(opts: &Options) {
-    let brief = "Usage: sound_card_init [options]".to_owned();
-    print!("{}", opts.usage(&brief));
-}
+    let snd_cards = match (&args.sound_card_id, &topology) {
+        (Some(snd_card), _) => vec![snd_card.clone()],
+        (None, Some(topology)) => topology.cards.iter().map(|card| card.id.clone()).collect(),
+        (None, None) => match internal_sound_cards(&args.config_dir) {
+            Ok(snd_cards) => snd_cards,
+            Err(e) => {
+                error!("failed to find the internal sound cards: {}", e);
+                process::exit(e.exit_code() as i32);
+            }
+        },
+    };
 
-fn parse_args() -> Result<Args> {
-    let mut opts = Options::new();
-    opts.optopt("", "id", "sound card id", "ID");
-    opts.optflag("h", "help", "print help menu");
-    let matches = opts
-        .parse(&env::args().collect::<Vec<_>>()[1..])
-        .map_err(|e| {
-            print_usage(&opts);
-            Error::ParseArgsFailed(e)
-        })?;
-
-    if matches.opt_present("h") {
-        print_usage(&opts);
-        process::exit(0);
+    if args.command == Command::Reset
+        && !args.force
+        && !reset::confirm_reset(args.reset_channel, &snd_cards)
+    {
+        let e = Error::ResetCancelled;
+        error!("{}", e);
+        process::exit(e.exit_code() as i32);
     }
 
-    let sound_card_id = matches
-        .opt_str("id")
-        .ok_or_else(|| Error::MissingOption("id".to_owned()))
-        .map_err(|e| {
-            print_usage(&opts);
-            e
-        })?;
-
-    Ok(Args { sound_card_id })
-}
+    // cros_config describes the sound card of the speaker amp, which is given by `--id`. The
+    // other sound cards found by `internal_sound_cards()` have their own configs.
+    let board = match args.sound_card_id {
+        Some(_) => BoardConfig::query(),
+        None => BoardConfig {
+            model: cros_config::model(),
+            ..Default::default()
+        },
+    };
 
-fn get_config(args: &Args) -> Result<String> {
-    let config_path = PathBuf::from(CONF_DIR)
-        .join(&args.sound_card_id)
-        .with_extension("yaml");
+    // The boot time calibration is confined before the threads are spawned, since landlock only
+    // confines the calling thread and the threads it creates afterwards.
+    if args.command == Command::BootTimeCalibration {
+        let mut writable_dirs: Vec<_> = snd_cards
+            .iter()
+            .map(|id| utils::datastore_dir(id))
+            .collect();
+        writable_dirs.extend(
+            [
+                anomaly::CRASH_SPOOL_DIR,
+                diagnostics::DIAGNOSTICS_DIR,
+                bootstat::BOOTSTAT_DIR,
+                metrics::METRICS_DIR,
+            ]
+            .iter()
+            .map(PathBuf::from),
+        );
+        writable_dirs.push(utils::run_dir());
+        if let Err(e) = confine(&writable_dirs) {
+            error!("{}", e);
+            process::exit(e.exit_code() as i32);
+        }
+    }
 
-    fs::read_to_string(&config_path)
-        .map_err(|e| Error::OpenConfigFailed(config_path.to_string_lossy().to_string(), e))
-}
+    // The dry run must not touch the amps even on expiry.
+    let mut watchdog = args.timeout.map(|timeout| {
+        let boot = args.command == Command::BootTimeCalibration;
+        let dry_run = args.run_options.dry_run;
+        Watchdog::start(timeout, !dry_run, boot && !dry_run)
+    });
 
-/// Parses the CONF_DIR/<sound_card_id>.yaml and starts sound card initialization.
-fn sound_card_init(args: &Args) -> std::result::Result<(), Box<dyn error::Error>> {
-    info!("sound_card_id: {}", args.sound_card_id);
-    let conf = get_config(args)?;
+    // Each sound card is initialized in its own thread. The sound card handles of all the
+    // sound cards are opened as root before the privileges are dropped, and then the commands
+    // run concurrently. Nothing is written to the disk before the drop, since the jail only
+    // keeps CAP_SETUID and CAP_SETGID, and root can't write the files of the sound_card_init
+    // user without CAP_DAC_OVERRIDE. The lock of the sound card is opened read only.
+    let barrier = Barrier::new(snd_cards.len() + 1);
+    let (opened_tx, opened_rx) = mpsc::channel::<(String, Option<SafeStateOpener>)>();
+    let codes: Vec<ExitCode> = thread::scope(|s| {
+        let handles: Vec<_> = snd_cards
+            .iter()
+            .map(|snd_card| {
+                let (args, board, barrier) = (&args, &board, &barrier);
+                let card = topology
+                    .as_ref()
+                    .and_then(|topology| topology.card(snd_card));
+                let opened_tx = opened_tx.clone();
+                let watchdog = watchdog.clone();
+                s.spawn(move || {
+                    // The lock is held until the command finishes.
+                    let locked = args
+                        .command
+                        .locks_card()
+                        .then(|| CardLock::lock(snd_card))
+                        .transpose();
+                    let (lock, amp) = match locked {
+                        Ok(lock) => (lock, init_amp(args, board, card, snd_card)),
+                        Err(e) => (None, Err(e.into())),
+                    };
+                    let opener = amp.as_ref().ok().map(|amp| amp.safe_state_opener());
+                    let _ = opened_tx.send((snd_card.clone(), opener));
+                    drop(opened_tx);
+                    barrier.wait();
+                    let code = commands::run(args, board, card, snd_card, amp, watchdog);
+                    drop(lock);
+                    code
+                })
+            })
+            .collect();
+        drop(opened_tx);
 
-    match args.sound_card_id.as_str() {
-        "sofcmlmax98390d" => {
-            run_max98390d(&args.sound_card_id, &conf)?;
-            info!("run_max98390d() finished successfully.");
-            Ok(())
+        let openers: Vec<_> = opened_rx
+            .iter()
+            .filter_map(|(snd_card, opener)| Some((snd_card, opener?)))
+            .collect();
+        if let Some(watchdog) = watchdog.take() {
+            watchdog.arm(openers);
         }
-        _ => Err(Error::UnsupportedSoundCard(args.sound_card_id.clone()).into()),
-    }
-}
-
-fn main() {
-    syslog::init().expect("failed to initialize syslog");
-    let args = match parse_args() {
-        Ok(args) => args,
-        Err(e) => {
-            error!("failed to parse arguments: {}", e);
-            return;
+        if let Some(user) = &args.user {
+            if let Err(e) = drop_privileges(user) {
+                error!("{}", e);
+                process::exit(e.exit_code() as i32);
+            }
+            info!("dropped privileges to {}", user);
         }
-    };
+        // The run log is created after the privileges are dropped, since root can't write the
+        // directory of the sound_card_init user in the jail. It has the records of the commands.
+        if args.command == Command::BootTimeCalibration {
+            match logger::open_run_log(Path::new(diagnostics::DIAGNOSTICS_DIR)) {
+                Ok(path) => info!("run log: {}", path.display()),
+                Err(e) => warn!("failed to open the run log: {}", e),
+            }
+        }
+        barrier.wait();
 
-    if let Err(e) = sound_card_init(&args) {
-        error!("sound_card_init: {}", e);
-    }
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or(ExitCode::Failure))
+            .collect()
+    });
 
-    if let Err(e) = run_time::now_to_file(&args.sound_card_id) {
-        error!("failed to create sound_card_init run time file: {}", e);
+    if snd_cards.len() > 1 {
+        for (snd_card, code) in snd_cards.iter().zip(codes.iter()) {
+            info!(
+                "sound_card_init {} {}: {:?}",
+                args.command.name(),
+                snd_card,
+                code
+            );
+        }
+    }
+    if args.time_phases {
+        print_phase_times();
     }
+    // Every sound card is initialized even if the others fail, and the exit code is the code
+    // of the first failure in the order of the sound cards.
+    let code = codes
+        .into_iter()
+        .fold(ExitCode::Success, |first, code| match first {
+            ExitCode::Success => code,
+            _ => first,
+        });
+    trace.flush();
+    process::exit(code as i32);
 }
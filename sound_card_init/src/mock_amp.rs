@@ -0,0 +1,473 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It implements `MockAmp`, the amp driver of the `mock-amp` feature, which runs the boot time
+//! calibration workflow of sound_card_init without the hardware, ex: the retries, the last run
+//! outcome and the safe mode. It's selected by `--amp=mock` or `amp: mock`, and scripted by the
+//! `mock` section of the config:
+//!
+//! ```yaml
+//! amp: mock
+//! mock:
+//!   channels:
+//!     - measurements: [[12000, 30], [12100, 90]]
+//!       temp_lower_limit: 0
+//!       temp_upper_limit: 80
+//!   failures: [transient, null, permanent]
+//!   cool_down_secs: 180
+//!   shutdowns: [600, 10, null]
+//! ```
+//!
+//! Each boot time calibration takes the next failure, and measures every channel if it's null
+//! or there are no failures left. A channel takes its measurements in order, and repeats the
+//! last one after they run out. The values are applied if the temperature is within the
+//! limits, and the volume is left low otherwise.
+//!
+//! Before the measurement, each boot time calibration also takes the seconds since the CRAS
+//! shutdown from `shutdowns`, where null is a shutdown before the last run, and decides like
+//! max98390d by `max98390d::check_cool_down()`. The applied values are restored if the speakers
+//! may be hot, and removed if the shutdown time is invalid. The check is skipped once there
+//! are no shutdowns left, like on the first boot.
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::time::Duration;
+
+use log::info;
+use remain::sorted;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use utils::RunOptions;
+
+use crate::amp::{Amp, AmpType};
+
+/// The failures injected into the boot time calibration.
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Failure {
+    /// A failure which goes away by retrying.
+    Transient,
+    /// A failure which does not go away by retrying.
+    Permanent,
+}
+
+/// The errors of `MockAmp`.
+#[sorted]
+#[derive(Debug)]
+pub enum Error {
+    Injected(Failure),
+    InvalidTemperature(usize, i32),
+    InvalidThresholds(usize),
+    NoMeasurement(usize),
+}
+
+impl Error {
+    /// Returns true if the error may go away by retrying.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Error::Injected(Failure::Transient))
+    }
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Error::*;
+        match self {
+            Injected(failure) => write!(f, "injected {:?} failure", failure),
+            InvalidTemperature(ch, temp) => {
+                write!(f, "channel {}: invalid temperature: {}", ch, temp)
+            }
+            InvalidThresholds(ch) => write!(f, "channel {}: temp limits are not ordered", ch),
+            NoMeasurement(ch) => write!(f, "channel {}: no measurement", ch),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+// The unix time of the scripted boot time calibrations.
+const MOCK_NOW: Duration = Duration::from_secs(1_600_000_000);
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MockConfig {
+    channels: Vec<ChannelConfig>,
+    #[serde(default)]
+    failures: VecDeque<Option<Failure>>,
+    #[serde(default)]
+    cool_down_secs: u64,
+    #[serde(default)]
+    shutdowns: VecDeque<Option<u64>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChannelConfig {
+    // The rdc and ambient temperature of each calibration.
+    measurements: VecDeque<(i32, i32)>,
+    temp_lower_limit: i32,
+    temp_upper_limit: i32,
+}
+
+// The state of a mock amp channel.
+#[derive(Debug)]
+struct Channel {
+    config: ChannelConfig,
+    // The applied rdc and ambient temperature.
+    applied: Option<(i32, i32)>,
+    volume_high: bool,
+}
+
+impl Channel {
+    fn measure(&mut self, ch: usize) -> Result<(i32, i32)> {
+        let measurements = &mut self.config.measurements;
+        let values = match measurements.len() {
+            0 => return Err(Error::NoMeasurement(ch)),
+            1 => measurements[0],
+            _ => measurements.pop_front().unwrap_or_default(),
+        };
+        Ok(values)
+    }
+
+    fn status(&self) -> Value {
+        json!({
+            "rdc": self.applied.map(|(rdc, _)| rdc),
+            "ambient_temp": self.applied.map(|(_, temp)| temp),
+            "volume": if self.volume_high { "high" } else { "low" },
+        })
+    }
+}
+
+/// `MockAmp` is an amp whose measurements and failures are scripted by the config.
+pub struct MockAmp {
+    channels: Vec<Channel>,
+    failures: VecDeque<Option<Failure>>,
+    cool_down: Duration,
+    shutdowns: VecDeque<Option<u64>>,
+}
+
+impl MockAmp {
+    /// Creates the `MockAmp` of the script in the config.
+    pub fn from_config(
+        _snd_card: &str,
+        conf: &str,
+    ) -> std::result::Result<Box<dyn Amp>, Box<dyn error::Error>> {
+        let config: MockConfig = serde_yaml::from_str(conf)?;
+        for (i, ch) in config.channels.iter().enumerate() {
+            if ch.temp_lower_limit >= ch.temp_upper_limit {
+                return Err(Box::new(Error::InvalidThresholds(i)));
+            }
+        }
+        Ok(Box::new(MockAmp {
+            channels: config
+                .channels
+                .into_iter()
+                .map(|config| Channel {
+                    config,
+                    applied: None,
+                    volume_high: false,
+                })
+                .collect(),
+            failures: config.failures,
+            cool_down: Duration::from_secs(config.cool_down_secs),
+            shutdowns: config.shutdowns,
+        }))
+    }
+
+    // Checks the cool down of the speakers by the next shutdown of the script.
+    fn check_cool_down(&mut self) -> std::result::Result<(), Box<dyn error::Error>> {
+        let shutdown = match self.shutdowns.pop_front() {
+            Some(shutdown) => shutdown,
+            None => return Ok(()),
+        };
+        // The last run is right before the shutdown, or right after it if the shutdown time is
+        // invalid.
+        let second = Duration::from_secs(1);
+        let (last_run, last_shutdown) = match shutdown {
+            Some(secs) => {
+                let last_shutdown = MOCK_NOW.saturating_sub(Duration::from_secs(secs));
+                (last_shutdown.saturating_sub(second), last_shutdown)
+            }
+            None => (MOCK_NOW, MOCK_NOW - second),
+        };
+        max98390d::check_cool_down(last_run, last_shutdown, MOCK_NOW, self.cool_down)?;
+        Ok(())
+    }
+
+    // Calibrates every channel, and returns the first error. The other channels are still
+    // calibrated.
+    fn calibrate(&mut self, opts: &RunOptions) -> Result<()> {
+        let mut first_error = None;
+        for (i, ch) in self.channels.iter_mut().enumerate() {
+            ch.volume_high = false;
+            let res = ch.measure(i).and_then(|(rdc, temp)| {
+                if temp <= ch.config.temp_lower_limit || temp >= ch.config.temp_upper_limit {
+                    return Err(Error::InvalidTemperature(i, temp));
+                }
+                Ok((rdc, temp))
+            });
+            match res {
+                Ok(values) if opts.dry_run => info!("dry run: skip applying {:?}", values),
+                Ok(values) => {
+                    ch.applied = Some(values);
+                    ch.volume_high = true;
+                }
+                Err(e) => {
+                    info!("{}, volume remains low", e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    }
+}
+
+impl Amp for MockAmp {
+    fn amp_type(&self) -> AmpType {
+        AmpType::Mock
+    }
+
+    fn boot_time_calibration(
+        &mut self,
+        opts: &RunOptions,
+    ) -> std::result::Result<(), Box<dyn error::Error>> {
+        if let Some(failure) = self.failures.pop_front().flatten() {
+            return Err(Box::new(Error::Injected(failure)));
+        }
+        if let Err(e) = self.check_cool_down() {
+            // The values of the last calibration are restored if the speakers may be hot, and
+            // are no longer trusted if the shutdown time is invalid.
+            let hot = max98390d::is_hot_speaker(e.as_ref());
+            for ch in self.channels.iter_mut() {
+                if !hot {
+                    ch.applied = None;
+                }
+                ch.volume_high = ch.applied.is_some();
+            }
+            info!("{}, skip the calibration", e);
+            return Err(e);
+        }
+        Ok(self.calibrate(opts)?)
+    }
+
+    fn validate(&mut self) -> std::result::Result<(), Box<dyn error::Error>> {
+        Ok(())
+    }
+
+    fn show(&mut self) -> std::result::Result<String, Box<dyn error::Error>> {
+        Ok(self
+            .channels
+            .iter()
+            .enumerate()
+            .map(|(i, ch)| format!("channel {}: {}", i, ch.status()))
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+
+    fn live_status(&mut self) -> std::result::Result<String, Box<dyn error::Error>> {
+        self.show()
+    }
+
+    fn show_json(&mut self) -> std::result::Result<Value, Box<dyn error::Error>> {
+        Ok(Value::Array(
+            self.channels.iter().map(Channel::status).collect(),
+        ))
+    }
+
+    fn channel_count(&self) -> std::result::Result<usize, Box<dyn error::Error>> {
+        Ok(self.channels.len())
+    }
+
+    fn reset(&mut self, channel: Option<usize>) -> std::result::Result<(), Box<dyn error::Error>> {
+        for (i, ch) in self.channels.iter_mut().enumerate() {
+            if channel.is_none() || channel == Some(i) {
+                ch.applied = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn self_test(&mut self) -> std::result::Result<Value, Box<dyn error::Error>> {
+        Ok(Value::Array(
+            (0..self.channels.len())
+                .map(|i| json!({ "check": "mock", "channel": i, "result": "PASS" }))
+                .collect(),
+        ))
+    }
+
+    fn factory_calibrate(&mut self) -> std::result::Result<Value, Box<dyn error::Error>> {
+        let values = self
+            .channels
+            .iter_mut()
+            .enumerate()
+            .map(|(i, ch)| {
+                let (rdc, temp) = ch.measure(i)?;
+                Ok(json!({ "rdc": rdc, "ambient_temp": temp }))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Value::Array(values))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The `mock` section of the config.
+    const CONF: &str = "
+channels:
+  - measurements: [[12000, 30], [12100, 40]]
+    temp_lower_limit: 0
+    temp_upper_limit: 80
+  - measurements: [[13000, 30], [13100, 90]]
+    temp_lower_limit: 0
+    temp_upper_limit: 80
+cool_down_secs: 180
+";
+
+    fn mock_amp(script: &str) -> Box<dyn Amp> {
+        MockAmp::from_config("mock", &format!("{}{}", CONF, script)).unwrap()
+    }
+
+    fn boot(amp: &mut Box<dyn Amp>) -> std::result::Result<(), Box<dyn error::Error>> {
+        amp.boot_time_calibration(&RunOptions::default())
+    }
+
+    // Returns the applied rdc and the volume of each channel.
+    fn state(amp: &mut Box<dyn Amp>) -> Vec<(Option<i64>, String)> {
+        amp.show_json()
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|ch| {
+                (
+                    ch["rdc"].as_i64(),
+                    ch["volume"].as_str().unwrap().to_owned(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn calibrates_cool_speakers() {
+        let mut amp = mock_amp("shutdowns: [600]");
+        boot(&mut amp).unwrap();
+        assert_eq!(
+            state(&mut amp),
+            vec![
+                (Some(12000), "high".to_owned()),
+                (Some(13000), "high".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn hot_speaker_keeps_applied_values() {
+        let mut amp = mock_amp("shutdowns: [600, 10]");
+        boot(&mut amp).unwrap();
+        let err = boot(&mut amp).unwrap_err();
+        assert!(max98390d::is_hot_speaker(err.as_ref()));
+        assert_eq!(crate::error::error_code(err.as_ref()), 208);
+        assert!(!crate::error::is_transient(err.as_ref()));
+        // The values of the first calibration stay in effect without a measurement.
+        assert_eq!(
+            state(&mut amp),
+            vec![
+                (Some(12000), "high".to_owned()),
+                (Some(13000), "high".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn hot_speaker_without_values_keeps_volume_low() {
+        let mut amp = mock_amp("shutdowns: [10]");
+        let err = boot(&mut amp).unwrap_err();
+        assert!(max98390d::is_hot_speaker(err.as_ref()));
+        assert_eq!(
+            state(&mut amp),
+            vec![(None, "low".to_owned()), (None, "low".to_owned())]
+        );
+    }
+
+    #[test]
+    fn invalid_shutdown_time_removes_applied_values() {
+        let mut amp = mock_amp("shutdowns: [600, null]");
+        boot(&mut amp).unwrap();
+        let err = boot(&mut amp).unwrap_err();
+        assert!(!max98390d::is_hot_speaker(err.as_ref()));
+        assert_eq!(crate::error::error_code(err.as_ref()), 215);
+        assert_eq!(
+            state(&mut amp),
+            vec![(None, "low".to_owned()), (None, "low".to_owned())]
+        );
+    }
+
+    #[test]
+    fn rejected_values_leave_volume_low() {
+        let mut amp = mock_amp("");
+        boot(&mut amp).unwrap();
+        // The second measurement of channel 1 is over the temperature limit.
+        let err = boot(&mut amp).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidTemperature(1, 90))
+        ));
+        assert!(!crate::error::is_transient(err.as_ref()));
+        // The other channel is still calibrated, and the rejected channel keeps its values.
+        assert_eq!(
+            state(&mut amp),
+            vec![
+                (Some(12100), "high".to_owned()),
+                (Some(13000), "low".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn dry_run_does_not_apply_values() {
+        let mut amp = mock_amp("");
+        let opts = RunOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        amp.boot_time_calibration(&opts).unwrap();
+        assert_eq!(
+            state(&mut amp),
+            vec![(None, "low".to_owned()), (None, "low".to_owned())]
+        );
+    }
+
+    #[test]
+    fn injected_failures_are_taken_in_order() {
+        let mut amp = mock_amp("failures: [transient, null, permanent]");
+        let err = boot(&mut amp).unwrap_err();
+        assert!(crate::error::is_transient(err.as_ref()));
+        boot(&mut amp).unwrap();
+        let err = boot(&mut amp).unwrap_err();
+        assert!(!crate::error::is_transient(err.as_ref()));
+        // The calibration after the failures takes the second measurements.
+        let err = boot(&mut amp).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidTemperature(1, 90))
+        ));
+    }
+
+    #[test]
+    fn rejects_unordered_limits() {
+        let conf = "
+channels:
+  - measurements: [[12000, 30]]
+    temp_lower_limit: 80
+    temp_upper_limit: 0
+";
+        let err = MockAmp::from_config("mock", conf).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::InvalidThresholds(0))
+        ));
+    }
+}
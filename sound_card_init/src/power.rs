@@ -0,0 +1,343 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It coordinates the boot time calibration with powerd over D-Bus. The calibration is deferred
+//! on a low battery or while the device is thermally throttled, since the measurement plays the
+//! pilot tone at a high current and the hot speakers read a wrong rdc. During the calibration,
+//! a suspend delay is registered, so that an opportunistic suspend never cuts off the
+//! measurement.
+//!
+//! It needs the `dbus` feature. Without it, or if powerd can't be reached, the calibration runs
+//! as if the device was on AC power.
+//!
+//! The powerd methods exchange serialized protocol buffers of
+//! system_api/dbus/power_manager. Only the few fields used here are encoded and decoded.
+
+/// Returns true if the boot time calibration should be deferred for the power state.
+pub fn defer_calibration() -> bool {
+    imp::defer_calibration()
+}
+
+/// `SuspendDelay` defers the suspends until it's dropped. A suspend requested meanwhile goes
+/// ahead once the guard is dropped, or after the delay timeout of powerd.
+pub struct SuspendDelay {
+    #[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+    inner: imp::SuspendDelay,
+}
+
+impl SuspendDelay {
+    /// Registers a suspend delay of powerd, or returns None if it can't be registered. The
+    /// description is shown in the powerd logs.
+    pub fn register(description: &str) -> Option<SuspendDelay> {
+        imp::SuspendDelay::register(description).map(|inner| SuspendDelay { inner })
+    }
+}
+
+#[cfg(feature = "dbus")]
+mod imp {
+    use std::convert::TryInto;
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::sync::{Arc, Mutex, PoisonError};
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    use dbus::blocking::Connection;
+    use dbus::message::MatchRule;
+    use log::{error, info, warn};
+
+    const SERVICE_NAME: &str = "org.chromium.PowerManager";
+    const OBJECT_PATH: &str = "/org/chromium/PowerManager";
+    const INTERFACE: &str = "org.chromium.PowerManager";
+    const METHOD_TIMEOUT: Duration = Duration::from_secs(5);
+    // The longest time powerd waits for the calibration before suspending anyway.
+    const SUSPEND_DELAY_TIMEOUT: Duration = Duration::from_secs(30);
+    // The timeout of each round of processing the suspend signals.
+    const PROCESS_TIMEOUT: Duration = Duration::from_millis(100);
+    // The battery percent below which the calibration is deferred on battery power.
+    const MIN_BATTERY_PERCENT: f64 = 20.0;
+
+    // PowerSupplyProperties.external_power, which is AC if it's absent.
+    const EXTERNAL_POWER_FIELD: u32 = 14;
+    const EXTERNAL_POWER_AC: u64 = 0;
+    // PowerSupplyProperties.battery_percent, a double.
+    const BATTERY_PERCENT_FIELD: u32 = 7;
+    // ThermalEvent.thermal_state, of which SERIOUS and CRITICAL throttle the device.
+    const THERMAL_STATE_FIELD: u32 = 1;
+    const THERMAL_STATE_SERIOUS: u64 = 3;
+
+    type Result<T> = std::result::Result<T, dbus::Error>;
+
+    // Appends a varint.
+    fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+        while v >= 0x80 {
+            buf.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        buf.push(v as u8);
+    }
+
+    // Appends a varint field.
+    fn put_uint(buf: &mut Vec<u8>, field: u32, v: u64) {
+        put_varint(buf, (field << 3) as u64);
+        put_varint(buf, v);
+    }
+
+    // Appends a length-delimited field.
+    fn put_bytes(buf: &mut Vec<u8>, field: u32, v: &[u8]) {
+        put_varint(buf, ((field << 3) | 2) as u64);
+        put_varint(buf, v.len() as u64);
+        buf.extend_from_slice(v);
+    }
+
+    fn get_varint(bytes: &[u8], pos: &mut usize) -> Option<u64> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = *bytes.get(*pos)?;
+            *pos += 1;
+            v |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Some(v);
+            }
+        }
+        None
+    }
+
+    // Returns the varint and fixed-width fields of a message by field number. The fixed-width
+    // values are kept as their bits, and the length-delimited fields are skipped.
+    fn get_fields(bytes: &[u8]) -> Option<Vec<(u32, u64)>> {
+        let mut fields = Vec::new();
+        let mut pos = 0;
+        while pos < bytes.len() {
+            let key = get_varint(bytes, &mut pos)?;
+            let field = (key >> 3) as u32;
+            match key & 7 {
+                0 => fields.push((field, get_varint(bytes, &mut pos)?)),
+                1 => {
+                    let v = bytes.get(pos..pos + 8)?;
+                    fields.push((field, u64::from_le_bytes(v.try_into().ok()?)));
+                    pos += 8;
+                }
+                2 => pos += get_varint(bytes, &mut pos)? as usize,
+                5 => {
+                    let v = bytes.get(pos..pos + 4)?;
+                    fields.push((field, u32::from_le_bytes(v.try_into().ok()?) as u64));
+                    pos += 4;
+                }
+                _ => return None,
+            }
+        }
+        Some(fields)
+    }
+
+    fn get_field(fields: &[(u32, u64)], field: u32) -> Option<u64> {
+        fields
+            .iter()
+            .rev()
+            .find(|(f, _)| *f == field)
+            .map(|(_, v)| *v)
+    }
+
+    fn parse_err(method: &str) -> dbus::Error {
+        dbus::Error::new_failed(&format!("malformed reply of {}", method))
+    }
+
+    // Calls a powerd method with a serialized request, and returns the serialized reply.
+    fn call(conn: &Connection, method: &str, request: Option<Vec<u8>>) -> Result<Vec<u8>> {
+        let proxy = conn.with_proxy(SERVICE_NAME, OBJECT_PATH, METHOD_TIMEOUT);
+        let (reply,): (Vec<u8>,) = match request {
+            Some(request) => proxy.method_call(INTERFACE, method, (request,))?,
+            None => proxy.method_call(INTERFACE, method, ())?,
+        };
+        Ok(reply)
+    }
+
+    fn on_low_power(conn: &Connection) -> Result<bool> {
+        let reply = call(conn, "GetPowerSupplyProperties", None)?;
+        let fields = get_fields(&reply).ok_or_else(|| parse_err("GetPowerSupplyProperties"))?;
+        let on_ac = get_field(&fields, EXTERNAL_POWER_FIELD).unwrap_or(EXTERNAL_POWER_AC)
+            == EXTERNAL_POWER_AC;
+        let battery_percent = get_field(&fields, BATTERY_PERCENT_FIELD).map(f64::from_bits);
+        info!(
+            "power supply: ac: {}, battery: {:?}%",
+            on_ac, battery_percent
+        );
+        Ok(!on_ac && battery_percent.is_some_and(|p| p >= 0.0 && p < MIN_BATTERY_PERCENT))
+    }
+
+    fn is_throttled(conn: &Connection) -> Result<bool> {
+        let reply = call(conn, "GetThermalState", None)?;
+        let fields = get_fields(&reply).ok_or_else(|| parse_err("GetThermalState"))?;
+        let state = get_field(&fields, THERMAL_STATE_FIELD).unwrap_or(0);
+        info!("thermal state: {}", state);
+        Ok(state >= THERMAL_STATE_SERIOUS)
+    }
+
+    pub fn defer_calibration() -> bool {
+        let conn = match Connection::new_system() {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("failed to connect to the system bus: {}", e);
+                return false;
+            }
+        };
+        [on_low_power, is_throttled]
+            .iter()
+            .any(|check| match check(&conn) {
+                Ok(defer) => defer,
+                Err(e) => {
+                    warn!("failed to query powerd: {}", e);
+                    false
+                }
+            })
+    }
+
+    pub struct SuspendDelay {
+        stop: Option<Sender<()>>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl SuspendDelay {
+        pub fn register(description: &str) -> Option<SuspendDelay> {
+            let (ready_tx, ready_rx) = mpsc::channel();
+            let (stop_tx, stop_rx) = mpsc::channel();
+            let description = description.to_owned();
+            let thread = thread::spawn(move || {
+                let delay = match Delay::register(&description) {
+                    Ok(delay) => delay,
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = ready_tx.send(Ok(()));
+                delay.run(stop_rx);
+            });
+            match ready_rx.recv() {
+                Ok(Ok(())) => Some(SuspendDelay {
+                    stop: Some(stop_tx),
+                    thread: Some(thread),
+                }),
+                Ok(Err(e)) => {
+                    warn!("failed to register the suspend delay: {}", e);
+                    None
+                }
+                Err(_) => None,
+            }
+        }
+    }
+
+    impl Drop for SuspendDelay {
+        fn drop(&mut self) {
+            // Dropping the sender stops the thread.
+            self.stop.take();
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    // The suspend delay registered on the connection of its thread.
+    struct Delay {
+        conn: Connection,
+        delay_id: u64,
+        // The id of the suspend waiting for the calibration. The poisoning of the lock is
+        // ignored, since the id is always replaced as a whole.
+        pending: Arc<Mutex<Option<u64>>>,
+    }
+
+    impl Delay {
+        fn register(description: &str) -> Result<Delay> {
+            let conn = Connection::new_system()?;
+            // RegisterSuspendDelayRequest { timeout (us) = 1, description = 2 }.
+            let mut request = Vec::new();
+            put_uint(&mut request, 1, SUSPEND_DELAY_TIMEOUT.as_micros() as u64);
+            put_bytes(&mut request, 2, description.as_bytes());
+            let reply = call(&conn, "RegisterSuspendDelay", Some(request))?;
+            // RegisterSuspendDelayReply { delay_id = 1 }.
+            let delay_id = get_fields(&reply)
+                .and_then(|fields| get_field(&fields, 1))
+                .ok_or_else(|| parse_err("RegisterSuspendDelay"))?;
+
+            let pending = Arc::new(Mutex::new(None));
+            let suspends = pending.clone();
+            conn.add_match(
+                MatchRule::new_signal(INTERFACE, "SuspendImminent"),
+                move |(signal,): (Vec<u8>,), _, _| {
+                    // SuspendImminent { suspend_id = 1 }.
+                    let suspend_id = get_fields(&signal).and_then(|fields| get_field(&fields, 1));
+                    info!(
+                        "defer suspend {:?} until the calibration finishes",
+                        suspend_id
+                    );
+                    *suspends.lock().unwrap_or_else(PoisonError::into_inner) = suspend_id;
+                    true
+                },
+            )?;
+            info!("registered suspend delay {}", delay_id);
+            Ok(Delay {
+                conn,
+                delay_id,
+                pending,
+            })
+        }
+
+        // Processes the suspend signals until the stop channel is closed, then lets the pending
+        // suspend go ahead and unregisters the delay.
+        fn run(self, stop: Receiver<()>) {
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stop.recv_timeout(PROCESS_TIMEOUT) {
+                if let Err(e) = self.conn.process(Duration::ZERO) {
+                    error!("failed to process the suspend signals: {}", e);
+                    break;
+                }
+            }
+            if let Some(suspend_id) = self
+                .pending
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take()
+            {
+                // SuspendReadinessInfo { delay_id = 1, suspend_id = 2 }.
+                let mut info = Vec::new();
+                put_uint(&mut info, 1, self.delay_id);
+                put_uint(&mut info, 2, suspend_id);
+                if let Err(e) = self.handle_readiness(info) {
+                    error!("failed to report the suspend readiness: {}", e);
+                }
+            }
+            // UnregisterSuspendDelayRequest { delay_id = 1 }.
+            let mut request = Vec::new();
+            put_uint(&mut request, 1, self.delay_id);
+            if let Err(e) = self.unregister(request) {
+                error!("failed to unregister the suspend delay: {}", e);
+            }
+        }
+
+        // The methods of the suspend delay have no reply.
+        fn handle_readiness(&self, info: Vec<u8>) -> Result<()> {
+            self.conn
+                .with_proxy(SERVICE_NAME, OBJECT_PATH, METHOD_TIMEOUT)
+                .method_call(INTERFACE, "HandleSuspendReadiness", (info,))
+        }
+
+        fn unregister(&self, request: Vec<u8>) -> Result<()> {
+            self.conn
+                .with_proxy(SERVICE_NAME, OBJECT_PATH, METHOD_TIMEOUT)
+                .method_call(INTERFACE, "UnregisterSuspendDelay", (request,))
+        }
+    }
+}
+
+#[cfg(not(feature = "dbus"))]
+mod imp {
+    pub fn defer_calibration() -> bool {
+        false
+    }
+
+    pub struct SuspendDelay;
+
+    impl SuspendDelay {
+        pub fn register(_description: &str) -> Option<SuspendDelay> {
+            None
+        }
+    }
+}
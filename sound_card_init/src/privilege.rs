@@ -0,0 +1,77 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It drops the privileges of sound_card_init after the sound card handles are opened.
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem;
+use std::ptr;
+
+use libc::{c_char, gid_t, passwd, uid_t};
+
+use crate::{Error, Result};
+
+// The buffer size of the passwd entry strings.
+const PASSWD_BUF_SIZE: usize = 4096;
+
+/// Switches the process to the user, its primary group and its supplementary groups.
+/// Switching all the uids away from root also clears the capability sets of the process, so
+/// the opened file descriptors are the only privileges kept.
+///
+/// # Errors
+///
+/// * If the user does not exist.
+/// * If it fails to set the groups or the uids.
+pub fn drop_privileges(user: &str) -> Result<()> {
+    let name = CString::new(user).map_err(|_| Error::UnknownUser(user.to_owned()))?;
+    let (uid, gid) = lookup_user(&name)?;
+    let failed = |e| Error::DropPrivilegesFailed(user.to_owned(), e);
+
+    // Safe because name is a valid C string. The groups must be set before the uid changes.
+    if unsafe { libc::initgroups(name.as_ptr(), gid) } < 0 {
+        return Err(failed(io::Error::last_os_error()));
+    }
+    // Safe because it has no pointer arguments and the return value is checked.
+    if unsafe { libc::setresgid(gid, gid, gid) } < 0 {
+        return Err(failed(io::Error::last_os_error()));
+    }
+    // Safe because it has no pointer arguments and the return value is checked.
+    if unsafe { libc::setresuid(uid, uid, uid) } < 0 {
+        return Err(failed(io::Error::last_os_error()));
+    }
+    // Makes sure root can't be regained.
+    // Safe because it has no pointer arguments.
+    if unsafe { libc::setresuid(0, 0, 0) } == 0 {
+        return Err(failed(io::Error::from(io::ErrorKind::PermissionDenied)));
+    }
+    Ok(())
+}
+
+fn lookup_user(name: &CStr) -> Result<(uid_t, gid_t)> {
+    let user = || name.to_string_lossy().to_string();
+    // Safe because passwd is a plain C struct which can be zero initialized.
+    let mut pwd: passwd = unsafe { mem::zeroed() };
+    let mut result: *mut passwd = ptr::null_mut();
+    let mut buf = vec![0 as c_char; PASSWD_BUF_SIZE];
+    // Safe because all the pointers are valid, buf.len() is the size of buf and result is
+    // checked before use.
+    let rc = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut pwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if rc != 0 {
+        return Err(Error::DropPrivilegesFailed(
+            user(),
+            io::Error::from_raw_os_error(rc),
+        ));
+    }
+    if result.is_null() {
+        return Err(Error::UnknownUser(user()));
+    }
+    Ok((pwd.pw_uid, pwd.pw_gid))
+}
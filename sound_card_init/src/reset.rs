@@ -0,0 +1,52 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It implements `reset`, which removes the stored calibration values of a channel or all the
+//! channels, ex: after a speaker or amp replacement.
+use std::error;
+use std::io::{self, Write};
+
+use utils::last_run;
+
+use crate::amp::Amp;
+use crate::show::write_status_summary;
+
+/// Asks for the confirmation of resetting the channel, or all the channels if it's None, of the
+/// sound cards.
+pub fn confirm_reset(channel: Option<usize>, snd_cards: &[String]) -> bool {
+    let channels = match channel {
+        Some(channel) => format!("channel {}", channel),
+        None => "all the channels".to_owned(),
+    };
+    confirm(&format!(
+        "Reset the calibration of {} of {}?",
+        channels,
+        snd_cards.join(", ")
+    ))
+}
+
+/// Resets the channel, or all the channels if it's None. Resetting all the channels also
+/// re-enables the boot time calibration in safe mode.
+pub fn reset(
+    snd_card: &str,
+    amp: &mut dyn Amp,
+    channel: Option<usize>,
+) -> std::result::Result<(), Box<dyn error::Error>> {
+    amp.reset(channel)?;
+    if channel.is_none() {
+        last_run::remove(snd_card)?;
+    }
+    write_status_summary(snd_card, Some(amp));
+    Ok(())
+}
+
+// Asks for confirmation on stdin. It's true only if the answer is y or yes.
+fn confirm(prompt: &str) -> bool {
+    print!("{} [y/N] ", prompt);
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
@@ -0,0 +1,106 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It publishes the state of sound_card_init on a sound card to the state file,
+//! /run/sound_card_init/<sound_card_id>.status, which is the integration point for the tools
+//! that can't speak D-Bus, ex: the shell scripts of the autotests. The file is replaced
+//! atomically after every boot time calibration, and refreshed after every check of the daemon
+//! mode.
+//!
+//! The file has one `key=value` line per field, and starts with the version of the format:
+//!
+//! ```text
+//! version=1
+//! sound_card_id=sofcmlmax98390d
+//! time=1600000000
+//! pid=1234
+//! state=monitoring
+//! exit_code=0
+//! error_code=0
+//! safe_mode=0
+//! ```
+//!
+//! The `state` is `calibrated` or `failed` after the boot time calibration, and `monitoring`
+//! while the daemon mode runs. The `exit_code` is the `ExitCode` of the calibration, and the
+//! `error_code` is the code of its error, or 0 if it succeeds. The fields may be added in the
+//! same version, so the readers must ignore the unknown keys. The version is bumped when a
+//! field is removed or changes its meaning.
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+
+use utils::clock::clock;
+use utils::{run_dir, ExitCode};
+
+/// The version of the format of the state file.
+pub const VERSION: u32 = 1;
+
+const STATE_FILE_EXTENSION: &str = "status";
+
+/// The state of sound_card_init on a sound card.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    /// The boot time calibration succeeded.
+    Calibrated,
+    /// The boot time calibration failed.
+    Failed,
+    /// The daemon mode is monitoring the amps.
+    Monitoring,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            State::Calibrated => write!(f, "calibrated"),
+            State::Failed => write!(f, "failed"),
+            State::Monitoring => write!(f, "monitoring"),
+        }
+    }
+}
+
+/// The fields of the state file.
+#[derive(Debug, Clone)]
+pub struct RunState {
+    pub state: State,
+    pub exit_code: ExitCode,
+    pub error_code: u32,
+    pub safe_mode: bool,
+}
+
+/// Returns the path of the state file of the sound card.
+pub fn state_file(snd_card: &str) -> PathBuf {
+    run_dir()
+        .join(snd_card)
+        .with_extension(STATE_FILE_EXTENSION)
+}
+
+impl RunState {
+    /// Replaces the state file of the sound card atomically, so the readers never see a
+    /// partial state. It returns the path of the file.
+    ///
+    /// # Errors
+    ///
+    /// * If the file can't be written.
+    pub fn write(&self, snd_card: &str) -> io::Result<PathBuf> {
+        let path = state_file(snd_card);
+        let content = format!(
+            "version={}\nsound_card_id={}\ntime={}\npid={}\nstate={}\nexit_code={}\n\
+             error_code={}\nsafe_mode={}\n",
+            VERSION,
+            snd_card,
+            clock().now().map_or(0, |t| t.as_secs()),
+            process::id(),
+            self.state,
+            self.exit_code as i32,
+            self.error_code,
+            self.safe_mode as u8,
+        );
+        // The events of the monitor use <sound_card_id>.tmp in the same directory.
+        let tmp = path.with_extension("status.tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &path)?;
+        Ok(path)
+    }
+}
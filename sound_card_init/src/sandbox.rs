@@ -0,0 +1,214 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It confines the boot time calibration before the sound card threads are spawned, so that the
+//! confinement does not depend on the minijail wrapper alone. The calibration can only write the
+//! datastores of its sound cards, the crash report spool and the sound card devices, and can't
+//! gain privileges by execve.
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+
+use libc::{c_int, c_long, c_void};
+use log::{info, warn};
+
+use crate::{Error, Result};
+
+// The landlock syscall numbers, which are shared by all the architectures.
+const SYS_LANDLOCK_CREATE_RULESET: c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: c_long = 446;
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: c_int = 1;
+
+// The filesystem access rights of landlock ABI 1. All of them are handled, so the rights
+// which are not allowed by any rule are denied, ex: execute.
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_FS_ALL: u64 = (1 << 13) - 1;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: c_int,
+}
+
+// The sound card devices, which are reopened when a sound card is reconnected.
+const SND_DEV_DIR: &str = "/dev/snd";
+
+/// Confines the calling thread and the threads it creates afterwards, so it must be called
+/// before the threads are spawned. The whole filesystem stays readable, and `writable_dirs` and
+/// the sound card devices are the only writable paths. It's skipped if the kernel does not
+/// support landlock.
+///
+/// The missing writable directories are created, ex: a fresh `--datastore-dir`, since the rules
+/// are only added on the existing paths. A directory which can't be created is left read only
+/// with a warning, so that only its writes fail.
+///
+/// # Errors
+///
+/// * If it fails to set up or enforce the confinement.
+pub fn confine(writable_dirs: &[PathBuf]) -> Result<()> {
+    let writable_dirs: Vec<&PathBuf> = writable_dirs
+        .iter()
+        .filter(|dir| match fs::create_dir_all(dir) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("{} is left read only: {}", dir.display(), e);
+                false
+            }
+        })
+        .collect();
+    // Safe because it has no pointer arguments and the return value is checked.
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+        return Err(Error::SandboxFailed(io::Error::last_os_error()));
+    }
+
+    let ruleset = match create_ruleset()? {
+        Some(ruleset) => ruleset,
+        None => {
+            info!("landlock is not supported, skip the filesystem confinement");
+            return Ok(());
+        }
+    };
+    add_rule(
+        &ruleset,
+        Path::new("/"),
+        ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR,
+    )?;
+    // There is no sound card device to write without the directory, ex: on a host.
+    if Path::new(SND_DEV_DIR).exists() {
+        add_rule(
+            &ruleset,
+            Path::new(SND_DEV_DIR),
+            ACCESS_FS_READ_FILE | ACCESS_FS_WRITE_FILE,
+        )?;
+    }
+    for dir in &writable_dirs {
+        add_rule(
+            &ruleset,
+            dir,
+            ACCESS_FS_READ_FILE
+                | ACCESS_FS_READ_DIR
+                | ACCESS_FS_WRITE_FILE
+                | ACCESS_FS_REMOVE_FILE
+                | ACCESS_FS_MAKE_DIR
+                | ACCESS_FS_MAKE_REG,
+        )?;
+    }
+    // Safe because it has no pointer arguments and the return value is checked.
+    if unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset.as_raw_fd(), 0) } < 0 {
+        return Err(Error::SandboxFailed(io::Error::last_os_error()));
+    }
+    info!(
+        "confined to write {:?} only, executables are denied",
+        writable_dirs
+    );
+    Ok(())
+}
+
+// Returns None if the kernel does not support landlock.
+fn create_ruleset() -> Result<Option<File>> {
+    // Safe because a NULL attr with size 0 only queries the ABI version.
+    let abi = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        return Ok(None);
+    }
+    let attr = RulesetAttr {
+        handled_access_fs: ACCESS_FS_ALL,
+    };
+    // Safe because attr is a valid ruleset attr and its size is passed along.
+    let fd = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::SandboxFailed(io::Error::last_os_error()));
+    }
+    // Safe because fd is a new file descriptor exclusively owned by the `File`.
+    Ok(Some(unsafe { File::from_raw_fd(fd as c_int) }))
+}
+
+fn add_rule(ruleset: &File, path: &Path, allowed_access: u64) -> Result<()> {
+    let name = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::SandboxFailed(io::Error::from(io::ErrorKind::InvalidInput)))?;
+    // Safe because name is a valid C string and the return value is checked.
+    let fd = unsafe { libc::open(name.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        let e = io::Error::last_os_error();
+        return Err(Error::SandboxFailed(io::Error::new(
+            e.kind(),
+            format!("{}: {}", path.display(), e),
+        )));
+    }
+    // Safe because fd is a new file descriptor exclusively owned by the `File`.
+    let parent = unsafe { File::from_raw_fd(fd) };
+    let attr = PathBeneathAttr {
+        allowed_access,
+        parent_fd: parent.as_raw_fd(),
+    };
+    // Safe because attr is a valid path beneath attr which outlives the call.
+    let rc = unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_ADD_RULE,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr as *const PathBeneathAttr as *const c_void,
+            0,
+        )
+    };
+    if rc < 0 {
+        return Err(Error::SandboxFailed(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn missing_writable_dirs() {
+        let root = env::temp_dir().join(format!("sandbox_{}", process::id()));
+        let missing = root.join("datastore").join("sofcmlmax98390d");
+        let file = root.join("file");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(&file, "").unwrap();
+        // The confinement only applies to the thread which calls it.
+        let dirs = vec![missing.clone(), file.join("dir")];
+        let confined = thread::spawn(move || confine(&dirs).map_err(|e| e.to_string()))
+            .join()
+            .unwrap();
+        let created = missing.is_dir();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(confined, Ok(()));
+        assert!(created);
+    }
+}
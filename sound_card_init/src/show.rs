@@ -0,0 +1,134 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It implements `show`, which prints the calibration state of the amps, and `show-config`,
+//! which prints the config in effect. The state of `show --json` is also kept as the status
+//! summary of the sound card for the feedback reports.
+use std::error;
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info};
+use serde_json::json;
+use utils::{last_run, run_time, skips};
+
+use crate::amp::Amp;
+use crate::args::Args;
+use crate::calibration::SAFE_MODE_THRESHOLD;
+use crate::diagnostics;
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Prints the calibration state of the amps, in JSON by `json`, or refreshes the live state
+/// every `WATCH_INTERVAL` by `watch` until it's interrupted.
+pub fn show(
+    args: &Args,
+    snd_card: &str,
+    amp: &mut dyn Amp,
+) -> std::result::Result<(), Box<dyn error::Error>> {
+    if args.watch {
+        loop {
+            // Clears the terminal like watch(1).
+            print!("\x1b[2J\x1b[H");
+            println!("{}", amp.live_status()?);
+            thread::sleep(WATCH_INTERVAL);
+        }
+    }
+    if args.json {
+        println!("{}", status_json(snd_card, amp.show_json()?));
+        return Ok(());
+    }
+    println!("{}", amp.show()?);
+    if let Ok(skips) = skips::from_file(snd_card) {
+        let counts: Vec<String> = skips
+            .counts
+            .iter()
+            .map(|(reason, count)| format!("{}={}", reason, count))
+            .collect();
+        println!("calibration skips: {}", counts.join(", "));
+    }
+    Ok(())
+}
+
+/// Prints the config in effect for the sound card. It's a config in the driver section form,
+/// which can be used as is.
+pub fn show_config(amp: &mut dyn Amp) -> std::result::Result<(), Box<dyn error::Error>> {
+    let name = amp.amp_type().name();
+    let mut conf = serde_yaml::Mapping::new();
+    conf.insert("amp".into(), name.into());
+    let driver_conf = amp.effective_config()?;
+    if !driver_conf.is_null() {
+        conf.insert(name.into(), driver_conf);
+    }
+    print!("{}", serde_yaml::to_string(&conf)?);
+    Ok(())
+}
+
+/// Returns the status of the sound card with the status of its channels, which is printed by
+/// `show --json` and kept as the status summary. The fields must match `SpeakerStatus` of
+/// proto/speaker_status.proto.
+pub fn status_json(snd_card: &str, channels: serde_json::Value) -> serde_json::Value {
+    json!({
+        "sound_card_id": snd_card,
+        "run_time": run_time::from_file(snd_card).ok().map(|t| t.as_secs()),
+        "last_run": last_run::from_file(snd_card).ok().map(|run| json!({
+            "time": run.time.as_secs(),
+            "success": run.error.is_none(),
+            "error": run.error,
+            "error_code": run.error_code,
+            "failures": run.failures,
+            "fatal_failures": run.fatal_failures,
+            "safe_mode": run.fatal_failures >= SAFE_MODE_THRESHOLD,
+        })),
+        "skips": skips::from_file(snd_card).ok(),
+        "channels": channels,
+    })
+}
+
+/// Updates the status summary of the sound card for the feedback reports. The channels are
+/// null if the amp can't be initialized.
+pub fn write_status_summary(snd_card: &str, amp: Option<&mut dyn Amp>) {
+    let channels = match amp.map(|amp| amp.show_json()) {
+        Some(Ok(channels)) => channels,
+        Some(Err(e)) => json!({ "error": e.to_string() }),
+        None => serde_json::Value::Null,
+    };
+    match diagnostics::write_status(snd_card, &status_json(snd_card, channels)) {
+        Ok(path) => info!("wrote status summary {}", path.display()),
+        Err(e) => error!("failed to write status summary: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::process;
+    use std::sync::Arc;
+
+    use utils::clock::{set_clock, FakeClock};
+
+    #[test]
+    fn show_json() {
+        let dir = std::env::temp_dir().join(format!("sound_card_init_{}", process::id()));
+        utils::set_datastore_dir(dir.clone());
+        set_clock(Arc::new(FakeClock::new(Duration::from_secs(1_600_000_000))));
+        let snd_card = "sofcmlmax98390d";
+        fs::create_dir_all(utils::datastore_dir(snd_card)).unwrap();
+        run_time::to_file(snd_card, Duration::from_secs(1_599_999_000)).unwrap();
+        last_run::now_to_file(
+            snd_card,
+            Some(last_run::RunError {
+                message: "skip boot time calibration as the speakers may be hot".to_owned(),
+                code: 208,
+                transient: true,
+            }),
+        )
+        .unwrap();
+        let channels = json!([{ "rdc_ctrl": "Left Rdc", "provenance": "datastore" }]);
+        let status = status_json(snd_card, channels);
+        fs::remove_dir_all(&dir).unwrap();
+        insta::assert_snapshot!(serde_json::to_string_pretty(&status).unwrap());
+    }
+}
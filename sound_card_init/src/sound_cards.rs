@@ -0,0 +1,135 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It finds the sound cards to initialize, and creates the `Amp` of each sound card from its
+//! config.
+use std::error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{info, warn};
+use utils::sound_card;
+
+use crate::amp::{new_amp, Amp, AmpType};
+use crate::args::{Args, Command};
+use crate::config;
+use crate::cros_config::BoardConfig;
+use crate::error::{Error, Result};
+use crate::topology::CardTopology;
+
+// USB and some ACPI-enumerated codecs may not be ready when sound_card_init starts. It's the
+// default of `card_wait_timeout_secs` of the config.
+const CARD_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+// The driver of the USB sound cards, which are external.
+const USB_AUDIO_DRIVER: &str = "USB-Audio";
+
+// The config of a sound card is either <sound_card_id>.yaml or <sound_card_id>.json, and the
+// YAML one is preferred.
+fn config_path(config_dir: &Path, snd_card: &str) -> PathBuf {
+    let yaml = config_dir.join(snd_card).with_extension("yaml");
+    let json = yaml.with_extension("json");
+    if !yaml.exists() && json.exists() {
+        return json;
+    }
+    yaml
+}
+
+/// Returns the config of the sound card and the values of its placeholders. The config is
+/// named by `conf` of the command line, `card`, or `board` if given.
+pub fn config_source(
+    args: &Args,
+    board: &BoardConfig,
+    card: Option<&CardTopology>,
+    snd_card: &str,
+) -> (PathBuf, config::Placeholders) {
+    let conf_file = match args
+        .conf_file
+        .as_ref()
+        .or(card.and_then(|card| card.conf.as_ref()))
+        .or(board.conf_file.as_ref())
+    {
+        // The absolute path replaces the config directory.
+        Some(file) => args.config_dir.join(file),
+        None => config_path(&args.config_dir, snd_card),
+    };
+    // The configs which refer to the UCM config fail on the unresolved placeholders if it
+    // can't be read.
+    let ucm = config::speaker_values(snd_card).unwrap_or_else(|e| {
+        warn!("failed to read the UCM config: {}", e);
+        None
+    });
+    let placeholders = config::Placeholders {
+        card: snd_card.to_owned(),
+        model: board.model.clone(),
+        ucm,
+    };
+    (conf_file, placeholders)
+}
+
+/// Returns the ids of the internal sound cards which have configs. The USB sound cards are
+/// external and skipped.
+pub fn internal_sound_cards(config_dir: &Path) -> Result<Vec<String>> {
+    let mut ids = Vec::new();
+    for info in cros_alsa::cards() {
+        let info = info.map_err(Error::OpenCardFailed)?;
+        if info.driver == USB_AUDIO_DRIVER {
+            info!("skip external sound card: {}", info.id);
+        } else if !config_path(config_dir, &info.id).exists() {
+            info!("skip sound card without config: {}", info.id);
+        } else {
+            ids.push(info.id);
+        }
+    }
+    if ids.is_empty() {
+        return Err(Error::NoInternalSoundCard);
+    }
+    Ok(ids)
+}
+
+/// Parses the CONF_DIR/<sound_card_id>.yaml and creates the `Amp` of the sound card. The
+/// sound card handles needed by the boot time calibration are opened.
+///
+/// The config filename and the amp of the command line, of `card` in the topology, and then of
+/// `board`, take precedence over the defaults of the sound card.
+pub fn init_amp(
+    args: &Args,
+    board: &BoardConfig,
+    card: Option<&CardTopology>,
+    snd_card: &str,
+) -> std::result::Result<Box<dyn Amp>, Box<dyn error::Error>> {
+    info!(
+        "command: {}, sound_card_id: {}",
+        args.command.name(),
+        snd_card
+    );
+    let (conf_file, placeholders) = config_source(args, board, card, snd_card);
+    let conf = config::load(&conf_file, &placeholders)?;
+    let card_wait_timeout = config::card_wait_timeout(&conf)?.unwrap_or(CARD_WAIT_TIMEOUT);
+    sound_card::wait_for_card(snd_card, card_wait_timeout)?;
+    let board_amp = board.speaker_amp.as_deref().and_then(|name| {
+        let amp = AmpType::from_speaker_amp(name);
+        if amp.is_none() {
+            info!("unsupported speaker amp in cros_config: {}", name);
+        }
+        amp
+    });
+    let card_amp = match card.and_then(|card| card.amp.as_deref()) {
+        Some(name) => {
+            Some(AmpType::from_name(name).ok_or_else(|| Error::UnknownAmp(name.to_owned()))?)
+        }
+        None => None,
+    };
+    let mut amp = new_amp(snd_card, &conf, args.amp.or(card_amp), board_amp)?;
+    if let Some(card) = card.filter(|card| !card.channels.is_empty()) {
+        let count = amp.channel_count()?;
+        if count != card.channels.len() {
+            return Err(
+                Error::TopologyMismatch(snd_card.to_owned(), card.channels.len(), count).into(),
+            );
+        }
+    }
+    if args.command == Command::BootTimeCalibration {
+        amp.open_card()?;
+    }
+    Ok(amp)
+}
@@ -0,0 +1,104 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It loads the optional audio topology of the board from CONF_DIR/topology.yaml, which
+//! declares the sound cards of the board and their amps, ex:
+//!
+//! ```yaml
+//! cards:
+//!   - id: sofcmlmax98390d
+//!     role: speaker
+//!     amp: max98390d
+//!     channels: [left, right]
+//!   - id: sofrt5682
+//!     role: headset
+//!     amp: none
+//! ```
+//!
+//! The declared sound cards are initialized instead of the internal sound cards found at
+//! runtime, so a missing sound card fails the command instead of being skipped.
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::{Error, Result};
+
+/// The filename of the topology in the config directory.
+pub const TOPOLOGY_FILE: &str = "topology.yaml";
+
+/// `Topology` is the audio topology of the board.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Topology {
+    /// The sound cards of the board.
+    pub cards: Vec<CardTopology>,
+}
+
+/// `CardTopology` declares a sound card of the board.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CardTopology {
+    /// The sound card id.
+    pub id: String,
+    /// The role of the sound card on the board.
+    pub role: Role,
+    /// The config filename of the sound card, which defaults to <id>.yaml.
+    #[serde(default)]
+    pub conf: Option<String>,
+    /// The amp driver of the sound card, which takes precedence over the config.
+    #[serde(default)]
+    pub amp: Option<String>,
+    /// The names of the amp channels in the order of the config. The amp config must have as
+    /// many channels if they are given.
+    #[serde(default)]
+    pub channels: Vec<String>,
+}
+
+/// The role of a sound card on the board.
+#[derive(Debug, Deserialize, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// The sound card of the internal speakers.
+    Speaker,
+    /// The sound card of the headset jack.
+    Headset,
+    /// The other sound cards, ex: HDMI.
+    Other,
+}
+
+impl Topology {
+    /// Loads the topology from the config directory. It's None if the board does not declare
+    /// its topology.
+    ///
+    /// # Errors
+    ///
+    /// * If the topology can't be read or parsed.
+    /// * If a sound card is declared twice.
+    pub fn load(config_dir: &Path) -> Result<Option<Topology>> {
+        let path = config_dir.join(TOPOLOGY_FILE);
+        let conf = match fs::read_to_string(&path) {
+            Ok(conf) => conf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(Error::OpenConfigFailed(
+                    path.to_string_lossy().to_string(),
+                    e,
+                ))
+            }
+        };
+        let topology: Topology = serde_yaml::from_str(&conf).map_err(Error::ParseConfigFailed)?;
+        for (i, card) in topology.cards.iter().enumerate() {
+            if topology.cards[..i].iter().any(|c| c.id == card.id) {
+                return Err(Error::DuplicateCard(card.id.clone()));
+            }
+        }
+        Ok(Some(topology))
+    }
+
+    /// Returns the declaration of the sound card.
+    pub fn card(&self, snd_card: &str) -> Option<&CardTopology> {
+        self.cards.iter().find(|card| card.id == snd_card)
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It bounds the execution time of sound_card_init. A wedged ALSA ioctl can't be interrupted,
+//! so a watchdog thread puts the amps into the safe state by its own sound card handles and
+//! exits the process on expiry.
+use std::process;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::error;
+use utils::ExitCode;
+
+use crate::amp::{SafeState, SafeStateOpener};
+use crate::calibration::record_run;
+use crate::Error;
+
+enum Message {
+    // Opens the safe state handles of the sound cards, and acks once they are opened.
+    Arm(Vec<(String, SafeStateOpener)>, Sender<()>),
+    // The command has finished on the sound card.
+    Finish(String),
+}
+
+/// `Watchdog` exits the process with `ExitCode::Timeout` if it is not dropped before the
+/// timeout. The amps of the unfinished sound cards are put into the safe state before exit.
+/// The clones share the same timeout, which is stopped when all of them are dropped.
+#[derive(Clone)]
+pub struct Watchdog {
+    tx: Sender<Message>,
+}
+
+impl Watchdog {
+    /// Starts a `Watchdog`.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The timeout of the whole execution.
+    /// * `safe_state` - Puts the amps into the safe state on expiry if it's true.
+    /// * `record` - Records the timeout as the outcome of the boot time calibration of the
+    ///   unfinished sound cards if it's true.
+    pub fn start(timeout: Duration, safe_state: bool, record: bool) -> Self {
+        let deadline = Instant::now() + timeout;
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || watch(rx, deadline, timeout, safe_state, record));
+        Watchdog { tx }
+    }
+
+    /// Opens the handles which put the amps of the sound cards into the safe state. It blocks
+    /// until the handles are opened, so that they are opened before dropping privileges.
+    pub fn arm(&self, openers: Vec<(String, SafeStateOpener)>) {
+        let (ack_tx, ack_rx) = mpsc::channel();
+        if self.tx.send(Message::Arm(openers, ack_tx)).is_ok() {
+            // The watchdog exits the process if the handles can't be opened in time.
+            let _ = ack_rx.recv();
+        }
+    }
+
+    /// Marks the command finished on the sound card.
+    pub fn finish(&self, snd_card: &str) {
+        let _ = self.tx.send(Message::Finish(snd_card.to_owned()));
+    }
+}
+
+fn watch(
+    rx: Receiver<Message>,
+    deadline: Instant,
+    timeout: Duration,
+    safe_state: bool,
+    record: bool,
+) {
+    let mut states: Vec<(String, Box<dyn SafeState>)> = Vec::new();
+    loop {
+        match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Message::Arm(openers, ack)) => {
+                for (snd_card, open) in openers {
+                    match open() {
+                        Ok(state) => states.push((snd_card, state)),
+                        Err(e) => error!("failed to open the safe state of {}: {}", snd_card, e),
+                    }
+                }
+                let _ = ack.send(());
+            }
+            Ok(Message::Finish(snd_card)) => states.retain(|(s, _)| *s != snd_card),
+            // All the `Watchdog` clones are dropped before the timeout.
+            Err(RecvTimeoutError::Disconnected) => return,
+            Err(RecvTimeoutError::Timeout) => break,
+        }
+    }
+
+    error!("sound_card_init does not finish in {:?}", timeout);
+    for (snd_card, state) in &mut states {
+        if safe_state {
+            if let Err(e) = state.apply() {
+                error!("failed to restore the safe state of {}: {}", snd_card, e);
+            }
+        }
+        if record {
+            record_run(snd_card, Some(&Error::WatchdogTimeout(timeout)));
+        }
+    }
+    process::exit(ExitCode::Timeout as i32);
+}
@@ -0,0 +1,42 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It records the bootstat markers of sound_card_init, so that the boot time calibration shows
+//! up in the boot time analysis, ex: bootperf, alongside the other boot services.
+//!
+//! Like bootstat(8), a marker appends the content of /proc/uptime to /tmp/uptime-<event>. The
+//! bootstat binary is not run since the calibration is not allowed to execve.
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use log::{debug, warn};
+
+/// The marker of the start of the boot time calibration.
+pub const CALIBRATION_START: &str = "sound-card-init-calibration-start";
+/// The marker of the end of the boot time calibration.
+pub const CALIBRATION_END: &str = "sound-card-init-calibration-end";
+/// The marker of the start of the calibration playback stream.
+pub const STREAM_START: &str = "sound-card-init-stream-start";
+
+/// The directory of the bootstat markers.
+pub const BOOTSTAT_DIR: &str = "/tmp";
+const UPTIME_FILE: &str = "/proc/uptime";
+
+/// Records the bootstat marker of the event. The failure is logged only, since the markers are
+/// not needed by the calibration.
+pub fn mark(event: &str) {
+    match append_uptime(event) {
+        Ok(()) => debug!("bootstat marker {} recorded", event),
+        Err(e) => warn!("failed to record bootstat marker {}: {}", event, e),
+    }
+}
+
+fn append_uptime(event: &str) -> io::Result<()> {
+    let uptime = fs::read(UPTIME_FILE)?;
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(Path::new(BOOTSTAT_DIR).join(format!("uptime-{}", event)))?
+        .write_all(&uptime)
+}
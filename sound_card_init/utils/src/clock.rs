@@ -0,0 +1,93 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It abstracts the clock of the time-dependent logic, ex: the cool down time of the speakers
+//! and the timestamps of the datastore, so that the time can be fast-forwarded by `FakeClock`.
+//!
+//! The clock of the process is `SystemClock` unless it's replaced by `set_clock()`.
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, SystemTimeError};
+
+/// `Clock` is the source of the time.
+pub trait Clock: Send + Sync {
+    /// Returns the wall clock time since the unix epoch.
+    fn now(&self) -> Result<Duration, SystemTimeError>;
+    /// Returns the monotonic time.
+    fn monotonic(&self) -> Instant;
+    /// Waits for the duration.
+    fn sleep(&self, duration: Duration);
+}
+
+/// `SystemClock` is the clock of the system.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Result<Duration, SystemTimeError> {
+        SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+    }
+
+    fn monotonic(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// `FakeClock` is a clock which only moves by `advance()` or `sleep()`, and `sleep()` returns
+/// immediately.
+#[derive(Debug)]
+pub struct FakeClock {
+    // The wall clock time and the monotonic time.
+    time: Mutex<(Duration, Instant)>,
+}
+
+impl FakeClock {
+    /// Creates a `FakeClock` of the unix time.
+    pub fn new(now: Duration) -> Self {
+        FakeClock {
+            time: Mutex::new((now, Instant::now())),
+        }
+    }
+
+    /// Moves the clock forward.
+    pub fn advance(&self, duration: Duration) {
+        if let Ok(mut time) = self.time.lock() {
+            time.0 += duration;
+            time.1 += duration;
+        }
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> Result<Duration, SystemTimeError> {
+        Ok(self.time.lock().map_or(Duration::ZERO, |time| time.0))
+    }
+
+    fn monotonic(&self) -> Instant {
+        self.time
+            .lock()
+            .map_or_else(|_| Instant::now(), |time| time.1)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+}
+
+// The clock set by `set_clock()`.
+static CLOCK: OnceLock<Arc<dyn Clock>> = OnceLock::new();
+
+/// Replaces the clock of the process. It must be called before the time is read, and only the
+/// first call takes effect.
+pub fn set_clock(clock: Arc<dyn Clock>) {
+    let _ = CLOCK.set(clock);
+}
+
+/// Returns the clock of the process.
+pub fn clock() -> &'static dyn Clock {
+    CLOCK.get_or_init(|| Arc::new(SystemClock)).as_ref()
+}
@@ -9,6 +9,9 @@ use std::time;
 use std::{error, path::PathBuf};
 
 use remain::sorted;
+use serde::{Serialize, Serializer};
+
+use crate::ExitCode;
 
 /// Alias for a `Result` with the error type `utils::Error`.
 pub type Result<T> = std::result::Result<T, Error>;
@@ -19,21 +22,143 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
     /// It wraps file path with the io::Error.
     FileIOFailed(PathBuf, io::Error),
+    /// The log spec is malformed.
+    InvalidLogSpec(String),
+    /// Failed to initialize the logger.
+    LoggerInitFailed(String),
+    /// Failed to send the readiness notification.
+    NotifyFailed(io::Error),
     /// It wraps file path with the serde_yaml::Error.
     SerdeError(PathBuf, serde_yaml::Error),
     /// It wraps time::SystemTimeError.
     SystemTimeError(time::SystemTimeError),
+    /// Failed to receive the udev events.
+    UeventFailed(io::Error),
+    /// The sound card is not available before the timeout.
+    WaitForCardTimeout(String, time::Duration),
+}
+
+impl Error {
+    /// Returns the `ExitCode` of the error category.
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            Error::InvalidLogSpec(_) => ExitCode::InvalidArgs,
+            Error::WaitForCardTimeout(_, _) => ExitCode::SoundCardUnavailable,
+            _ => ExitCode::Failure,
+        }
+    }
+
+    /// Returns the stable code of the error, which identifies the failure mode in the logs,
+    /// the last run outcome and the UMA metrics. The utils errors use 100 to 199, and the
+    /// codes must not be renumbered or reused.
+    pub fn code(&self) -> u32 {
+        use Error::*;
+        match self {
+            FileIOFailed(_, _) => 100,
+            InvalidLogSpec(_) => 101,
+            LoggerInitFailed(_) => 102,
+            NotifyFailed(_) => 103,
+            SerdeError(_, _) => 104,
+            SystemTimeError(_) => 105,
+            UeventFailed(_) => 106,
+            WaitForCardTimeout(_, _) => 107,
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        use Error::*;
+        match self {
+            FileIOFailed(_, e) | NotifyFailed(e) | UeventFailed(e) => Some(e),
+            SerdeError(_, e) => Some(e),
+            SystemTimeError(e) => Some(e),
+            InvalidLogSpec(_) | LoggerInitFailed(_) | WaitForCardTimeout(_, _) => None,
+        }
+    }
+}
+
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        ErrorReport::new(self, self.code()).serialize(serializer)
+    }
+}
+
+/// `ErrorContext` tells where an error occurred.
+#[derive(Debug, Default, Serialize)]
+pub struct ErrorContext {
+    /// The sound card of the command.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sound_card: Option<String>,
+    /// The index of the amp channel in the config.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<usize>,
+}
+
+/// `ErrorReport` is the structured form of an error, which is emitted in the reports instead of
+/// the formatted message.
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+    /// The stable code of the error.
+    pub code: u32,
+    /// The name of the error variant, ex: `WaitForCardTimeout`.
+    pub variant: String,
+    /// The message of the error without the context.
+    pub message: String,
+    /// Where the error occurred.
+    pub context: ErrorContext,
+    /// The messages of the source chain, starting from the direct source.
+    pub sources: Vec<String>,
+    /// The remediation hint of the error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+    /// The errors aggregated by the error, ex: the errors of the failed amps.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<ErrorReport>,
 }
 
-impl error::Error for Error {}
+impl ErrorReport {
+    /// Creates the `ErrorReport` of `err` with an empty context and no hint.
+    pub fn new(err: &dyn error::Error, code: u32) -> Self {
+        // The variant is the leading identifier of the derived `Debug` form.
+        let debug = format!("{:?}", err);
+        let variant = debug
+            .split(|c: char| !c.is_alphanumeric() && c != '_')
+            .next()
+            .unwrap_or_default()
+            .to_owned();
+        let mut sources = Vec::new();
+        let mut source = err.source();
+        while let Some(e) = source {
+            sources.push(e.to_string());
+            source = e.source();
+        }
+        ErrorReport {
+            code,
+            variant,
+            message: err.to_string(),
+            context: Default::default(),
+            sources,
+            hint: None,
+            errors: Vec::new(),
+        }
+    }
+}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use Error::*;
         match self {
             FileIOFailed(file, e) => write!(f, "{:?}: {}", file, e),
+            InvalidLogSpec(spec) => write!(f, "invalid log spec: {}", spec),
+            LoggerInitFailed(e) => write!(f, "failed to initialize logger: {}", e),
+            NotifyFailed(e) => write!(f, "failed to send readiness notification: {}", e),
             SerdeError(file, e) => write!(f, "{:?}: {}", file, e),
             SystemTimeError(e) => write!(f, "{}", e),
+            UeventFailed(e) => write!(f, "failed to receive udev events: {}", e),
+            WaitForCardTimeout(name, timeout) => {
+                write!(f, "sound card {} is not available in {:?}", name, timeout)
+            }
         }
     }
 }
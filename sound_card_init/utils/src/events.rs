@@ -0,0 +1,97 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It emits the structured events of the boot time calibration to the event feed,
+//! /var/log/sound_card_init/events.jsonl, which is collected by audio_diagnostics, so that the
+//! audio debug bundles include the speaker protection history.
+//!
+//! Each line is a JSON object with the unix `time`, the `run_id` of the run log, the
+//! `sound_card_id`, and the `event` with its fields, ex:
+//!
+//! ```json
+//! {"time": 1600000000, "run_id": "1234-1600000000", "sound_card_id": "sofcmlmax98390d",
+//!  "event": "channel_result", "channel": 0, "result": "applied_measured"}
+//! ```
+//!
+//! The feed is rotated to events.jsonl.1 once it exceeds `MAX_EVENTS_BYTES`.
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use log::warn;
+use serde::Serialize;
+
+use crate::clock::clock;
+use crate::logger;
+use crate::skips::SkipReason;
+
+/// The path of the event feed.
+pub const EVENTS_FILE: &str = "/var/log/sound_card_init/events.jsonl";
+/// The size of the event feed beyond which it's rotated.
+pub const MAX_EVENTS_BYTES: u64 = 256 * 1024;
+
+// Serializes the appends of the concurrent sound cards.
+static FEED: Mutex<()> = Mutex::new(());
+
+/// The events of a run.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The run of the command starts.
+    RunStart {
+        /// The command, ex: boot_time_calibration.
+        command: String,
+    },
+    /// The calibration of an amp channel finishes.
+    ChannelResult {
+        /// The index of the channel in the config.
+        channel: usize,
+        /// The result of the channel, ex: applied_measured.
+        result: String,
+    },
+    /// The measurement is skipped, and the stored values or the safe state are used instead.
+    Fallback {
+        /// The reason of the skip.
+        reason: SkipReason,
+    },
+    /// The run fails.
+    Error {
+        /// The stable code of the error.
+        error_code: u32,
+        /// The error message.
+        error: String,
+    },
+    /// The run ends.
+    RunEnd {
+        /// The exit code of the run.
+        exit_code: i32,
+    },
+}
+
+/// Appends the event of the sound card to the event feed. The failure is logged only, since the
+/// feed is not needed by the calibration.
+pub fn emit(snd_card: &str, event: Event) {
+    if let Err(e) = append(snd_card, &event) {
+        warn!("failed to emit event {:?}: {}", event, e);
+    }
+}
+
+fn append(snd_card: &str, event: &Event) -> io::Result<()> {
+    let mut record = serde_json::to_value(event)?;
+    record["time"] = clock().now().map_or(0, |t| t.as_secs()).into();
+    record["run_id"] = logger::run_id().into();
+    record["sound_card_id"] = snd_card.into();
+    let line = format!("{}\n", record);
+
+    let _guard = FEED.lock();
+    let path = Path::new(EVENTS_FILE);
+    if fs::metadata(path).is_ok_and(|m| m.len() >= MAX_EVENTS_BYTES) {
+        fs::rename(path, path.with_extension("jsonl.1"))?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())
+}
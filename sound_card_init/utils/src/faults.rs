@@ -0,0 +1,120 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It injects the failures which only happen in the field, so that their recovery paths can be
+//! exercised on a test image. The faults are ignored unless the `fault-injection` feature is
+//! enabled, and are set by the `SOUND_CARD_INIT_FAULTS` environment variable, a comma
+//! separated list of:
+//!
+//! * `control_write=<n>`: fails the nth control write of the calibration, which starts from 1.
+//! * `playback_delay_ms=<ms>`: delays the start of the calibration playback.
+//! * `corrupt_datastore`: writes a corrupt datastore on save.
+//!
+//! ex: `SOUND_CARD_INIT_FAULTS=control_write=2,playback_delay_ms=3000`
+use std::time::Duration;
+
+/// The environment variable of the faults.
+pub const FAULTS_ENV: &str = "SOUND_CARD_INIT_FAULTS";
+
+/// Returns true if the control write should fail. Each call counts as a control write.
+pub fn fail_control_write() -> bool {
+    imp::fail_control_write()
+}
+
+/// Returns the delay to inject before the calibration playback starts.
+pub fn playback_delay() -> Duration {
+    imp::playback_delay()
+}
+
+/// Returns true if the datastore should be corrupted on save.
+pub fn corrupt_datastore() -> bool {
+    imp::corrupt_datastore()
+}
+
+#[cfg(feature = "fault-injection")]
+mod imp {
+    use std::env;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::OnceLock;
+    use std::time::Duration;
+
+    use log::{error, warn};
+
+    use super::FAULTS_ENV;
+
+    #[derive(Debug, Default)]
+    struct Faults {
+        control_write: Option<u32>,
+        playback_delay: Duration,
+        corrupt_datastore: bool,
+    }
+
+    impl Faults {
+        // Parses the faults of the environment variable. The malformed faults are logged and
+        // ignored.
+        fn from_env() -> Faults {
+            let mut faults = Faults::default();
+            let spec = match env::var(FAULTS_ENV) {
+                Ok(spec) => spec,
+                Err(_) => return faults,
+            };
+            for fault in spec.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+                let (name, value) = match fault.split_once('=') {
+                    Some((name, value)) => (name, Some(value)),
+                    None => (fault, None),
+                };
+                match (name, value.map(str::parse::<u32>)) {
+                    ("control_write", Some(Ok(n))) if n > 0 => faults.control_write = Some(n),
+                    ("playback_delay_ms", Some(Ok(ms))) => {
+                        faults.playback_delay = Duration::from_millis(ms.into())
+                    }
+                    ("corrupt_datastore", None) => faults.corrupt_datastore = true,
+                    _ => {
+                        error!("invalid fault in {}: {}", FAULTS_ENV, fault);
+                        continue;
+                    }
+                }
+                warn!("fault injected: {}", fault);
+            }
+            faults
+        }
+    }
+
+    static FAULTS: OnceLock<Faults> = OnceLock::new();
+    // The number of the control writes so far.
+    static CONTROL_WRITES: AtomicU32 = AtomicU32::new(0);
+
+    fn faults() -> &'static Faults {
+        FAULTS.get_or_init(Faults::from_env)
+    }
+
+    pub fn fail_control_write() -> bool {
+        let n = CONTROL_WRITES.fetch_add(1, Ordering::SeqCst) + 1;
+        faults().control_write == Some(n)
+    }
+
+    pub fn playback_delay() -> Duration {
+        faults().playback_delay
+    }
+
+    pub fn corrupt_datastore() -> bool {
+        faults().corrupt_datastore
+    }
+}
+
+#[cfg(not(feature = "fault-injection"))]
+mod imp {
+    use std::time::Duration;
+
+    pub fn fail_control_write() -> bool {
+        false
+    }
+
+    pub fn playback_delay() -> Duration {
+        Duration::ZERO
+    }
+
+    pub fn corrupt_datastore() -> bool {
+        false
+    }
+}
@@ -5,24 +5,152 @@
 #![deny(missing_docs)]
 
 //! The error definitions for utils.
+pub mod bootstat;
+pub mod clock;
 pub mod error;
+pub mod events;
+pub mod faults;
+pub mod lock;
+pub mod logger;
+pub mod metrics;
+pub mod phases;
+pub mod readiness;
+pub mod trace;
+mod uevent;
 
 use std::fs::File;
-use std::io::{prelude::*, BufReader, BufWriter};
-use std::path::PathBuf;
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 use std::time::Duration;
 
+use serde::{de::DeserializeOwned, Serialize};
+
 use crate::error::{Error, Result};
 
 /// The path of datastore.
 pub const DATASTORE_DIR: &str = "/var/lib/sound_card_init";
 
-fn duration_from_file(path: &PathBuf) -> Result<Duration> {
+/// The directory of the runtime files which are consumed by the other services, ex: the
+/// overheat events.
+pub const RUN_DIR: &str = "/run/sound_card_init";
+
+// The datastore directory set by `set_datastore_dir()`.
+static DATASTORE_DIR_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Overrides DATASTORE_DIR for the process, ex: to use a local datastore on a test image. It
+/// must be called before the datastore is accessed, and only the first call takes effect.
+pub fn set_datastore_dir(dir: PathBuf) {
+    let _ = DATASTORE_DIR_OVERRIDE.set(dir);
+}
+
+/// Returns the datastore directory of the sound card, which is DATASTORE_DIR/<snd_card>
+/// unless it's overridden by `set_datastore_dir()`.
+pub fn datastore_dir(snd_card: &str) -> PathBuf {
+    DATASTORE_DIR_OVERRIDE
+        .get()
+        .cloned()
+        .unwrap_or_else(|| PathBuf::from(DATASTORE_DIR))
+        .join(snd_card)
+}
+
+// The runtime directory set by `set_run_dir()`.
+static RUN_DIR_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Overrides RUN_DIR for the process, ex: to keep the runtime files of a test out of the
+/// device. It must be called before the runtime files are written, and only the first call
+/// takes effect.
+pub fn set_run_dir(dir: PathBuf) {
+    let _ = RUN_DIR_OVERRIDE.set(dir);
+}
+
+/// Returns the directory of the runtime files, which is RUN_DIR unless it's overridden by
+/// `set_run_dir()`.
+pub fn run_dir() -> PathBuf {
+    RUN_DIR_OVERRIDE
+        .get()
+        .cloned()
+        .unwrap_or_else(|| PathBuf::from(RUN_DIR))
+}
+
+/// Flushes the filesystem of the path to the storage, ex: after writing all the files of a
+/// datastore, so that one sync covers them instead of an fsync per file.
+pub fn sync_fs(path: &Path) -> io::Result<()> {
+    let dir = File::open(path)?;
+    // Safe because it has no pointer arguments and the return value is checked.
+    if unsafe { libc::syncfs(dir.as_raw_fd()) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// The exit codes of sound_card_init by the error category. The values are part of the
+/// interface with the upstart job and the tests, and must not be renumbered.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ExitCode {
+    /// The command succeeded.
+    Success = 0,
+    /// The command failed for an uncategorized reason.
+    Failure = 1,
+    /// The command line arguments are invalid.
+    InvalidArgs = 2,
+    /// The config is missing, malformed or does not match the sound card.
+    InvalidConfig = 3,
+    /// The sound card is not supported by sound_card_init.
+    UnsupportedSoundCard = 4,
+    /// The sound card is not available.
+    SoundCardUnavailable = 5,
+    /// CRAS or its internal speaker node is not available.
+    CrasUnavailable = 6,
+    /// The calibration values are rejected by the sanity checks.
+    CalibrationRejected = 7,
+    /// The datastore can't be parsed.
+    CorruptDatastore = 8,
+    /// The command does not finish before the timeout.
+    Timeout = 9,
+    /// The command failed for an uncategorized reason which may go away by retrying, ex: the
+    /// sound card was removed during the command.
+    Transient = 10,
+}
+
+/// The options of a boot time calibration run.
+#[derive(Debug, Default, Clone)]
+pub struct RunOptions {
+    /// Performs all the reads, computations and checks, but skips the control writes and the
+    /// datastore updates, and logs what would have been applied instead.
+    pub dry_run: bool,
+    /// Records the control accesses of the calibration to the file, which can be replayed by
+    /// `cros_alsa::FakeCard::from_trace()`.
+    pub trace_file: Option<PathBuf>,
+    /// Skips the measurement for the power state of the device, ex: a low battery or thermal
+    /// throttling, and applies the datastore values like after a hot shutdown.
+    pub low_power: bool,
+}
+
+fn from_yaml_file<T: DeserializeOwned>(path: &PathBuf) -> Result<T> {
     let reader =
         BufReader::new(File::open(&path).map_err(|e| Error::FileIOFailed(path.clone(), e))?);
     serde_yaml::from_reader(reader).map_err(|e| Error::SerdeError(path.clone(), e))
 }
 
+fn to_yaml_file<T: Serialize>(path: &PathBuf, val: &T) -> Result<()> {
+    let mut writer =
+        BufWriter::new(File::create(&path).map_err(|e| Error::FileIOFailed(path.clone(), e))?);
+    writer
+        .write_all(
+            serde_yaml::to_string(val)
+                .map_err(|e| Error::SerdeError(path.clone(), e))?
+                .as_bytes(),
+        )
+        .map_err(|e| Error::FileIOFailed(path.clone(), e))?;
+    writer
+        .flush()
+        .map_err(|e| Error::FileIOFailed(path.clone(), e))?;
+    Ok(())
+}
+
 /// The utils to parse CRAS shutdown time file.
 pub mod shutdown_time {
     use super::*;
@@ -31,15 +159,14 @@ pub mod shutdown_time {
 
     /// Reads the unix time from CRAS shutdown time file.
     pub fn from_file() -> Result<Duration> {
-        duration_from_file(&PathBuf::from(SHUTDOWN_TIME_FILE))
+        from_yaml_file(&PathBuf::from(SHUTDOWN_TIME_FILE))
     }
 }
 
 /// The utils to create and parse sound_card_init run time file.
 pub mod run_time {
-    use std::time::SystemTime;
-
     use super::*;
+    use crate::clock::clock;
     // The filename of sound_card_init run time file.
     const RUN_TIME_FILE: &str = "run";
 
@@ -50,12 +177,12 @@ pub mod run_time {
 
     /// Reads the unix time from sound_card_init run time file.
     pub fn from_file(snd_card: &str) -> Result<Duration> {
-        duration_from_file(&run_time_file(snd_card))
+        from_yaml_file(&run_time_file(snd_card))
     }
 
     /// Saves the current unix time to sound_card_init run time file.
     pub fn now_to_file(snd_card: &str) -> Result<()> {
-        match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        match clock().now() {
             Ok(t) => to_file(snd_card, t),
             Err(e) => Err(Error::SystemTimeError(e)),
         }
@@ -63,25 +190,242 @@ pub mod run_time {
 
     /// Saves the unix time to sound_card_init run time file.
     pub fn to_file(snd_card: &str, duration: Duration) -> Result<()> {
-        let path = run_time_file(snd_card);
-        let mut writer =
-            BufWriter::new(File::create(&path).map_err(|e| Error::FileIOFailed(path.clone(), e))?);
-        writer
-            .write_all(
-                serde_yaml::to_string(&duration)
-                    .map_err(|e| Error::SerdeError(path.clone(), e))?
-                    .as_bytes(),
-            )
-            .map_err(|e| Error::FileIOFailed(path.clone(), e))?;
-        writer
-            .flush()
-            .map_err(|e| Error::FileIOFailed(path.clone(), e))?;
-        Ok(())
+        to_yaml_file(&run_time_file(snd_card), &duration)
     }
 
     fn run_time_file(snd_card: &str) -> PathBuf {
-        PathBuf::from(DATASTORE_DIR)
-            .join(snd_card)
-            .join(RUN_TIME_FILE)
+        datastore_dir(snd_card).join(RUN_TIME_FILE)
+    }
+}
+
+/// The utils to save and parse the outcome of the last boot time calibration.
+pub mod last_run {
+    use std::fs;
+    use std::io;
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::clock::clock;
+    // The filename of sound_card_init last run outcome file.
+    const LAST_RUN_FILE: &str = "last_run";
+
+    /// `LastRun` represents the outcome of a boot time calibration.
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct LastRun {
+        /// The unix time when the run finished.
+        pub time: Duration,
+        /// The error of the run, or None if it succeeded.
+        pub error: Option<String>,
+        /// The stable code of the error, or None if it succeeded.
+        #[serde(default)]
+        pub error_code: Option<u32>,
+        /// The number of the consecutive failed runs up to this run.
+        #[serde(default)]
+        pub failures: u32,
+        /// The number of the consecutive runs failed for a permanent reason up to this run. The
+        /// transient failures don't break the streak.
+        #[serde(default)]
+        pub fatal_failures: u32,
+    }
+
+    /// `RunError` is the error of a failed boot time calibration.
+    #[derive(Debug)]
+    pub struct RunError {
+        /// The message of the error.
+        pub message: String,
+        /// The stable code of the error.
+        pub code: u32,
+        /// True if the error may go away by retrying.
+        pub transient: bool,
+    }
+
+    /// Reads the outcome of the last boot time calibration.
+    pub fn from_file(snd_card: &str) -> Result<LastRun> {
+        from_yaml_file(&last_run_file(snd_card))
+    }
+
+    /// Saves the outcome of a boot time calibration finished now, and returns it. The failures
+    /// are counted from the outcome of the previous run.
+    pub fn now_to_file(snd_card: &str, error: Option<RunError>) -> Result<LastRun> {
+        let time = clock().now().map_err(Error::SystemTimeError)?;
+        let last = from_file(snd_card).ok();
+        let (failures, fatal_failures) = match (&error, &last) {
+            (None, _) => (0, 0),
+            (Some(e), Some(last)) => (
+                last.failures + 1,
+                last.fatal_failures + u32::from(!e.transient),
+            ),
+            (Some(e), None) => (1, u32::from(!e.transient)),
+        };
+        let last_run = LastRun {
+            time,
+            error: error.as_ref().map(|e| e.message.clone()),
+            error_code: error.as_ref().map(|e| e.code),
+            failures,
+            fatal_failures,
+        };
+        to_yaml_file(&last_run_file(snd_card), &last_run)?;
+        Ok(last_run)
+    }
+
+    /// Removes the outcome of the last boot time calibration, which starts the failure counts
+    /// over. It's a no-op if there is no outcome.
+    pub fn remove(snd_card: &str) -> Result<()> {
+        let path = last_run_file(snd_card);
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Error::FileIOFailed(path, e)),
+            _ => Ok(()),
+        }
+    }
+
+    fn last_run_file(snd_card: &str) -> PathBuf {
+        datastore_dir(snd_card).join(LAST_RUN_FILE)
+    }
+}
+
+/// The persistent counts of the reasons the boot time calibration is skipped, which tell why a
+/// device has not recalibrated for a long time.
+pub mod skips {
+    use std::collections::BTreeMap;
+    use std::fmt;
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::clock::clock;
+    use crate::events::{self, Event};
+    // The filename of the skip counts.
+    const SKIPS_FILE: &str = "skips";
+
+    /// The reasons the boot time calibration is skipped.
+    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum SkipReason {
+        /// The speakers may be hot since CRAS shut down recently.
+        HotSpeaker,
+        /// The CRAS shutdown time is missing or invalid, ex: no clean shutdown before the boot.
+        InvalidShutdownTime,
+        /// The device is on a low battery or thermally throttled.
+        PowerState,
+        /// The calibration is disabled after the repeated permanent failures.
+        SafeMode,
+    }
+
+    impl fmt::Display for SkipReason {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            use SkipReason::*;
+            match self {
+                HotSpeaker => write!(f, "hot_speaker"),
+                InvalidShutdownTime => write!(f, "invalid_shutdown_time"),
+                PowerState => write!(f, "power_state"),
+                SafeMode => write!(f, "safe_mode"),
+            }
+        }
+    }
+
+    /// `Skips` represents the skips of the boot time calibration across the boots.
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct Skips {
+        /// The number of the skips by reason.
+        pub counts: BTreeMap<SkipReason, u32>,
+        /// The reason of the latest skip.
+        pub last_reason: Option<SkipReason>,
+        /// The unix time of the latest skip.
+        pub last_time: Option<Duration>,
+    }
+
+    /// Reads the skips of the boot time calibration.
+    pub fn from_file(snd_card: &str) -> Result<Skips> {
+        from_yaml_file(&skips_file(snd_card))
+    }
+
+    /// Counts a skip of the boot time calibration now, emits it as a fallback event, and returns
+    /// the updated skips.
+    pub fn record(snd_card: &str, reason: SkipReason) -> Result<Skips> {
+        events::emit(snd_card, Event::Fallback { reason });
+        let time = clock().now().map_err(Error::SystemTimeError)?;
+        let mut skips = from_file(snd_card).unwrap_or_default();
+        *skips.counts.entry(reason).or_insert(0) += 1;
+        skips.last_reason = Some(reason);
+        skips.last_time = Some(time);
+        to_yaml_file(&skips_file(snd_card), &skips)?;
+        Ok(skips)
+    }
+
+    fn skips_file(snd_card: &str) -> PathBuf {
+        datastore_dir(snd_card).join(SKIPS_FILE)
+    }
+}
+
+/// The utils to wait for a sound card to be enumerated.
+pub mod sound_card {
+    use std::fs;
+    use std::path::Path;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::uevent::UeventMonitor;
+    // The procfs directory of sound cards.
+    const PROC_ASOUND_DIR: &str = "/proc/asound";
+    // The directory of sound device nodes.
+    const DEV_SND_DIR: &str = "/dev/snd";
+    // The udev subsystem of the sound devices.
+    const SOUND_SUBSYSTEM: &str = "sound";
+    // The interval of polling the sound card existence. The udev events only wake up the
+    // polling earlier.
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// Returns true if the control device of the sound card is available.
+    pub fn exists(snd_card: &str) -> bool {
+        // /proc/asound/<snd_card> is a symlink to /proc/asound/card<index>.
+        let card_dir = match fs::read_link(PathBuf::from(PROC_ASOUND_DIR).join(snd_card)) {
+            Ok(dir) => dir,
+            Err(_) => return false,
+        };
+        let index = match card_dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("card"))
+        {
+            Some(index) => index.to_owned(),
+            None => return false,
+        };
+        Path::new(DEV_SND_DIR)
+            .join(format!("controlC{}", index))
+            .exists()
+    }
+
+    /// Blocks until the control device of the sound card is available or `timeout` elapses.
+    /// The sound card existence is polled every `POLL_INTERVAL`, and checked again earlier on
+    /// each udev event of the sound subsystem. The events are a latency optimization only, since
+    /// they don't reach the sockets in another network namespace, ex: the jail of `minijail0 -e`.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to receive the udev events.
+    /// * If the sound card is not available before `timeout`.
+    pub fn wait_for_card(snd_card: &str, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        // Subscribes to the events before checking the existence so that no event is missed.
+        let monitor = UeventMonitor::new().ok();
+        while !exists(snd_card) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Err(Error::WaitForCardTimeout(snd_card.to_owned(), timeout));
+            }
+            let interval = POLL_INTERVAL.min(remaining);
+            match &monitor {
+                Some(monitor) => {
+                    monitor
+                        .wait_for_event(SOUND_SUBSYSTEM, interval)
+                        .map_err(Error::UeventFailed)?;
+                }
+                None => thread::sleep(interval),
+            }
+        }
+        Ok(())
     }
 }
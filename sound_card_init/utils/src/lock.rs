@@ -0,0 +1,135 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It serializes the sound_card_init instances running on the same sound card, ex: a udev
+//! retrigger overlapping the upstart job.
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use log::info;
+
+use crate::datastore_dir;
+use crate::error::{Error, Result};
+
+// The filename of the lock file in the datastore directory of the sound card, which is shared
+// by the sandboxes of all the instances. It's pre-created by the upstart job.
+const LOCK_FILE: &str = "lock";
+
+/// `CardLock` holds the lock of a sound card. It's released when the `CardLock` is dropped.
+pub struct CardLock {
+    _file: File,
+}
+
+impl CardLock {
+    /// Locks the sound card. It blocks until another instance on the sound card finishes.
+    ///
+    /// # Errors
+    ///
+    /// * If the lock file can't be opened or locked.
+    pub fn lock(snd_card: &str) -> Result<Self> {
+        let path = datastore_dir(snd_card).join(LOCK_FILE);
+        let file = open_lock_file(&path).map_err(|e| Error::FileIOFailed(path.clone(), e))?;
+        if !flock(&file, libc::LOCK_EX | libc::LOCK_NB)
+            .map_err(|e| Error::FileIOFailed(path.clone(), e))?
+        {
+            info!("another instance is initializing hw:{}, waiting", snd_card);
+            flock(&file, libc::LOCK_EX).map_err(|e| Error::FileIOFailed(path, e))?;
+        }
+        Ok(CardLock { _file: file })
+    }
+}
+
+// Opens the lock file read only, since flock() does not need the write access. The lock is
+// taken as root before the privileges are dropped, and the jail does not keep
+// CAP_DAC_OVERRIDE to write the files of the sound_card_init user. The file is created only if
+// it's missing, ex: a manual run with `--datastore-dir`.
+fn open_lock_file(path: &Path) -> io::Result<File> {
+    match File::open(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path),
+        res => res,
+    }
+}
+
+// Returns false if the lock is held by another instance and LOCK_NB is set.
+fn flock(file: &File, operation: libc::c_int) -> io::Result<bool> {
+    loop {
+        // Safe because it has no pointer arguments and the return value is checked.
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(true);
+        }
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::WouldBlock => return Ok(false),
+            io::ErrorKind::Interrupted => continue,
+            _ => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::process::CommandExt;
+    use std::path::PathBuf;
+    use std::process::{self, Command};
+
+    use super::*;
+    use crate::set_datastore_dir;
+
+    // The datastore directory of the child process of the test.
+    const CHILD_DIR_ENV: &str = "CARD_LOCK_TEST_DATASTORE_DIR";
+    // The uid and gid of nobody.
+    const NOBODY: u32 = 65534;
+
+    fn set_mode(path: &Path, mode: u32) {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    // The lock path as the user who does not own the datastore, like root without
+    // CAP_DAC_OVERRIDE in the jail. As root, the test runs itself as nobody in a child process.
+    // Otherwise, the datastore of the test user is made read only.
+    #[test]
+    fn locks_as_non_owner() {
+        if let Ok(dir) = env::var(CHILD_DIR_ENV) {
+            set_datastore_dir(PathBuf::from(dir));
+            CardLock::lock("card").unwrap();
+            return;
+        }
+        let root = env::temp_dir().join(format!("card_lock_{}", process::id()));
+        let dir = root.join("card");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(LOCK_FILE), "").unwrap();
+        set_mode(&root, 0o755);
+        // Safe because it has no arguments.
+        let is_root = unsafe { libc::geteuid() } == 0;
+        let locked = if is_root {
+            set_mode(&dir, 0o755);
+            set_mode(&dir.join(LOCK_FILE), 0o644);
+            Command::new(env::current_exe().unwrap())
+                .args(["--exact", "lock::tests::locks_as_non_owner"])
+                .env(CHILD_DIR_ENV, &root)
+                .uid(NOBODY)
+                .gid(NOBODY)
+                .status()
+                .unwrap()
+                .success()
+        } else {
+            set_mode(&dir.join(LOCK_FILE), 0o444);
+            set_mode(&dir, 0o555);
+            set_datastore_dir(root.clone());
+            let locked = CardLock::lock("card").is_ok();
+            set_mode(&dir, 0o755);
+            locked
+        };
+        fs::remove_dir_all(&root).unwrap();
+        assert!(locked);
+    }
+}
@@ -0,0 +1,309 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! A `log` backend which writes the records to syslog, and optionally to stderr.
+//!
+//! The records are filtered by the level of their targets, which are the module paths by
+//! default, ex: `max98390d::amp_calibration`. The levels are given by a `LogSpec`. The recent
+//! records are also kept in memory for the diagnostic snapshots.
+//!
+//! The syslog messages are key=value pairs with the module and the run id of the process, ex:
+//! `module=dsm run_id=1234-1600000000 msg="apply datastore values."`, so the events of a run
+//! can be filtered out of /var/log/messages by `run_id=1234-1600000000`.
+//!
+//! The records can also be written to a run log file of each run, ex:
+//! /var/log/sound_card_init/run.1234-1600000000.log. A run log has at most `MAX_RUN_LOG_BYTES`,
+//! and the oldest run logs are removed to keep all of them within `MAX_RUN_LOGS_BYTES`.
+use std::collections::VecDeque;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use sys_util::syslog::{self, Facility, Priority};
+
+use crate::error::{Error, Result};
+
+/// `LogSpec` represents the log levels of the targets.
+///
+/// It's parsed from a comma separated list of `<level>` and `<target>=<level>`, ex:
+/// `info,max98390d::amp_calibration=debug`. A target also applies to its submodules, and the
+/// longest matching target wins. `<level>` is one of off, error, warn, info, debug and trace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogSpec {
+    level: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Default for LogSpec {
+    fn default() -> Self {
+        LogSpec {
+            level: LevelFilter::Info,
+            targets: Vec::new(),
+        }
+    }
+}
+
+impl FromStr for LogSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidLogSpec(s.to_owned());
+        let mut spec = LogSpec::default();
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => spec.targets.push((
+                    target.trim().to_owned(),
+                    level.trim().parse().map_err(|_| invalid())?,
+                )),
+                None => spec.level = directive.parse().map_err(|_| invalid())?,
+            }
+        }
+        Ok(spec)
+    }
+}
+
+impl LogSpec {
+    /// Returns the level of the target.
+    pub fn level_of(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(t, _)| is_within(target, t))
+            .max_by_key(|(t, _)| t.len())
+            .map_or(self.level, |(_, level)| *level)
+    }
+
+    // The most verbose level of all the targets.
+    fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.level, Ord::max)
+    }
+}
+
+// Returns true if the target is the module or one of its submodules.
+fn is_within(target: &str, module: &str) -> bool {
+    target == module
+        || target
+            .strip_prefix(module)
+            .map_or(false, |rest| rest.starts_with("::"))
+}
+
+// The module names of the targets in the syslog messages. The other targets are named by their
+// last path segment.
+const MODULES: &[(&str, &str)] = &[
+    ("max98390d::amp_calibration", "dsm"),
+    ("max98390d::amp_calibration::zero_player", "zero_player"),
+    ("max98390d::datastore", "datastore"),
+    ("sound_card_init::amp", "amp"),
+];
+
+/// Returns the module name of the target.
+pub fn module_of(target: &str) -> &str {
+    MODULES
+        .iter()
+        .filter(|(t, _)| is_within(target, t))
+        .max_by_key(|(t, _)| t.len())
+        .map_or_else(
+            || target.rsplit("::").next().unwrap_or(target),
+            |(_, module)| *module,
+        )
+}
+
+static RUN_ID: OnceLock<String> = OnceLock::new();
+
+/// Returns the id of this run, which is `<pid>-<unix time of the first call>`.
+pub fn run_id() -> &'static str {
+    RUN_ID.get_or_init(|| {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |t| t.as_secs());
+        format!("{}-{}", process::id(), time)
+    })
+}
+
+// Quotes the message value, so the message can contain spaces and the other keys.
+fn quote(msg: &str) -> String {
+    format!("\"{}\"", msg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// The number of the recent records kept in memory.
+const HISTORY_LEN: usize = 100;
+static HISTORY: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Returns the recent log records, from the oldest one.
+pub fn recent() -> Vec<String> {
+    HISTORY
+        .lock()
+        .map_or_else(|_| Vec::new(), |history| history.iter().cloned().collect())
+}
+
+fn remember(record: &Record) {
+    if let Ok(mut history) = HISTORY.lock() {
+        if history.len() == HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back(format!(
+            "{} {}: {}",
+            record.level(),
+            record.target(),
+            record.args()
+        ));
+    }
+}
+
+/// The maximum size of a run log.
+pub const MAX_RUN_LOG_BYTES: u64 = 256 * 1024;
+/// The maximum total size of the run logs in the directory.
+pub const MAX_RUN_LOGS_BYTES: u64 = 1024 * 1024;
+const RUN_LOG_PREFIX: &str = "run.";
+const RUN_LOG_EXTENSION: &str = "log";
+
+struct RunLog {
+    file: File,
+    written: u64,
+}
+
+static RUN_LOG: Mutex<Option<RunLog>> = Mutex::new(None);
+
+/// Opens the run log of this run in the directory, and removes the oldest run logs so that the
+/// run logs stay within `MAX_RUN_LOGS_BYTES` after this run. The records afterwards are also
+/// written to the run log. It returns the path of the run log.
+///
+/// # Errors
+///
+/// * If the directory can't be read or the run log can't be created.
+pub fn open_run_log(dir: &Path) -> io::Result<PathBuf> {
+    remove_old_run_logs(dir)?;
+    let path = dir
+        .join(format!("{}{}", RUN_LOG_PREFIX, run_id()))
+        .with_extension(RUN_LOG_EXTENSION);
+    let file = File::create(&path)?;
+    if let Ok(mut run_log) = RUN_LOG.lock() {
+        *run_log = Some(RunLog { file, written: 0 });
+    }
+    Ok(path)
+}
+
+// Removes the oldest run logs until the others and a full run log fit in MAX_RUN_LOGS_BYTES.
+fn remove_old_run_logs(dir: &Path) -> io::Result<()> {
+    let mut logs: Vec<(SystemTime, u64, PathBuf)> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension() == Some(OsStr::new(RUN_LOG_EXTENSION))
+                && path
+                    .file_name()
+                    .and_then(OsStr::to_str)
+                    .map_or(false, |name| name.starts_with(RUN_LOG_PREFIX))
+        })
+        .filter_map(|path| {
+            let metadata = fs::metadata(&path).ok()?;
+            Some((metadata.modified().ok()?, metadata.len(), path))
+        })
+        .collect();
+    logs.sort();
+    let mut total: u64 = logs.iter().map(|(_, len, _)| len).sum();
+    for (_, len, path) in &logs {
+        if total + MAX_RUN_LOG_BYTES <= MAX_RUN_LOGS_BYTES {
+            break;
+        }
+        fs::remove_file(path)?;
+        total -= len;
+    }
+    Ok(())
+}
+
+// Appends the record to the run log until it's full. The write failures are dropped since
+// syslog has the records as well.
+fn write_run_log(record: &Record, msg: &str) {
+    let mut run_log = match RUN_LOG.lock() {
+        Ok(run_log) => run_log,
+        Err(_) => return,
+    };
+    let log = match run_log.as_mut() {
+        Some(log) if log.written < MAX_RUN_LOG_BYTES => log,
+        _ => return,
+    };
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut line = format!(
+        "{}.{:03} {} {}\n",
+        time.as_secs(),
+        time.subsec_millis(),
+        record.level(),
+        msg
+    );
+    if log.written + line.len() as u64 > MAX_RUN_LOG_BYTES {
+        line = "run log is full, the remaining records are in syslog only\n".to_owned();
+    }
+    log.written += line.len() as u64;
+    if log.file.write_all(line.as_bytes()).is_err() {
+        log.written = MAX_RUN_LOG_BYTES;
+    }
+}
+
+struct SyslogLogger {
+    spec: LogSpec,
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.spec.level_of(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let priority = match record.level() {
+            Level::Error => Priority::Error,
+            Level::Warn => Priority::Warning,
+            Level::Info => Priority::Info,
+            Level::Debug | Level::Trace => Priority::Debug,
+        };
+        remember(record);
+        let msg = format!(
+            "module={} run_id={} msg={}",
+            module_of(record.target()),
+            run_id(),
+            quote(&record.args().to_string())
+        );
+        write_run_log(record, &msg);
+        syslog::log(
+            priority,
+            Facility::User,
+            record.file().zip(record.line()),
+            format_args!("{}", msg),
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+/// Initializes syslog and installs the logger of the `log` macros.
+///
+/// # Arguments
+///
+/// * `spec` - The log levels of the targets.
+/// * `stderr` - Also writes the records to stderr if it's true.
+///
+/// # Errors
+///
+/// * If syslog can't be initialized.
+/// * If a logger is already installed.
+pub fn init(spec: LogSpec, stderr: bool) -> Result<()> {
+    syslog::init().map_err(|e| Error::LoggerInitFailed(e.to_string()))?;
+    syslog::echo_stderr(stderr);
+    // Fixes the run id before the first record.
+    run_id();
+    log::set_max_level(spec.max_level());
+    log::set_boxed_logger(Box::new(SyslogLogger { spec }))
+        .map_err(|e| Error::LoggerInitFailed(e.to_string()))
+}
@@ -0,0 +1,119 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It reports UMA metrics through metrics_lib. The metrics are dropped unless the `metrics`
+//! feature is enabled, which links libmetrics.
+//!
+//! The metrics are best-effort. The failures are logged and never fail the caller.
+//!
+//! libmetrics appends the samples to the events file in `METRICS_DIR`, which the metrics
+//! daemon uploads, so the directory must be writable in the sandbox.
+
+/// The directory of the events file of libmetrics.
+pub const METRICS_DIR: &str = "/var/lib/metrics";
+
+/// Sends an enum sample in [0, max) to UMA.
+pub fn send_enum(name: &str, sample: i32, max: i32) {
+    log::debug!("UMA enum {}: {}", name, sample);
+    imp::send_enum(name, sample, max);
+}
+
+/// Sends a sample to the UMA histogram with exponential buckets in [min, max].
+pub fn send_histogram(name: &str, sample: i32, min: i32, max: i32, nbuckets: i32) {
+    log::debug!("UMA histogram {}: {}", name, sample);
+    imp::send_histogram(name, sample, min, max, nbuckets);
+}
+
+/// Sends a sample to the UMA sparse histogram, which has a bucket of each distinct sample.
+pub fn send_sparse(name: &str, sample: i32) {
+    log::debug!("UMA sparse {}: {}", name, sample);
+    imp::send_sparse(name, sample);
+}
+
+#[cfg(feature = "metrics")]
+mod imp {
+    use std::ffi::CString;
+
+    use libc::{c_char, c_int, c_void};
+    use log::error;
+
+    type CMetricsLibrary = *mut c_void;
+
+    #[link(name = "metrics")]
+    extern "C" {
+        fn CMetricsLibraryNew() -> CMetricsLibrary;
+        fn CMetricsLibraryDelete(handle: CMetricsLibrary);
+        fn CMetricsLibrarySendEnumToUMA(
+            handle: CMetricsLibrary,
+            name: *const c_char,
+            sample: c_int,
+            max: c_int,
+        ) -> c_int;
+        fn CMetricsLibrarySendToUMA(
+            handle: CMetricsLibrary,
+            name: *const c_char,
+            sample: c_int,
+            min: c_int,
+            max: c_int,
+            nbuckets: c_int,
+        ) -> c_int;
+        fn CMetricsLibrarySendSparseToUMA(
+            handle: CMetricsLibrary,
+            name: *const c_char,
+            sample: c_int,
+        ) -> c_int;
+    }
+
+    // Runs `f` with a new metrics library handle, and logs the failure if `f` returns 0.
+    fn send(name: &str, f: impl FnOnce(CMetricsLibrary, *const c_char) -> c_int) {
+        let c_name = match CString::new(name) {
+            Ok(c_name) => c_name,
+            Err(_) => {
+                error!("invalid UMA metric name: {}", name);
+                return;
+            }
+        };
+        // Safe because the handle is only used by `f` and deleted before return.
+        unsafe {
+            let handle = CMetricsLibraryNew();
+            if handle.is_null() {
+                error!("failed to create metrics library");
+                return;
+            }
+            if f(handle, c_name.as_ptr()) == 0 {
+                error!("failed to send UMA metric {}", name);
+            }
+            CMetricsLibraryDelete(handle);
+        }
+    }
+
+    pub fn send_enum(name: &str, sample: i32, max: i32) {
+        // Safe because the handle and name are valid during the call.
+        send(name, |handle, name| unsafe {
+            CMetricsLibrarySendEnumToUMA(handle, name, sample, max)
+        });
+    }
+
+    pub fn send_histogram(name: &str, sample: i32, min: i32, max: i32, nbuckets: i32) {
+        // Safe because the handle and name are valid during the call.
+        send(name, |handle, name| unsafe {
+            CMetricsLibrarySendToUMA(handle, name, sample, min, max, nbuckets)
+        });
+    }
+
+    pub fn send_sparse(name: &str, sample: i32) {
+        // Safe because the handle and name are valid during the call.
+        send(name, |handle, name| unsafe {
+            CMetricsLibrarySendSparseToUMA(handle, name, sample)
+        });
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod imp {
+    pub fn send_enum(_name: &str, _sample: i32, _max: i32) {}
+
+    pub fn send_histogram(_name: &str, _sample: i32, _min: i32, _max: i32, _nbuckets: i32) {}
+
+    pub fn send_sparse(_name: &str, _sample: i32) {}
+}
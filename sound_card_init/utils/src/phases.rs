@@ -0,0 +1,73 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It records the durations of the phases of sound_card_init, ex: the sound card open and the
+//! datastore write, so that the boot time regressions can be attributed to a step. The
+//! durations are printed by `--time-phases` and reported to UMA.
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static PHASE_TIMES: Mutex<Vec<PhaseTime>> = Mutex::new(Vec::new());
+
+/// `PhaseTime` is the duration of a phase on a sound card.
+#[derive(Debug, Clone)]
+pub struct PhaseTime {
+    /// The sound card name.
+    pub snd_card: String,
+    /// The phase name, ex: vpd read.
+    pub phase: &'static str,
+    /// The duration of the phase.
+    pub duration: Duration,
+}
+
+/// `Phase` records the duration of a phase when it's dropped.
+#[must_use = "the phase ends when it's dropped"]
+pub struct Phase<'a> {
+    snd_card: &'a str,
+    phase: &'static str,
+    start: Instant,
+}
+
+impl Drop for Phase<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut times) = PHASE_TIMES.lock() {
+            times.push(PhaseTime {
+                snd_card: self.snd_card.to_owned(),
+                phase: self.phase,
+                duration: self.start.elapsed(),
+            });
+        }
+    }
+}
+
+/// Starts a phase on the sound card. The phase ends when the returned `Phase` is dropped.
+pub fn start<'a>(snd_card: &'a str, phase: &'static str) -> Phase<'a> {
+    Phase {
+        snd_card,
+        phase,
+        start: Instant::now(),
+    }
+}
+
+/// Returns the total duration of each phase on the sound card in the order the phases first
+/// ended. The phases of the amp channels are added up, ex: the VPD read of every channel.
+pub fn totals_of(snd_card: &str) -> Vec<(&'static str, Duration)> {
+    let mut totals: Vec<(&'static str, Duration)> = Vec::new();
+    if let Ok(times) = PHASE_TIMES.lock() {
+        for t in times.iter().filter(|t| t.snd_card == snd_card) {
+            match totals.iter_mut().find(|(phase, _)| *phase == t.phase) {
+                Some((_, total)) => *total += t.duration,
+                None => totals.push((t.phase, t.duration)),
+            }
+        }
+    }
+    totals
+}
+
+/// Returns the recorded phase durations in the order the phases ended, and clears them.
+pub fn take() -> Vec<PhaseTime> {
+    PHASE_TIMES
+        .lock()
+        .map(|mut times| times.drain(..).collect())
+        .unwrap_or_default()
+}
@@ -0,0 +1,42 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It notifies the service manager that the speaker protection is in place by the sd_notify
+//! protocol. The upstart job emits its own event when sound_card_init exits, so it's only
+//! needed by the service managers which set NOTIFY_SOCKET, ex: in the daemon mode.
+use std::env;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+
+use crate::error::{Error, Result};
+
+// The environment variable of the notification socket path.
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+
+/// Notifies the service manager that the audio path of the sound card is safe to use. It does
+/// nothing if NOTIFY_SOCKET is not set.
+///
+/// # Errors
+///
+/// * If the socket is an abstract socket, which is not supported.
+/// * If it fails to send the notification.
+pub fn notify_ready(snd_card: &str) -> Result<()> {
+    let path = match env::var_os(NOTIFY_SOCKET_ENV) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    if path.to_string_lossy().starts_with('@') {
+        return Err(Error::NotifyFailed(io::Error::from(
+            io::ErrorKind::Unsupported,
+        )));
+    }
+    let msg = format!(
+        "READY=1\nSTATUS=speaker protection of {} is in place",
+        snd_card
+    );
+    let socket = UnixDatagram::unbound().map_err(Error::NotifyFailed)?;
+    socket
+        .send_to(msg.as_bytes(), path)
+        .map_err(Error::NotifyFailed)?;
+    Ok(())
+}
@@ -0,0 +1,82 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! It instruments the calibration pipeline with `tracing` spans if the `tracing` feature is
+//! enabled, ex: the sound card open, the measurement of each channel and the control writes.
+//!
+//! The spans are written to `TRACE_FILE` in the folded stack format, so that a flamegraph can
+//! be made on the DUT by `inferno-flamegraph < /tmp/sound_card_init.folded > flame.svg`.
+//! `trace_span!` expands to nothing without the feature.
+
+#[cfg(feature = "tracing")]
+pub use tracing;
+
+/// The file of the folded stacks of the spans.
+pub const TRACE_FILE: &str = "/tmp/sound_card_init.folded";
+
+/// Enters a span until the returned guard is dropped. The arguments are the ones of
+/// `tracing::info_span!`, ex: `trace_span!("control write", ctrl = %name)`.
+#[cfg(feature = "tracing")]
+#[macro_export]
+macro_rules! trace_span {
+    ($($args:tt)*) => {
+        $crate::trace::tracing::info_span!($($args)*).entered()
+    };
+}
+
+/// Enters a span until the returned guard is dropped. It's a no-op without the `tracing`
+/// feature.
+#[cfg(not(feature = "tracing"))]
+#[macro_export]
+macro_rules! trace_span {
+    ($($args:tt)*) => {
+        $crate::trace::NoSpan
+    };
+}
+
+/// The span guard without the `tracing` feature.
+#[cfg(not(feature = "tracing"))]
+pub struct NoSpan;
+
+/// `TraceGuard` writes out the remaining spans when it's dropped.
+pub struct TraceGuard {
+    #[cfg(feature = "tracing")]
+    flush: Option<tracing_flame::FlushGuard<std::io::BufWriter<std::fs::File>>>,
+}
+
+impl TraceGuard {
+    /// Writes out the remaining spans. It's needed before `process::exit`, which skips the
+    /// drops.
+    pub fn flush(&self) {
+        #[cfg(feature = "tracing")]
+        if let Some(Err(e)) = self.flush.as_ref().map(|flush| flush.flush()) {
+            log::warn!("failed to write {}: {}", TRACE_FILE, e);
+        }
+    }
+}
+
+/// Installs the subscriber which writes the spans to `TRACE_FILE`. The spans are dropped if
+/// the file can't be created, and the failure is logged only.
+#[cfg(feature = "tracing")]
+pub fn init() -> TraceGuard {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let (layer, flush) = match tracing_flame::FlameLayer::with_file(TRACE_FILE) {
+        Ok(flame) => flame,
+        Err(e) => {
+            log::warn!("failed to create {}: {}", TRACE_FILE, e);
+            return TraceGuard { flush: None };
+        }
+    };
+    let subscriber = tracing_subscriber::registry().with(layer);
+    if let Err(e) = tracing::subscriber::set_global_default(subscriber) {
+        log::warn!("failed to install the tracing subscriber: {}", e);
+    }
+    TraceGuard { flush: Some(flush) }
+}
+
+/// Installs the subscriber of the spans. It's a no-op without the `tracing` feature.
+#[cfg(not(feature = "tracing"))]
+pub fn init() -> TraceGuard {
+    TraceGuard {}
+}
@@ -0,0 +1,150 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! A minimal monitor of the udev events broadcast over the kobject uevent netlink socket.
+//! The multicast groups are local to the network namespace, so no event is received in a new
+//! network namespace.
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::time::{Duration, Instant};
+
+use libc::{c_void, pollfd, sockaddr, sockaddr_nl, POLLIN};
+
+// The netlink multicast group of the events sent by udevd after the rules are processed.
+// The device nodes have their final permissions by the time the events are received.
+const UDEV_MONITOR_GROUP: u32 = 2;
+// The udev monitor messages start with the prefix, followed by a header which holds the
+// offset and the length of the NUL separated properties.
+const UDEV_MONITOR_PREFIX: &[u8] = b"libudev\0";
+const PROPERTIES_OFF_IDX: usize = 16;
+const PROPERTIES_LEN_IDX: usize = 20;
+const MESSAGE_SIZE: usize = 8192;
+
+/// `UeventMonitor` receives the udev events.
+///
+/// The sender of the events is not verified, so the events should only be used as hints to
+/// check the device state again.
+pub struct UeventMonitor {
+    socket: File,
+}
+
+impl UeventMonitor {
+    /// Creates a `UeventMonitor` subscribing to the udev events.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to create or bind the netlink socket.
+    pub fn new() -> io::Result<Self> {
+        // Safe because it has no pointer arguments and the return value is checked.
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because fd is a new file descriptor exclusively owned by the `File`.
+        let socket = unsafe { File::from_raw_fd(fd) };
+
+        // Safe because sockaddr_nl is a plain C struct which can be zero initialized.
+        let mut addr: sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        addr.nl_groups = UDEV_MONITOR_GROUP;
+        // Safe because addr is a valid sockaddr_nl and its size is passed along.
+        let rc = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &addr as *const sockaddr_nl as *const sockaddr,
+                mem::size_of::<sockaddr_nl>() as u32,
+            )
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(UeventMonitor { socket })
+    }
+
+    /// Waits for an event of the subsystem, ex: sound.
+    ///
+    /// # Results
+    ///
+    /// * true if an event of the subsystem is received, false if `timeout` elapses.
+    ///
+    /// # Errors
+    ///
+    /// * If it fails to poll or read the netlink socket.
+    pub fn wait_for_event(&self, subsystem: &str, timeout: Duration) -> io::Result<bool> {
+        let deadline = Instant::now() + timeout;
+        let property = format!("SUBSYSTEM={}", subsystem);
+        let mut buf = vec![0u8; MESSAGE_SIZE];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if !self.poll(remaining)? {
+                return Ok(false);
+            }
+            // Safe because buf is valid for writes of buf.len() bytes.
+            let len = unsafe {
+                libc::recv(
+                    self.socket.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut c_void,
+                    buf.len(),
+                    libc::MSG_DONTWAIT,
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => continue,
+                    _ => return Err(err),
+                }
+            }
+            if properties(&buf[..len as usize]).any(|p| p == property.as_bytes()) {
+                return Ok(true);
+            }
+        }
+    }
+
+    // Returns true if the socket is readable before `timeout`.
+    fn poll(&self, timeout: Duration) -> io::Result<bool> {
+        // Rounds up the timeout so that it does not return before the deadline.
+        let timeout_ms = timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32;
+        let mut pfd = pollfd {
+            fd: self.socket.as_raw_fd(),
+            events: POLLIN,
+            revents: 0,
+        };
+        // Safe because pfd is a valid pollfd and nfds is 1.
+        let rc = unsafe { libc::poll(&mut pfd, 1, timeout_ms) };
+        if rc < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(true);
+            }
+            return Err(err);
+        }
+        Ok(rc > 0)
+    }
+}
+
+// Iterates over the properties of a udev monitor message. It's empty if the message is
+// malformed.
+fn properties(msg: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let read_u32 = |idx: usize| {
+        msg.get(idx..idx + 4)
+            .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    };
+    let props = match (
+        msg.starts_with(UDEV_MONITOR_PREFIX),
+        read_u32(PROPERTIES_OFF_IDX),
+        read_u32(PROPERTIES_LEN_IDX),
+    ) {
+        (true, Some(off), Some(len)) => msg.get(off..off.saturating_add(len)).unwrap_or(&[]),
+        _ => &[],
+    };
+    props.split(|&b| b == 0).filter(|p| !p.is_empty())
+}